@@ -0,0 +1,227 @@
+use std::{io::{self, Write}, path::Path, fs};
+
+use tiny_skia::Pixmap;
+
+use crate::pool::{NodeId, CarveEvent, CarveObserver};
+
+
+
+/// What a generator was doing when a frame was taken.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FrameMark {
+    /// How many of the recorded links had been carved.
+    pub links: usize,
+    /// The node the generator was at.
+    pub current: Option<NodeId>,
+    /// The random walk in progress, loop erased, from its start to its head, less any steps already carved. Only Wilson's
+    /// algorithm walks.
+    pub walk: Vec<NodeId>,
+}
+
+/// Records the links carved by a generator, marking a frame every `every` links.
+///
+/// The frames are drawn afterwards by replaying the links, see [`crate::maze::Maze::animation_frames`].
+#[derive(Debug)]
+pub struct FrameRecorder {
+    every: usize,
+    pub links: Vec<(NodeId, NodeId)>,
+    pub frames: Vec<FrameMark>,
+    current: Option<NodeId>,
+    walk: Vec<NodeId>,
+}
+
+impl FrameRecorder {
+    pub fn new(every: usize) -> Self {
+        assert!(every > 0, "Frames must be at least one link apart");
+        FrameRecorder { every, links: vec![], frames: vec![], current: None, walk: vec![] }
+    }
+}
+
+impl CarveObserver for FrameRecorder {
+    fn observe(&mut self, event: CarveEvent<'_>) {
+        match event {
+            CarveEvent::Visit(n) => self.current = Some(n),
+            CarveEvent::WalkerStep { head, path } => {
+                self.current = Some(head);
+                self.walk = path.to_vec();
+            },
+            CarveEvent::Link(here, there) => {
+                self.links.push((here, there));
+                self.current = Some(there);
+                // Walks are carved from their start, and the carved steps become part of the maze
+                if self.walk.first() == Some(&here) {
+                    self.walk.remove(0);
+                }
+                if self.walk.len() < 2 {
+                    self.walk.clear();
+                }
+                if self.links.len().is_multiple_of(self.every) {
+                    self.frames.push(FrameMark { links: self.links.len(), current: self.current, walk: self.walk.clone() });
+                }
+            },
+            CarveEvent::LinkRemoved(here, there) => self.links.retain(|&link| link != (here, there) && link != (there, here)),
+            // The walker step that follows carries the shortened walk
+            CarveEvent::LoopErased { .. } => {},
+        }
+    }
+}
+
+/// Writes every frame as a numbered PNG into `dir`, creating it if needed.
+pub fn write_frames(frames: &[Pixmap], dir: &Path) -> io::Result<()> {
+    fs::create_dir_all(dir)?;
+    for (i, frame) in frames.iter().enumerate() {
+        frame.save_png(dir.join(format!("frame-{:05}.png", i))).map_err(io::Error::other)?;
+    }
+    Ok(())
+}
+
+/// Encodes frames of the same size as an animated PNG that loops forever, showing each frame for `delay_ms` milliseconds.
+///
+/// Each frame is encoded as a PNG on its own, then its image data is moved into the frame chunks of the animation.
+pub fn encode_apng(frames: &[Pixmap], delay_ms: u16, mut out: impl Write) -> io::Result<()> {
+    let (first, _) = frames.split_first().ok_or_else(|| io::Error::other("an animation needs at least one frame"))?;
+    out.write_all(PNG_SIGNATURE)?;
+    let first_png = first.encode_png().map_err(io::Error::other)?;
+    for (kind, data) in png_chunks(&first_png)? {
+        if kind == *b"IHDR" {
+            write_chunk(&mut out, b"IHDR", data)?;
+        }
+    }
+    let mut animation_control = (frames.len() as u32).to_be_bytes().to_vec();
+    // Loop forever
+    animation_control.extend(0u32.to_be_bytes());
+    write_chunk(&mut out, b"acTL", &animation_control)?;
+
+    let mut sequence = 0u32;
+    for (i, frame) in frames.iter().enumerate() {
+        if (frame.width(), frame.height()) != (first.width(), first.height()) {
+            return Err(io::Error::other("every frame of an animation must be the same size"));
+        }
+        let mut frame_control = sequence.to_be_bytes().to_vec();
+        frame_control.extend(frame.width().to_be_bytes());
+        frame_control.extend(frame.height().to_be_bytes());
+        // Offsets of the frame within the image
+        frame_control.extend(0u32.to_be_bytes());
+        frame_control.extend(0u32.to_be_bytes());
+        frame_control.extend(delay_ms.to_be_bytes());
+        frame_control.extend(1000u16.to_be_bytes());
+        // Neither dispose of the frame afterwards nor blend it over the previous one
+        frame_control.extend([0, 0]);
+        write_chunk(&mut out, b"fcTL", &frame_control)?;
+        sequence += 1;
+
+        let png = frame.encode_png().map_err(io::Error::other)?;
+        for (kind, data) in png_chunks(&png)? {
+            if kind != *b"IDAT" {
+                continue;
+            }
+            if i == 0 {
+                write_chunk(&mut out, b"IDAT", data)?;
+            } else {
+                let mut frame_data = sequence.to_be_bytes().to_vec();
+                frame_data.extend(data);
+                write_chunk(&mut out, b"fdAT", &frame_data)?;
+                sequence += 1;
+            }
+        }
+    }
+    write_chunk(&mut out, b"IEND", &[])
+}
+
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+/// Splits an encoded PNG into its chunks, by type.
+fn png_chunks(png: &[u8]) -> io::Result<Vec<([u8; 4], &[u8])>> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "malformed PNG chunk");
+    let mut rest = png.strip_prefix(PNG_SIGNATURE).ok_or_else(invalid)?;
+    let mut chunks = vec![];
+    while !rest.is_empty() {
+        let header = rest.get(..8).ok_or_else(invalid)?;
+        let length = u32::from_be_bytes(header[..4].try_into().unwrap()) as usize;
+        let kind: [u8; 4] = header[4..].try_into().unwrap();
+        let data = rest.get(8..8 + length).ok_or_else(invalid)?;
+        chunks.push((kind, data));
+        // Skip the checksum
+        rest = rest.get(12 + length..).ok_or_else(invalid)?;
+    }
+    Ok(chunks)
+}
+
+fn write_chunk(out: &mut impl Write, kind: &[u8; 4], data: &[u8]) -> io::Result<()> {
+    out.write_all(&(data.len() as u32).to_be_bytes())?;
+    out.write_all(kind)?;
+    out.write_all(data)?;
+    out.write_all(&crc32(kind.iter().chain(data)).to_be_bytes())
+}
+
+/// The CRC-32 checksum closing every PNG chunk.
+fn crc32<'a>(bytes: impl Iterator<Item = &'a u8>) -> u32 {
+    let mut crc = u32::MAX;
+    for byte in bytes {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, SeedableRng};
+    use tiny_skia::Color;
+
+    use crate::pool::Pool;
+
+    use super::*;
+
+    #[test]
+    fn frames_follow_links() {
+        let mut pool: Pool<()> = Pool::new();
+        let ids: Vec<NodeId> = (0..9).map(|_| pool.new_node(|_| ())).collect();
+        for i in 0..9 {
+            if i % 3 < 2 {
+                pool.make_adjacent(ids[i], ids[i + 1], true);
+            }
+            if i < 6 {
+                pool.make_adjacent(ids[i], ids[i + 3], true);
+            }
+        }
+        let mut recorder = FrameRecorder::new(3);
+        pool.wilson(&mut StdRng::seed_from_u64(1), &mut recorder);
+        assert_eq!(recorder.links.len(), 8);
+        let carved: Vec<usize> = recorder.frames.iter().map(|f| f.links).collect();
+        assert_eq!(carved, [3, 6]);
+    }
+
+    #[test]
+    fn frames_show_walks_being_carved() {
+        let mut grid = crate::masked_grid::MaskedGrid::new_unmasked(15, 15);
+        for every in [1, 2, 5] {
+            let mut recorder = FrameRecorder::new(every);
+            grid.pool.clear_links();
+            grid.pool.wilson(&mut StdRng::seed_from_u64(3), &mut recorder);
+            assert!(recorder.frames.iter().any(|frame| !frame.walk.is_empty()), "no walk in frames every {} links", every);
+            for frame in &recorder.frames {
+                assert!(frame.walk.windows(2).all(|step| grid.pool.neighborhood_of(step[0]).contains(&step[1])));
+            }
+        }
+    }
+
+    #[test]
+    fn animated_png_layout() {
+        assert_eq!(crc32(b"IEND".iter()), 0xAE42_6082);
+        let frames: Vec<Pixmap> = [Color::WHITE, Color::BLACK, Color::WHITE].into_iter().map(|color| {
+            let mut frame = Pixmap::new(4, 3).unwrap();
+            frame.fill(color);
+            frame
+        }).collect();
+        let mut apng = vec![];
+        encode_apng(&frames, 100, &mut apng).unwrap();
+        let kinds: Vec<[u8; 4]> = png_chunks(&apng).unwrap().into_iter().map(|(kind, _)| kind).collect();
+        assert_eq!(kinds, [*b"IHDR", *b"acTL", *b"fcTL", *b"IDAT", *b"fcTL", *b"fdAT", *b"fcTL", *b"fdAT", *b"IEND"]);
+        // Still a valid PNG, showing the first frame
+        let decoded = Pixmap::decode_png(&apng).unwrap();
+        assert_eq!(decoded, frames[0]);
+    }
+}
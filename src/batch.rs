@@ -0,0 +1,287 @@
+use std::{path::{Path, PathBuf}, fmt::Display, str::FromStr, sync::{Mutex, atomic::{AtomicUsize, Ordering}}, panic::{self, AssertUnwindSafe}, io, thread, num::NonZeroUsize};
+
+use crate::{cli::{Command, CommandBuilder, CommandError, Destination, Source, ShapeArgs, RenderArgs, EncodingArgs, parse_cell_icon}, parsers::{self, JobSection, JobFileError}, render_style::{parse_color, parse_line_cap, parse_line_join, parse_inset}};
+
+
+
+/// A problem with the fields of a single job. Other jobs in the file are unaffected.
+#[derive(Debug, PartialEq, Eq)]
+pub enum JobError {
+    UnknownField(String),
+    DuplicateField(String),
+    InvalidValue { key: String, value: String, message: String },
+    /// Two fields that cannot be used together were both given.
+    ConflictingFields(&'static str, &'static str),
+    Command(CommandError),
+}
+
+impl Display for JobError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            JobError::UnknownField(key) => write!(f, "unknown field `{}`", key),
+            JobError::DuplicateField(key) => write!(f, "field `{}` is given more than once", key),
+            JobError::InvalidValue { key, value, message } => write!(f, "invalid value `{}` for `{}`: {}", value, key, message),
+            JobError::ConflictingFields(a, b) => write!(f, "give either `{}` or `{}`, not both", a, b),
+            JobError::Command(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<CommandError> for JobError {
+    fn from(e: CommandError) -> Self {
+        JobError::Command(e)
+    }
+}
+
+/// Errors that stop a whole batch from running.
+#[derive(Debug)]
+pub enum BatchError {
+    Open { path: PathBuf, error: io::Error },
+    Layout(JobFileError),
+}
+
+impl Display for BatchError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BatchError::Open { path, error } => write!(f, "could not read {}: {}", path.display(), error),
+            BatchError::Layout(e) => write!(f, "line {} of the job file: {}", e.line, e.kind),
+        }
+    }
+}
+
+/// How a job went.
+#[derive(Debug)]
+pub struct JobReport {
+    pub name: String,
+    pub result: Result<(), String>,
+}
+
+/// The fields of a job section, handed out by key so that leftover keys can be reported as unknown.
+struct JobFields {
+    fields: Vec<(String, String)>,
+}
+
+impl JobFields {
+    fn get<T: FromStr>(&mut self, key: &str) -> Result<Option<T>, JobError> where T::Err: Display {
+        self.get_with(key, |value| value.parse().map_err(|e: T::Err| e.to_string()))
+    }
+
+    fn get_with<T>(&mut self, key: &str, parse: impl Fn(&str) -> Result<T, String>) -> Result<Option<T>, JobError> {
+        let mut matching = self.fields.iter().filter(|(k, _)| k == key);
+        let value = match (matching.next(), matching.next()) {
+            (None, _) => return Ok(None),
+            (Some(_), Some(_)) => return Err(JobError::DuplicateField(key.to_string())),
+            (Some((_, value)), None) => value.clone(),
+        };
+        self.fields.retain(|(k, _)| k != key);
+        parse(&value).map(Some).map_err(|message| JobError::InvalidValue { key: key.to_string(), value, message })
+    }
+
+    fn flag(&mut self, key: &str) -> Result<bool, JobError> {
+        Ok(self.get(key)?.unwrap_or(false))
+    }
+
+    fn finish(self) -> Result<(), JobError> {
+        match self.fields.into_iter().next() {
+            Some((key, _)) => Err(JobError::UnknownField(key)),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Builds the command described by a job section.
+///
+/// The fields are the long command line options with `_` in place of `-`, as the "Batch jobs" section of the README
+/// describes, along with `input`, `solve` and `stats`.
+pub fn job_command(section: &JobSection) -> Result<Command, JobError> {
+    let mut fields = JobFields { fields: section.fields.clone() };
+    let input: Option<PathBuf> = fields.get("input")?;
+    let shape = ShapeArgs {
+        width: fields.get("width")?,
+        height: fields.get("height")?,
+        rings: fields.get("rings")?,
+        branches: fields.get("branches")?.unwrap_or(6),
+        mask: fields.get("mask")?,
+        mask_channel: fields.get("mask_channel")?.unwrap_or_default(),
+        mask_threshold: fields.get("mask_threshold")?.unwrap_or(0.5),
+        invert_mask: fields.flag("invert_mask")?,
+        mask_cell_pixels: fields.get("mask_cell_pixels")?.unwrap_or(NonZeroUsize::MIN),
+        despeckle: fields.get("despeckle")?.unwrap_or(0),
+        fill_holes: fields.get("fill_holes")?.unwrap_or(0),
+        mask_preview: fields.get("mask_preview")?,
+        shape: fields.get("shape")?,
+        lettering: fields.get("lettering")?,
+        letter_scale: fields.get("letter_scale")?.unwrap_or(NonZeroUsize::new(2).unwrap()),
+        letter_spacing: fields.get("letter_spacing")?.unwrap_or(1),
+        procedural: fields.get("procedural")?,
+        mask_seed: fields.get("mask_seed")?,
+        regions: fields.get("regions")?,
+        region_wall_width: fields.get("region_wall_width")?,
+        nest: fields.get_with("nest", |value| value.split(';').map(|level| level.trim().parse()).collect())?.unwrap_or_default(),
+        subdivide: fields.get_with("subdivide", |value| value.split(';').map(|size| size.trim().parse().map_err(|_| format!("`{}` is not a positive number of cells", size.trim()))).collect())?.unwrap_or_default(),
+        disconnected: fields.get("disconnected")?.unwrap_or_default(),
+    };
+    let render = RenderArgs {
+        image_width: fields.get("image_width")?.unwrap_or(1000),
+        cell_size: fields.get("cell_size")?,
+        padding: fields.get("padding")?.unwrap_or(8),
+        distances: fields.flag("distances")?,
+        palette: fields.get("palette")?.unwrap_or_default(),
+        blend: fields.get("blend")?.unwrap_or_default(),
+        easing: fields.get("easing")?.unwrap_or_default(),
+        remap: fields.get("remap")?.unwrap_or_default(),
+        unicode: fields.flag("unicode")?,
+        wall_width: fields.get("wall_width")?,
+        wall_scale: fields.get("wall_scale")?,
+        border_width: fields.get("border_width")?,
+        block_walls: fields.get("block_walls")?,
+        block_wall_width: fields.get("block_wall_width")?,
+        wall_color: fields.get_with("wall_color", parse_color)?,
+        background: fields.get_with("background", parse_color)?,
+        masked_color: fields.get_with("masked_color", parse_color)?,
+        line_cap: fields.get_with("line_cap", parse_line_cap)?,
+        line_join: fields.get_with("line_join", parse_line_join)?,
+        opacity: fields.get("opacity")?,
+        inset: fields.get_with("inset", parse_inset)?,
+        corridors: fields.flag("corridors")?,
+        corridor_width: fields.get("corridor_width")?,
+        corner_radius: fields.get("corner_radius")?,
+        start_icon: fields.get("start_icon")?,
+        end_icon: fields.get("end_icon")?,
+        no_icons: fields.flag("no_icons")?,
+        icons: fields.get_with("icons", |value| value.split(';').map(|icon| parse_cell_icon(icon.trim())).collect())?.unwrap_or_default(),
+    };
+    if render.wall_width.is_some() && render.wall_scale.is_some() {
+        return Err(JobError::ConflictingFields("wall_width", "wall_scale"));
+    }
+    if render.no_icons && (render.start_icon.is_some() || render.end_icon.is_some()) {
+        let icon = if render.start_icon.is_some() { "start_icon" } else { "end_icon" };
+        return Err(JobError::ConflictingFields("no_icons", icon));
+    }
+    let encoding = EncodingArgs {
+        layout: fields.get("layout")?.unwrap_or_default(),
+        compression: fields.get("compression")?.unwrap_or_default(),
+    };
+    let solve = fields.flag("solve")?;
+    let stats = fields.flag("stats")?;
+    let output: Option<PathBuf> = fields.get("output")?;
+    let algorithm = fields.get("algorithm")?;
+    let seed = fields.get("seed")?;
+    fields.finish()?;
+
+    let source = match input {
+        Some(input) if shape.width.is_none() && shape.height.is_none() && shape.rings.is_none() && shape.mask.is_none() && shape.shape.is_none() && shape.lettering.is_none() && shape.procedural.is_none() && shape.regions.is_none() => Source::existing(input),
+        Some(_) => return Err(CommandError::ConflictingShapes.into()),
+        None => shape.source()?,
+    };
+    let destination = match (output, stats) {
+        (Some(output), false) => render.destination(&output, solve, encoding.encoding()),
+        (None, true) => Destination::Stats,
+        (None, false) => return Err(CommandError::MissingDestination.into()),
+        (Some(_), true) => return Err(JobError::ConflictingFields("output", "stats")),
+    };
+    let builder = CommandBuilder::new().source(source).destination(destination);
+    let builder = match algorithm { Some(a) => builder.algorithm(a), None => builder };
+    let builder = match seed { Some(s) => builder.seed(s), None => builder };
+    Ok(builder.build()?)
+}
+
+/// Runs every job on up to `threads` threads. A job that fails, or panics, is reported without stopping the others.
+///
+/// `on_finish` is called from the worker threads as each job completes. The reports are returned in job order.
+pub fn run_jobs(jobs: Vec<(String, Result<Command, JobError>)>, threads: usize, on_finish: impl Fn(&JobReport) + Sync) -> Vec<JobReport> {
+    let next_job = AtomicUsize::new(0);
+    let reports: Mutex<Vec<Option<JobReport>>> = Mutex::new((0..jobs.len()).map(|_| None).collect());
+    thread::scope(|scope| {
+        for _ in 0..threads.max(1).min(jobs.len()) {
+            scope.spawn(|| {
+                loop {
+                    let index = next_job.fetch_add(1, Ordering::Relaxed);
+                    let Some((name, job)) = jobs.get(index) else { break };
+                    let result = match job {
+                        Ok(command) => match panic::catch_unwind(AssertUnwindSafe(|| command.run())) {
+                            Ok(result) => result.map_err(|e| e.to_string()),
+                            Err(payload) => Err(panic_message(payload)),
+                        },
+                        Err(e) => Err(e.to_string()),
+                    };
+                    let report = JobReport { name: name.clone(), result };
+                    on_finish(&report);
+                    reports.lock().unwrap()[index] = Some(report);
+                }
+            });
+        }
+    });
+    reports.into_inner().unwrap().into_iter().map(|r| r.expect("Every job is run")).collect()
+}
+
+fn panic_message(payload: Box<dyn std::any::Any + Send>) -> String {
+    let message = payload.downcast_ref::<&str>().map(|s| s.to_string()).or_else(|| payload.downcast_ref::<String>().cloned());
+    format!("panicked: {}", message.unwrap_or_else(|| "unknown cause".to_string()))
+}
+
+/// Reads a job file and runs its jobs. See [`job_command`] for the fields of each job, and [`parsers::job_file`] for the layout.
+pub fn run_file(path: &Path, threads: usize, on_finish: impl Fn(&JobReport) + Sync) -> Result<Vec<JobReport>, BatchError> {
+    let text = std::fs::read_to_string(path).map_err(|error| BatchError::Open { path: path.to_path_buf(), error })?;
+    let sections = parsers::job_file(&text).map_err(BatchError::Layout)?;
+    let jobs = sections.iter().map(|section| (section.name.clone(), job_command(section))).collect();
+    Ok(run_jobs(jobs, threads, on_finish))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    fn section(fields: &[(&str, &str)]) -> JobSection {
+        JobSection { name: "test".into(), line: 1, fields: fields.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect() }
+    }
+
+    #[test]
+    fn fields_map_onto_commands() {
+        let command = job_command(&section(&[("width", "5"), ("height", "4"), ("algorithm", "wilson"), ("seed", "3"), ("output", "a.maze")])).unwrap();
+        assert!(matches!(command.source, Source::Unmasked { width: 5, height: 4 }));
+        assert_eq!(command.seed, Some(3));
+        assert!(matches!(command.destination, Destination::Mazefile { .. }));
+
+        assert_eq!(job_command(&section(&[("input", "a.maze"), ("colour", "red"), ("stats", "true")])).err(), Some(JobError::UnknownField("colour".into())));
+        assert_eq!(job_command(&section(&[("input", "a.maze"), ("stats", "yes")])).err(), Some(JobError::InvalidValue {
+            key: "stats".into(), value: "yes".into(), message: "provided string was not `true` or `false`".into(),
+        }));
+        assert_eq!(job_command(&section(&[("input", "a.maze"), ("rings", "4"), ("stats", "true")])).err(), Some(JobError::Command(CommandError::ConflictingShapes)));
+        assert_eq!(job_command(&section(&[("input", "a.maze")])).err(), Some(JobError::Command(CommandError::MissingDestination)));
+    }
+
+    #[test]
+    fn layout_errors_are_readable() {
+        let error = BatchError::Layout(parsers::job_file("[a]
+width 3").unwrap_err());
+        assert_eq!(error.to_string(), "line 2 of the job file: expected a `[name]` header or a `key = value` field");
+    }
+
+    #[test]
+    fn failed_jobs_do_not_stop_the_rest() {
+        let dir = std::env::temp_dir().join(format!("mazes-batch-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let jobs = format!(
+            "[first]\nwidth = 6\nheight = 6\nseed = 1\noutput = {0}/first.maze\n\n\
+             [missing input]\ninput = {0}/nothing-here.maze\nstats = true\n\n\
+             [bad field]\nwidth = six\n\n\
+             [radial]\nrings = 4\noutput = {0}/radial.txt\n",
+            dir.display(),
+        );
+        let job_file = dir.join("jobs.txt");
+        fs::write(&job_file, jobs).unwrap();
+
+        let finished = AtomicUsize::new(0);
+        let reports = run_file(&job_file, 3, |_| { finished.fetch_add(1, Ordering::Relaxed); }).unwrap();
+        let names: Vec<&str> = reports.iter().map(|r| r.name.as_str()).collect();
+        assert_eq!(names, ["first", "missing input", "bad field", "radial"]);
+        let succeeded: Vec<bool> = reports.iter().map(|r| r.result.is_ok()).collect();
+        assert_eq!(succeeded, [true, false, false, true]);
+        assert_eq!(finished.into_inner(), 4);
+        assert!(dir.join("first.maze").exists() && dir.join("radial.txt").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
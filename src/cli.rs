@@ -1,11 +1,70 @@
-use std::path::PathBuf;
+use std::{borrow::Cow, path::{PathBuf, Path}, fs::File, fmt::Display, io::{self, BufWriter, Write}, thread, num::NonZeroUsize};
+
+use clap::{Parser, Subcommand, Args};
+use rand::{rngs::StdRng, SeedableRng, Rng, thread_rng};
+use tiny_skia::{Pixmap, Color, LineCap, LineJoin};
+
+use crate::{batch, animation::{self, FrameRecorder}, trace::{Trace, TraceFormat, ReplayError}, pool::CarveObserver, render_style::{RenderStyle, WallWidth, CorridorStyle, ZoneWalls, Zones, parse_color, parse_line_cap, parse_line_join, parse_inset}, maze::{Maze, Algorithm, RenderOptions, Coloring, Icon, IconOptions, IconError}, maze_file::{MazeMetadata, CellEncoding, CellLayout}, masked_grid::{MaskedGrid, GridReadError, DisconnectedMask, DisconnectedMaskError, NestLevel}, text_render::TextCharset, color_gradients::Gradient, lerp::ColorSpace, easing::Easing, dijkstra::DistanceRemap, compression::Compression, image_mask::{ImageMask, MaskOptions, MaskChannel}, mask::Mask, font::UnknownGlyph, procedural::Procedure, vector_mask::VectorMask, regions::RegionMap};
 
 
 pub enum Source {
     Mazefile {
         input: std::path::PathBuf
     },
+    /// A maze over the cells of a mask read from an image, whose mask is saved as an image to `preview` if given.
     FromInputMask {
+        input: std::path::PathBuf,
+        options: MaskOptions,
+        preview: Option<PathBuf>,
+        disconnected: DisconnectedMask,
+    },
+    /// A maze over the cells of `mask` within a grid of `height` rows of `width` cells.
+    Shape {
+        mask: Mask,
+        width: usize,
+        height: usize,
+        disconnected: DisconnectedMask,
+    },
+    /// A maze over the cells of a mask drawn as text, such as `mask.txt`, starting and ending on the cells marked `S`
+    /// and `E`.
+    TextMask {
+        input: PathBuf,
+        disconnected: DisconnectedMask,
+    },
+    /// A maze over the cells filling the path read from `input`, such as `star.path`, drawn onto a grid of `size` cells,
+    /// or one cell to a unit of the path if absent.
+    VectorMask {
+        input: PathBuf,
+        size: Option<(usize, usize)>,
+        disconnected: DisconnectedMask,
+    },
+    /// A maze over the cells grown by `procedure` within a grid of `height` rows of `width` cells, with the seed of the
+    /// maze if `seed` is absent.
+    Procedural {
+        procedure: Procedure,
+        width: usize,
+        height: usize,
+        seed: Option<u64>,
+    },
+    /// A maze of rooms over the regions of flat color in the image `input`, such as `rooms.png`, one cell to a pixel.
+    /// Fully transparent pixels are left out. The walls between regions are drawn `wall_width` pixels wide, or twice as
+    /// wide as the other walls if absent.
+    Regions {
+        input: PathBuf,
+        wall_width: Option<f32>,
+    },
+    /// The maze from `source`, drawn into a finer grid and carved again for each of `levels`.
+    Nested {
+        source: Box<Source>,
+        levels: Vec<NestLevel>,
+    },
+    /// The maze from `source`, every cell turned into a maze of its own for each of `sizes`.
+    Subdivided {
+        source: Box<Source>,
+        sizes: Vec<usize>,
+    },
+    /// A maze drawn as text, such as `maze.txt`.
+    TextMaze {
         input: std::path::PathBuf
     },
     Unmasked {
@@ -20,24 +79,152 @@ pub enum Source {
 
 pub enum Destination {
     Mazefile {
-        output: PathBuf
+        output: PathBuf,
+        encoding: CellEncoding,
     },
     Image {
         output: PathBuf,
         image_width: usize,
-        padding: usize,
-    }
+        options: RenderOptions,
+    },
+    Text {
+        output: PathBuf,
+        charset: TextCharset,
+        options: RenderOptions,
+    },
+    /// An animated PNG of the maze being generated, with a frame every `every` passages carved.
+    Animation {
+        output: PathBuf,
+        /// Also writes every frame as a numbered PNG into this directory.
+        frames_dir: Option<PathBuf>,
+        image_width: usize,
+        options: RenderOptions,
+        every: usize,
+        delay_ms: u16,
+    },
+    /// Every step taken while generating the maze, replayed to check it before being written.
+    Trace {
+        output: PathBuf,
+        format: TraceFormat,
+    },
+    /// Prints statistics about the maze to standard output.
+    Stats,
 }
 
 pub struct Command {
     pub destination: Destination,
 
     pub source: Source,
+
+    /// Algorithm used when the source generates a new maze.
+    pub algorithm: Algorithm,
+    /// Seed used when the source generates a new maze. A random one is picked, and recorded in the metadata, if absent.
+    pub seed: Option<u64>,
 }
 
 pub struct CommandBuilder {
     b_destination: Option<Destination>,
     b_source: Option<Source>,
+    b_algorithm: Option<Algorithm>,
+    b_seed: Option<u64>,
+}
+
+/// Reasons a [`CommandBuilder`] cannot build a [`Command`].
+#[derive(Debug, PartialEq, Eq)]
+pub enum CommandError {
+    MissingSource,
+    MissingDestination,
+    EmptyGrid { width: usize, height: usize },
+    /// The shape holds no cells of its grid.
+    EmptyShape,
+    /// The shape goes on forever, so the size of its grid must be given.
+    UnboundedShape,
+    Lettering(UnknownGlyph),
+    /// Only rectangular mazes can be drawn into finer grids.
+    NestedRadial,
+    /// Only rectangular mazes can have their cells turned into mazes.
+    SubdividedRadial,
+    /// The rooms of a maze of regions would not line up with the cells of a finer grid.
+    NestedRegions,
+    TooFewBranches(usize),
+    TooFewRings(usize),
+    /// The padding leaves no room for the maze in an image this wide.
+    PaddingTooLarge { image_width: usize, padding: usize },
+    /// An algorithm or seed was given, but the source reads an existing maze rather than generating one.
+    NotGenerating,
+    /// The command line did not describe the shape of the maze to generate.
+    MissingShape,
+    /// The command line described more than one shape for the maze to generate.
+    ConflictingShapes,
+    /// Batch files hold many commands, and are run with [`Cli::run`].
+    BatchFile,
+    /// Only mazes being generated can be animated.
+    NothingToAnimate,
+    /// Only mazes being generated can be traced.
+    NothingToTrace,
+}
+
+impl Display for CommandError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CommandError::MissingSource => write!(f, "no source was given to read or generate a maze from"),
+            CommandError::MissingDestination => write!(f, "no destination was given for the maze"),
+            CommandError::EmptyGrid { width, height } => write!(f, "a {}x{} grid has no cells, both dimensions must be at least 1", width, height),
+            CommandError::EmptyShape => write!(f, "the shape holds no cells of its grid"),
+            CommandError::UnboundedShape => write!(f, "the shape goes on forever, give --width and --height to cut it to size"),
+            CommandError::Lettering(e) => write!(f, "{}", e),
+            CommandError::NestedRadial => write!(f, "only rectangular mazes can be nested"),
+            CommandError::SubdividedRadial => write!(f, "only rectangular mazes can be subdivided"),
+            CommandError::NestedRegions => write!(f, "mazes of --regions can be neither nested nor subdivided"),
+            CommandError::TooFewBranches(n) => write!(f, "radial mazes need at least 2 branches from the center, got {}", n),
+            CommandError::TooFewRings(n) => write!(f, "radial mazes need at least 2 rings, got {}", n),
+            CommandError::PaddingTooLarge { image_width, padding } => write!(f, "padding of {} on each side leaves no room for the maze in an image {} pixels wide", padding, image_width),
+            CommandError::NotGenerating => write!(f, "an algorithm or seed only applies when generating a maze, not when reading one"),
+            CommandError::MissingShape => write!(f, "give --width and --height, --rings, --mask, --shape, --lettering, --procedural or --regions to describe the maze to generate"),
+            CommandError::ConflictingShapes => write!(f, "--width/--height, --rings, --mask, --shape, --lettering, --procedural and --regions each describe a different maze, give only one, or --width/--height with --shape, --lettering, --procedural or a `.path` mask"),
+            CommandError::BatchFile => write!(f, "a batch file describes many commands rather than one"),
+            CommandError::NothingToAnimate => write!(f, "only a maze being generated can be animated, not one read from a file"),
+            CommandError::NothingToTrace => write!(f, "only a maze being generated can be traced, not one read from a file"),
+        }
+    }
+}
+
+/// Errors while running a [`Command`].
+#[derive(Debug)]
+pub enum RunError {
+    Open { path: PathBuf, error: io::Error },
+    Write { path: PathBuf, error: io::Error },
+    Read { path: PathBuf, error: GridReadError },
+    Decode { path: PathBuf, message: String },
+    Icon(IconError),
+    /// No cell of the image is part of its mask.
+    EmptyMask(PathBuf),
+    /// No cell grew from the procedure.
+    NothingGrown(Procedure),
+    /// The mask, read from the image at `path` if any, falls into several parts, and was not to be split or joined.
+    DisconnectedMask { path: Option<PathBuf>, error: DisconnectedMaskError },
+    /// The recorded trace does not rebuild the generated maze.
+    Replay(ReplayError),
+    /// The destination cannot show what was asked of it.
+    Unsupported(&'static str),
+}
+
+impl Display for RunError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RunError::Open { path, error } => write!(f, "could not open {}: {}", path.display(), error),
+            RunError::Write { path, error } => write!(f, "could not write {}: {}", path.display(), error),
+            RunError::Read { path, error } => write!(f, "could not read a maze from {}: {}", path.display(), error),
+            RunError::Decode { path, message } => write!(f, "could not decode {}: {}", path.display(), message),
+            RunError::Icon(e) => write!(f, "{}", e),
+            RunError::EmptyMask(path) => write!(f, "no cell of the mask read from {} is part of the maze", path.display()),
+            RunError::NothingGrown(procedure) => write!(f, "no cell of the grid grew from {}", procedure),
+            RunError::DisconnectedMask { path: Some(path), error } => write!(f, "{}: {}, see --disconnected", path.display(), error),
+            RunError::DisconnectedMask { path: None, error } => write!(f, "{}, see --disconnected", error),
+            RunError::Replay(e) => write!(f, "the trace does not match the generated maze: {}", e),
+            RunError::Unsupported(what) => write!(f, "{}", what),
+        }
+    }
 }
 
 impl Source {
@@ -46,7 +233,11 @@ impl Source {
     }
 
     pub fn input_mask(input: impl Into<PathBuf>) -> Self {
-        Self::FromInputMask { input: input.into() }
+        Self::FromInputMask { input: input.into(), options: MaskOptions::default(), preview: None, disconnected: DisconnectedMask::default() }
+    }
+
+    pub fn text_maze(input: impl Into<PathBuf>) -> Self {
+        Self::TextMaze { input: input.into() }
     }
 
     pub fn unmasked(width: usize, height: usize) -> Self {
@@ -56,21 +247,237 @@ impl Source {
     pub fn unmasked_radial(starting_branch_count: usize, rings: usize) -> Self {
         Self::UnmaskedRadial { starting_branch_count, ring_count: rings }
     }
+
+    /// Reads an existing maze, choosing the format from the file extension: `.txt` for text mazes, and `.maze` otherwise.
+    pub fn existing(input: impl Into<PathBuf>) -> Self {
+        let input = input.into();
+        match extension_of(&input).as_deref() {
+            Some("txt") => Self::text_maze(input),
+            _ => Self::mazefile(input),
+        }
+    }
+
+    fn read_regions(input: &PathBuf) -> Result<RegionMap, RunError> {
+        let image = Pixmap::load_png(input).map_err(|e| RunError::Decode { path: input.clone(), message: e.to_string() })?;
+        let regions = RegionMap::from_pixmap(&image);
+        if regions.count() == 0 {
+            return Err(RunError::EmptyMask(input.clone()));
+        }
+        Ok(regions)
+    }
+
+    fn generates(&self) -> bool {
+        !matches!(self, Source::Mazefile { .. } | Source::TextMaze { .. })
+    }
+
+    /// Reads or generates the maze, along with its metadata and the walls it is drawn with heavier unless others are
+    /// given, such as those between the regions of a maze of rooms. Generators report each step to `observer`.
+    pub fn load(&self, algorithm: Algorithm, seed: Option<u64>, observer: &mut impl CarveObserver) -> Result<(Maze, MazeMetadata, Option<ZoneWalls>), RunError> {
+        let seed = seed.unwrap_or_else(|| thread_rng().gen());
+        let mut rng = StdRng::seed_from_u64(seed);
+        let generated = MazeMetadata { seed: Some(seed), algorithm: Some(algorithm.name().to_string()), ..MazeMetadata::default() };
+        match self {
+            Source::Mazefile { input } => {
+                let file = File::open(input).map_err(|error| RunError::Open { path: input.clone(), error })?;
+                let (maze, metadata) = Maze::read_maze(file).map_err(|error| RunError::Read { path: input.clone(), error })?;
+                Ok((maze, metadata, None))
+            },
+            Source::TextMaze { input } => {
+                let file = File::open(input).map_err(|error| RunError::Open { path: input.clone(), error })?;
+                let (g, openings) = MaskedGrid::read_text_maze(file).map_err(|error| RunError::Read { path: input.clone(), error })?;
+                let (start, end) = openings.or_furthest(&g.pool).unwrap();
+                Ok((Maze::MaskedMaze { maze: g, start, end }, MazeMetadata::default(), None))
+            },
+            Source::FromInputMask { input, options, preview, disconnected } => {
+                let mask_image = Pixmap::load_png(input).map_err(|e| RunError::Decode { path: input.clone(), message: e.to_string() })?;
+                let mask = ImageMask::from_pixmap(&mask_image, options);
+                if let Some(preview) = preview {
+                    mask.preview(options.cell_pixels as u32).save_png(preview)
+                        .map_err(|e| RunError::Write { path: preview.clone(), error: io::Error::other(e) })?;
+                }
+                if mask.cell_count() == 0 {
+                    return Err(RunError::EmptyMask(input.clone()));
+                }
+                let grid = MaskedGrid::with_policy(mask.width(), mask.height(), mask.into(), *disconnected)
+                    .map_err(|error| RunError::DisconnectedMask { path: Some(input.clone()), error })?;
+                Ok((Maze::carve_masked(grid, algorithm, &mut rng, observer), generated, None))
+            },
+            Source::TextMask { input, disconnected } => {
+                let file = File::open(input).map_err(|error| RunError::Open { path: input.clone(), error })?;
+                let (grid, marked) = MaskedGrid::read_text_mask(file, *disconnected).map_err(|error| match error {
+                    GridReadError::DisconnectedMask(error) => RunError::DisconnectedMask { path: Some(input.clone()), error },
+                    error => RunError::Read { path: input.clone(), error },
+                })?;
+                if grid.total_cells() == 0 {
+                    return Err(RunError::EmptyMask(input.clone()));
+                }
+                Ok((Maze::carve_masked_between(grid, marked, algorithm, &mut rng, observer), generated, None))
+            },
+            Source::Shape { mask, width, height, disconnected } => {
+                let grid = MaskedGrid::with_policy(*width, *height, mask.clone(), *disconnected)
+                    .map_err(|error| RunError::DisconnectedMask { path: None, error })?;
+                Ok((Maze::carve_masked(grid, algorithm, &mut rng, observer), generated, None))
+            },
+            Source::VectorMask { input, size, disconnected } => {
+                let bytes = std::fs::read(input).map_err(|error| RunError::Open { path: input.clone(), error })?;
+                let path = VectorMask::read(&bytes).map_err(|e| RunError::Decode { path: input.clone(), message: e.to_string() })?;
+                let (width, height) = size.unwrap_or_else(|| path.natural_grid());
+                let grid = MaskedGrid::with_policy(width, height, path.rasterize(width, height), *disconnected)
+                    .map_err(|error| RunError::DisconnectedMask { path: Some(input.clone()), error })?;
+                if grid.total_cells() == 0 {
+                    return Err(RunError::EmptyMask(input.clone()));
+                }
+                Ok((Maze::carve_masked(grid, algorithm, &mut rng, observer), generated, None))
+            },
+            Source::Procedural { procedure, width, height, seed: mask_seed } => {
+                let mask = procedure.generate(*width, *height, &mut StdRng::seed_from_u64(mask_seed.unwrap_or(seed)));
+                let grid = MaskedGrid::with_parts(*width, *height, mask);
+                if grid.total_cells() == 0 {
+                    return Err(RunError::NothingGrown(procedure.clone()));
+                }
+                Ok((Maze::carve_masked(grid, algorithm, &mut rng, observer), generated, None))
+            },
+            Source::Regions { input, wall_width } => {
+                let regions = Self::read_regions(input)?;
+                let mut grid = MaskedGrid::with_policy(regions.width(), regions.height(), regions.mask(), DisconnectedMask::Reject)
+                    .map_err(|error| RunError::DisconnectedMask { path: Some(input.clone()), error })?;
+                let doors = regions.carve(&mut grid, algorithm, &mut rng, observer);
+                let (start, end) = grid.pool.furthest_pair().unwrap();
+                let doors: Vec<String> = doors.into_iter().map(|(here, there)| {
+                    let ((row, col), (next_row, next_col)) = (grid.pool[here].payload, grid.pool[there].payload);
+                    format!("{},{}-{},{}", row, col, next_row, next_col)
+                }).collect();
+                let properties = vec![("regions".to_string(), regions.count().to_string()), ("doors".to_string(), doors.join(";"))];
+                let zone_walls = ZoneWalls { zones: Zones::Regions(regions), width: wall_width.map(WallWidth::Pixels) };
+                Ok((Maze::MaskedMaze { maze: grid, start, end }, MazeMetadata { properties, ..generated }, Some(zone_walls)))
+            },
+            Source::Nested { source, levels } => {
+                let (coarse, ..) = source.load(algorithm, Some(seed), &mut ())?;
+                let maze = coarse.nest(levels, algorithm, &mut rng, observer).ok_or(RunError::Unsupported("only rectangular mazes can be nested"))?;
+                Ok((maze, generated, None))
+            },
+            Source::Subdivided { source, sizes } => {
+                let (coarse, ..) = source.load(algorithm, Some(seed), &mut ())?;
+                let maze = coarse.subdivide(sizes, algorithm, &mut rng, observer).ok_or(RunError::Unsupported("only rectangular mazes can be subdivided"))?;
+                Ok((maze, generated, None))
+            },
+            Source::Unmasked { width, height } => {
+                Ok((Maze::new_unmasked_cartesian(*width, *height, algorithm, &mut rng, observer), generated, None))
+            },
+            Source::UnmaskedRadial { starting_branch_count, ring_count } => {
+                Ok((Maze::new_unmasked_radial(*starting_branch_count, *ring_count, algorithm, &mut rng, observer), generated, None))
+            }
+        }
+    }
 }
 
 impl Destination {
     pub fn image(image_width: usize, padding: usize, output: impl Into<PathBuf>) -> Self {
-        Self::Image{ image_width, padding, output: output.into() }
+        let style = RenderStyle { padding, ..RenderStyle::default() };
+        Self::Image{ image_width, options: RenderOptions { style, ..RenderOptions::default() }, output: output.into() }
     }
 
     pub fn mazefile(output: impl Into<PathBuf>) -> Self {
-        Self::Mazefile{ output: output.into() }
+        Self::Mazefile{ output: output.into(), encoding: CellEncoding::default() }
+    }
+
+    pub fn text(charset: TextCharset, output: impl Into<PathBuf>) -> Self {
+        Self::Text { output: output.into(), charset, options: RenderOptions::default() }
+    }
+
+    /// Writes the maze in the format matching the file extension: `.png` images, `.txt` text, and `.maze` files otherwise.
+    pub fn by_extension(output: impl Into<PathBuf>, image_width: usize, charset: TextCharset, options: RenderOptions, encoding: CellEncoding) -> Self {
+        let output = output.into();
+        match extension_of(&output).as_deref() {
+            Some("png") => Self::Image { output, image_width, options },
+            Some("txt") => Self::Text { output, charset, options },
+            _ => Self::Mazefile { output, encoding },
+        }
+    }
+
+    /// Writes or prints `maze`, drawing images with `zone_walls` unless their style gives its own.
+    pub fn save(&self, maze: &Maze, metadata: &MazeMetadata, zone_walls: Option<ZoneWalls>) -> Result<(), RunError> {
+        match self {
+            Destination::Mazefile { output, encoding } => {
+                let file = File::create(output).map_err(|error| RunError::Write { path: output.clone(), error })?;
+                maze.write_maze_with_metadata(file, metadata, *encoding).map_err(|error| RunError::Write { path: output.clone(), error })
+            },
+            Destination::Image { output, image_width, options } => {
+                maze.print_image(*image_width, &with_zone_walls(options, zone_walls)).map_err(RunError::Icon)?.save_png(output).map_err(|e| RunError::Write {
+                    path: output.clone(), error: io::Error::other(e),
+                })
+            },
+            Destination::Text { output, charset, options } => {
+                let text = maze.text_print(*charset, options).ok_or(RunError::Unsupported("radial mazes can only be printed as text without distances or a solution"))?;
+                std::fs::write(output, text).map_err(|error| RunError::Write { path: output.clone(), error })
+            },
+            Destination::Animation { .. } => Err(RunError::Unsupported("animations are recorded while generating, see `Command::run`")),
+            Destination::Trace { .. } => Err(RunError::Unsupported("traces are recorded while generating, see `Command::run`")),
+            Destination::Stats => {
+                // One write, so that the summaries of jobs run side by side don't interleave
+                io::stdout().lock().write_all(stats_summary(maze, metadata).as_bytes())
+                    .map_err(|error| RunError::Write { path: "standard output".into(), error })
+            },
+        }
+    }
+}
+
+/// The statistics of `maze`, followed by how it was made.
+fn stats_summary(maze: &Maze, metadata: &MazeMetadata) -> String {
+    let mut summary = maze.stats().to_string();
+    if let Some(seed) = metadata.seed {
+        summary += &format!("seed: {}\n", seed);
+    }
+    if let Some(algorithm) = &metadata.algorithm {
+        summary += &format!("algorithm: {}\n", algorithm);
+    }
+    for (key, value) in &metadata.properties {
+        summary += &format!("{}: {}\n", key, value);
+    }
+    summary
+}
+
+/// `options`, drawing the walls of `zone_walls` heavier if they do not draw any of their own.
+fn with_zone_walls(options: &RenderOptions, zone_walls: Option<ZoneWalls>) -> Cow<'_, RenderOptions> {
+    match zone_walls {
+        Some(zone_walls) if options.style.zone_walls.is_none() => {
+            Cow::Owned(RenderOptions { style: RenderStyle { zone_walls: Some(zone_walls), ..options.style.clone() }, ..options.clone() })
+        },
+        _ => Cow::Borrowed(options),
+    }
+}
+
+fn extension_of(path: &Path) -> Option<String> {
+    path.extension().map(|e| e.to_string_lossy().to_lowercase())
+}
+
+impl Command {
+    pub fn run(&self) -> Result<(), RunError> {
+        if let Destination::Animation { output, frames_dir, image_width, options, every, delay_ms } = &self.destination {
+            let mut recorder = FrameRecorder::new(*every);
+            let (mut maze, _, zone_walls) = self.source.load(self.algorithm, self.seed, &mut recorder)?;
+            let frames = maze.animation_frames(&recorder, *image_width, &with_zone_walls(options, zone_walls)).map_err(RunError::Icon)?;
+            if let Some(dir) = frames_dir {
+                animation::write_frames(&frames, dir).map_err(|error| RunError::Write { path: dir.clone(), error })?;
+            }
+            let file = File::create(output).map_err(|error| RunError::Write { path: output.clone(), error })?;
+            return animation::encode_apng(&frames, *delay_ms, BufWriter::new(file)).map_err(|error| RunError::Write { path: output.clone(), error });
+        }
+        if let Destination::Trace { output, format } = &self.destination {
+            let mut trace = Trace::new();
+            let (maze, ..) = self.source.load(self.algorithm, self.seed, &mut trace)?;
+            maze.verify_trace(&trace).map_err(RunError::Replay)?;
+            let file = File::create(output).map_err(|error| RunError::Write { path: output.clone(), error })?;
+            return trace.write(*format, BufWriter::new(file)).map_err(|error| RunError::Write { path: output.clone(), error });
+        }
+        let (maze, metadata, zone_walls) = self.source.load(self.algorithm, self.seed, &mut ())?;
+        self.destination.save(&maze, &metadata, zone_walls)
     }
 }
 
 impl CommandBuilder {
     pub fn new() -> Self {
-        CommandBuilder { b_destination: None, b_source: None }
+        CommandBuilder { b_destination: None, b_source: None, b_algorithm: None, b_seed: None }
     }
 
     pub fn destination(mut self, destination: Destination) -> Self {
@@ -83,10 +490,713 @@ impl CommandBuilder {
         self
     }
 
-    pub fn build(self) -> Option<Command> {
-        Some(Command {
-            destination: self.b_destination?,
-            source: self.b_source?
+    pub fn algorithm(mut self, algorithm: Algorithm) -> Self {
+        self.b_algorithm = Some(algorithm);
+        self
+    }
+
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.b_seed = Some(seed);
+        self
+    }
+
+    pub fn build(self) -> Result<Command, CommandError> {
+        let source = self.b_source.ok_or(CommandError::MissingSource)?;
+        let destination = self.b_destination.ok_or(CommandError::MissingDestination)?;
+        let mut innermost = &source;
+        while let Source::Nested { source, .. } | Source::Subdivided { source, .. } = innermost {
+            innermost = source;
+        }
+        match *innermost {
+            Source::Unmasked { width, height } if width == 0 || height == 0 => return Err(CommandError::EmptyGrid { width, height }),
+            Source::Shape { ref mask, width, height, .. } if !(0..height).any(|row| (0..width).any(|col| mask.contains(row, col))) => {
+                return Err(CommandError::EmptyShape)
+            },
+            Source::UnmaskedRadial { starting_branch_count, .. } if starting_branch_count < 2 => return Err(CommandError::TooFewBranches(starting_branch_count)),
+            Source::UnmaskedRadial { ring_count, .. } if ring_count < 2 => return Err(CommandError::TooFewRings(ring_count)),
+            _ => {},
+        }
+        if !source.generates() && (self.b_algorithm.is_some() || self.b_seed.is_some()) {
+            return Err(CommandError::NotGenerating);
+        }
+        if !source.generates() && matches!(destination, Destination::Animation { .. }) {
+            return Err(CommandError::NothingToAnimate);
+        }
+        if !source.generates() && matches!(destination, Destination::Trace { .. }) {
+            return Err(CommandError::NothingToTrace);
+        }
+        if let Destination::Image { image_width, options: RenderOptions { style, cell_size: None, .. }, .. }
+            | Destination::Animation { image_width, options: RenderOptions { style, cell_size: None, .. }, .. } = &destination {
+            if 2 * style.padding >= *image_width {
+                return Err(CommandError::PaddingTooLarge { image_width: *image_width, padding: style.padding });
+            }
+        }
+        Ok(Command {
+            destination,
+            source,
+            algorithm: self.b_algorithm.unwrap_or_default(),
+            seed: self.b_seed,
         })
     }
-}
\ No newline at end of file
+}
+
+/// Generate, render and solve mazes.
+#[derive(Parser, Debug)]
+#[command(name = "mazes", version)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: CliCommand,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum CliCommand {
+    /// Generate a new maze and save it as a .maze file, a .png image or a .txt drawing.
+    Generate {
+        #[command(flatten)]
+        shape: ShapeArgs,
+        /// One of aldous-broder, hunt-and-kill, recursive-backtracker, wilson.
+        #[arg(short, long)]
+        algorithm: Option<Algorithm>,
+        /// Seed for the random number generator, to generate the same maze again.
+        #[arg(short, long)]
+        seed: Option<u64>,
+        #[arg(short, long)]
+        output: PathBuf,
+        #[command(flatten)]
+        render: RenderArgs,
+        #[command(flatten)]
+        encoding: EncodingArgs,
+    },
+    /// Record a maze being generated as an animated .png, highlighting where the algorithm is.
+    Animate {
+        #[command(flatten)]
+        shape: ShapeArgs,
+        /// One of aldous-broder, hunt-and-kill, recursive-backtracker, wilson.
+        #[arg(short, long)]
+        algorithm: Option<Algorithm>,
+        #[arg(short, long)]
+        seed: Option<u64>,
+        #[arg(short, long)]
+        output: PathBuf,
+        /// Number of passages carved between frames.
+        #[arg(long, default_value = "1")]
+        every: NonZeroUsize,
+        /// How long each frame is shown, in milliseconds.
+        #[arg(long, default_value_t = 40)]
+        delay: u16,
+        /// Also write every frame as a numbered .png into this directory.
+        #[arg(long)]
+        frames: Option<PathBuf>,
+        #[command(flatten)]
+        render: RenderArgs,
+    },
+    /// Record every step taken while generating a maze, as JSON lines for .json and .jsonl files or a compact binary log
+    /// otherwise. The trace is replayed to check it rebuilds the maze before it is written.
+    Trace {
+        #[command(flatten)]
+        shape: ShapeArgs,
+        /// One of aldous-broder, hunt-and-kill, recursive-backtracker, wilson.
+        #[arg(short, long)]
+        algorithm: Option<Algorithm>,
+        #[arg(short, long)]
+        seed: Option<u64>,
+        #[arg(short, long)]
+        output: PathBuf,
+    },
+    /// Render a .maze or .txt maze as a .png image or a .txt drawing.
+    Render {
+        input: PathBuf,
+        #[arg(short, long)]
+        output: PathBuf,
+        #[command(flatten)]
+        render: RenderArgs,
+    },
+    /// Render a maze with the path from its start to its end marked.
+    Solve {
+        input: PathBuf,
+        #[arg(short, long)]
+        output: PathBuf,
+        #[command(flatten)]
+        render: RenderArgs,
+    },
+    /// Convert a maze between .maze encodings and the .txt format.
+    Convert {
+        input: PathBuf,
+        #[arg(short, long)]
+        output: PathBuf,
+        #[command(flatten)]
+        encoding: EncodingArgs,
+    },
+    /// Print statistics about a maze.
+    Stats {
+        input: PathBuf,
+    },
+    /// Run every job in a job file, in parallel. Failed jobs are reported without stopping the others.
+    Batch {
+        file: PathBuf,
+        /// Number of jobs to run at once. Defaults to the number of processors.
+        #[arg(short = 'j', long)]
+        threads: Option<usize>,
+    },
+}
+
+#[derive(Args, Debug)]
+pub struct ShapeArgs {
+    /// Width of a rectangular maze, in cells.
+    #[arg(long, requires = "height")]
+    pub width: Option<usize>,
+    /// Height of a rectangular maze, in cells.
+    #[arg(long, requires = "width")]
+    pub height: Option<usize>,
+    /// Number of rings of a radial maze.
+    #[arg(long)]
+    pub rings: Option<usize>,
+    /// Number of cells around the center of a radial maze.
+    #[arg(long, default_value_t = 6)]
+    pub branches: usize,
+    /// PNG image whose dark pixels are the cells of the maze, a `.txt` file drawing them with `#` and `.`, and marking
+    /// the start and end with `S` and `E`, or a `.path` file of `move`, `line`, `quad` and `close` commands filled in at
+    /// --width and --height if given.
+    #[arg(long)]
+    pub mask: Option<PathBuf>,
+    /// What to read the mask from: luminance, alpha, red, green or blue. Pixels low in a color, or opaque, are cells.
+    #[arg(long, requires = "mask", default_value = "luminance")]
+    pub mask_channel: MaskChannel,
+    /// Cells whose share of ink in the mask channel, from 0 to 1, is above this are part of the maze.
+    #[arg(long, requires = "mask", default_value_t = 0.5)]
+    pub mask_threshold: f64,
+    /// Make the light pixels of the mask the cells of the maze instead.
+    #[arg(long, requires = "mask")]
+    pub invert_mask: bool,
+    /// Width and height of the square of mask pixels averaged into each cell.
+    #[arg(long, requires = "mask", default_value = "1")]
+    pub mask_cell_pixels: NonZeroUsize,
+    /// Drop islands of at most this many cells from the mask.
+    #[arg(long, requires = "mask", default_value_t = 0)]
+    pub despeckle: usize,
+    /// Fill in holes of at most this many cells enclosed by the mask.
+    #[arg(long, requires = "mask", default_value_t = 0)]
+    pub fill_holes: usize,
+    /// Save the cells read from the mask as a PNG image.
+    #[arg(long, requires = "mask")]
+    pub mask_preview: Option<PathBuf>,
+    /// Shape of the maze, such as `difference(disk(20,20,19),disk(20,20,6))`, within --width and --height if given.
+    #[arg(long)]
+    pub shape: Option<Mask>,
+    /// Words whose letters, in a built-in font, are the cells of the maze. Lines are split by newlines.
+    #[arg(long)]
+    pub lettering: Option<String>,
+    /// Width and height of the square of cells drawing each cell of a letter.
+    #[arg(long, requires = "lettering", default_value = "2")]
+    pub letter_scale: NonZeroUsize,
+    /// Number of cells between letters, and between lines.
+    #[arg(long, requires = "lettering", default_value_t = 1)]
+    pub letter_spacing: usize,
+    /// Grows the cells of the maze within --width and --height, such as `noise(perlin,8,0.5)`, `cave(0.55,B5678/S45678,5)`
+    /// or `blob(4,800)`. Only the largest part is kept.
+    #[arg(long)]
+    pub procedural: Option<Procedure>,
+    /// Image whose regions of flat color are the rooms of the maze, one cell to a pixel, leaving out transparent pixels.
+    /// Rooms are joined through one door each along a tree over the rooms that touch, and each carved into a maze.
+    #[arg(long)]
+    pub regions: Option<PathBuf>,
+    /// Width of the walls between the rooms of --regions in pixels. Defaults to twice the width of walls.
+    #[arg(long, requires = "regions")]
+    pub region_wall_width: Option<f32>,
+    /// Seed for growing the cells of the maze, the seed of the maze if absent.
+    #[arg(long, requires = "procedural")]
+    pub mask_seed: Option<u64>,
+    /// Draws the maze into a finer grid and carves that, such as `3,1,0.05,1` for cells 3 wide, walls 1 wide on each side
+    /// of a cell, and a 5% chance of a shortcut 1 wide through each wall. Repeat to nest deeper.
+    #[arg(long)]
+    pub nest: Vec<NestLevel>,
+    /// Turns every cell of the maze into a maze this many cells across, joined to its neighbors through one door wherever
+    /// the cells are linked. Repeat to subdivide deeper. Comes after any --nest.
+    #[arg(long)]
+    pub subdivide: Vec<NonZeroUsize>,
+    /// What to do when the mask or shape falls into several parts: reject it, keep the largest, carve a separate maze in
+    /// each, or bridge them with extra cells.
+    #[arg(long, default_value = "reject")]
+    pub disconnected: DisconnectedMask,
+}
+
+#[derive(Args, Debug)]
+pub struct RenderArgs {
+    /// Width of rendered images in pixels, ignored when --cell-size is given.
+    #[arg(long, default_value_t = 1000)]
+    pub image_width: usize,
+    /// Size of each cell, or width of each ring, in pixels.
+    #[arg(long)]
+    pub cell_size: Option<usize>,
+    #[arg(long, default_value_t = 8)]
+    pub padding: usize,
+    /// Color cells by their distance from the start.
+    #[arg(long)]
+    pub distances: bool,
+    /// Gradient for --distances: glacier, fire, trans, viridis, magma, inferno or cividis, or colors separated by commas,
+    /// each optionally followed by its position from 0 to 1, such as "#330000,#ff0 0.8,#fff".
+    #[arg(long, default_value = "fire")]
+    pub palette: Gradient,
+    /// Color space the palette is blended in: srgb, linear, hsl, hsv, oklab or oklch.
+    #[arg(long, default_value = "srgb")]
+    pub blend: ColorSpace,
+    /// Easing applied across the whole palette, such as linear or exp-in-out.
+    #[arg(long, default_value = "linear")]
+    pub easing: Easing,
+    /// How distances are spread along the palette: equalize, so that each color covers about as many cells, or an easing
+    /// such as linear, quad-in, sine-in-out, smoothstep or gamma:2.2.
+    #[arg(long, default_value = "linear")]
+    pub remap: DistanceRemap,
+    /// Draw text mazes with box drawing characters.
+    #[arg(long)]
+    pub unicode: bool,
+    /// Width of walls in pixels.
+    #[arg(long, conflicts_with = "wall_scale")]
+    pub wall_width: Option<f32>,
+    /// Width of walls as a fraction of the cell size.
+    #[arg(long)]
+    pub wall_scale: Option<f32>,
+    /// Width of the outline around the maze in pixels. Defaults to the width of walls.
+    #[arg(long)]
+    pub border_width: Option<f32>,
+    /// Draw the walls between square blocks of this many cells across heavier, such as the cells of the coarsest level
+    /// of a --subdivide maze.
+    #[arg(long)]
+    pub block_walls: Option<NonZeroUsize>,
+    /// Width of the walls between blocks in pixels. Defaults to twice the width of walls.
+    #[arg(long, requires = "block_walls")]
+    pub block_wall_width: Option<f32>,
+    /// Colors are #rgb, #rrggbb, #rrggbbaa, black, white or transparent.
+    #[arg(long, value_parser = parse_color)]
+    pub wall_color: Option<Color>,
+    /// Color of the whole image behind the maze. Transparent by default.
+    #[arg(long, value_parser = parse_color)]
+    pub background: Option<Color>,
+    /// Color of cells left out by a mask. Transparent by default.
+    #[arg(long, value_parser = parse_color)]
+    pub masked_color: Option<Color>,
+    /// Ends of walls: butt, round or square.
+    #[arg(long, value_parser = parse_line_cap)]
+    pub line_cap: Option<LineCap>,
+    /// Corners of walls: miter, round or bevel.
+    #[arg(long, value_parser = parse_line_join)]
+    pub line_join: Option<LineJoin>,
+    /// Opacity of cells and walls over the background, from 0 to 1.
+    #[arg(long)]
+    pub opacity: Option<f32>,
+    /// Draw square cells as rooms joined by corridors, trimming this fraction of the cell size off each side.
+    #[arg(long, value_parser = parse_inset)]
+    pub inset: Option<f32>,
+    /// Draw the passages through the cells instead of the walls between them.
+    #[arg(long)]
+    pub corridors: bool,
+    /// Width of corridors as a fraction of the cell size.
+    #[arg(long, requires = "corridors")]
+    pub corridor_width: Option<f32>,
+    /// Radius of the turns of corridors as a fraction of the cell size.
+    #[arg(long, requires = "corridors")]
+    pub corner_radius: Option<f32>,
+    /// PNG picture for the start cell, instead of the mouse.
+    #[arg(long, conflicts_with = "no_icons")]
+    pub start_icon: Option<PathBuf>,
+    /// PNG picture for the end cell, instead of the cheese.
+    #[arg(long, conflicts_with = "no_icons")]
+    pub end_icon: Option<PathBuf>,
+    /// Leave out the start and end icons.
+    #[arg(long)]
+    pub no_icons: bool,
+    /// PNG picture for any cell, as ROW,COLUMN=PATH (RING,COLUMN=PATH for radial mazes). Can be given more than once.
+    #[arg(long = "icon", value_name = "CELL=PATH", value_parser = parse_cell_icon)]
+    pub icons: Vec<((usize, usize), PathBuf)>,
+}
+
+/// Parses an icon for a cell, as `ROW,COLUMN=PATH`.
+pub(crate) fn parse_cell_icon(s: &str) -> Result<((usize, usize), PathBuf), String> {
+    let invalid = || format!("invalid cell icon `{}`, expected ROW,COLUMN=PATH", s);
+    let (cell, path) = s.split_once('=').ok_or_else(invalid)?;
+    let (row, column) = cell.split_once(',').ok_or_else(invalid)?;
+    let position = (row.trim().parse().map_err(|_| invalid())?, column.trim().parse().map_err(|_| invalid())?);
+    match path.trim() {
+        "" => Err(invalid()),
+        path => Ok((position, PathBuf::from(path))),
+    }
+}
+
+#[derive(Args, Debug)]
+pub struct EncodingArgs {
+    /// Cell layout of .maze output: bytes or packed.
+    #[arg(long, default_value = "packed")]
+    pub layout: CellLayout,
+    /// Compression of .maze output: none, run-length or lzss.
+    #[arg(long, default_value = "none")]
+    pub compression: Compression,
+}
+
+impl ShapeArgs {
+    pub(crate) fn source(&self) -> Result<Source, CommandError> {
+        let mut source = self.unnested_source()?;
+        let radial = matches!(source, Source::UnmaskedRadial { .. });
+        if matches!(source, Source::Regions { .. }) && !(self.nest.is_empty() && self.subdivide.is_empty()) {
+            return Err(CommandError::NestedRegions);
+        }
+        if !self.nest.is_empty() {
+            if radial {
+                return Err(CommandError::NestedRadial);
+            }
+            source = Source::Nested { source: Box::new(source), levels: self.nest.clone() };
+        }
+        if !self.subdivide.is_empty() {
+            if radial {
+                return Err(CommandError::SubdividedRadial);
+            }
+            source = Source::Subdivided { source: Box::new(source), sizes: self.subdivide.iter().map(|size| size.get()).collect() };
+        }
+        Ok(source)
+    }
+
+    fn unnested_source(&self) -> Result<Source, CommandError> {
+        if self.width.is_some() != self.height.is_some() {
+            return Err(CommandError::MissingShape);
+        }
+        let lettering = self.lettering.as_deref()
+            .map(|text| Mask::lettering(text, self.letter_scale.get(), self.letter_spacing))
+            .transpose()
+            .map_err(CommandError::Lettering)?;
+        let shape = match (&self.shape, lettering) {
+            (Some(_), Some(_)) => return Err(CommandError::ConflictingShapes),
+            (shape, lettering) => shape.clone().or(lettering),
+        };
+        if let Some(input) = &self.regions {
+            if self.width.is_some() || self.rings.is_some() || self.mask.is_some() || shape.is_some() || self.procedural.is_some() {
+                return Err(CommandError::ConflictingShapes);
+            }
+            return Ok(Source::Regions { input: input.clone(), wall_width: self.region_wall_width });
+        }
+        match (self.width.zip(self.height), self.rings, &self.mask, &shape, &self.procedural) {
+            (Some((width, height)), None, None, None, None) => Ok(Source::unmasked(width, height)),
+            (None, Some(rings), None, None, None) => Ok(Source::unmasked_radial(self.branches, rings)),
+            (size, None, Some(mask), None, None) if extension_of(mask).as_deref() == Some("path") => {
+                Ok(Source::VectorMask { input: mask.clone(), size, disconnected: self.disconnected })
+            },
+            (None, None, Some(mask), None, None) if extension_of(mask).as_deref() == Some("txt") => {
+                Ok(Source::TextMask { input: mask.clone(), disconnected: self.disconnected })
+            },
+            (None, None, Some(mask), None, None) => {
+                Ok(Source::FromInputMask { input: mask.clone(), options: self.mask_options(), preview: self.mask_preview.clone(), disconnected: self.disconnected })
+            },
+            (size, None, None, Some(shape), None) => {
+                let (width, height) = size.or_else(|| shape.bounds()).ok_or(CommandError::UnboundedShape)?;
+                Ok(Source::Shape { mask: shape.clone(), width, height, disconnected: self.disconnected })
+            },
+            (Some((width, height)), None, None, None, Some(procedure)) => {
+                Ok(Source::Procedural { procedure: procedure.clone(), width, height, seed: self.mask_seed })
+            },
+            (None, None, None, None, Some(_)) => Err(CommandError::UnboundedShape),
+            (None, None, None, None, None) => Err(CommandError::MissingShape),
+            _ => Err(CommandError::ConflictingShapes),
+        }
+    }
+
+    fn mask_options(&self) -> MaskOptions {
+        MaskOptions {
+            channel: self.mask_channel,
+            threshold: self.mask_threshold,
+            invert: self.invert_mask,
+            cell_pixels: self.mask_cell_pixels.get(),
+            despeckle: self.despeckle,
+            fill_holes: self.fill_holes,
+        }
+    }
+}
+
+impl RenderArgs {
+    fn options(&self, show_solution: bool) -> RenderOptions {
+        RenderOptions {
+            style: self.style(),
+            cell_size: self.cell_size,
+            coloring: if self.distances { Coloring::Distances(self.palette.clone().in_space(self.blend).eased(self.easing), self.remap) } else { Coloring::Plain },
+            show_solution,
+            icons: IconOptions {
+                start: (!self.no_icons).then(|| self.start_icon.clone().map_or(Icon::Embedded, Icon::File)),
+                end: (!self.no_icons).then(|| self.end_icon.clone().map_or(Icon::Embedded, Icon::File)),
+                cells: self.icons.clone(),
+            },
+        }
+    }
+
+    fn style(&self) -> RenderStyle {
+        let default = RenderStyle::default();
+        let wall_width = match (self.wall_width, self.wall_scale) {
+            (_, Some(fraction)) => WallWidth::CellFraction(fraction),
+            (Some(pixels), None) => WallWidth::Pixels(pixels),
+            (None, None) => default.wall_width,
+        };
+        RenderStyle {
+            padding: self.padding,
+            wall_color: self.wall_color.unwrap_or(default.wall_color),
+            wall_width,
+            border_width: self.border_width.map(WallWidth::Pixels),
+            line_cap: self.line_cap.unwrap_or(default.line_cap),
+            line_join: self.line_join.unwrap_or(default.line_join),
+            background: self.background.unwrap_or(default.background),
+            masked_out: self.masked_color.unwrap_or(default.masked_out),
+            opacity: self.opacity.unwrap_or(default.opacity),
+            inset: self.inset.unwrap_or(default.inset),
+            corridors: self.corridors.then(|| {
+                let default = CorridorStyle::default();
+                CorridorStyle {
+                    width: self.corridor_width.map_or(default.width, WallWidth::CellFraction),
+                    corner_radius: self.corner_radius.unwrap_or(default.corner_radius),
+                }
+            }),
+            zone_walls: self.block_walls.map(|size| ZoneWalls {
+                zones: Zones::Blocks(size.get()),
+                width: self.block_wall_width.map(WallWidth::Pixels),
+            }),
+        }
+    }
+
+    pub(crate) fn destination(&self, output: &Path, show_solution: bool, encoding: CellEncoding) -> Destination {
+        let charset = if self.unicode { TextCharset::Unicode } else { TextCharset::Ascii };
+        Destination::by_extension(output, self.image_width, charset, self.options(show_solution), encoding)
+    }
+
+    fn animation(&self, output: PathBuf, frames_dir: Option<PathBuf>, every: usize, delay_ms: u16) -> Destination {
+        Destination::Animation { output, frames_dir, image_width: self.image_width, options: self.options(false), every, delay_ms }
+    }
+}
+
+impl EncodingArgs {
+    pub(crate) fn encoding(&self) -> CellEncoding {
+        CellEncoding::new(self.layout, self.compression)
+    }
+}
+
+impl Cli {
+    /// Maps the parsed command line onto a [`Command`].
+    pub fn into_command(self) -> Result<Command, CommandError> {
+        let builder = CommandBuilder::new();
+        let builder = match self.command {
+            CliCommand::Generate { shape, algorithm, seed, output, render, encoding } => {
+                let builder = builder.source(shape.source()?).destination(render.destination(&output, false, encoding.encoding()));
+                let builder = match algorithm { Some(a) => builder.algorithm(a), None => builder };
+                match seed { Some(s) => builder.seed(s), None => builder }
+            },
+            CliCommand::Animate { shape, algorithm, seed, output, every, delay, frames, render } => {
+                let builder = builder.source(shape.source()?).destination(render.animation(output, frames, every.get(), delay));
+                let builder = match algorithm { Some(a) => builder.algorithm(a), None => builder };
+                match seed { Some(s) => builder.seed(s), None => builder }
+            },
+            CliCommand::Trace { shape, algorithm, seed, output } => {
+                let format = TraceFormat::by_extension(&output);
+                let builder = builder.source(shape.source()?).destination(Destination::Trace { output, format });
+                let builder = match algorithm { Some(a) => builder.algorithm(a), None => builder };
+                match seed { Some(s) => builder.seed(s), None => builder }
+            },
+            CliCommand::Render { input, output, render } => {
+                builder.source(Source::existing(input)).destination(render.destination(&output, false, CellEncoding::default()))
+            },
+            CliCommand::Solve { input, output, render } => {
+                builder.source(Source::existing(input)).destination(render.destination(&output, true, CellEncoding::default()))
+            },
+            CliCommand::Convert { input, output, encoding } => {
+                let destination = Destination::by_extension(output, 1000, TextCharset::default(), RenderOptions::default(), encoding.encoding());
+                builder.source(Source::existing(input)).destination(destination)
+            },
+            CliCommand::Stats { input } => {
+                builder.source(Source::existing(input)).destination(Destination::Stats)
+            },
+            CliCommand::Batch { .. } => return Err(CommandError::BatchFile),
+        };
+        builder.build()
+    }
+
+    /// Runs the command line, reporting failures as messages.
+    pub fn run(self) -> Result<(), String> {
+        if let CliCommand::Batch { file, threads } = &self.command {
+            let threads = threads.unwrap_or_else(|| thread::available_parallelism().map(|n| n.get()).unwrap_or(1));
+            let reports = batch::run_file(file, threads, |report| match &report.result {
+                Ok(()) => println!("done: {}", report.name),
+                Err(e) => println!("FAILED: {}: {}", report.name, e),
+            }).map_err(|e| e.to_string())?;
+            let failed = reports.iter().filter(|r| r.result.is_err()).count();
+            return if failed == 0 { Ok(()) } else { Err(format!("{} of {} jobs failed", failed, reports.len())) };
+        }
+        self.into_command().map_err(|e| e.to_string())?.run().map_err(|e| e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{color_gradients::Palette, parsers::{TextMazeError, TextMazeErrorKind}};
+
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Command, CommandError> {
+        Cli::try_parse_from(std::iter::once("mazes").chain(args.iter().copied())).unwrap().into_command()
+    }
+
+    #[test]
+    fn generate_maps_onto_command() {
+        let command = parse(&["generate", "--width", "10", "--height", "5", "-a", "wilson", "-s", "42", "-o", "out.maze", "--compression", "lzss"]).unwrap();
+        assert!(matches!(command.source, Source::Unmasked { width: 10, height: 5 }));
+        assert_eq!(command.algorithm, Algorithm::Wilson);
+        assert_eq!(command.seed, Some(42));
+        match command.destination {
+            Destination::Mazefile { encoding, .. } => assert_eq!(encoding.compression, Compression::Lzss),
+            _ => panic!("expected a maze file destination"),
+        }
+        let command = parse(&["generate", "--mask", "logo.png", "--mask-channel", "alpha", "--mask-cell-pixels", "4", "--despeckle", "2", "-o", "logo.png"]).unwrap();
+        match command.source {
+            Source::FromInputMask { options, preview: None, .. } => {
+                assert_eq!(options, MaskOptions { channel: MaskChannel::Alpha, cell_pixels: 4, despeckle: 2, ..MaskOptions::default() });
+            },
+            _ => panic!("expected a masked source"),
+        }
+        let command = parse(&["generate", "--mask", "rooms.txt", "--disconnected", "largest", "-o", "rooms.png"]).unwrap();
+        assert!(matches!(command.source, Source::TextMask { disconnected: DisconnectedMask::KeepLargest, .. }));
+        let command = parse(&["generate", "--shape", "difference(disk(10,10,9.5),disk(10,10,3))", "-o", "ring.png"]).unwrap();
+        assert!(matches!(command.source, Source::Shape { width: 20, height: 20, .. }));
+        assert!(matches!(parse(&["generate", "--shape", "stripes(4,2)", "-o", "s.png"]), Err(CommandError::UnboundedShape)));
+        let command = parse(&["generate", "--shape", "stripes(4,2)", "--width", "8", "--height", "6", "-o", "s.png"]).unwrap();
+        assert!(matches!(command.source, Source::Shape { width: 8, height: 6, .. }));
+        assert!(matches!(parse(&["generate", "--shape", "rect(9,9,2,2)", "--width", "8", "--height", "6", "-o", "s.png"]), Err(CommandError::EmptyShape)));
+        let command = parse(&["generate", "--lettering", "Hi", "--letter-scale", "3", "--letter-spacing", "2", "-o", "hi.png"]).unwrap();
+        assert!(matches!(command.source, Source::Shape { width: 32, height: 21, .. }));
+        assert_eq!(parse(&["generate", "--lettering", "Hi~", "-o", "hi.png"]).err(), Some(CommandError::Lettering(UnknownGlyph('~'))));
+        let command = parse(&["generate", "--width", "8", "--height", "6", "--nest", "3,1,0.1,1", "--nest", "2,1,0,1", "-o", "deep.png"]).unwrap();
+        match command.source {
+            Source::Nested { source, levels } => {
+                assert!(matches!(*source, Source::Unmasked { width: 8, height: 6 }));
+                assert_eq!(levels, ["3,1,0.1,1".parse().unwrap(), NestLevel { cell_size: 2, ..NestLevel::default() }]);
+            },
+            _ => panic!("expected a nested source"),
+        }
+        assert_eq!(parse(&["generate", "--rings", "4", "--nest", "3,1,0,1", "-o", "x.png"]).err(), Some(CommandError::NestedRadial));
+        assert_eq!(parse(&["generate", "--width", "0", "--height", "4", "--nest", "3,1,0,1", "-o", "x.png"]).err(), Some(CommandError::EmptyGrid { width: 0, height: 4 }));
+        let command = parse(&["generate", "--width", "6", "--height", "4", "--nest", "3,1,0,1", "--subdivide", "4", "--block-walls", "20", "-o", "rooms.png"]).unwrap();
+        match (command.source, command.destination) {
+            (Source::Subdivided { source, sizes }, Destination::Image { options, .. }) => {
+                assert!(matches!(*source, Source::Nested { .. }));
+                assert_eq!(sizes, [4]);
+                assert_eq!(options.style.zone_walls, Some(ZoneWalls { zones: Zones::Blocks(20), width: None }));
+            },
+            _ => panic!("expected a subdivided source drawn to an image"),
+        }
+        let command = parse(&["generate", "--regions", "rooms.png", "--region-wall-width", "6", "-o", "level.png"]).unwrap();
+        assert!(matches!(command.source, Source::Regions { wall_width: Some(6.0), .. }));
+        assert_eq!(parse(&["generate", "--regions", "rooms.png", "--width", "4", "--height", "4", "-o", "x.png"]).err(), Some(CommandError::ConflictingShapes));
+        assert_eq!(parse(&["generate", "--regions", "rooms.png", "--nest", "3,1,0,1", "-o", "x.png"]).err(), Some(CommandError::NestedRegions));
+        assert_eq!(parse(&["generate", "--regions", "rooms.png", "--subdivide", "3", "-o", "x.png"]).err(), Some(CommandError::NestedRegions));
+        assert_eq!(parse(&["generate", "--rings", "4", "--subdivide", "3", "-o", "x.png"]).err(), Some(CommandError::SubdividedRadial));
+        let command = parse(&["generate", "--mask", "star.path", "--width", "300", "--height", "200", "-o", "star.png"]).unwrap();
+        assert!(matches!(command.source, Source::VectorMask { size: Some((300, 200)), .. }));
+        let command = parse(&["generate", "--procedural", "cave(0.55,B5678/S45678,5)", "--mask-seed", "9", "--width", "30", "--height", "20", "-o", "cave.png"]).unwrap();
+        assert!(matches!(command.source, Source::Procedural { procedure: Procedure::Cave { iterations: 5, .. }, width: 30, height: 20, seed: Some(9) }));
+        assert_eq!(parse(&["generate", "--procedural", "blob(2,50)", "-o", "blob.png"]).err(), Some(CommandError::UnboundedShape));
+    }
+
+    #[test]
+    fn destination_follows_extension() {
+        let command = parse(&["solve", "maze.txt", "-o", "solved.png", "--distances", "--palette", "glacier"]).unwrap();
+        assert!(matches!(command.source, Source::TextMaze { .. }));
+        match command.destination {
+            Destination::Image { options, .. } => {
+                assert!(options.show_solution);
+                assert_eq!(options.coloring, Coloring::Distances(Palette::Glacier.gradient(), DistanceRemap::default()));
+            },
+            _ => panic!("expected an image destination"),
+        }
+        let command = parse(&["render", "in.maze", "-o", "out.png", "--distances", "--easing", "exp-in-out", "--remap", "equalize"]).unwrap();
+        match command.destination {
+            Destination::Image { options, .. } => {
+                assert_eq!(options.coloring, Coloring::Distances(Gradient::default().eased(Easing::ExpInOut), DistanceRemap::Equalized));
+            },
+            _ => panic!("expected an image destination"),
+        }
+        let command = parse(&["render", "in.maze", "-o", "out.txt", "--unicode"]).unwrap();
+        assert!(matches!(command.destination, Destination::Text { charset: TextCharset::Unicode, .. }));
+        let command = parse(&["trace", "--rings", "5", "-o", "run.JSONL"]).unwrap();
+        assert!(matches!(command.destination, Destination::Trace { format: TraceFormat::JsonLines, .. }));
+        let command = parse(&["trace", "--rings", "5", "-o", "run.trace"]).unwrap();
+        assert!(matches!(command.destination, Destination::Trace { format: TraceFormat::Binary, .. }));
+    }
+
+    #[test]
+    fn icons() {
+        let command = parse(&["render", "in.maze", "-o", "out.png", "--end-icon", "flag.png", "--icon", "2,3=key.png", "--icon", "0, 1=door.png"]).unwrap();
+        match command.destination {
+            Destination::Image { options, .. } => assert_eq!(options.icons, IconOptions {
+                start: Some(Icon::Embedded),
+                end: Some(Icon::File("flag.png".into())),
+                cells: vec![((2, 3), "key.png".into()), ((0, 1), "door.png".into())],
+            }),
+            _ => panic!("expected an image destination"),
+        }
+        assert!(parse_cell_icon("2,3").is_err());
+        assert!(parse_cell_icon("2,x=key.png").is_err());
+        assert!(Cli::try_parse_from(["mazes", "render", "in.maze", "-o", "out.png", "--no-icons", "--start-icon", "a.png"]).is_err());
+    }
+
+    #[test]
+    fn build_explains_problems() {
+        assert_eq!(CommandBuilder::new().destination(Destination::Stats).build().err(), Some(CommandError::MissingSource));
+        assert_eq!(CommandBuilder::new().source(Source::unmasked(3, 3)).build().err(), Some(CommandError::MissingDestination));
+        assert_eq!(parse(&["generate", "--width", "0", "--height", "5", "-o", "x.maze"]).err(), Some(CommandError::EmptyGrid { width: 0, height: 5 }));
+        assert_eq!(parse(&["generate", "--rings", "1", "-o", "x.maze"]).err(), Some(CommandError::TooFewRings(1)));
+        assert_eq!(parse(&["generate", "-o", "x.maze"]).err(), Some(CommandError::MissingShape));
+        assert_eq!(parse(&["generate", "--rings", "4", "--mask", "m.png", "-o", "x.maze"]).err(), Some(CommandError::ConflictingShapes));
+        assert_eq!(parse(&["render", "in.maze", "-o", "x.png", "--image-width", "10", "--padding", "5"]).err(),
+            Some(CommandError::PaddingTooLarge { image_width: 10, padding: 5 }));
+        let reading_with_seed = CommandBuilder::new().source(Source::mazefile("in.maze")).destination(Destination::Stats).seed(1).build();
+        assert_eq!(reading_with_seed.err(), Some(CommandError::NotGenerating));
+        let tracing_a_file = CommandBuilder::new().source(Source::mazefile("in.maze")).destination(Destination::Trace { output: "t.jsonl".into(), format: TraceFormat::JsonLines }).build();
+        assert_eq!(tracing_a_file.err(), Some(CommandError::NothingToTrace));
+    }
+
+    #[test]
+    fn read_errors_are_readable() {
+        let error = RunError::Read { path: "in.txt".into(), error: TextMazeError::new(3, 5, TextMazeErrorKind::UnexpectedCharacter('#')).into() };
+        assert_eq!(error.to_string(), "could not read a maze from in.txt: line 3, column 5: unexpected '#'");
+        let error = RunError::Read { path: "in.maze".into(), error: GridReadError::EndpointOutOfMaze((2, 7)) };
+        assert_eq!(error.to_string(), "could not read a maze from in.maze: the endpoint at (2, 7) does not lie on a cell of the maze");
+    }
+
+    #[test]
+    fn seeded_sources_repeat() {
+        let source = Source::unmasked(8, 6);
+        let (first, metadata, _) = source.load(Algorithm::Wilson, Some(9), &mut ()).unwrap();
+        let (second, ..) = source.load(Algorithm::Wilson, Some(9), &mut ()).unwrap();
+        assert_eq!(metadata.seed, Some(9));
+        assert_eq!(metadata.algorithm.as_deref(), Some("wilson"));
+        match (first, second) {
+            (Maze::MaskedMaze { maze: a, .. }, Maze::MaskedMaze { maze: b, .. }) => assert!(a == b),
+            _ => panic!("expected cartesian mazes"),
+        }
+    }
+
+    #[test]
+    fn stats_summaries_end_with_the_metadata() {
+        let (maze, metadata, _) = Source::unmasked(4, 3).load(Algorithm::Wilson, Some(2), &mut ()).unwrap();
+        let summary = stats_summary(&maze, &metadata);
+        assert!(summary.starts_with("topology: cartesian 4x3\n"));
+        assert!(summary.ends_with("seed: 2\nalgorithm: wilson\n"), "{}", summary);
+    }
+
+    #[test]
+    fn regions_load_with_their_walls() {
+        // Two rooms side by side, each two cells wide
+        let pixels = (0..8).flat_map(|i| if i % 4 < 2 { [0, 0, 0, 255] } else { [255; 4] }).collect();
+        let image = Pixmap::from_vec(pixels, tiny_skia::IntSize::from_wh(4, 2).unwrap()).unwrap();
+        let input = std::env::temp_dir().join(format!("mazes-regions-{}.png", std::process::id()));
+        image.save_png(&input).unwrap();
+
+        let source = Source::Regions { input: input.clone(), wall_width: Some(6.0) };
+        let (_, metadata, zone_walls) = source.load(Algorithm::Wilson, Some(3), &mut ()).unwrap();
+        std::fs::remove_file(&input).unwrap();
+        assert!(metadata.properties.contains(&("regions".to_string(), "2".to_string())));
+        let zone_walls = zone_walls.unwrap();
+        assert_eq!(zone_walls.width, Some(WallWidth::Pixels(6.0)));
+        assert!(zone_walls.zones.divides((0, 1), (0, 2)) && !zone_walls.zones.divides((0, 0), (1, 1)));
+    }
+}
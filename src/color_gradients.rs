@@ -1,5 +1,9 @@
+use std::str::FromStr;
+
 use tiny_skia::Color;
 
+use crate::{lerp::{lerp_stops, ColorSpace}, easing::Easing, render_style::parse_color};
+
 
 
 pub fn glacier_colors() -> [Color; 3] {
@@ -27,4 +31,324 @@ pub fn trans_colors() -> [Color; 5] {
         Color::from_rgba8(245, 169, 184, u8::MAX),
         Color::from_rgba8(91, 206, 250, u8::MAX),
     ]
-}
\ No newline at end of file
+}
+
+/// Perceptually uniform from dark purple to yellow, and readable with any kind of colour blindness.
+pub fn viridis_colors() -> [Color; 9] {
+    [
+        Color::from_rgba8(68, 1, 84, u8::MAX),
+        Color::from_rgba8(71, 45, 123, u8::MAX),
+        Color::from_rgba8(59, 82, 139, u8::MAX),
+        Color::from_rgba8(44, 114, 142, u8::MAX),
+        Color::from_rgba8(33, 145, 140, u8::MAX),
+        Color::from_rgba8(40, 174, 128, u8::MAX),
+        Color::from_rgba8(94, 201, 98, u8::MAX),
+        Color::from_rgba8(173, 220, 48, u8::MAX),
+        Color::from_rgba8(253, 231, 37, u8::MAX),
+    ]
+}
+
+/// Perceptually uniform from black through purple to pale yellow.
+pub fn magma_colors() -> [Color; 9] {
+    [
+        Color::from_rgba8(0, 0, 4, u8::MAX),
+        Color::from_rgba8(28, 16, 68, u8::MAX),
+        Color::from_rgba8(79, 18, 123, u8::MAX),
+        Color::from_rgba8(129, 37, 129, u8::MAX),
+        Color::from_rgba8(181, 54, 122, u8::MAX),
+        Color::from_rgba8(229, 89, 100, u8::MAX),
+        Color::from_rgba8(251, 135, 97, u8::MAX),
+        Color::from_rgba8(254, 194, 135, u8::MAX),
+        Color::from_rgba8(252, 253, 191, u8::MAX),
+    ]
+}
+
+/// Perceptually uniform from black through red to pale yellow.
+pub fn inferno_colors() -> [Color; 9] {
+    [
+        Color::from_rgba8(0, 0, 4, u8::MAX),
+        Color::from_rgba8(31, 12, 72, u8::MAX),
+        Color::from_rgba8(85, 15, 109, u8::MAX),
+        Color::from_rgba8(136, 34, 106, u8::MAX),
+        Color::from_rgba8(186, 54, 85, u8::MAX),
+        Color::from_rgba8(227, 89, 51, u8::MAX),
+        Color::from_rgba8(249, 142, 9, u8::MAX),
+        Color::from_rgba8(249, 203, 53, u8::MAX),
+        Color::from_rgba8(252, 255, 164, u8::MAX),
+    ]
+}
+
+/// Perceptually uniform from blue to yellow, designed to look the same with red-green colour blindness.
+pub fn cividis_colors() -> [Color; 10] {
+    [
+        Color::from_rgba8(0, 34, 78, u8::MAX),
+        Color::from_rgba8(18, 53, 112, u8::MAX),
+        Color::from_rgba8(59, 73, 108, u8::MAX),
+        Color::from_rgba8(87, 93, 109, u8::MAX),
+        Color::from_rgba8(112, 113, 115, u8::MAX),
+        Color::from_rgba8(138, 135, 121, u8::MAX),
+        Color::from_rgba8(166, 157, 117, u8::MAX),
+        Color::from_rgba8(196, 181, 108, u8::MAX),
+        Color::from_rgba8(228, 207, 91, u8::MAX),
+        Color::from_rgba8(254, 232, 56, u8::MAX),
+    ]
+}
+
+/// The built in gradients, by name.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Palette {
+    Glacier,
+    #[default]
+    Fire,
+    Trans,
+    Viridis,
+    Magma,
+    Inferno,
+    Cividis,
+}
+
+impl Palette {
+    /// Every built in palette, in the order they are listed to users.
+    pub const ALL: [Palette; 7] = [Palette::Glacier, Palette::Fire, Palette::Trans, Palette::Viridis, Palette::Magma, Palette::Inferno, Palette::Cividis];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Palette::Glacier => "glacier",
+            Palette::Fire => "fire",
+            Palette::Trans => "trans",
+            Palette::Viridis => "viridis",
+            Palette::Magma => "magma",
+            Palette::Inferno => "inferno",
+            Palette::Cividis => "cividis",
+        }
+    }
+
+    /// The palette's colors, evenly spaced along a gradient.
+    pub fn gradient(&self) -> Gradient {
+        match self {
+            Palette::Glacier => Gradient::even(glacier_colors()),
+            Palette::Fire => Gradient::even(fire_colors()),
+            Palette::Trans => Gradient::even(trans_colors()),
+            Palette::Viridis => Gradient::even(viridis_colors()),
+            Palette::Magma => Gradient::even(magma_colors()),
+            Palette::Inferno => Gradient::even(inferno_colors()),
+            Palette::Cividis => Gradient::even(cividis_colors()),
+        }
+    }
+}
+
+fn palette_names() -> String {
+    Palette::ALL.iter().map(|p| p.name()).collect::<Vec<_>>().join(", ")
+}
+
+impl FromStr for Palette {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Palette::ALL.into_iter().find(|p| p.name() == s).ok_or_else(|| format!("unknown palette `{}`, expected one of: {}", s, palette_names()))
+    }
+}
+
+/// Colors at increasing positions in `[0, 1]`, blended in between.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Gradient {
+    stops: Vec<(f64, Color)>,
+    /// The easing of each segment between two stops.
+    segment_easing: Vec<Easing>,
+    /// Applied to the whole gradient, before finding the segment.
+    easing: Easing,
+    space: ColorSpace,
+}
+
+impl Gradient {
+    /// Spreads `colors` evenly from `0` to `1`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if there are no colors.
+    pub fn even(colors: impl IntoIterator<Item = Color>) -> Self {
+        let colors: Vec<Color> = colors.into_iter().collect();
+        assert!(!colors.is_empty(), "A gradient needs at least one color");
+        let last = (colors.len() - 1).max(1) as f64;
+        Self::from_stops(colors.into_iter().enumerate().map(|(i, c)| (i as f64 / last, c)).collect())
+    }
+
+    fn from_stops(stops: Vec<(f64, Color)>) -> Self {
+        let segments = stops.len() - 1;
+        Gradient { stops, segment_easing: vec![Easing::Linear; segments], easing: Easing::Linear, space: ColorSpace::default() }
+    }
+
+    /// Blends colors in `space` rather than sRGB.
+    pub fn in_space(mut self, space: ColorSpace) -> Self {
+        self.space = space;
+        self
+    }
+
+    /// Reshapes the whole gradient with `easing`, on top of the easing of each segment.
+    pub fn eased(mut self, easing: Easing) -> Self {
+        self.easing = easing;
+        self
+    }
+
+    /// Eases the blend from stop `segment` to the next one.
+    ///
+    /// # Panics
+    ///
+    /// Panics if there is no stop after `segment`.
+    pub fn with_segment_easing(mut self, segment: usize, easing: Easing) -> Self {
+        self.segment_easing[segment] = easing;
+        self
+    }
+
+    pub fn stops(&self) -> &[(f64, Color)] {
+        &self.stops
+    }
+
+    pub fn space(&self) -> ColorSpace {
+        self.space
+    }
+
+    /// Maps a normalized distance in `[0, 1]` to a color along the gradient. Values outside the range get the end colors.
+    pub fn color_at(&self, t: f64) -> Color {
+        let t = self.easing.apply(t.clamp(0.0, 1.0));
+        lerp_stops(&self.stops, t, |segment, start, end, t| self.space.mix(start, end, self.segment_easing[segment].apply(t)))
+    }
+}
+
+impl Default for Gradient {
+    fn default() -> Self {
+        Palette::default().gradient()
+    }
+}
+
+/// Reads either the name of a [`Palette`] or a list of colors separated by commas, each optionally followed by its
+/// position, such as `#330000,#ff0 0.8,#fff` or `black,#f00 25%,white`.
+///
+/// Colors without a position are spread evenly between their neighbours, with the first at `0` and the last at `1`. Any
+/// color but the last may also name the [`Easing`] of the blend into the next one, as in `#000 exp-in-out,#fff`.
+impl FromStr for Gradient {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(palette) = s.parse::<Palette>() {
+            return Ok(palette.gradient());
+        }
+        if !s.contains(',') && !s.starts_with('#') {
+            return Err(format!("unknown palette `{}`, expected one of: {}, or a list of colors such as `#330000,#ff0 0.8,#fff`", s, palette_names()));
+        }
+        let mut colors = vec![];
+        let mut positions = vec![];
+        let mut easings = vec![];
+        for stop in s.split(',') {
+            let mut parts = stop.split_whitespace();
+            let color = parse_color(parts.next().ok_or_else(|| format!("empty color stop in `{}`", s))?)?;
+            let (mut position, mut easing) = (None, None);
+            for part in parts {
+                if part.starts_with(|c: char| c.is_ascii_digit() || c == '.') && position.is_none() {
+                    position = Some(parse_stop_position(part)?);
+                } else if easing.is_none() {
+                    easing = Some(part.parse::<Easing>()?);
+                } else {
+                    return Err(format!("unexpected `{}` after color stop `{}`", part, stop.trim()));
+                }
+            }
+            colors.push(color);
+            positions.push(position);
+            easings.push(easing);
+        }
+        if easings.pop().flatten().is_some() {
+            return Err(format!("the last color of `{}` has no blend after it to ease", s));
+        }
+        let last = positions.len() - 1;
+        positions[0] = positions[0].or(Some(0.0));
+        positions[last] = positions[last].or(Some(1.0));
+        // Fill gaps between known positions evenly
+        let mut known = 0;
+        for i in 1..positions.len() {
+            if let Some(position) = positions[i] {
+                let from = positions[known].unwrap();
+                for (k, gap) in positions.iter_mut().enumerate().take(i).skip(known + 1) {
+                    *gap = Some(from + (position - from) * (k - known) as f64 / (i - known) as f64);
+                }
+                known = i;
+            }
+        }
+        let stops: Vec<(f64, Color)> = positions.into_iter().map(Option::unwrap).zip(colors).collect();
+        if stops.windows(2).any(|pair| pair[1].0 < pair[0].0) {
+            return Err(format!("color stop positions in `{}` must not decrease", s));
+        }
+        let mut gradient = Gradient::from_stops(stops);
+        gradient.segment_easing = easings.into_iter().map(Option::unwrap_or_default).collect();
+        Ok(gradient)
+    }
+}
+
+/// A position along a gradient, as a fraction such as `0.8` or a percentage such as `80%`.
+fn parse_stop_position(s: &str) -> Result<f64, String> {
+    let position = match s.strip_suffix('%') {
+        Some(percent) => percent.parse::<f64>().map(|p| p / 100.0),
+        None => s.parse::<f64>(),
+    }.map_err(|_| format!("invalid color stop position `{}`", s))?;
+    if (0.0..=1.0).contains(&position) {
+        Ok(position)
+    } else {
+        Err(format!("color stop position `{}` is outside 0 to 1", s))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rgb(color: Color) -> (u8, u8, u8) {
+        let c = color.to_color_u8();
+        (c.red(), c.green(), c.blue())
+    }
+
+    #[test]
+    fn every_palette_by_name() {
+        for palette in Palette::ALL {
+            assert_eq!(palette.name().parse::<Palette>(), Ok(palette));
+            assert_eq!(palette.name().parse::<Gradient>(), Ok(palette.gradient()));
+        }
+        assert_eq!(rgb(Palette::Fire.gradient().color_at(1.0)), (255, 255, 0));
+        assert!("lava".parse::<Gradient>().unwrap_err().contains("viridis"));
+    }
+
+    #[test]
+    fn gradient_specs() {
+        let gradient: Gradient = "#330000,#ff0 0.8,#fff".parse().unwrap();
+        let positions: Vec<f64> = gradient.stops().iter().map(|s| s.0).collect();
+        assert_eq!(positions, [0.0, 0.8, 1.0]);
+        assert_eq!(rgb(gradient.color_at(0.8)), (255, 255, 0));
+        assert_eq!(rgb(gradient.color_at(0.9)), (255, 255, 128));
+        assert_eq!(rgb(gradient.color_at(-1.0)), (0x33, 0, 0));
+
+        let gradient: Gradient = "black, #f00, #00f 40%, white, white".parse().unwrap();
+        let positions: Vec<f64> = gradient.stops().iter().map(|s| s.0).collect();
+        assert_eq!(positions, [0.0, 0.2, 0.4, 0.7, 1.0]);
+
+        let hard: Gradient = "black,black 0.5,white 0.5,white".parse().unwrap();
+        assert_eq!(rgb(hard.color_at(0.5)), (0, 0, 0));
+        assert_eq!(rgb(hard.color_at(0.50001)), (255, 255, 255));
+
+        assert!("#fff 0.6,#000 0.4".parse::<Gradient>().is_err());
+        assert!("#fff,#000 1.5".parse::<Gradient>().is_err());
+        assert!("#fff,,#000".parse::<Gradient>().is_err());
+        assert!("#fff 0.1 0.2,#000".parse::<Gradient>().is_err());
+        assert_eq!(rgb("#123".parse::<Gradient>().unwrap().color_at(0.7)), (0x11, 0x22, 0x33));
+    }
+
+    #[test]
+    fn eased_segments() {
+        let gradient = "#000 exp-in-out,#fff 0.5,#f00".parse::<Gradient>().unwrap().in_space(ColorSpace::Srgb);
+        assert_eq!(rgb(gradient.color_at(0.2)), (32, 32, 32));
+        assert_eq!(rgb(gradient.color_at(0.25)), (128, 128, 128));
+        assert_eq!(rgb(gradient.color_at(0.75)), (255, 128, 128));
+        let gradient = gradient.eased(Easing::ExpInOut);
+        assert_eq!(rgb(gradient.color_at(0.2)), (0, 0, 0));
+        assert!("#000,#fff exp-in-out".parse::<Gradient>().is_err());
+        assert!("#000 bounce,#fff".parse::<Gradient>().is_err());
+        assert!("#000 0.1 exp-in-out linear,#fff".parse::<Gradient>().is_err());
+    }
+}
@@ -0,0 +1,460 @@
+use std::{io::{self, Read, Write}, collections::VecDeque, str::FromStr};
+
+
+
+/// Compression applied to the cell data of a maze file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Compression {
+    #[default]
+    None,
+    /// Pairs of `(count, byte)`. Good for large masked out regions.
+    RunLength,
+    /// LZ77 style back references into a sliding window of previous output.
+    Lzss,
+}
+
+impl Compression {
+    pub fn kind(&self) -> u8 {
+        match self {
+            Compression::None => 0,
+            Compression::RunLength => 1,
+            Compression::Lzss => 2,
+        }
+    }
+
+    pub fn from_kind(kind: u8) -> Option<Self> {
+        match kind {
+            0 => Some(Compression::None),
+            1 => Some(Compression::RunLength),
+            2 => Some(Compression::Lzss),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for Compression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Compression::None),
+            "run-length" | "rle" => Ok(Compression::RunLength),
+            "lzss" => Ok(Compression::Lzss),
+            _ => Err(format!("unknown compression `{}`, expected one of: none, run-length, lzss", s)),
+        }
+    }
+}
+
+/// Reads a single byte, returning `None` at the end of the input.
+fn read_byte(input: &mut impl Read) -> io::Result<Option<u8>> {
+    let mut byte = [0u8];
+    loop {
+        match input.read(&mut byte) {
+            Ok(0) => return Ok(None),
+            Ok(_) => return Ok(Some(byte[0])),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+pub struct RunLengthWriter<W: Write> {
+    out: W,
+    /// The byte being repeated, and how many times it has been so far.
+    run: Option<(u8, u8)>,
+}
+
+impl<W: Write> RunLengthWriter<W> {
+    pub fn new(out: W) -> Self {
+        RunLengthWriter { out, run: None }
+    }
+
+    /// Writes out the pending run. Must be called once all data has been written.
+    pub fn finish(mut self) -> io::Result<W> {
+        if let Some((byte, count)) = self.run.take() {
+            self.out.write_all(&[count, byte])?;
+        }
+        Ok(self.out)
+    }
+}
+
+impl<W: Write> Write for RunLengthWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for &b in buf {
+            match self.run {
+                Some((byte, count)) if byte == b && count < u8::MAX => {
+                    self.run = Some((byte, count + 1));
+                },
+                Some((byte, count)) => {
+                    self.out.write_all(&[count, byte])?;
+                    self.run = Some((b, 1));
+                },
+                None => {
+                    self.run = Some((b, 1));
+                },
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+pub struct RunLengthReader<R: Read> {
+    input: R,
+    /// The byte being repeated, and how many more times it will be.
+    run: (u8, u8),
+}
+
+impl<R: Read> RunLengthReader<R> {
+    pub fn new(input: R) -> Self {
+        RunLengthReader { input, run: (0, 0) }
+    }
+}
+
+impl<R: Read> Read for RunLengthReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut filled = 0;
+        while filled < buf.len() {
+            if self.run.1 == 0 {
+                let count = match read_byte(&mut self.input)? {
+                    Some(count) => count,
+                    None => break,
+                };
+                let byte = read_byte(&mut self.input)?.ok_or(io::ErrorKind::UnexpectedEof)?;
+                if count == 0 {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "Empty run in run length data"));
+                }
+                self.run = (byte, count);
+            }
+            buf[filled] = self.run.0;
+            self.run.1 -= 1;
+            filled += 1;
+        }
+        Ok(filled)
+    }
+}
+
+const WINDOW_SIZE: usize = 4096;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 18;
+/// Input is compressed in blocks of this many bytes, so memory use stays bounded however large the maze.
+const BLOCK_SIZE: usize = 1 << 16;
+/// How many earlier occurrences of a prefix are tried when looking for a match.
+const MAX_CHAIN: usize = 64;
+const HASH_BITS: usize = 12;
+
+/// LZSS compressor.
+///
+/// Output is a sequence of groups: a flag byte followed by up to eight tokens. A clear flag bit (starting from the least
+/// significant) marks a literal byte, a set one marks a back reference of two bytes: a 12 bit offset minus one, then a 4 bit
+/// length minus [`MIN_MATCH`].
+pub struct LzssWriter<W: Write> {
+    out: W,
+    block: Vec<u8>,
+    flags: u8,
+    token_count: u8,
+    group: Vec<u8>,
+}
+
+enum LzssToken {
+    Literal(u8),
+    Reference {
+        offset: usize,
+        length: usize,
+    },
+}
+
+fn prefix_hash(bytes: &[u8]) -> usize {
+    let h = ((bytes[0] as usize) << 16 | (bytes[1] as usize) << 8 | bytes[2] as usize).wrapping_mul(2654435761);
+    (h >> 16) & ((1 << HASH_BITS) - 1)
+}
+
+impl<W: Write> LzssWriter<W> {
+    pub fn new(out: W) -> Self {
+        LzssWriter { out, block: Vec::with_capacity(BLOCK_SIZE), flags: 0, token_count: 0, group: vec![] }
+    }
+
+    /// Compresses any buffered input and writes out the final group. Must be called once all data has been written.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.compress_block()?;
+        if self.token_count > 0 {
+            self.write_group()?;
+        }
+        Ok(self.out)
+    }
+
+    fn write_group(&mut self) -> io::Result<()> {
+        self.out.write_all(&[self.flags])?;
+        self.out.write_all(&self.group)?;
+        self.flags = 0;
+        self.token_count = 0;
+        self.group.clear();
+        Ok(())
+    }
+
+    fn push_token(&mut self, token: LzssToken) -> io::Result<()> {
+        match token {
+            LzssToken::Literal(b) => self.group.push(b),
+            LzssToken::Reference { offset, length } => {
+                self.flags |= 1 << self.token_count;
+                let packed = ((offset - 1) << 4 | (length - MIN_MATCH)) as u16;
+                self.group.extend_from_slice(&packed.to_be_bytes());
+            },
+        }
+        self.token_count += 1;
+        if self.token_count == 8 {
+            self.write_group()?;
+        }
+        Ok(())
+    }
+
+    fn compress_block(&mut self) -> io::Result<()> {
+        let block = std::mem::take(&mut self.block);
+        let mut head = vec![usize::MAX; 1 << HASH_BITS];
+        let mut previous = vec![usize::MAX; block.len()];
+        let mut i = 0;
+        while i < block.len() {
+            let mut best = (0, 0);
+            if i + MIN_MATCH <= block.len() {
+                let longest_possible = MAX_MATCH.min(block.len() - i);
+                let mut candidate = head[prefix_hash(&block[i..])];
+                let mut tried = 0;
+                while candidate != usize::MAX && i - candidate <= WINDOW_SIZE && tried < MAX_CHAIN {
+                    let length = (0..longest_possible).take_while(|&k| block[candidate + k] == block[i + k]).count();
+                    if length > best.0 {
+                        best = (length, i - candidate);
+                        if length == longest_possible {
+                            break;
+                        }
+                    }
+                    candidate = previous[candidate];
+                    tried += 1;
+                }
+            }
+
+            let step = if best.0 >= MIN_MATCH {
+                self.push_token(LzssToken::Reference { offset: best.1, length: best.0 })?;
+                best.0
+            } else {
+                self.push_token(LzssToken::Literal(block[i]))?;
+                1
+            };
+            for j in i..i + step {
+                if j + MIN_MATCH <= block.len() {
+                    let h = prefix_hash(&block[j..]);
+                    previous[j] = head[h];
+                    head[h] = j;
+                }
+            }
+            i += step;
+        }
+        self.block = block;
+        self.block.clear();
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for LzssWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let taken = buf.len().min(BLOCK_SIZE - self.block.len());
+        self.block.extend_from_slice(&buf[..taken]);
+        if self.block.len() == BLOCK_SIZE {
+            self.compress_block()?;
+        }
+        Ok(taken)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+pub struct LzssReader<R: Read> {
+    input: R,
+    window: Vec<u8>,
+    /// Total number of bytes decompressed so far.
+    position: usize,
+    flags: u8,
+    flags_left: u8,
+    pending: VecDeque<u8>,
+}
+
+impl<R: Read> LzssReader<R> {
+    pub fn new(input: R) -> Self {
+        LzssReader { input, window: vec![0; WINDOW_SIZE], position: 0, flags: 0, flags_left: 0, pending: VecDeque::new() }
+    }
+
+    fn emit(&mut self, b: u8) {
+        self.window[self.position % WINDOW_SIZE] = b;
+        self.position += 1;
+        self.pending.push_back(b);
+    }
+
+    /// Decodes the next token into `pending`. Returns `false` once the input is exhausted.
+    fn decode_token(&mut self) -> io::Result<bool> {
+        if self.flags_left == 0 {
+            match read_byte(&mut self.input)? {
+                Some(flags) => self.flags = flags,
+                None => return Ok(false),
+            }
+            self.flags_left = 8;
+        }
+        let is_reference = self.flags & 1 == 1;
+        self.flags >>= 1;
+        self.flags_left -= 1;
+
+        let first = match read_byte(&mut self.input)? {
+            Some(b) => b,
+            // The final group may have fewer than eight tokens
+            None => return Ok(false),
+        };
+        if is_reference {
+            let second = read_byte(&mut self.input)?.ok_or(io::ErrorKind::UnexpectedEof)?;
+            let packed = u16::from_be_bytes([first, second]) as usize;
+            let offset = (packed >> 4) + 1;
+            let length = (packed & 0xF) + MIN_MATCH;
+            if offset > self.position {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "Back reference before start of data"));
+            }
+            for _ in 0..length {
+                let b = self.window[(self.position - offset) % WINDOW_SIZE];
+                self.emit(b);
+            }
+        } else {
+            self.emit(first);
+        }
+        Ok(true)
+    }
+}
+
+impl<R: Read> Read for LzssReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pending.len() < buf.len() {
+            if !self.decode_token()? {
+                break;
+            }
+        }
+        let n = buf.len().min(self.pending.len());
+        for (slot, b) in buf.iter_mut().zip(self.pending.drain(..n)) {
+            *slot = b;
+        }
+        Ok(n)
+    }
+}
+
+/// A writer applying any [`Compression`].
+pub enum CompressedWriter<W: Write> {
+    None(W),
+    RunLength(RunLengthWriter<W>),
+    Lzss(LzssWriter<W>),
+}
+
+impl<W: Write> CompressedWriter<W> {
+    pub fn new(out: W, compression: Compression) -> Self {
+        match compression {
+            Compression::None => CompressedWriter::None(out),
+            Compression::RunLength => CompressedWriter::RunLength(RunLengthWriter::new(out)),
+            Compression::Lzss => CompressedWriter::Lzss(LzssWriter::new(out)),
+        }
+    }
+
+    pub fn finish(self) -> io::Result<W> {
+        match self {
+            CompressedWriter::None(out) => Ok(out),
+            CompressedWriter::RunLength(w) => w.finish(),
+            CompressedWriter::Lzss(w) => w.finish(),
+        }
+    }
+}
+
+impl<W: Write> Write for CompressedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            CompressedWriter::None(out) => out.write(buf),
+            CompressedWriter::RunLength(w) => w.write(buf),
+            CompressedWriter::Lzss(w) => w.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            CompressedWriter::None(out) => out.flush(),
+            CompressedWriter::RunLength(w) => w.flush(),
+            CompressedWriter::Lzss(w) => w.flush(),
+        }
+    }
+}
+
+/// A reader undoing any [`Compression`].
+pub enum DecompressedReader<R: Read> {
+    None(R),
+    RunLength(RunLengthReader<R>),
+    Lzss(LzssReader<R>),
+}
+
+impl<R: Read> DecompressedReader<R> {
+    pub fn new(input: R, compression: Compression) -> Self {
+        match compression {
+            Compression::None => DecompressedReader::None(input),
+            Compression::RunLength => DecompressedReader::RunLength(RunLengthReader::new(input)),
+            Compression::Lzss => DecompressedReader::Lzss(LzssReader::new(input)),
+        }
+    }
+}
+
+impl<R: Read> Read for DecompressedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            DecompressedReader::None(input) => input.read(buf),
+            DecompressedReader::RunLength(r) => r.read(buf),
+            DecompressedReader::Lzss(r) => r.read(buf),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(data: &[u8], compression: Compression) -> Vec<u8> {
+        let mut w = CompressedWriter::new(vec![], compression);
+        w.write_all(data).unwrap();
+        let compressed = w.finish().unwrap();
+        let mut out = vec![];
+        DecompressedReader::new(compressed.as_slice(), compression).read_to_end(&mut out).unwrap();
+        out
+    }
+
+    fn sample_data() -> Vec<u8> {
+        let mut data = vec![0u8; 1000];
+        data.extend((0..BLOCK_SIZE * 2 + 77).map(|i| ((i * 7) % 13 + (i / 300) % 5) as u8));
+        data.extend(std::iter::repeat_n(0xAB, 700));
+        data
+    }
+
+    #[test]
+    fn run_length_round_trip() {
+        let data = sample_data();
+        assert_eq!(round_trip(&data, Compression::RunLength), data);
+        assert_eq!(round_trip(&[], Compression::RunLength), Vec::<u8>::new());
+    }
+
+    #[test]
+    fn lzss_round_trip() {
+        let data = sample_data();
+        assert_eq!(round_trip(&data, Compression::Lzss), data);
+        assert_eq!(round_trip(&[], Compression::Lzss), Vec::<u8>::new());
+        assert_eq!(round_trip(&[1, 2], Compression::Lzss), vec![1, 2]);
+    }
+
+    #[test]
+    fn lzss_shrinks_repetitive_data() {
+        let data: Vec<u8> = (0..10_000).map(|i| (i % 10) as u8).collect();
+        let mut w = LzssWriter::new(vec![]);
+        w.write_all(&data).unwrap();
+        assert!(w.finish().unwrap().len() < data.len() / 4);
+    }
+}
@@ -1,8 +1,8 @@
-use std::{collections::HashSet, fmt::Display};
+use std::{collections::HashSet, fmt::Display, str::FromStr};
 
 use indicatif::{ProgressBar, ProgressStyle};
 
-use crate::pool::{Pool, NodeId};
+use crate::{pool::{Pool, NodeId}, easing::Easing};
 
 
 
@@ -62,6 +62,84 @@ impl Display for Distance {
     }
 }
 
+/// How distances are spread along a gradient.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DistanceRemap {
+    /// Each distance over the largest one, reshaped by the easing.
+    Eased(Easing),
+    /// Histogram equalisation: each distance maps to the share of reachable cells no further away, so that every part of
+    /// the gradient colors about as many cells. Long mazes, whose cells mostly sit at middling distances, would otherwise be
+    /// mostly one color.
+    Equalized,
+}
+
+impl Default for DistanceRemap {
+    fn default() -> Self {
+        DistanceRemap::Eased(Easing::Linear)
+    }
+}
+
+impl FromStr for DistanceRemap {
+    type Err = String;
+
+    /// Reads `equalize`, or the name of an [`Easing`].
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "equalize" => Ok(DistanceRemap::Equalized),
+            _ => s.parse().map(DistanceRemap::Eased).map_err(|e| format!("{}, or equalize", e)),
+        }
+    }
+}
+
+impl Distances {
+    /// The furthest distance of any reachable node.
+    pub fn max_finite(&self) -> usize {
+        self.pool.payloads().filter_map(|d| d.as_finite()).max().unwrap_or(0)
+    }
+
+    /// Maps the distance of every node to a position in `[0, 1]`, such as along a gradient. The start maps to `0` and the
+    /// furthest nodes to `1`. Unreachable nodes, and every node when nothing else is reachable, map to `0`.
+    pub fn normalized(&self, remap: DistanceRemap) -> impl Fn(NodeId) -> f64 + '_ {
+        let max = self.max_finite();
+        // Position of each distance along [0, 1]
+        let positions: Vec<f64> = match remap {
+            _ if max == 0 => vec![0.0],
+            DistanceRemap::Eased(easing) => (0..=max).map(|d| easing.apply(d as f64 / max as f64)).collect(),
+            DistanceRemap::Equalized => {
+                let mut counts = vec![0usize; max + 1];
+                for d in self.pool.payloads().filter_map(|d| d.as_finite()) {
+                    counts[d] += 1;
+                }
+                let (start, total) = (counts[0], counts.iter().sum::<usize>());
+                let mut seen = 0;
+                counts.iter().map(|&count| {
+                    seen += count;
+                    (seen - start) as f64 / (total - start) as f64
+                }).collect()
+            },
+        };
+        move |n| self.pool.get(n).payload.as_finite().map_or(0.0, |d| positions[d])
+    }
+
+    /// Finds a shortest path from the start node to `end`, by walking back along passages of decreasing distance.
+    ///
+    /// Returns `None` if `end` is not reachable.
+    pub fn path_to(&self, end: NodeId) -> Option<Vec<NodeId>> {
+        let mut current = end;
+        let mut distance = self.pool.get(end).payload.as_finite()?;
+        let mut path = vec![end];
+        while distance > 0 {
+            current = self.pool.passages_of(current).into_iter().find(|&n| {
+                self.pool.get(n).payload.as_finite() == Some(distance - 1)
+            })?;
+            distance -= 1;
+            path.push(current);
+        }
+        path.reverse();
+        Some(path)
+    }
+}
+
 impl DijkstraPad {
     pub fn new<T>(source: &Pool<T>, start_node: NodeId) -> Self {
         use Distance as D;
@@ -97,4 +175,35 @@ impl DijkstraPad {
             start_node: self.start_node,
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn remapped_distances() {
+        // A path of four nodes, with three more hanging off the second
+        let mut pool: Pool<()> = Pool::new();
+        let ids: Vec<NodeId> = (0..8).map(|_| pool.new_node(|_| ())).collect();
+        for (a, b) in [(0, 1), (1, 2), (2, 3), (1, 4), (1, 5), (1, 6)] {
+            pool.make_adjacent(ids[a], ids[b], true);
+            pool.link_cells(ids[a], ids[b], true);
+        }
+        let distances = DijkstraPad::new(&pool, ids[0]).perform();
+        assert_eq!(distances.max_finite(), 3);
+
+        let linear = distances.normalized(DistanceRemap::default());
+        let positions: Vec<f64> = ids.iter().map(|&n| linear(n)).collect();
+        assert_eq!(positions, [0.0, 1.0 / 3.0, 2.0 / 3.0, 1.0, 2.0 / 3.0, 2.0 / 3.0, 2.0 / 3.0, 0.0]);
+
+        // Of the six reachable nodes past the start, one is at distance 1, four at distance 2 and one at distance 3
+        let equalized = distances.normalized(DistanceRemap::Equalized);
+        let positions: Vec<f64> = ids.iter().map(|&n| equalized(n)).collect();
+        assert_eq!(positions, [0.0, 1.0 / 6.0, 5.0 / 6.0, 1.0, 5.0 / 6.0, 5.0 / 6.0, 5.0 / 6.0, 0.0]);
+
+        let eased = distances.normalized("quad-in".parse().unwrap());
+        assert_eq!(eased(ids[1]), 1.0 / 9.0);
+        assert!("sideways".parse::<DistanceRemap>().unwrap_err().ends_with("or equalize"));
+    }
+}
@@ -1,4 +1,4 @@
-
+use std::{f64::consts::PI, str::FromStr};
 
 pub fn exp_in_out(t: f64) -> f64 {
     if t < 0.5 {
@@ -6,4 +6,168 @@ pub fn exp_in_out(t: f64) -> f64 {
     } else {
         (2.0 - 2.0f64.powf(-20.0 * t + 10.0)) / 2.0
     }
-}
\ No newline at end of file
+}
+
+/// A curve reshaping progress through `[0, 1]`, leaving both ends in place.
+///
+/// `In` curves start slowly, `Out` curves end slowly, and `InOut` curves do both.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Easing {
+    #[default]
+    Linear,
+    QuadIn,
+    QuadOut,
+    QuadInOut,
+    CubicIn,
+    CubicOut,
+    CubicInOut,
+    SineIn,
+    SineOut,
+    SineInOut,
+    ExpIn,
+    ExpOut,
+    /// See [`exp_in_out`].
+    ExpInOut,
+    /// Overshoots backwards a few times before leaving the start.
+    ElasticIn,
+    /// Overshoots the end a few times before settling.
+    ElasticOut,
+    ElasticInOut,
+    /// Hermite smoothing, `3t² - 2t³`.
+    Smoothstep,
+    /// `t` raised to the given power. Above 1 favours the start, below 1 the end.
+    Gamma(f64),
+}
+
+impl Easing {
+    /// Every easing but [`Easing::Gamma`], which is named with its power, as in `gamma:2.2`.
+    pub const ALL: [Easing; 17] = [
+        Easing::Linear,
+        Easing::QuadIn, Easing::QuadOut, Easing::QuadInOut,
+        Easing::CubicIn, Easing::CubicOut, Easing::CubicInOut,
+        Easing::SineIn, Easing::SineOut, Easing::SineInOut,
+        Easing::ExpIn, Easing::ExpOut, Easing::ExpInOut,
+        Easing::ElasticIn, Easing::ElasticOut, Easing::ElasticInOut,
+        Easing::Smoothstep,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Easing::Linear => "linear",
+            Easing::QuadIn => "quad-in",
+            Easing::QuadOut => "quad-out",
+            Easing::QuadInOut => "quad-in-out",
+            Easing::CubicIn => "cubic-in",
+            Easing::CubicOut => "cubic-out",
+            Easing::CubicInOut => "cubic-in-out",
+            Easing::SineIn => "sine-in",
+            Easing::SineOut => "sine-out",
+            Easing::SineInOut => "sine-in-out",
+            Easing::ExpIn => "exp-in",
+            Easing::ExpOut => "exp-out",
+            Easing::ExpInOut => "exp-in-out",
+            Easing::ElasticIn => "elastic-in",
+            Easing::ElasticOut => "elastic-out",
+            Easing::ElasticInOut => "elastic-in-out",
+            Easing::Smoothstep => "smoothstep",
+            Easing::Gamma(_) => "gamma",
+        }
+    }
+
+    pub fn apply(&self, t: f64) -> f64 {
+        // Every curve but the elastic ones stays within [0, 1], and they all meet the ends exactly
+        if t <= 0.0 || t >= 1.0 {
+            return t.clamp(0.0, 1.0);
+        }
+        // Out curves mirror their in curve, and in-out curves join the two halves
+        let out = |ease_in: fn(f64) -> f64| 1.0 - ease_in(1.0 - t);
+        let in_out = |ease_in: fn(f64) -> f64| if t < 0.5 { ease_in(2.0 * t) / 2.0 } else { 1.0 - ease_in(2.0 - 2.0 * t) / 2.0 };
+        let quad = |t: f64| t * t;
+        let cubic = |t: f64| t * t * t;
+        let sine = |t: f64| 1.0 - (t * PI / 2.0).cos();
+        let expo = |t: f64| 2.0f64.powf(10.0 * t - 10.0);
+        let elastic = |t: f64| -(2.0f64.powf(10.0 * t - 10.0)) * ((10.0 * t - 10.75) * 2.0 * PI / 3.0).sin();
+        match self {
+            Easing::Linear => t,
+            Easing::QuadIn => quad(t),
+            Easing::QuadOut => out(quad),
+            Easing::QuadInOut => in_out(quad),
+            Easing::CubicIn => cubic(t),
+            Easing::CubicOut => out(cubic),
+            Easing::CubicInOut => in_out(cubic),
+            Easing::SineIn => sine(t),
+            Easing::SineOut => out(sine),
+            Easing::SineInOut => in_out(sine),
+            Easing::ExpIn => expo(t),
+            Easing::ExpOut => out(expo),
+            Easing::ExpInOut => exp_in_out(t),
+            Easing::ElasticIn => elastic(t),
+            Easing::ElasticOut => out(elastic),
+            Easing::ElasticInOut => in_out(elastic),
+            Easing::Smoothstep => t * t * (3.0 - 2.0 * t),
+            Easing::Gamma(power) => t.powf(*power),
+        }
+    }
+
+    /// Eases the input of `f`, such as a gradient made by [`crate::lerp::multi_lerp`].
+    pub fn ease<F>(self, f: impl Fn(f64) -> F) -> impl Fn(f64) -> F {
+        move |t| f(self.apply(t))
+    }
+}
+
+impl FromStr for Easing {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(power) = s.strip_prefix("gamma:") {
+            return match power.parse::<f64>() {
+                Ok(power) if power > 0.0 && power.is_finite() => Ok(Easing::Gamma(power)),
+                _ => Err(format!("invalid gamma `{}`, expected a positive number", power)),
+            };
+        }
+        Easing::ALL.into_iter().find(|e| e.name() == s).ok_or_else(|| {
+            let names: Vec<&str> = Easing::ALL.iter().map(|e| e.name()).collect();
+            format!("unknown easing `{}`, expected gamma:POWER or one of: {}", s, names.join(", "))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::lerp::multi_lerp;
+
+    use super::*;
+
+    #[test]
+    fn curves_keep_their_ends() {
+        for easing in Easing::ALL.into_iter().chain([Easing::Gamma(2.2), Easing::Gamma(0.5)]) {
+            assert_eq!(easing.apply(0.0), 0.0, "{:?}", easing);
+            assert_eq!(easing.apply(1.0), 1.0, "{:?}", easing);
+            assert!((easing.apply(1e-9)).abs() < 1e-3, "{:?} jumps at the start", easing);
+            assert!((easing.apply(1.0 - 1e-9) - 1.0).abs() < 1e-3, "{:?} jumps at the end", easing);
+            if !matches!(easing, Easing::ElasticIn | Easing::ElasticOut | Easing::ElasticInOut) {
+                let samples: Vec<f64> = (0..=100).map(|i| easing.apply(i as f64 / 100.0)).collect();
+                assert!(samples.windows(2).all(|w| w[0] <= w[1]), "{:?} goes backwards", easing);
+            }
+            if easing.name() != "gamma" {
+                assert_eq!(easing.name().parse::<Easing>(), Ok(easing));
+            }
+        }
+        for symmetric in [Easing::QuadInOut, Easing::CubicInOut, Easing::SineInOut, Easing::ExpInOut, Easing::Smoothstep] {
+            assert!((symmetric.apply(0.5) - 0.5).abs() < 1e-9, "{:?}", symmetric);
+        }
+        assert!(Easing::ElasticOut.apply(0.2) > 1.0);
+    }
+
+    #[test]
+    fn named_curves() {
+        assert_eq!(Easing::QuadIn.apply(0.5), 0.25);
+        assert_eq!(Easing::QuadOut.apply(0.5), 0.75);
+        assert_eq!(Easing::CubicInOut.apply(0.25), 0.0625);
+        assert_eq!("gamma:2".parse::<Easing>(), Ok(Easing::Gamma(2.0)));
+        assert!("gamma:-1".parse::<Easing>().is_err());
+        assert!("bounce".parse::<Easing>().is_err());
+        let eased = Easing::QuadIn.ease(multi_lerp([0.0, 100.0]));
+        assert_eq!(eased(0.5), 25.0);
+    }
+}
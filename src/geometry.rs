@@ -2,13 +2,13 @@ use std::ops::{Mul, Add, Div};
 
 
 
-#[derive(PartialEq, PartialOrd, Clone, Copy)]
+#[derive(Debug, PartialEq, PartialOrd, Clone, Copy)]
 pub struct PolarPoint {
     pub r: f64,
     pub theta: f64,
 }
 
-#[derive(PartialEq, PartialOrd, Clone, Copy)]
+#[derive(Debug, PartialEq, PartialOrd, Clone, Copy)]
 pub struct CartesianPoint { 
     pub x: f64,
     pub y: f64,
@@ -1,14 +1,13 @@
-use std::collections::HashSet;
-
 use rand::{
-    random, rngs::ThreadRng, thread_rng, Rng,
+    random, thread_rng, Rng,
 };
-use tiny_skia::{Color, LineCap, LineJoin, Paint, PathBuilder, Pixmap, Rect, Stroke, Transform};
+use tiny_skia::{FillRule, Paint, PathBuilder, Pixmap, Rect, Transform};
 
 use crate::{
-    dijkstra::{DijkstraPad, Distance},
+    color_gradients::Gradient,
+    dijkstra::{DijkstraPad, DistanceRemap},
     pool::{NodeId, Pool},
-    sample_uniform,
+    render_style::RenderStyle,
 };
 
 pub mod walker;
@@ -31,6 +30,17 @@ pub enum Direction {
     West,
 }
 
+impl std::fmt::Display for Direction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Direction::North => write!(f, "north"),
+            Direction::South => write!(f, "south"),
+            Direction::East => write!(f, "east"),
+            Direction::West => write!(f, "west"),
+        }
+    }
+}
+
 pub struct FlatSquareGrid {
     pub node_pool: Pool<FlatSquareCell>,
     node_grid: Vec<Vec<NodeId>>,
@@ -268,7 +278,7 @@ impl FlatSquareGrid {
         }
     }
 
-    pub fn aldous_broder(&mut self, rng: &mut ThreadRng) {
+    pub fn aldous_broder(&mut self, rng: &mut impl Rng) {
         // Pick a random starting cell
         let mut cell = self.node_pool.get_random_node_id(rng);
         let mut unvisited = self.size() - 1;
@@ -283,35 +293,16 @@ impl FlatSquareGrid {
         }
     }
 
-    pub fn hunt_and_kill(&mut self, rng: &mut ThreadRng) {
-        self.node_pool.hunt_and_kill(rng);
+    pub fn hunt_and_kill(&mut self, rng: &mut impl Rng) {
+        self.node_pool.hunt_and_kill(rng, &mut ());
         return;
     }
 
-    pub fn recursive_backtracker(&mut self, rng: &mut ThreadRng) {
-        let (mut visited, mut stack) = {
-            let start = self.node_pool.get_arbitrary_node_id();
-            (HashSet::from([start]), vec![start])
-        };
-        while let Some(&top_of_stack) = stack.last() {
-            let viable_cells: Vec<NodeId> = self
-                .node_pool
-                .unvisited_neighborhood_of(&visited, top_of_stack)
-                .into_iter()
-                .collect();
-            if viable_cells.is_empty() {
-                stack.pop();
-                continue;
-            } else {
-                let next_cell = *sample_uniform(&viable_cells, rng);
-                self.node_pool.link_cells(top_of_stack, next_cell, true);
-                stack.push(next_cell);
-                visited.insert(next_cell);
-            }
-        }
+    pub fn recursive_backtracker(&mut self, rng: &mut impl Rng) {
+        self.node_pool.recursive_backtracker(rng, &mut ());
     }
 
-    fn take_out_of(max: usize, rng: &mut ThreadRng) -> usize {
+    fn take_out_of(max: usize, rng: &mut impl Rng) -> usize {
         assert_ne!(max, 0);
         let mut taken = 1;
         while rng.gen() && taken < max {
@@ -369,27 +360,16 @@ impl FlatSquareGrid {
     pub fn image_print(
         &self,
         cell_size: usize,
-        padding: usize,
+        style: &RenderStyle,
         paint_function: impl Fn(NodeId) -> Paint<'static>,
     ) -> Pixmap {
+        if style.is_inset() {
+            return self.image_print_inset(cell_size, style, paint_function);
+        }
+        let padding = style.padding;
         let image_width = self.width * cell_size + 2 * padding;
         let image_height = self.height * cell_size + 2 * padding;
-        let mut pixmap = Pixmap::new(image_width as u32, image_height as u32).unwrap();
-
-        let paint = {
-            let mut paint = Paint::default();
-            paint.set_color_rgba8(0, 0, 0, 255);
-            paint.anti_alias = true;
-            paint
-        };
-
-        let stroke = {
-            let mut stroke = Stroke::default();
-            stroke.width = 3.0;
-            stroke.line_cap = LineCap::Round;
-            stroke.line_join = LineJoin::Round;
-            stroke
-        };
+        let mut pixmap = style.new_pixmap(image_width, image_height);
 
         let path = {
             let mut pb = PathBuilder::new();
@@ -436,21 +416,20 @@ impl FlatSquareGrid {
                     pb.line_to(right, top);
                 }
             }
-            // Then finish off by drawing the enclosing rectangle of entire maze
-            pb.move_to(padding as f32, padding as f32);
-            pb.push_rect(
-                Rect::from_ltrb(
-                    padding as f32,
-                    padding as f32,
-                    (padding + cell_size * self.width) as f32,
-                    (padding + cell_size * self.height) as f32,
-                )
-                .unwrap(),
-            );
-
-            pb.finish().unwrap()
+            pb.finish()
         };
 
+        // The enclosing rectangle of the entire maze is stroked as the border
+        let border = PathBuilder::from_rect(
+            Rect::from_ltrb(
+                padding as f32,
+                padding as f32,
+                (padding + cell_size * self.width) as f32,
+                (padding + cell_size * self.height) as f32,
+            )
+            .unwrap(),
+        );
+
         // Paint the interior of every cell according to the `paint_function`
         for row in 0..self.height {
             for col in 0..self.width {
@@ -461,48 +440,87 @@ impl FlatSquareGrid {
 
                 pixmap.fill_rect(
                     Rect::from_ltrb(left, top, right, bottom).unwrap(),
-                    &paint_function(self.get_by_position(row, col).id),
+                    &style.faded(paint_function(self.get_by_position(row, col).id)),
                     Transform::identity(),
                     None,
                 );
             }
         }
 
-        pixmap.stroke_path(&path, &paint, &stroke, Transform::identity(), None);
+        let wall_paint = style.wall_paint();
+        if let Some(path) = path {
+            pixmap.stroke_path(&path, &wall_paint, &style.wall_stroke(cell_size as f32), Transform::identity(), None);
+        }
+        pixmap.stroke_path(&border, &wall_paint, &style.border_stroke(cell_size as f32), Transform::identity(), None);
 
         return pixmap;
     }
 
+    /// Draws every cell as a room with corridors toward its linked neighbours, as set by the style's `inset`.
+    fn image_print_inset(
+        &self,
+        cell_size: usize,
+        style: &RenderStyle,
+        paint_function: impl Fn(NodeId) -> Paint<'static>,
+    ) -> Pixmap {
+        let padding = style.padding;
+        let image_width = self.width * cell_size + 2 * padding;
+        let image_height = self.height * cell_size + 2 * padding;
+        let mut pixmap = style.new_pixmap(image_width, image_height);
+
+        let mut walls = PathBuilder::new();
+        for row in 0..self.height {
+            for col in 0..self.width {
+                let cell = self.get_by_position(row, col);
+                let top = (row * cell_size + padding) as f32;
+                let left = (col * cell_size + padding) as f32;
+                let cell_rect = Rect::from_xywh(left, top, cell_size as f32, cell_size as f32).unwrap();
+
+                let mut floor = PathBuilder::new();
+                style.inset_cell(
+                    cell_rect,
+                    |direction| cell.at_direction(direction).is_some_and(|there| self.is_linked(cell.id, there)),
+                    &mut floor,
+                    &mut walls,
+                );
+                pixmap.fill_path(
+                    &floor.finish().unwrap(),
+                    &style.faded(paint_function(cell.id)),
+                    FillRule::Winding,
+                    Transform::identity(),
+                    None,
+                );
+            }
+        }
+
+        if let Some(walls) = walls.finish() {
+            pixmap.stroke_path(&walls, &style.wall_paint(), &style.wall_stroke(cell_size as f32), Transform::identity(), None);
+        }
+
+        pixmap
+    }
+
     pub fn image_print_distances(
         &self,
         cell_size: usize,
-        padding: usize,
+        style: &RenderStyle,
         start_node: NodeId,
-        color_function: impl Fn(f64) -> Color,
+        gradient: &Gradient,
+        remap: DistanceRemap,
     ) -> Pixmap {
         let distances = DijkstraPad::new(&self.node_pool, start_node).perform();
-        let max_finite_distance = distances
-            .pool
-            .payloads()
-            .map(|d| match d {
-                Distance::Infinite => 0,
-                Distance::Finite(dist) => *dist,
-            })
-            .max()
-            .unwrap_or(0) as f64;
+        let position = distances.normalized(remap);
 
-        if max_finite_distance == 0.0 {
-            self.image_print(cell_size, padding, |_| {
+        if distances.max_finite() == 0 {
+            self.image_print(cell_size, style, |_| {
                 let mut p = Paint::default();
                 p.set_color_rgba8(u8::MAX, u8::MAX, u8::MAX, u8::MAX);
                 p
             })
         } else {
-            self.image_print(cell_size, padding, |node_id| {
-                let dist = distances.pool.get(node_id).payload.as_finite().unwrap_or(0) as f64;
-                let normalized_distance = dist / max_finite_distance;
+            self.image_print(cell_size, style, |node_id| {
                 let mut p = Paint::default();
-                p.set_color(color_function(normalized_distance));
+                p.set_color(gradient.color_at(position(node_id)));
                 p
             })
         }
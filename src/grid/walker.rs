@@ -1,8 +1,8 @@
 use std::collections::HashSet;
 
-use rand::{rngs::ThreadRng, seq::IteratorRandom};
+use rand::{Rng, seq::SliceRandom};
 
-use crate::pool::{NodeId, Pool};
+use crate::pool::{NodeId, Pool, CarveEvent, CarveObserver, sorted_ids};
 
 use super::{FlatSquareGrid, Direction};
 
@@ -26,8 +26,8 @@ impl Walker {
         }
     }
 
-    pub fn random_loop_erased_step<T>(&mut self, pool: &Pool<T>, rng: &mut ThreadRng) {
-        let new_head = pool.neighborhood_of(self.final_node()).into_iter().choose(rng);
+    pub fn random_loop_erased_step<T>(&mut self, pool: &Pool<T>, rng: &mut impl Rng) {
+        let new_head = sorted_ids(pool.neighborhood_of(self.final_node())).choose(rng).copied();
         match new_head {
             Some(new_head) => self.loop_erased_step(new_head),
             None => panic!("Attempted to walk out of node {} with empty neighborhood.", self.final_node()),
@@ -40,7 +40,7 @@ impl Walker {
             RepeatedDirection::Start => {
                 self.path.truncate(0);
             },
-            RepeatedDirection::Middle(i) => self.path.truncate(i),
+            RepeatedDirection::Middle(i) => self.path.truncate(i + 1),
             RepeatedDirection::Never => self.path.push(next_cell),
         }
     }
@@ -72,16 +72,22 @@ impl Walker {
         *self.path.last().unwrap_or(&self.start_node)
     }
 
-    pub fn loop_erased_walk_into_haystack<N>(&mut self, pool: &Pool<N>, targets: &HashSet<NodeId>, rng: &mut ThreadRng) {
+    pub fn loop_erased_walk_into_haystack<N>(&mut self, pool: &Pool<N>, targets: &HashSet<NodeId>, rng: &mut impl Rng, observer: &mut impl CarveObserver) {
         while !targets.contains(&self.final_node()) {
-            self.random_loop_erased_step(pool, rng)
+            let before = self.total_path();
+            self.random_loop_erased_step(pool, rng);
+            if self.path.len() < before.len() {
+                observer.observe(CarveEvent::LoopErased { head: self.final_node(), erased: &before[self.path.len() + 1..] });
+            }
+            observer.observe(CarveEvent::WalkerStep { head: self.final_node(), path: &self.total_path() });
         }
     }
 
-    pub fn carve_path<T>(&self, pool: &mut Pool<T>) {
+    pub fn carve_path<T>(&self, pool: &mut Pool<T>, observer: &mut impl CarveObserver) {
         let path = self.total_path();
         for pair in path.windows(2) {
             pool.link_cells(pair[0], pair[1], true);
+            observer.observe(CarveEvent::Link(pair[0], pair[1]));
         }
     }
 }
@@ -91,25 +97,9 @@ impl FlatSquareGrid {
     /// Wilson's algorithm.
     /// 
     /// ~~Bad.~~ Fixed! Good!
-    pub fn wilson(&mut self, rng: &mut ThreadRng) {
-        let mut starts_list = self.node_pool.iter_node_ids().collect::<Vec<NodeId>>();
-        let mut visited_set: HashSet<NodeId> = HashSet::new();
-        if let Some(needle) = starts_list.pop() {
-            visited_set.insert(needle);   
-        }
-        while let Some(start) = starts_list.pop() {
-            let path = {
-                let mut path = Walker::new(start);
-                path.loop_erased_walk_into_haystack(&self.node_pool, &visited_set, rng);
-                path
-            };
-            let path_nodes = path.total_path();
-            path.carve_path(&mut self.node_pool);
-            visited_set.extend(path_nodes.into_iter());
-        }
+    pub fn wilson(&mut self, rng: &mut impl Rng) {
+        self.node_pool.wilson(rng, &mut ());
     }
-
-    
 }
 
 #[cfg(test)]
@@ -146,8 +136,9 @@ mod tests {
         assert_eq!(w.total_path(), vec![5,9,10,11,7]);
         w.loop_erased_step(w.next_step_at_direction(&grid, Direction::West).unwrap());
         assert_eq!(w.total_path(), vec![5,9,10,11,7,6]);
+        // Back onto 10, erasing the loop after it
         w.loop_erased_step(w.next_step_at_direction(&grid, Direction::South).unwrap());
-        assert_eq!(w.total_path(), vec![5,9]);
+        assert_eq!(w.total_path(), vec![5,9,10]);
     }
 
     #[test]
@@ -0,0 +1,275 @@
+use std::{str::FromStr, collections::VecDeque};
+
+use tiny_skia::{Pixmap, PremultipliedColorU8, Color};
+
+
+
+/// The part of each pixel an image mask is read from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MaskChannel {
+    /// How dark the pixel is, as drawn over white.
+    #[default]
+    Luminance,
+    /// How opaque the pixel is.
+    Alpha,
+    /// How little red the pixel has, as drawn over white.
+    Red,
+    Green,
+    Blue,
+}
+
+impl MaskChannel {
+    pub const ALL: [MaskChannel; 5] = [MaskChannel::Luminance, MaskChannel::Alpha, MaskChannel::Red, MaskChannel::Green, MaskChannel::Blue];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            MaskChannel::Luminance => "luminance",
+            MaskChannel::Alpha => "alpha",
+            MaskChannel::Red => "red",
+            MaskChannel::Green => "green",
+            MaskChannel::Blue => "blue",
+        }
+    }
+
+    /// How much ink the pixel holds in this channel, from 0 for none to 1 for full.
+    ///
+    /// Transparent pixels are drawn over white first, so they hold no ink in any channel but alpha.
+    pub fn ink(&self, pixel: PremultipliedColorU8) -> f64 {
+        let over_white = |c: u8| (c as f64 + (u8::MAX - pixel.alpha()) as f64) / u8::MAX as f64;
+        let [r, g, b] = [pixel.red(), pixel.green(), pixel.blue()].map(over_white);
+        match self {
+            MaskChannel::Luminance => 1.0 - (0.2126 * r + 0.7152 * g + 0.0722 * b),
+            MaskChannel::Alpha => pixel.alpha() as f64 / u8::MAX as f64,
+            MaskChannel::Red => 1.0 - r,
+            MaskChannel::Green => 1.0 - g,
+            MaskChannel::Blue => 1.0 - b,
+        }
+    }
+}
+
+impl FromStr for MaskChannel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        MaskChannel::ALL.into_iter().find(|channel| channel.name() == s).ok_or_else(|| {
+            let names: Vec<&str> = MaskChannel::ALL.iter().map(|channel| channel.name()).collect();
+            format!("unknown mask channel `{}`, expected one of: {}", s, names.join(", "))
+        })
+    }
+}
+
+/// How an image is turned into the cells of a mask.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MaskOptions {
+    pub channel: MaskChannel,
+    /// Cells holding more ink than this, from 0 to 1, are part of the maze.
+    pub threshold: f64,
+    /// Swaps the cells that are part of the maze with those that are not.
+    pub invert: bool,
+    /// Width and height of the square of pixels making up each cell, whose ink is averaged.
+    pub cell_pixels: usize,
+    /// Islands of at most this many cells are dropped.
+    pub despeckle: usize,
+    /// Holes of at most this many cells, enclosed by the maze, are filled in.
+    pub fill_holes: usize,
+}
+
+impl Default for MaskOptions {
+    fn default() -> Self {
+        MaskOptions { channel: MaskChannel::default(), threshold: 0.5, invert: false, cell_pixels: 1, despeckle: 0, fill_holes: 0 }
+    }
+}
+
+/// The cells of a grid that are part of a maze, read from an image.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImageMask {
+    width: usize,
+    height: usize,
+    cells: Vec<bool>,
+}
+
+impl ImageMask {
+    /// Reads a mask from `image`, one cell for each square of `options.cell_pixels` pixels, the last row and column of
+    /// cells covering what is left of the image.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `options.cell_pixels` is 0.
+    pub fn from_pixmap(image: &Pixmap, options: &MaskOptions) -> Self {
+        assert!(options.cell_pixels > 0, "Cells must cover at least one pixel");
+        let k = options.cell_pixels;
+        let (image_width, image_height) = (image.width() as usize, image.height() as usize);
+        let width = image_width.div_ceil(k);
+        let height = image_height.div_ceil(k);
+        let mut cells = Vec::with_capacity(width * height);
+        for row in 0..height {
+            for col in 0..width {
+                let ys = row * k..((row + 1) * k).min(image_height);
+                let xs = col * k..((col + 1) * k).min(image_width);
+                let count = ys.len() * xs.len();
+                let ink: f64 = ys.flat_map(|y| xs.clone().map(move |x| (x, y)))
+                    .map(|(x, y)| options.channel.ink(image.pixel(x as u32, y as u32).unwrap()))
+                    .sum();
+                cells.push((ink / count as f64 > options.threshold) != options.invert);
+            }
+        }
+        let mut mask = ImageMask { width, height, cells };
+        mask.despeckle(options.despeckle);
+        mask.fill_holes(options.fill_holes);
+        mask
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn contains(&self, row: usize, col: usize) -> bool {
+        row < self.height && col < self.width && self.cells[row * self.width + col]
+    }
+
+    /// Number of cells that are part of the maze.
+    pub fn cell_count(&self) -> usize {
+        self.cells.iter().filter(|&&c| c).count()
+    }
+
+    /// Drops the islands of at most `size` cells.
+    pub fn despeckle(&mut self, size: usize) {
+        for island in self.regions(true) {
+            if island.len() <= size {
+                island.into_iter().for_each(|i| self.cells[i] = false);
+            }
+        }
+    }
+
+    /// Fills in the holes of at most `size` cells. Gaps reaching the edge of the mask are not holes.
+    pub fn fill_holes(&mut self, size: usize) {
+        for hole in self.regions(false) {
+            let on_edge = hole.iter().any(|&i| {
+                let (row, col) = (i / self.width, i % self.width);
+                row == 0 || col == 0 || row + 1 == self.height || col + 1 == self.width
+            });
+            if !on_edge && hole.len() <= size {
+                hole.into_iter().for_each(|i| self.cells[i] = true);
+            }
+        }
+    }
+
+    /// Groups the cells that are, or are not, part of the maze into regions joined by their sides.
+    fn regions(&self, present: bool) -> Vec<Vec<usize>> {
+        let mut seen = vec![false; self.cells.len()];
+        let mut regions = vec![];
+        for start in 0..self.cells.len() {
+            if seen[start] || self.cells[start] != present {
+                continue;
+            }
+            seen[start] = true;
+            let mut region = vec![];
+            let mut queue = VecDeque::from([start]);
+            while let Some(i) = queue.pop_front() {
+                region.push(i);
+                let (row, col) = (i / self.width, i % self.width);
+                let neighbours = [
+                    (row > 0).then(|| i - self.width),
+                    (row + 1 < self.height).then(|| i + self.width),
+                    (col > 0).then(|| i - 1),
+                    (col + 1 < self.width).then(|| i + 1),
+                ];
+                for j in neighbours.into_iter().flatten() {
+                    if !seen[j] && self.cells[j] == present {
+                        seen[j] = true;
+                        queue.push_back(j);
+                    }
+                }
+            }
+            regions.push(region);
+        }
+        regions
+    }
+
+    /// Draws the mask with `cell_pixels` pixels to a cell, black where the maze is and white elsewhere.
+    pub fn preview(&self, cell_pixels: u32) -> Pixmap {
+        let mut image = Pixmap::new(self.width as u32 * cell_pixels, self.height as u32 * cell_pixels).unwrap();
+        image.fill(Color::WHITE);
+        let black = PremultipliedColorU8::from_rgba(0, 0, 0, u8::MAX).unwrap();
+        let image_width = image.width() as usize;
+        for (i, pixel) in image.pixels_mut().iter_mut().enumerate() {
+            let (y, x) = (i / image_width, i % image_width);
+            if self.contains(y / cell_pixels as usize, x / cell_pixels as usize) {
+                *pixel = black;
+            }
+        }
+        image
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn image(rows: &[&str]) -> Pixmap {
+        let mut image = Pixmap::new(rows[0].len() as u32, rows.len() as u32).unwrap();
+        let width = image.width() as usize;
+        for (i, pixel) in image.pixels_mut().iter_mut().enumerate() {
+            let (r, g, b, a) = match rows[i / width].as_bytes()[i % width] {
+                b'#' => (0, 0, 0, 255),
+                b'g' => (100, 100, 100, 255),
+                b'r' => (255, 0, 0, 255),
+                b' ' => (0, 0, 0, 0),
+                _ => (255, 255, 255, 255),
+            };
+            *pixel = PremultipliedColorU8::from_rgba(r, g, b, a).unwrap();
+        }
+        image
+    }
+
+    fn cells(mask: &ImageMask) -> Vec<String> {
+        (0..mask.height()).map(|row| (0..mask.width()).map(|col| if mask.contains(row, col) { '#' } else { '.' }).collect()).collect()
+    }
+
+    #[test]
+    fn thresholds_and_channels() {
+        let picture = image(&["#gr. "]);
+        let read = |options: MaskOptions| cells(&ImageMask::from_pixmap(&picture, &options));
+        assert_eq!(read(MaskOptions::default()), ["###.."]);
+        assert_eq!(read(MaskOptions { threshold: 0.7, ..MaskOptions::default() }), ["#.#.."]);
+        assert_eq!(read(MaskOptions { channel: MaskChannel::Red, ..MaskOptions::default() }), ["##..."]);
+        assert_eq!(read(MaskOptions { channel: MaskChannel::Green, ..MaskOptions::default() }), ["###.."]);
+        assert_eq!(read(MaskOptions { channel: MaskChannel::Alpha, ..MaskOptions::default() }), ["####."]);
+        assert_eq!(read(MaskOptions { invert: true, ..MaskOptions::default() }), ["...##"]);
+    }
+
+    #[test]
+    fn downsampled_and_cleaned() {
+        let picture = image(&[
+            "######..#",
+            "######...",
+            "##..##...",
+            "##..##..#",
+            "######...",
+        ]);
+        let read = |options: MaskOptions| cells(&ImageMask::from_pixmap(&picture, &options));
+        assert_eq!(read(MaskOptions { cell_pixels: 2, ..MaskOptions::default() }), ["###..", "#.#..", "###.."]);
+        assert_eq!(read(MaskOptions { despeckle: 1, ..MaskOptions::default() }), [
+            "######...",
+            "######...",
+            "##..##...",
+            "##..##...",
+            "######...",
+        ]);
+        assert_eq!(read(MaskOptions { despeckle: 1, fill_holes: 4, ..MaskOptions::default() }), [
+            "######...",
+            "######...",
+            "######...",
+            "######...",
+            "######...",
+        ]);
+        // Too big to be a hole
+        assert_eq!(read(MaskOptions { fill_holes: 3, ..MaskOptions::default() })[2], "##..##...");
+        let preview = ImageMask::from_pixmap(&picture, &MaskOptions { cell_pixels: 2, ..MaskOptions::default() }).preview(2);
+        assert_eq!((preview.width(), preview.height()), (10, 6));
+    }
+}
@@ -1,3 +1,5 @@
+use std::str::FromStr;
+
 use tiny_skia::Color;
 
 
@@ -56,6 +58,218 @@ pub fn multi_lerp<F: Lerpable + Clone, const N: usize>(points: [F; N]) -> impl F
     }
 }
 
+/// Blends between values at increasing positions in `[0, 1]`. `mix` is given the index of the segment `t` falls in, the
+/// values at either end of it, and how far along the segment `t` is. Values outside the stops get the end values.
+///
+/// # Panics
+///
+/// Panics if there are no stops.
+pub fn lerp_stops<F: Clone>(stops: &[(f64, F)], t: f64, mix: impl Fn(usize, &F, &F, f64) -> F) -> F {
+    let (first, last) = (&stops[0], &stops[stops.len() - 1]);
+    if t <= first.0 {
+        return first.1.clone();
+    }
+    match stops.windows(2).position(|pair| t <= pair[1].0) {
+        Some(i) => {
+            let ((from, start), (to, end)) = (&stops[i], &stops[i + 1]);
+            // Two stops at the same position switch values abruptly
+            if to > from { mix(i, start, end, (t - from) / (to - from)) } else { end.clone() }
+        },
+        None => last.1.clone(),
+    }
+}
+
+/// Spaces colors can be blended in. Blending sRGB values as they are stored darkens and muddies the middle of most blends.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ColorSpace {
+    /// Blends the stored values directly, as gradients always have.
+    #[default]
+    Srgb,
+    /// Mixes light evenly, as paint on a screen would.
+    LinearRgb,
+    /// Hue, saturation and lightness, going the short way around the hue circle.
+    Hsl,
+    /// Hue, saturation and value, going the short way around the hue circle.
+    Hsv,
+    /// Changes lightness and hue at an even pace to the eye.
+    Oklab,
+    /// OKLab as lightness, chroma and hue, going the short way around the hue circle, which keeps blends vivid.
+    Oklch,
+}
+
+impl ColorSpace {
+    pub const ALL: [ColorSpace; 6] = [ColorSpace::Srgb, ColorSpace::LinearRgb, ColorSpace::Hsl, ColorSpace::Hsv, ColorSpace::Oklab, ColorSpace::Oklch];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ColorSpace::Srgb => "srgb",
+            ColorSpace::LinearRgb => "linear",
+            ColorSpace::Hsl => "hsl",
+            ColorSpace::Hsv => "hsv",
+            ColorSpace::Oklab => "oklab",
+            ColorSpace::Oklch => "oklch",
+        }
+    }
+
+    /// Blends `start` into `end` in this space. Alpha is always blended linearly.
+    pub fn mix(&self, start: &Color, end: &Color, t: f64) -> Color {
+        let [from, to] = [start, end].map(|c| self.encode([c.red(), c.green(), c.blue()].map(f64::from)));
+        let mixed = match self.hue_channel() {
+            Some((hue, chroma)) => {
+                let mut mixed = <[f64; 3]>::inner_lerp(&from, &to, t);
+                // A gray has no hue of its own, so it takes the other color's
+                let (from_hue, to_hue) = match (from[chroma] < 1e-6, to[chroma] < 1e-6) {
+                    (true, false) => (to[hue], to[hue]),
+                    (false, true) => (from[hue], from[hue]),
+                    _ => (from[hue], to[hue]),
+                };
+                let turn = (to_hue - from_hue + 0.5).rem_euclid(1.0) - 0.5;
+                mixed[hue] = (from_hue + turn * t).rem_euclid(1.0);
+                mixed
+            },
+            None => <[f64; 3]>::inner_lerp(&from, &to, t),
+        };
+        let [r, g, b] = self.decode(mixed).map(|c| c.clamp(0.0, 1.0) as f32);
+        Color::from_rgba(r, g, b, f32::inner_lerp(&start.alpha(), &end.alpha(), t)).unwrap()
+    }
+
+    /// For spaces with a hue, the channels holding the hue, in turns, and the saturation or chroma.
+    fn hue_channel(&self) -> Option<(usize, usize)> {
+        match self {
+            ColorSpace::Hsl | ColorSpace::Hsv => Some((0, 1)),
+            ColorSpace::Oklch => Some((2, 1)),
+            _ => None,
+        }
+    }
+
+    /// Converts sRGB channels in `[0, 1]` into this space.
+    fn encode(&self, rgb: [f64; 3]) -> [f64; 3] {
+        match self {
+            ColorSpace::Srgb => rgb,
+            ColorSpace::LinearRgb => rgb.map(srgb_to_linear),
+            ColorSpace::Hsl => rgb_to_hsl(rgb),
+            ColorSpace::Hsv => rgb_to_hsv(rgb),
+            ColorSpace::Oklab => linear_to_oklab(rgb.map(srgb_to_linear)),
+            ColorSpace::Oklch => {
+                let [l, a, b] = linear_to_oklab(rgb.map(srgb_to_linear));
+                [l, a.hypot(b), (b.atan2(a) / std::f64::consts::TAU).rem_euclid(1.0)]
+            },
+        }
+    }
+
+    /// Converts a color in this space back into sRGB channels, which may fall outside `[0, 1]`.
+    fn decode(&self, color: [f64; 3]) -> [f64; 3] {
+        match self {
+            ColorSpace::Srgb => color,
+            ColorSpace::LinearRgb => color.map(linear_to_srgb),
+            ColorSpace::Hsl => hsl_to_rgb(color),
+            ColorSpace::Hsv => hsv_to_rgb(color),
+            ColorSpace::Oklab => oklab_to_linear(color).map(linear_to_srgb),
+            ColorSpace::Oklch => {
+                let [l, c, h] = color;
+                let angle = h * std::f64::consts::TAU;
+                oklab_to_linear([l, c * angle.cos(), c * angle.sin()]).map(linear_to_srgb)
+            },
+        }
+    }
+}
+
+impl FromStr for ColorSpace {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        ColorSpace::ALL.into_iter().find(|space| space.name() == s).ok_or_else(|| {
+            let names: Vec<&str> = ColorSpace::ALL.iter().map(|space| space.name()).collect();
+            format!("unknown color space `{}`, expected one of: {}", s, names.join(", "))
+        })
+    }
+}
+
+fn srgb_to_linear(c: f64) -> f64 {
+    if c <= 0.04045 { c / 12.92 } else { ((c + 0.055) / 1.055).powf(2.4) }
+}
+
+fn linear_to_srgb(c: f64) -> f64 {
+    if c <= 0.0031308 { c * 12.92 } else { 1.055 * c.max(0.0).powf(1.0 / 2.4) - 0.055 }
+}
+
+/// Björn Ottosson's OKLab, from linear sRGB.
+fn linear_to_oklab([r, g, b]: [f64; 3]) -> [f64; 3] {
+    let l = (0.4122214708 * r + 0.5363325363 * g + 0.0514459929 * b).cbrt();
+    let m = (0.2119034982 * r + 0.6806995451 * g + 0.1073969566 * b).cbrt();
+    let s = (0.0883024619 * r + 0.2817188376 * g + 0.6299787005 * b).cbrt();
+    [
+        0.2104542553 * l + 0.7936177850 * m - 0.0040720468 * s,
+        1.9779984951 * l - 2.4285922050 * m + 0.4505937099 * s,
+        0.0259040371 * l + 0.7827717662 * m - 0.8086757660 * s,
+    ]
+}
+
+fn oklab_to_linear([l, a, b]: [f64; 3]) -> [f64; 3] {
+    let l_ = (l + 0.3963377774 * a + 0.2158037573 * b).powi(3);
+    let m_ = (l - 0.1055613458 * a - 0.0638541728 * b).powi(3);
+    let s_ = (l - 0.0894841775 * a - 1.2914855480 * b).powi(3);
+    [
+        4.0767416621 * l_ - 3.3077115913 * m_ + 0.2309699292 * s_,
+        -1.2684380046 * l_ + 2.6097574011 * m_ - 0.3413193965 * s_,
+        -0.0041960863 * l_ - 0.7034186147 * m_ + 1.7076147010 * s_,
+    ]
+}
+
+/// The hue, in turns, and the largest and smallest channels.
+fn hue_of([r, g, b]: [f64; 3]) -> (f64, f64, f64) {
+    let max = r.max(g).max(b);
+    let min = r.min(g).min(b);
+    let delta = max - min;
+    let hue = if delta == 0.0 {
+        0.0
+    } else if max == r {
+        ((g - b) / delta).rem_euclid(6.0)
+    } else if max == g {
+        (b - r) / delta + 2.0
+    } else {
+        (r - g) / delta + 4.0
+    };
+    (hue / 6.0, max, min)
+}
+
+/// The color with the given hue, in turns, and chroma, before its lightness is raised by `offset`.
+fn from_hue(hue: f64, chroma: f64, offset: f64) -> [f64; 3] {
+    let sector = hue.rem_euclid(1.0) * 6.0;
+    let x = chroma * (1.0 - (sector % 2.0 - 1.0).abs());
+    let [r, g, b] = match sector as usize {
+        0 => [chroma, x, 0.0],
+        1 => [x, chroma, 0.0],
+        2 => [0.0, chroma, x],
+        3 => [0.0, x, chroma],
+        4 => [x, 0.0, chroma],
+        _ => [chroma, 0.0, x],
+    };
+    [r + offset, g + offset, b + offset]
+}
+
+fn rgb_to_hsv(rgb: [f64; 3]) -> [f64; 3] {
+    let (hue, max, min) = hue_of(rgb);
+    [hue, if max == 0.0 { 0.0 } else { (max - min) / max }, max]
+}
+
+fn hsv_to_rgb([h, s, v]: [f64; 3]) -> [f64; 3] {
+    let chroma = v * s;
+    from_hue(h, chroma, v - chroma)
+}
+
+fn rgb_to_hsl(rgb: [f64; 3]) -> [f64; 3] {
+    let (hue, max, min) = hue_of(rgb);
+    let lightness = (max + min) / 2.0;
+    let saturation = if max == min { 0.0 } else { (max - min) / (1.0 - (2.0 * lightness - 1.0).abs()) };
+    [hue, saturation, lightness]
+}
+
+fn hsl_to_rgb([h, s, l]: [f64; 3]) -> [f64; 3] {
+    let chroma = (1.0 - (2.0 * l - 1.0).abs()) * s;
+    from_hue(h, chroma, l - chroma / 2.0)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -95,6 +309,44 @@ mod tests {
         assert!(e_test(l(1.0), 0.0));
     }
 
+    fn rgb(color: Color) -> (u8, u8, u8) {
+        let c = color.to_color_u8();
+        (c.red(), c.green(), c.blue())
+    }
+
+    #[test]
+    fn uneven_stops() {
+        let stops = [(0.0, 0.0), (0.2, 100.0), (0.2, 50.0), (1.0, 10.0)];
+        let at = |t| lerp_stops(&stops, t, |_, a, b, t| f64::inner_lerp(a, b, t));
+        assert!((at(-1.0) - 0.0).abs() < 1e-10);
+        assert!((at(0.1) - 50.0).abs() < 1e-10);
+        assert!((at(0.2) - 100.0).abs() < 1e-10);
+        assert!((at(0.6) - 30.0).abs() < 1e-10);
+        assert!((at(2.0) - 10.0).abs() < 1e-10);
+    }
+
+    #[test]
+    fn color_spaces() {
+        let (red, green, blue) = (Color::from_rgba8(255, 0, 0, 255), Color::from_rgba8(0, 255, 0, 255), Color::from_rgba8(0, 0, 255, 255));
+        for space in ColorSpace::ALL {
+            assert_eq!(space.name().parse::<ColorSpace>(), Ok(space));
+            for color in [red, green, blue, Color::WHITE, Color::from_rgba8(12, 150, 99, 255)] {
+                assert_eq!(rgb(space.mix(&color, &color, 0.5)), rgb(color), "{:?} does not round trip", space);
+                assert_eq!(rgb(space.mix(&color, &Color::BLACK, 0.0)), rgb(color));
+            }
+        }
+        assert_eq!(rgb(ColorSpace::Srgb.mix(&red, &green, 0.5)), (128, 128, 0));
+        assert_eq!(rgb(ColorSpace::LinearRgb.mix(&red, &green, 0.5)), (188, 188, 0));
+        // The short way from red to blue goes through magenta
+        assert_eq!(rgb(ColorSpace::Hsv.mix(&red, &blue, 0.5)), (255, 0, 255));
+        assert_eq!(rgb(ColorSpace::Hsl.mix(&Color::WHITE, &blue, 0.5)), (159, 159, 223));
+        // OKLab puts the middle of black to white at middle gray to the eye, darker than the stored middle
+        let (gray, _, _) = rgb(ColorSpace::Oklab.mix(&Color::BLACK, &Color::WHITE, 0.5));
+        assert!((95..110).contains(&gray), "{}", gray);
+        let (r, g, b) = rgb(ColorSpace::Oklch.mix(&red, &green, 0.5));
+        assert!(r > 150 && g > 100 && b < 30, "{:?}", (r, g, b));
+    }
+
     #[test]
     fn multi_lerp_more_points() {
         let l = multi_lerp([0.0, 100.0, 50.0]);
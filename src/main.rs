@@ -1,11 +1,7 @@
-use std::fs::File;
-
-use cli::{Source, Destination};
+use clap::Parser;
+use cli::Cli;
 use indicatif::ProgressStyle;
-use masked_grid::MaskedGrid;
-use maze::{Maze, Algorithm};
-use rand::{rngs::ThreadRng, distributions::Uniform, prelude::Distribution, thread_rng};
-use tiny_skia::{Pixmap, PremultipliedColorU8};
+use rand::{Rng, distributions::Uniform, prelude::Distribution};
 
 pub mod pool;
 pub mod grid;
@@ -20,29 +16,27 @@ pub mod geometry;
 pub mod maze;
 pub mod parsers;
 pub mod triangle_grid;
+pub mod maze_file;
+pub mod compression;
+pub mod text_render;
+pub mod render_style;
+pub mod batch;
+pub mod animation;
+pub mod trace;
+pub mod image_mask;
+pub mod mask;
+pub mod font;
+pub mod procedural;
+pub mod vector_mask;
+pub mod regions;
 
 
-pub fn disk_mask(width: usize, height: usize, radius_ratio: f64, row: usize, col: usize) -> bool {
-    let x = col as f64;
-    let y = row as f64;
-    let hc = height as f64 / 2.0;
-    let wc = width as f64 / 2.0;
-    let dx = wc - x;
-    let dy = hc - y;
-    let dist = (dx * dx + dy * dy).sqrt();
-    return dist < hc.min(wc) * radius_ratio;
-}
-
-pub fn stripes_mask(width: usize, _height: usize, strip_width: usize, row: usize, col: usize) -> bool {
-    (width - row + col ) % (strip_width) < strip_width / 2
-}
-
 /// Samples an element of the slice, with equal probability each
 /// 
 /// # Panics
 /// 
 /// Panics if `slice` is empty
-pub fn sample_uniform<'s, A>(slice: &'s[A], rng: &mut ThreadRng) -> &'s A {
+pub fn sample_uniform<'s, A>(slice: &'s[A], rng: &mut impl Rng) -> &'s A {
     &slice[Uniform::from(0..slice.len()).sample(rng)]
 }
 
@@ -51,44 +45,9 @@ pub fn progress_style() -> ProgressStyle {
 }
 
 fn main() {
-    let mut rng = thread_rng();
-    let command = cli::CommandBuilder::new()
-        .source(Source::mazefile("TitanSanitized.maze"))
-        .destination(Destination::image(9000, 8, "output.png"))
-        .build().unwrap();
-
-
-    let g = match command.source {
-        Source::Mazefile { input } => {
-            let g = MaskedGrid::read_maze(File::open(input).unwrap()).unwrap();
-            let fp = g.pool.furthest_pair().unwrap();
-            Maze::MaskedMaze { maze: g, start: fp.0, end: fp.1 }
-        },
-        Source::FromInputMask { input } => {
-            let mask_image = Pixmap::load_png(input).unwrap();
-            let width = mask_image.width() as usize;
-            let height = mask_image.height() as usize;
-            let mask_function = move |row, col| {
-                mask_image.pixel(col as u32, row as u32).unwrap() == PremultipliedColorU8::from_rgba(0,0,0,u8::MAX).unwrap()
-            };
-            Maze::new_masked_cartesian(width, height, Box::new(mask_function), Algorithm::AldousBroder, &mut rng)
-        },
-        Source::Unmasked { width, height } => {
-            Maze::new_unmasked_cartesian(width, height, Algorithm::HuntAndKill, &mut rng)
-        },
-        Source::UnmaskedRadial { starting_branch_count, ring_count } => {
-            let g = Maze::new_unmasked_radial(starting_branch_count, ring_count, Algorithm::AldousBroder, &mut rng);
-            g
-        }
-    };
-
-    match command.destination {
-        Destination::Mazefile { output } => {
-            g.write_maze(File::create(output).unwrap()).unwrap()
-        },
-        Destination::Image { image_width, padding, output } => {
-            g.print_image(image_width, padding).save_png(output).unwrap()
-        },
+    if let Err(message) = Cli::parse().run() {
+        eprintln!("error: {}", message);
+        std::process::exit(1);
     }
 }
 
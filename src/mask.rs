@@ -0,0 +1,404 @@
+use std::{fmt::Display, str::FromStr};
+
+use tiny_skia::Pixmap;
+
+use crate::{image_mask::{ImageMask, MaskOptions}, parsers::{self, TextMaskError, TextMaskErrorKind}, font::{self, UnknownGlyph, GLYPH_WIDTH, GLYPH_HEIGHT}, masked_grid::{MaskedGrid, DisconnectedMask}};
+
+
+
+/// The cells marked as the start and end of a mask drawn as text, where there are any.
+pub type MarkedCells = (Option<(usize, usize)>, Option<(usize, usize)>);
+
+/// Which cells of a grid are part of a maze, built from shapes and pictures and the ways of combining them.
+///
+/// Points are `(row, col)` pairs, the cell at `row` and `col` being centered on `(row + 0.5, col + 0.5)`, and a shape
+/// holds the cells whose centers fall inside it. Masks are written and read as text, such as
+/// `difference(disk(10,10,9),rect(8,0,20,4))`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Mask {
+    /// Every cell.
+    Full,
+    /// `height` rows of `width` cells, from the cell at `row` and `col`.
+    Rectangle { row: usize, col: usize, width: usize, height: usize },
+    Disk { center: (f64, f64), radius: f64 },
+    /// An ellipse with `radii` along rows and along columns.
+    Ellipse { center: (f64, f64), radii: (f64, f64) },
+    /// The inside of the polygon through these points, by the even-odd rule.
+    Polygon(Vec<(f64, f64)>),
+    /// Stripes `thickness` cells wide, running down and to the right, repeating every `period` cells.
+    Stripes { period: usize, thickness: usize },
+    /// Squares of `size` cells, the one in the top left corner present.
+    Checkerboard { size: usize },
+    /// Cells listed row by row, as read from pictures and text.
+    Cells { width: usize, height: usize, cells: Vec<bool> },
+    Union(Box<Mask>, Box<Mask>),
+    Intersection(Box<Mask>, Box<Mask>),
+    /// The cells of the first mask that are not in the second.
+    Difference(Box<Mask>, Box<Mask>),
+    /// The cells in exactly one of the masks.
+    Xor(Box<Mask>, Box<Mask>),
+    Invert(Box<Mask>),
+    /// The mask moved down `rows` and right `cols`.
+    Translate { mask: Box<Mask>, rows: isize, cols: isize },
+    /// The mask grown `factor` times larger, away from the top left corner.
+    Scale { mask: Box<Mask>, factor: f64 },
+    /// The mask turned `degrees` clockwise around `center`.
+    Rotate { mask: Box<Mask>, degrees: f64, center: (f64, f64) },
+    /// The top left `height` rows of `width` cells of the mask, repeated in every direction.
+    Tile { mask: Box<Mask>, width: usize, height: usize },
+}
+
+impl Mask {
+    pub fn full() -> Self {
+        Mask::Full
+    }
+
+    pub fn rectangle(row: usize, col: usize, width: usize, height: usize) -> Self {
+        Mask::Rectangle { row, col, width, height }
+    }
+
+    pub fn disk(center: (f64, f64), radius: f64) -> Self {
+        Mask::Disk { center, radius }
+    }
+
+    pub fn ellipse(center: (f64, f64), radii: (f64, f64)) -> Self {
+        Mask::Ellipse { center, radii }
+    }
+
+    pub fn polygon(points: Vec<(f64, f64)>) -> Self {
+        Mask::Polygon(points)
+    }
+
+    /// Stripes `thickness` cells wide, running down and to the right, repeating every `period` cells.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `period` is 0.
+    pub fn stripes(period: usize, thickness: usize) -> Self {
+        assert!(period > 0, "Stripes must repeat at least every cell");
+        Mask::Stripes { period, thickness }
+    }
+
+    /// Squares `size` cells across, alternating in and out.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `size` is 0.
+    pub fn checkerboard(size: usize) -> Self {
+        assert!(size > 0, "Checkerboard squares must be at least one cell across");
+        Mask::Checkerboard { size }
+    }
+
+    /// Records which of `height` rows of `width` cells `contains` holds.
+    pub fn from_fn(width: usize, height: usize, contains: impl Fn(usize, usize) -> bool) -> Self {
+        let cells = (0..height).flat_map(|row| (0..width).map(move |col| (row, col))).map(|(row, col)| contains(row, col)).collect();
+        Mask::Cells { width, height, cells }
+    }
+
+    /// Reads the cells of a picture, see [`ImageMask::from_pixmap`].
+    pub fn image(image: &Pixmap, options: &MaskOptions) -> Self {
+        ImageMask::from_pixmap(image, options).into()
+    }
+
+    /// Lays out `text` in the built-in font, each of its cells drawn as a square of `scale` cells, with `spacing` cells
+    /// between letters and between lines. Letters that do not touch are joined with bridges one cell wide.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `scale` is 0.
+    pub fn lettering(text: &str, scale: usize, spacing: usize) -> Result<Self, UnknownGlyph> {
+        assert!(scale > 0, "Letters must be at least one cell large");
+        let lines: Vec<Vec<[u8; GLYPH_HEIGHT]>> = text.lines()
+            .map(|line| line.chars().map(|c| font::glyph(c).ok_or(UnknownGlyph(c))).collect())
+            .collect::<Result<_, _>>()?;
+        let span = |count: usize, size: usize| (count * (size * scale + spacing)).saturating_sub(spacing);
+        let width = span(lines.iter().map(|line| line.len()).max().unwrap_or(0), GLYPH_WIDTH);
+        let height = span(lines.len(), GLYPH_HEIGHT);
+        let letters = Mask::from_fn(width, height, |row, col| {
+            let (line, row) = (row / (GLYPH_HEIGHT * scale + spacing), row % (GLYPH_HEIGHT * scale + spacing) / scale);
+            let (letter, col) = (col / (GLYPH_WIDTH * scale + spacing), col % (GLYPH_WIDTH * scale + spacing) / scale);
+            row < GLYPH_HEIGHT && col < GLYPH_WIDTH
+                && lines[line].get(letter).is_some_and(|glyph| font::glyph_contains(glyph, row, col))
+        });
+        let joined = MaskedGrid::with_policy(width, height, letters, DisconnectedMask::Bridge).expect("Bridged masks are joined");
+        Ok(Mask::from_fn(width, height, |row, col| joined.get_id_at(row, col).is_some()))
+    }
+
+    /// Reads cells drawn as text, one line to a row, `#` or `X` for cells that are present and `.` or a space for those
+    /// that are not. Short lines are padded with absent cells. Cells marked `S` or `E` are present, see
+    /// [`Mask::read_text_with_endpoints`].
+    pub fn read_text(bytes: &[u8]) -> Result<Self, TextMaskError> {
+        Ok(Self::read_text_with_endpoints(bytes)?.0)
+    }
+
+    /// Reads cells drawn as text, like [`Mask::read_text`], along with the cells marked `S` and `E` for the start and end.
+    pub fn read_text_with_endpoints(bytes: &[u8]) -> Result<(Self, MarkedCells), TextMaskError> {
+        let text = parsers::decode_text(bytes).ok_or(TextMaskError::new(1, 1, TextMaskErrorKind::InvalidEncoding))?;
+        let text_mask = parsers::text_mask(&text)?;
+        let rows = text_mask.rows;
+        let width = rows.iter().map(|row| row.len()).max().unwrap_or(0);
+        let mask = Mask::from_fn(width, rows.len(), |row, col| rows[row].get(col).copied().unwrap_or(false));
+        Ok((mask, (text_mask.start, text_mask.end)))
+    }
+
+    pub fn union(self, other: Mask) -> Self {
+        Mask::Union(Box::new(self), Box::new(other))
+    }
+
+    pub fn intersection(self, other: Mask) -> Self {
+        Mask::Intersection(Box::new(self), Box::new(other))
+    }
+
+    pub fn difference(self, other: Mask) -> Self {
+        Mask::Difference(Box::new(self), Box::new(other))
+    }
+
+    pub fn xor(self, other: Mask) -> Self {
+        Mask::Xor(Box::new(self), Box::new(other))
+    }
+
+    pub fn invert(self) -> Self {
+        Mask::Invert(Box::new(self))
+    }
+
+    pub fn translate(self, rows: isize, cols: isize) -> Self {
+        Mask::Translate { mask: Box::new(self), rows, cols }
+    }
+
+    pub fn scale(self, factor: f64) -> Self {
+        Mask::Scale { mask: Box::new(self), factor }
+    }
+
+    pub fn rotate(self, degrees: f64, center: (f64, f64)) -> Self {
+        Mask::Rotate { mask: Box::new(self), degrees, center }
+    }
+
+    pub fn tile(self, width: usize, height: usize) -> Self {
+        Mask::Tile { mask: Box::new(self), width, height }
+    }
+
+    /// Whether the cell at `row` and `col` is part of the mask.
+    pub fn contains(&self, row: usize, col: usize) -> bool {
+        self.covers((row as f64 + 0.5, col as f64 + 0.5))
+    }
+
+    /// Whether the mask covers the point, those in the same cell covered alike unless the mask is a shape.
+    fn covers(&self, point: (f64, f64)) -> bool {
+        let (y, x) = point;
+        let (row, col) = (y.floor(), x.floor());
+        match self {
+            Mask::Full => true,
+            Mask::Rectangle { row: top, col: left, width, height } => {
+                (*top as f64..(top + height) as f64).contains(&row) && (*left as f64..(left + width) as f64).contains(&col)
+            },
+            Mask::Disk { center, radius } => (y - center.0).powi(2) + (x - center.1).powi(2) < radius * radius,
+            Mask::Ellipse { center, radii } => ((y - center.0) / radii.0).powi(2) + ((x - center.1) / radii.1).powi(2) < 1.0,
+            Mask::Polygon(points) => {
+                let mut inside = false;
+                for (i, &(y1, x1)) in points.iter().enumerate() {
+                    let (y2, x2) = points[(i + 1) % points.len()];
+                    if (y1 > y) != (y2 > y) && x < x1 + (y - y1) / (y2 - y1) * (x2 - x1) {
+                        inside = !inside;
+                    }
+                }
+                inside
+            },
+            Mask::Stripes { period, thickness } => ((col - row) as i64).rem_euclid(*period as i64) < *thickness as i64,
+            Mask::Checkerboard { size } => {
+                let size = *size as i64;
+                ((row as i64).div_euclid(size) + (col as i64).div_euclid(size)) % 2 == 0
+            },
+            Mask::Cells { width, height, cells } => {
+                row >= 0.0 && col >= 0.0 && (row as usize) < *height && (col as usize) < *width && cells[row as usize * width + col as usize]
+            },
+            Mask::Union(a, b) => a.covers(point) || b.covers(point),
+            Mask::Intersection(a, b) => a.covers(point) && b.covers(point),
+            Mask::Difference(a, b) => a.covers(point) && !b.covers(point),
+            Mask::Xor(a, b) => a.covers(point) != b.covers(point),
+            Mask::Invert(mask) => !mask.covers(point),
+            Mask::Translate { mask, rows, cols } => mask.covers((y - *rows as f64, x - *cols as f64)),
+            Mask::Scale { mask, factor } => mask.covers((y / factor, x / factor)),
+            Mask::Rotate { mask, degrees, center } => mask.covers(turn(point, -degrees, *center)),
+            Mask::Tile { mask, width, height } => mask.covers((y.rem_euclid(*height as f64), x.rem_euclid(*width as f64))),
+        }
+    }
+
+    /// The width and height of the smallest grid, from the top left corner, holding every cell of the mask. Masks going on
+    /// forever, such as stripes, have none.
+    pub fn bounds(&self) -> Option<(usize, usize)> {
+        let cells = |right: f64, bottom: f64| (right.max(0.0).ceil() as usize, bottom.max(0.0).ceil() as usize);
+        match self {
+            Mask::Full | Mask::Stripes { .. } | Mask::Checkerboard { .. } | Mask::Invert(_) | Mask::Tile { .. } => None,
+            Mask::Rectangle { row, col, width, height } => Some((col + width, row + height)),
+            Mask::Disk { center, radius } => Some(cells(center.1 + radius, center.0 + radius)),
+            Mask::Ellipse { center, radii } => Some(cells(center.1 + radii.1, center.0 + radii.0)),
+            Mask::Polygon(points) => Some(cells(points.iter().map(|p| p.1).fold(0.0, f64::max), points.iter().map(|p| p.0).fold(0.0, f64::max))),
+            Mask::Cells { width, height, .. } => Some((*width, *height)),
+            Mask::Union(a, b) | Mask::Xor(a, b) => {
+                let ((aw, ah), (bw, bh)) = a.bounds().zip(b.bounds())?;
+                Some((aw.max(bw), ah.max(bh)))
+            },
+            Mask::Intersection(a, b) => match (a.bounds(), b.bounds()) {
+                (Some((aw, ah)), Some((bw, bh))) => Some((aw.min(bw), ah.min(bh))),
+                (bounds, None) | (None, bounds) => bounds,
+            },
+            Mask::Difference(a, _) => a.bounds(),
+            Mask::Translate { mask, rows, cols } => {
+                let (width, height) = mask.bounds()?;
+                Some((width.saturating_add_signed(*cols), height.saturating_add_signed(*rows)))
+            },
+            Mask::Scale { mask, factor } => {
+                let (width, height) = mask.bounds()?;
+                Some(cells(width as f64 * factor, height as f64 * factor))
+            },
+            Mask::Rotate { mask, degrees, center } => {
+                let (width, height) = mask.bounds()?;
+                let (width, height) = (width as f64, height as f64);
+                let corners = [(0.0, 0.0), (0.0, width), (height, 0.0), (height, width)].map(|corner| turn(corner, *degrees, *center));
+                Some(cells(corners.iter().map(|c| c.1).fold(0.0, f64::max), corners.iter().map(|c| c.0).fold(0.0, f64::max)))
+            },
+        }
+    }
+}
+
+/// Turns a point `degrees` clockwise around `center`, rows growing downwards.
+fn turn(point: (f64, f64), degrees: f64, center: (f64, f64)) -> (f64, f64) {
+    let (sin, cos) = degrees.to_radians().sin_cos();
+    let (dy, dx) = (point.0 - center.0, point.1 - center.1);
+    (center.0 + dx * sin + dy * cos, center.1 + dx * cos - dy * sin)
+}
+
+impl From<ImageMask> for Mask {
+    fn from(mask: ImageMask) -> Self {
+        Mask::from_fn(mask.width(), mask.height(), |row, col| mask.contains(row, col))
+    }
+}
+
+impl Display for Mask {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Mask::Full => write!(f, "full"),
+            Mask::Rectangle { row, col, width, height } => write!(f, "rect({},{},{},{})", row, col, width, height),
+            Mask::Disk { center, radius } => write!(f, "disk({},{},{})", center.0, center.1, radius),
+            Mask::Ellipse { center, radii } => write!(f, "ellipse({},{},{},{})", center.0, center.1, radii.0, radii.1),
+            Mask::Polygon(points) => {
+                let coordinates: Vec<String> = points.iter().map(|(row, col)| format!("{},{}", row, col)).collect();
+                write!(f, "polygon({})", coordinates.join(","))
+            },
+            Mask::Stripes { period, thickness } => write!(f, "stripes({},{})", period, thickness),
+            Mask::Checkerboard { size } => write!(f, "checkerboard({})", size),
+            Mask::Cells { width, height, cells } => {
+                let rows: Vec<String> = (0..*height).map(|row| {
+                    cells[row * width..(row + 1) * width].iter().map(|&c| if c { '#' } else { '.' }).collect()
+                }).collect();
+                write!(f, "cells({},{},{})", width, height, rows.join("/"))
+            },
+            Mask::Union(a, b) => write!(f, "union({},{})", a, b),
+            Mask::Intersection(a, b) => write!(f, "intersection({},{})", a, b),
+            Mask::Difference(a, b) => write!(f, "difference({},{})", a, b),
+            Mask::Xor(a, b) => write!(f, "xor({},{})", a, b),
+            Mask::Invert(mask) => write!(f, "invert({})", mask),
+            Mask::Translate { mask, rows, cols } => write!(f, "translate({},{},{})", mask, rows, cols),
+            Mask::Scale { mask, factor } => write!(f, "scale({},{})", mask, factor),
+            Mask::Rotate { mask, degrees, center } => write!(f, "rotate({},{},{},{})", mask, degrees, center.0, center.1),
+            Mask::Tile { mask, width, height } => write!(f, "tile({},{},{})", mask, width, height),
+        }
+    }
+}
+
+impl FromStr for Mask {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match parsers::mask_spec(s) {
+            Ok(("", mask)) => Ok(mask),
+            Ok((rest, _)) => Err(format!("unexpected `{}` after the mask", rest)),
+            Err(_) => Err(format!("`{}` is not a mask, such as `union(disk(10,10,8),rect(0,0,20,4))`", s)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cells(mask: &Mask, width: usize, height: usize) -> Vec<String> {
+        (0..height).map(|row| (0..width).map(|col| if mask.contains(row, col) { '#' } else { '.' }).collect()).collect()
+    }
+
+    #[test]
+    fn lettering() {
+        // Joined along the top
+        assert_eq!(cells(&Mask::lettering("HI", 1, 1).unwrap(), 11, 7), [
+            "#...######.",
+            "#...#...#..",
+            "#...#...#..",
+            "#####...#..",
+            "#...#...#..",
+            "#...#...#..",
+            "#...#..###.",
+        ]);
+        for (text, scale, spacing) in [("HI", 1, 1), ("x-o!", 2, 3), ("A.\nB:C", 3, 2)] {
+            let mask = Mask::lettering(text, scale, spacing).unwrap();
+            let (width, height) = mask.bounds().unwrap();
+            assert!(MaskedGrid::new(width, height, mask).pool.is_adjacently_connected(), "{:?}", text);
+        }
+        assert_eq!(Mask::lettering("A~", 1, 1), Err(UnknownGlyph('~')));
+    }
+
+    #[test]
+    fn shapes() {
+        assert_eq!(cells(&Mask::rectangle(1, 2, 3, 2), 6, 4), ["......", "..###.", "..###.", "......"]);
+        assert_eq!(cells(&Mask::disk((2.5, 2.5), 2.3), 5, 5), [".###.", "#####", "#####", "#####", ".###."]);
+        assert_eq!(cells(&Mask::ellipse((1.5, 3.0), (1.5, 3.0)), 6, 3), [".####.", "######", ".####."]);
+        assert_eq!(cells(&Mask::polygon(vec![(0.0, 0.0), (4.0, 0.0), (4.0, 4.0)]), 4, 4), ["....", "#...", "##..", "###."]);
+        assert_eq!(cells(&Mask::stripes(4, 2), 6, 3), ["##..##", ".##..#", "..##.."]);
+        assert_eq!(cells(&Mask::checkerboard(2), 6, 3), ["##..##", "##..##", "..##.."]);
+        assert_eq!(Mask::disk((2.5, 2.5), 2.3).bounds(), Some((5, 5)));
+        assert_eq!(Mask::stripes(4, 2).bounds(), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "at least one cell across")]
+    fn empty_checkerboard_squares() {
+        Mask::checkerboard(0);
+    }
+
+    #[test]
+    fn combinators() {
+        let square = Mask::rectangle(0, 0, 2, 2);
+        let bar = Mask::rectangle(1, 0, 4, 1);
+        assert_eq!(cells(&square.clone().union(bar.clone()), 4, 3), ["##..", "####", "...."]);
+        assert_eq!(cells(&square.clone().intersection(bar.clone()), 4, 3), ["....", "##..", "...."]);
+        assert_eq!(cells(&square.clone().difference(bar.clone()), 4, 3), ["##..", "....", "...."]);
+        assert_eq!(cells(&square.clone().xor(bar.clone()), 4, 3), ["##..", "..##", "...."]);
+        assert_eq!(cells(&square.clone().invert(), 4, 3), ["..##", "..##", "####"]);
+        assert_eq!(cells(&square.clone().translate(1, 2), 4, 3), ["....", "..##", "..##"]);
+        assert_eq!(cells(&bar.clone().scale(2.0), 4, 4), ["....", "....", "####", "####"]);
+        assert_eq!(cells(&bar.clone().rotate(90.0, (1.5, 1.5)), 4, 4), [".#..", ".#..", ".#..", ".#.."]);
+        assert_eq!(cells(&Mask::rectangle(0, 0, 1, 1).tile(2, 2), 4, 3), ["#.#.", "....", "#.#."]);
+        assert_eq!(square.union(bar).translate(1, 0).bounds(), Some((4, 3)));
+    }
+
+    #[test]
+    fn written_and_read() {
+        let masks = [
+            Mask::disk((10.0, 10.5), 9.25).difference(Mask::rectangle(8, 0, 20, 4)),
+            Mask::polygon(vec![(0.0, 0.0), (4.0, 1.0), (2.0, 3.0)]).xor(Mask::checkerboard(3)).invert(),
+            Mask::ellipse((3.0, 4.0), (2.0, 3.0)).translate(-1, 2).scale(1.5).rotate(-30.0, (3.0, 3.0)).tile(5, 6),
+            Mask::from_fn(3, 2, |row, col| row != col).intersection(Mask::full()).union(Mask::stripes(5, 1)),
+        ];
+        for mask in masks {
+            assert_eq!(mask.to_string().parse::<Mask>(), Ok(mask.clone()), "{}", mask);
+        }
+        assert_eq!("union( disk(2, 2, 1.5), rect(0,0,1,1) )".parse::<Mask>(), Ok(Mask::disk((2.0, 2.0), 1.5).union(Mask::rectangle(0, 0, 1, 1))));
+        assert!("disk(2,2)".parse::<Mask>().is_err());
+        assert!("full full".parse::<Mask>().is_err());
+
+        assert_eq!(Mask::read_text(b"#.X\n. #\n##").unwrap(), Mask::from_fn(3, 3, |row, col| [[true, false, true], [false, false, true], [true, true, false]][row][col]));
+        assert_eq!(Mask::read_text(b"#.\n#?").unwrap_err(), TextMaskError::new(2, 2, TextMaskErrorKind::UnexpectedCharacter('?')));
+        let (mask, (start, end)) = Mask::read_text_with_endpoints(b"S#\n #\n E\n\n").unwrap();
+        assert_eq!((mask.bounds(), start, end), (Some((2, 3)), Some((0, 0)), Some((2, 1))));
+        assert_eq!(Mask::read_text(b"S#S").unwrap_err(), TextMaskError::new(1, 3, TextMaskErrorKind::RepeatedMarker('S')));
+    }
+}
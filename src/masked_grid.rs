@@ -1,30 +1,26 @@
-use std::{collections::{HashSet, HashMap}, io::{self, Write, BufWriter, Read, BufReader}};
+use std::{collections::{HashSet, HashMap, BTreeSet, VecDeque, hash_map::Entry}, io::{self, Write, BufWriter, Read, BufReader}, fmt::Display, str::FromStr};
 
 use indicatif::ProgressBar;
-use rand::{rngs::ThreadRng, random};
-use tiny_skia::{Pixmap, Paint, LineJoin, Stroke, LineCap, PathBuilder, Rect, Transform, Color, BlendMode, PixmapPaint, FilterQuality};
+use rand::Rng;
+use tiny_skia::{Pixmap, Paint, PathBuilder, Rect, Transform, FillRule};
 
-use crate::{pool::{Pool, NodeId}, dijkstra::{DijkstraPad, Distance}, grid::Direction};
+use crate::{pool::{Pool, NodeId, CarveObserver, CarveEvent}, maze::Algorithm, dijkstra::{DijkstraPad, DistanceRemap}, grid::Direction, maze_file::{MazeHeader, MazeMetadata, Topology, CellEncoding, CellLayout, BitWriter, BitReader}, compression::{CompressedWriter, DecompressedReader}, text_render::{TextCharset, centered}, render_style::{RenderStyle, Spoke, draw_icon}, color_gradients::Gradient, geometry::CartesianPoint, mask::Mask, parsers::{self, TextMazeWalls, TextMazeError, TextMazeErrorKind, TextMaskError}};
 
 
 
 pub struct MaskedGrid {
     pub pool: Pool<(usize, usize)>,
-    pub mask: Box<dyn Fn(usize, usize) -> bool>,
+    /// How the cells of the grid were chosen.
+    pub mask: Mask,
     pub width: usize,
     pub height: usize,
     pub cell_grid: HashMap<(usize, usize), NodeId>,
 }
 
+/// The cells of the grid are the keys of `cell_grid`, so grids whose masks were built differently but hold the same cells
+/// are equal.
 impl PartialEq for MaskedGrid {
     fn eq(&self, other: &Self) -> bool {
-        for row in 0..self.height {
-            for col in 0..self.width {
-                if (self.mask)(row, col) != (other.mask)(row, col) {
-                    return false;
-                }
-            }
-        }
         self.pool == other.pool && self.width == other.width && self.height == other.height && self.cell_grid == other.cell_grid
     }
 }
@@ -35,6 +31,23 @@ pub enum GridReadError {
     NotEnoughBytes,
     TooManyBytes,
     InvalidNewsGrid(NewsGridError),
+    /// The header could not be parsed. `offset` is the position of the first offending byte.
+    MalformedHeader {
+        offset: usize,
+    },
+    UnsupportedVersion(u16),
+    /// The file holds a different kind of grid than the one being read.
+    TopologyMismatch(Topology),
+    /// The file's dimensions cannot describe a grid of its topology.
+    InvalidTopology(Topology),
+    /// A stored endpoint does not lie on a cell of the maze.
+    EndpointOutOfMaze((usize, usize)),
+    InvalidText(TextMazeError),
+    InvalidMask(TextMaskError),
+    /// The mask falls into several parts, and was not to be split or joined.
+    DisconnectedMask(DisconnectedMaskError),
+    /// The marked start and end lie in parts of the mask that no cells join.
+    EndpointsApart { start: (usize, usize), end: (usize, usize) },
 }
 
 #[derive(Debug)]
@@ -52,6 +65,186 @@ pub enum NewsGridError {
         linked: (usize, usize),
         missing: (usize, usize),
         direction: Direction,
+    },
+    /// A cell outside the mask claims to be linked to a neighbor.
+    LinkedOutsideMask {
+        cell: (usize, usize),
+        direction: Direction,
+    },
+}
+
+impl Display for GridReadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GridReadError::IoError(e) => write!(f, "{}", e),
+            GridReadError::NotEnoughBytes => write!(f, "the file ends before the maze does"),
+            GridReadError::TooManyBytes => write!(f, "the file goes on after the maze ends"),
+            GridReadError::InvalidNewsGrid(e) => write!(f, "{}", e),
+            GridReadError::MalformedHeader { offset } => write!(f, "malformed header at byte {}", offset),
+            GridReadError::UnsupportedVersion(v) => write!(f, "maze format version {} is not supported", v),
+            GridReadError::TopologyMismatch(topology) => write!(f, "the file holds {}, not the kind of grid being read", topology),
+            GridReadError::InvalidTopology(topology) => write!(f, "the file describes {}, which has no cells", topology),
+            GridReadError::EndpointOutOfMaze(cell) => write!(f, "the endpoint at {:?} does not lie on a cell of the maze", cell),
+            GridReadError::InvalidText(e) => write!(f, "{}", e),
+            GridReadError::InvalidMask(e) => write!(f, "{}", e),
+            GridReadError::DisconnectedMask(e) => write!(f, "{}", e),
+            GridReadError::EndpointsApart { start, end } => {
+                write!(f, "the start at {:?} and the end at {:?} lie in parts of the mask that no cells join", start, end)
+            },
+        }
+    }
+}
+
+impl Display for NewsGridError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NewsGridError::UnrequitedConnection { linked, unlinked, direction } => {
+                write!(f, "the cell at {:?} is linked {} to {:?}, which is not linked back", linked, direction, unlinked)
+            },
+            NewsGridError::ConnectedOutOfBounds { cell, direction } => write!(f, "the cell at {:?} is linked {} out of the grid", cell, direction),
+            NewsGridError::ConnectedOutOfMask { linked, missing, direction } => {
+                write!(f, "the cell at {:?} is linked {} to {:?}, which is not part of the maze", linked, direction, missing)
+            },
+            NewsGridError::LinkedOutsideMask { cell, direction } => write!(f, "the cell at {:?} is not part of the maze, yet is linked {}", cell, direction),
+        }
+    }
+}
+
+/// The cells a maze is to start and end on, where they were marked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct MarkedEndpoints {
+    pub start: Option<NodeId>,
+    pub end: Option<NodeId>,
+}
+
+impl MarkedEndpoints {
+    /// The marked start and end, with a missing endpoint the node furthest from the other one, and with neither marked the
+    /// two nodes of `pool` furthest apart, if it has any.
+    pub fn or_furthest<T>(self, pool: &Pool<T>) -> Option<(NodeId, NodeId)> {
+        match (self.start, self.end) {
+            (Some(start), Some(end)) => Some((start, end)),
+            (Some(start), None) => Some((start, pool.furthest_from(start))),
+            (None, Some(end)) => Some((pool.furthest_from(end), end)),
+            (None, None) => pool.furthest_pair(),
+        }
+    }
+}
+
+/// What to do with a mask whose cells fall into parts that no neighboring cells join.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DisconnectedMask {
+    /// Refuse the mask with a [`DisconnectedMaskError`].
+    #[default]
+    Reject,
+    /// Drop every part but the largest from the mask.
+    KeepLargest,
+    /// Keep every part, each carved into a maze of its own.
+    Separate,
+    /// Join the parts by adding cells to the mask, each part in turn taking the fewest cells that join it to the others.
+    Bridge,
+}
+
+impl DisconnectedMask {
+    pub const ALL: [DisconnectedMask; 4] = [DisconnectedMask::Reject, DisconnectedMask::KeepLargest, DisconnectedMask::Separate, DisconnectedMask::Bridge];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            DisconnectedMask::Reject => "reject",
+            DisconnectedMask::KeepLargest => "largest",
+            DisconnectedMask::Separate => "separate",
+            DisconnectedMask::Bridge => "bridge",
+        }
+    }
+}
+
+impl FromStr for DisconnectedMask {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        DisconnectedMask::ALL.into_iter().find(|policy| policy.name() == s).ok_or_else(|| {
+            let names: Vec<&str> = DisconnectedMask::ALL.iter().map(|policy| policy.name()).collect();
+            format!("unknown way to handle disconnected masks `{}`, expected one of: {}", s, names.join(", "))
+        })
+    }
+}
+
+/// How each cell of a maze is drawn into a finer grid by [`MaskedGrid::nest`], written as
+/// `CELL_SIZE,WALL_HALF_WIDTH,SHORTCUT_PROBABILITY,SHORTCUT_THICKNESS`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NestLevel {
+    pub cell_size: usize,
+    pub wall_half_width: usize,
+    pub shortcut_probability: f32,
+    pub shortcut_thickness: usize,
+}
+
+impl NestLevel {
+    /// Width and height of the square of finer cells drawing each cell along with its walls.
+    pub fn spacing(&self) -> usize {
+        self.cell_size + 2 * self.wall_half_width
+    }
+
+    /// The finer cell in the middle of the one drawing cell `(row, col)`.
+    pub fn center_of(&self, (row, col): (usize, usize)) -> (usize, usize) {
+        let offset = self.wall_half_width + self.cell_size / 2;
+        (row * self.spacing() + offset, col * self.spacing() + offset)
+    }
+}
+
+impl Default for NestLevel {
+    fn default() -> Self {
+        NestLevel { cell_size: 3, wall_half_width: 1, shortcut_probability: 0.0, shortcut_thickness: 1 }
+    }
+}
+
+impl Display for NestLevel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{},{},{},{}", self.cell_size, self.wall_half_width, self.shortcut_probability, self.shortcut_thickness)
+    }
+}
+
+impl FromStr for NestLevel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let usage = || format!("`{}` is not a nesting level, such as `3,1,0.05,1` for cells of 3, walls of 1 on each side, and a 5% chance of shortcuts 1 wide", s);
+        let parts: Vec<&str> = s.split(',').map(str::trim).collect();
+        let [cell_size, wall_half_width, shortcut_probability, shortcut_thickness] = parts[..] else {
+            return Err(usage());
+        };
+        let level = NestLevel {
+            cell_size: cell_size.parse().map_err(|_| usage())?,
+            wall_half_width: wall_half_width.parse().map_err(|_| usage())?,
+            shortcut_probability: shortcut_probability.parse().map_err(|_| usage())?,
+            shortcut_thickness: shortcut_thickness.parse().map_err(|_| usage())?,
+        };
+        // Walls of no width would leave walled off cells touching in the finer grid
+        if level.cell_size == 0 || level.wall_half_width == 0 || level.shortcut_thickness > level.cell_size || !(0.0..=1.0).contains(&level.shortcut_probability) {
+            return Err(format!("`{}` needs cells and walls of at least 1, shortcuts no wider than cells, and a chance from 0 to 1", s));
+        }
+        Ok(level)
+    }
+}
+
+/// A mask given to [`MaskedGrid::with_policy`] falls into several parts.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DisconnectedMaskError {
+    /// The cells of each part, largest part first.
+    pub parts: Vec<Vec<(usize, usize)>>,
+}
+
+impl Display for DisconnectedMaskError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        const LISTED: usize = 5;
+        write!(f, "the mask falls into {} parts that no cells join:", self.parts.len())?;
+        for (i, part) in self.parts.iter().take(LISTED).enumerate() {
+            let cells = if part.len() == 1 { "cell" } else { "cells" };
+            write!(f, "{} {} {} from {:?}", if i == 0 { "" } else { "," }, part.len(), cells, part[0])?;
+        }
+        if self.parts.len() > LISTED {
+            write!(f, " and {} more", self.parts.len() - LISTED)?;
+        }
+        Ok(())
     }
 }
 
@@ -61,6 +254,24 @@ impl From<std::io::Error> for GridReadError {
     }
 }
 
+impl From<TextMazeError> for GridReadError {
+    fn from(value: TextMazeError) -> Self {
+        GridReadError::InvalidText(value)
+    }
+}
+
+impl From<TextMaskError> for GridReadError {
+    fn from(value: TextMaskError) -> Self {
+        GridReadError::InvalidMask(value)
+    }
+}
+
+impl From<DisconnectedMaskError> for GridReadError {
+    fn from(value: DisconnectedMaskError) -> Self {
+        GridReadError::DisconnectedMask(value)
+    }
+}
+
 impl From<NewsGridError> for GridReadError {
     fn from(value: NewsGridError) -> Self {
         GridReadError::InvalidNewsGrid(value)
@@ -70,16 +281,102 @@ impl From<NewsGridError> for GridReadError {
 impl MaskedGrid {
 
     pub fn new_unmasked(width: usize, height: usize) -> Self {
-        Self::new(width, height, Box::new(|_, _| true))
+        Self::new(width, height, Mask::Full)
+    }
+
+    /// Builds a grid over the cells of `mask`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the mask falls into several parts. [`MaskedGrid::with_policy`] handles such masks.
+    pub fn new(width: usize, height: usize, mask: Mask) -> Self {
+        match Self::with_policy(width, height, mask, DisconnectedMask::Reject) {
+            Ok(grid) => grid,
+            Err(e) => panic!("Given mask comprises of disjoint parts! {}", e),
+        }
+    }
+
+    /// Builds a grid over the cells of `mask`, handling a mask that falls into several parts as `policy` says.
+    pub fn with_policy(width: usize, height: usize, mask: Mask, policy: DisconnectedMask) -> Result<Self, DisconnectedMaskError> {
+        let grid = Self::with_parts(width, height, mask);
+        let mut parts: Vec<Vec<(usize, usize)>> = grid.pool.parts().into_iter()
+            .map(|part| part.into_iter().map(|id| grid.pool[id].payload).collect())
+            .collect();
+        if parts.len() <= 1 {
+            return Ok(grid);
+        }
+        parts.sort_by_key(|part| std::cmp::Reverse(part.len()));
+        match policy {
+            DisconnectedMask::Reject => Err(DisconnectedMaskError { parts }),
+            DisconnectedMask::KeepLargest => {
+                let largest: HashSet<(usize, usize)> = parts.swap_remove(0).into_iter().collect();
+                let largest = Mask::from_fn(width, height, |row, col| largest.contains(&(row, col)));
+                Ok(Self::with_parts(width, height, grid.mask.intersection(largest)))
+            },
+            DisconnectedMask::Separate => Ok(grid),
+            DisconnectedMask::Bridge => {
+                let bridges = grid.bridges(&parts);
+                let bridges = Mask::from_fn(width, height, |row, col| bridges.contains(&(row, col)));
+                Ok(Self::with_parts(width, height, grid.mask.union(bridges)))
+            },
+        }
     }
 
-    pub fn new(width: usize, height: usize, mask: Box<dyn Fn(usize, usize) -> bool>) -> Self {
+    /// Finds the cells outside the mask that join its `parts`, largest first. Starting from the largest part, the part
+    /// needing the fewest cells to reach is joined until none are left.
+    fn bridges(&self, parts: &[Vec<(usize, usize)>]) -> HashSet<(usize, usize)> {
+        let part_of: HashMap<(usize, usize), usize> = parts.iter().enumerate()
+            .flat_map(|(i, part)| part.iter().map(move |&cell| (cell, i)))
+            .collect();
+        let mut joined_parts = vec![false; parts.len()];
+        joined_parts[0] = true;
+        let mut joined: BTreeSet<(usize, usize)> = parts[0].iter().cloned().collect();
+        let mut bridges = HashSet::new();
+        for _ in 1..parts.len() {
+            // Search outwards from every joined cell at once, only through cells outside the mask
+            let mut came_from: HashMap<(usize, usize), Option<(usize, usize)>> = joined.iter().map(|&cell| (cell, None)).collect();
+            let mut queue: VecDeque<(usize, usize)> = joined.iter().cloned().collect();
+            'search: while let Some((row, col)) = queue.pop_front() {
+                let neighbors = [
+                    row.checked_sub(1).map(|r| (r, col)),
+                    (row + 1 < self.height).then_some((row + 1, col)),
+                    col.checked_sub(1).map(|c| (row, c)),
+                    (col + 1 < self.width).then_some((row, col + 1)),
+                ];
+                for neighbor in neighbors.into_iter().flatten() {
+                    if came_from.contains_key(&neighbor) {
+                        continue;
+                    }
+                    came_from.insert(neighbor, Some((row, col)));
+                    match part_of.get(&neighbor) {
+                        Some(&part) => {
+                            let mut cell = (row, col);
+                            while let Some(&Some(previous)) = came_from.get(&cell) {
+                                bridges.insert(cell);
+                                joined.insert(cell);
+                                cell = previous;
+                            }
+                            joined_parts[part] = true;
+                            joined.extend(parts[part].iter().cloned());
+                            break 'search;
+                        },
+                        None => queue.push_back(neighbor),
+                    }
+                }
+            }
+        }
+        debug_assert!(joined_parts.iter().all(|&j| j));
+        bridges
+    }
+
+    /// Builds a grid over the cells of `mask`, whatever parts they fall into.
+    pub fn with_parts(width: usize, height: usize, mask: Mask) -> Self {
         let mut pool: Pool<(usize, usize)> = Pool::new();
         let mut cell_grid: HashMap<(usize, usize), NodeId> = HashMap::new();
         // First populate the pool and grids. No connections are made yet.
         for row in 0..height {
             for col in 0..width {
-                if !mask(row, col) {
+                if !mask.contains(row, col) {
                     continue;
                 }
                 let id = pool.new_node(|_| (row, col));
@@ -90,7 +387,7 @@ impl MaskedGrid {
         // Stitch together the nodes
         for row in 0..height {
             for col in 0..width {
-                if !mask(row, col) {
+                if !mask.contains(row, col) {
                     continue;
                 }
                 let left_col = col.checked_sub(1);
@@ -118,10 +415,8 @@ impl MaskedGrid {
             }
         }
 
-        assert!(pool.is_adjacently_connected(), "Given mask comprises of disjoint parts!");
-
         Self {
-            pool, mask: Box::new(mask), width, height, cell_grid
+            pool, mask, width, height, cell_grid
         }
     }
 
@@ -129,8 +424,8 @@ impl MaskedGrid {
         self.pool.nodes.len()
     }
 
-    pub fn aldous_broder(&mut self, rng: &mut ThreadRng) {
-        self.pool.aldous_broder(rng);
+    pub fn aldous_broder(&mut self, rng: &mut impl Rng) {
+        self.pool.aldous_broder(rng, &mut ());
         return;
         
         /*// Pick a random starting cell
@@ -147,8 +442,8 @@ impl MaskedGrid {
         }*/
     }
 
-    pub fn hunt_and_kill(&mut self, rng: &mut ThreadRng) {
-        self.pool.hunt_and_kill(rng);
+    pub fn hunt_and_kill(&mut self, rng: &mut impl Rng) {
+        self.pool.hunt_and_kill(rng, &mut ());
         return;
     }
 
@@ -173,8 +468,8 @@ impl MaskedGrid {
     }
 
     pub fn is_linked_at(&self, row_here: usize, col_here: usize, row_there: usize, col_there: usize) -> bool {
-        (self.mask)(row_here, col_here)
-        && (self.mask)(row_there, col_there)
+        self.mask.contains(row_here, col_here)
+        && self.mask.contains(row_there, col_there)
         && self.is_linked(*self.cell_grid.get(&(row_here, col_here)).unwrap()
                          , *self.cell_grid.get(&(row_there, col_there)).unwrap())
     }
@@ -184,14 +479,14 @@ impl MaskedGrid {
             return false;
         }
         if row == 0 {
-            return (self.mask)(row, col);
+            return self.mask.contains(row, col);
         }
         if row == self.height {
-            return (self.mask)(row - 1, col);
+            return self.mask.contains(row - 1, col);
         }
         
-        let present_above = (self.mask)(row - 1, col);
-        let present_center = (self.mask)(row, col);
+        let present_above = self.mask.contains(row - 1, col);
+        let present_center = self.mask.contains(row, col);
         match (present_above, present_center) {
             (true, true) => {
                 return !self.is_linked_at(row, col, row - 1, col);
@@ -203,18 +498,18 @@ impl MaskedGrid {
     }
 
     pub fn is_v_wall(&self, row: usize, col: usize) -> bool {
-        if row == self.width {
+        if row == self.height {
             return false;
         }
         if col == 0 {
-            return (self.mask)(row, col);
+            return self.mask.contains(row, col);
         }
         if col == self.width {
-            return (self.mask)(row, col - 1);
+            return self.mask.contains(row, col - 1);
         }
 
-        let present_left = (self.mask)(row, col - 1);
-        let present_center = (self.mask)(row, col);
+        let present_left = self.mask.contains(row, col - 1);
+        let present_center = self.mask.contains(row, col);
         match (present_left, present_center) {
             (true, true) => {
                 return !self.is_linked_at(row, col, row, col - 1);
@@ -225,128 +520,253 @@ impl MaskedGrid {
         }
     }
     
-    pub fn print_image(&self, cell_size: usize, padding: usize, draw_walls: bool, paint_function: impl Fn(NodeId) -> Paint<'static>, icons: Vec<(NodeId, Pixmap)>) -> Pixmap {
+    pub fn print_image(&self, cell_size: usize, style: &RenderStyle, draw_walls: bool, paint_function: impl Fn(NodeId) -> Paint<'static>, icons: Vec<(NodeId, Pixmap)>) -> Pixmap {
+        let padding = style.padding;
         let image_width = self.width * cell_size + 2 * padding;
         let image_height = self.height * cell_size + 2 * padding;
-        let mut pixmap = Pixmap::new(image_width as u32, image_height as u32).unwrap();
-
-        let black = {
-            let mut paint = Paint::default();
-            paint.set_color_rgba8(0,0,0, u8::MAX);
-            paint.anti_alias = true;
-            paint
-        };
-
-        let stroke = {
-            let mut stroke = Stroke::default();
-            stroke.width = 3.0;
-            stroke.line_cap = LineCap::Round;
-            stroke.line_join = LineJoin::Round;
-            stroke
-        };
+        let mut pixmap = style.new_pixmap(image_width, image_height);
 
         let pb = ProgressBar::new((self.width * self.height) as u64).with_style(crate::progress_style()).with_prefix("Painting Interiors");
+        // Inset rooms are outlined as they are painted, instead of drawing walls on the lattice
+        let mut inset_walls = PathBuilder::new();
         // Paint the interiors
         for row in 0..self.height {
             for col in 0..self.width {
                 pb.inc(1);
-                if !(self.mask)(row, col) {
-                    continue;
-                }
-                
                 let top = (row * cell_size + padding) as f32;
                 let bottom = ((row + 1) * cell_size + padding) as f32;
                 let left = (col * cell_size + padding) as f32;
                 let right = ((col + 1) * cell_size + padding) as f32;
+                let cell_rect = Rect::from_ltrb(left, top, right, bottom).unwrap();
 
-                pixmap.fill_rect(Rect::from_ltrb(
-                    left,
-                    top,
-                    right,
-                    bottom,
-                ).unwrap(), &paint_function(*self.cell_grid.get(&(row, col)).unwrap()), Transform::identity(), None);
-
-                for (id, icon) in &icons {
-                    if self.cell_grid.get(&(row, col)) == Some(id) {
-                        let icon_size = icon.width().min(icon.height()) as f32;
-                        pixmap.draw_pixmap(0, 0, icon.as_ref(), &{ 
-                            let mut p = PixmapPaint::default();
-                            p.blend_mode = BlendMode::SourceOver;
-                            p.quality = FilterQuality::Bicubic;
-                            p
-                        }, Transform::identity().pre_scale(
-                            (right - left) * 0.6 / icon_size,
-                            (bottom - top) * 0.6 / icon_size,
-                        ).post_translate(left + (padding as f32), top + (padding as f32)), None);
+                let Some(&id) = self.cell_grid.get(&(row, col)) else {
+                    pixmap.fill_rect(cell_rect, &style.masked_out_paint(), Transform::identity(), None);
+                    continue;
+                };
+                let paint = style.faded(paint_function(id));
+                if let Some(corridors) = &style.corridors {
+                    let center = CartesianPoint { x: ((left + right) / 2.0) as f64, y: ((top + bottom) / 2.0) as f64 };
+                    let spokes: Vec<Spoke> = [Direction::North, Direction::South, Direction::West, Direction::East].into_iter()
+                        .filter(|&direction| self.is_open_toward(row, col, direction))
+                        .map(|direction| Spoke::straight(match direction {
+                            Direction::North => CartesianPoint { x: center.x, y: top as f64 },
+                            Direction::South => CartesianPoint { x: center.x, y: bottom as f64 },
+                            Direction::West => CartesianPoint { x: left as f64, y: center.y },
+                            Direction::East => CartesianPoint { x: right as f64, y: center.y },
+                        }))
+                        .collect();
+                    let mut pb = PathBuilder::new();
+                    corridors.push_cell(&mut pb, center, &spokes, (corridors.corner_radius * cell_size as f32) as f64);
+                    if let Some(path) = pb.finish() {
+                        pixmap.stroke_path(&path, &paint, &style.stroke(corridors.width.resolve(cell_size as f32)), Transform::identity(), None);
                     }
+                } else if style.is_inset() {
+                    let mut floor = PathBuilder::new();
+                    style.inset_cell(cell_rect, |direction| self.is_open_toward(row, col, direction), &mut floor, &mut inset_walls);
+                    pixmap.fill_path(&floor.finish().unwrap(), &paint, FillRule::Winding, Transform::identity(), None);
+                } else {
+                    pixmap.fill_rect(cell_rect, &paint, Transform::identity(), None);
                 }
+
             }
         }
         pb.finish_with_message("Done");
 
-        
-        if draw_walls {
+        // Corridors take the place of walls
+        let draw_walls = draw_walls && style.corridors.is_none();
+        if draw_walls && style.is_inset() {
+            if let Some(walls) = inset_walls.finish() {
+                pixmap.stroke_path(&walls, &style.wall_paint(), &style.wall_stroke(cell_size as f32), Transform::identity(), None);
+            }
+        } else if draw_walls {
             let progress = ProgressBar::new(((self.width + 1) * (self.height + 1)) as u64).with_style(crate::progress_style()).with_prefix("Drawing Walls");
-            let path = {
-                let mut pb = PathBuilder::new();
-    
-                for row in 0..=self.height {
-                    for col in 0..=self.width {
-                        progress.inc(1);
-                        let top = (row * cell_size + padding) as f32;
-                        let bottom = ((row + 1) * cell_size + padding) as f32;
-                        let left = (col * cell_size + padding) as f32;
-                        let right = ((col + 1) * cell_size + padding) as f32;
-    
-                        if self.is_h_wall(row, col) {
-                            pb.move_to(left, top);
-                            pb.line_to(right, top);
-                        }
-                        if self.is_v_wall(row, col) {
-                            pb.move_to(left, top);
-                            pb.line_to(left, bottom);
-                        }
+            // Walls between two cells, walls between zones, and the border between the maze and the outside, are stroked
+            // separately
+            let mut walls = PathBuilder::new();
+            let mut zone_walls = PathBuilder::new();
+            let mut border = PathBuilder::new();
+            let divides = |a: (usize, usize), b: (usize, usize)| style.zone_walls.as_ref().is_some_and(|zone_walls| zone_walls.zones.divides(a, b));
+
+            for row in 0..=self.height {
+                for col in 0..=self.width {
+                    progress.inc(1);
+                    let top = (row * cell_size + padding) as f32;
+                    let bottom = ((row + 1) * cell_size + padding) as f32;
+                    let left = (col * cell_size + padding) as f32;
+                    let right = ((col + 1) * cell_size + padding) as f32;
+
+                    if self.lattice_h_wall(row, col) {
+                        let pb = if self.is_h_border(row, col) {
+                            &mut border
+                        } else if divides((row - 1, col), (row, col)) {
+                            &mut zone_walls
+                        } else {
+                            &mut walls
+                        };
+                        pb.move_to(left, top);
+                        pb.line_to(right, top);
+                    }
+                    if self.lattice_v_wall(row, col) {
+                        let pb = if self.is_v_border(row, col) {
+                            &mut border
+                        } else if divides((row, col - 1), (row, col)) {
+                            &mut zone_walls
+                        } else {
+                            &mut walls
+                        };
+                        pb.move_to(left, top);
+                        pb.line_to(left, bottom);
                     }
                 }
-                
-                progress.finish_with_message("Done");
-                pb.finish().unwrap()
-                
-            };
+            }
+            progress.finish_with_message("Done");
 
-            pixmap.stroke_path(&path, &black, &stroke, Transform::identity(), None);
+            let wall_paint = style.wall_paint();
+            if let Some(walls) = walls.finish() {
+                pixmap.stroke_path(&walls, &wall_paint, &style.wall_stroke(cell_size as f32), Transform::identity(), None);
+            }
+            if let Some(zone_walls) = zone_walls.finish() {
+                pixmap.stroke_path(&zone_walls, &wall_paint, &style.zone_stroke(cell_size as f32), Transform::identity(), None);
+            }
+            if let Some(border) = border.finish() {
+                pixmap.stroke_path(&border, &wall_paint, &style.border_stroke(cell_size as f32), Transform::identity(), None);
+            }
+        }
+
+        // Icons go on top of everything, filling most of their cell
+        for (id, icon) in &icons {
+            let (row, col) = self.pool.get(*id).payload;
+            let center = ((col * cell_size + padding) as f32 + cell_size as f32 / 2.0, (row * cell_size + padding) as f32 + cell_size as f32 / 2.0);
+            draw_icon(&mut pixmap, icon, center, cell_size as f32 * 0.8);
         }
 
         return pixmap;
     }
 
 
-    pub fn print_image_distances(&self, cell_size: usize, padding: usize, start_node: NodeId, draw_walls: bool, color_function: impl Fn(f64) -> Color) -> Pixmap {
+    pub fn print_image_distances(&self, cell_size: usize, style: &RenderStyle, start_node: NodeId, draw_walls: bool, gradient: &Gradient, remap: DistanceRemap) -> Pixmap {
         let distances = DijkstraPad::new(&self.pool, start_node).perform();
-        let max_finite_distance = distances.pool.payloads().map(|d| {
-            match d {
-                Distance::Finite(dist) => *dist,
-                Distance::Infinite => 0,
-            }
-        }).max().unwrap_or(0) as f64;
+        let position = distances.normalized(remap);
 
-        if max_finite_distance == 0.0 {
-            self.print_image(cell_size, padding, draw_walls, |_| {
+        if distances.max_finite() == 0 {
+            self.print_image(cell_size, style, draw_walls, |_| {
                 let mut p = Paint::default();
                 p.set_color_rgba8(u8::MAX, u8::MAX, u8::MAX, u8::MAX);
                 p
             }, vec![])
         } else {
-            self.print_image(cell_size, padding, draw_walls, |node_id| {
-                let dist = distances.pool.get(node_id).payload.as_finite().unwrap_or(0) as f64;
-                let normalized_distance = dist / max_finite_distance;
+            self.print_image(cell_size, style, draw_walls, |node_id| {
                 let mut p = Paint::default();
-                p.set_color(color_function(normalized_distance));
+                p.set_color(gradient.color_at(position(node_id)));
                 p
             }, vec![])
         }
     }
 
+    fn is_present(&self, row: Option<usize>, col: Option<usize>) -> bool {
+        match (row, col) {
+            (Some(row), Some(col)) => self.cell_grid.contains_key(&(row, col)),
+            _ => false,
+        }
+    }
+
+    /// Whether there is a wall between two grid positions, either of which may lie outside the grid.
+    fn is_wall_between(&self, a: (Option<usize>, Option<usize>), b: (Option<usize>, Option<usize>)) -> bool {
+        match (self.is_present(a.0, a.1), self.is_present(b.0, b.1)) {
+            (true, true) => !self.is_linked_at(a.0.unwrap(), a.1.unwrap(), b.0.unwrap(), b.1.unwrap()),
+            (present_a, present_b) => present_a != present_b,
+        }
+    }
+
+    /// Whether the cell at `row` and `col` is linked to its neighbour in `direction`.
+    fn is_open_toward(&self, row: usize, col: usize, direction: Direction) -> bool {
+        let (there_row, there_col) = match direction {
+            Direction::North => (row.checked_sub(1), Some(col)),
+            Direction::South => (Some(row + 1), Some(col)),
+            Direction::West => (Some(row), col.checked_sub(1)),
+            Direction::East => (Some(row), Some(col + 1)),
+        };
+        self.is_present(there_row, there_col) && self.is_linked_at(row, col, there_row.unwrap(), there_col.unwrap())
+    }
+
+    /// Wall along the top of lattice row `row`, under column `col`.
+    fn lattice_h_wall(&self, row: usize, col: usize) -> bool {
+        self.is_wall_between((row.checked_sub(1), Some(col)), (Some(row), Some(col)))
+    }
+
+    /// Wall along the left of lattice column `col`, across row `row`.
+    fn lattice_v_wall(&self, row: usize, col: usize) -> bool {
+        self.is_wall_between((Some(row), col.checked_sub(1)), (Some(row), Some(col)))
+    }
+
+    /// Whether the top of lattice row `row`, under column `col`, separates a cell from the outside of the maze.
+    fn is_h_border(&self, row: usize, col: usize) -> bool {
+        self.is_present(row.checked_sub(1), Some(col)) != self.is_present(Some(row), Some(col))
+    }
+
+    /// Whether the left of lattice column `col`, across row `row`, separates a cell from the outside of the maze.
+    fn is_v_border(&self, row: usize, col: usize) -> bool {
+        self.is_present(Some(row), col.checked_sub(1)) != self.is_present(Some(row), Some(col))
+    }
+
+    /// Prints the maze as text. Cells outside the mask are left blank.
+    ///
+    /// Each cell is labeled by `label_function`, centered. Cells are widened to fit the longest label.
+    pub fn text_print(&self, charset: TextCharset, label_function: impl Fn(NodeId) -> Option<String>) -> String {
+        let labels: HashMap<NodeId, String> = self.pool.iter_node_ids().filter_map(|id| label_function(id).map(|l| (id, l))).collect();
+        let cell_width = labels.values().map(|l| l.chars().count()).max().unwrap_or(0).max(3);
+        let horizontal = charset.horizontal().to_string().repeat(cell_width);
+        let vertical = charset.vertical();
+
+        let mut result = String::new();
+        for row in 0..=self.height {
+            let mut line = String::new();
+            for col in 0..=self.width {
+                let up = row > 0 && self.lattice_v_wall(row - 1, col);
+                let down = row < self.height && self.lattice_v_wall(row, col);
+                let left = col > 0 && self.lattice_h_wall(row, col - 1);
+                let right = col < self.width && self.lattice_h_wall(row, col);
+                line.push(charset.junction(up, down, left, right));
+                if col < self.width {
+                    if right {
+                        line.push_str(&horizontal);
+                    } else {
+                        line.push_str(&" ".repeat(cell_width));
+                    }
+                }
+            }
+            result.push_str(line.trim_end());
+            result.push('\n');
+
+            if row == self.height {
+                break;
+            }
+            let mut line = String::new();
+            for col in 0..=self.width {
+                line.push(if self.lattice_v_wall(row, col) { vertical } else { ' ' });
+                if col < self.width {
+                    let label = self.get_id_at(row, col).and_then(|id| labels.get(&id)).map(|l| l.as_str()).unwrap_or("");
+                    line.push_str(&centered(label, cell_width));
+                }
+            }
+            result.push_str(line.trim_end());
+            result.push('\n');
+        }
+        result
+    }
+
+    /// Prints the maze as text, with every cell labeled by its distance from `start_node`.
+    pub fn text_print_distances(&self, charset: TextCharset, start_node: NodeId) -> String {
+        let distances = DijkstraPad::new(&self.pool, start_node).perform();
+        self.text_print(charset, |id| Some(distances.pool.get(id).payload.to_string()))
+    }
+
+    /// Prints the maze as text, marking every cell along `path`.
+    pub fn text_print_path(&self, charset: TextCharset, path: &[NodeId]) -> String {
+        let on_path: HashSet<NodeId> = path.iter().cloned().collect();
+        self.text_print(charset, |id| on_path.contains(&id).then(|| charset.path_marker().to_string()))
+    }
+
     fn mask_rectangle(top: usize, left: usize, bottom: usize, right: usize) -> HashSet<(usize, usize)> {
         (top..bottom).flat_map(|row| (left..right).map(move |col| (row, col))).collect()
     }
@@ -373,7 +793,10 @@ impl MaskedGrid {
         } else { 0 }
     }
 
-    pub fn render_to_mask(&self, cell_size: usize, wall_half_width: usize, shortcut_probability: f32, shortcut_thickness: usize) -> HashSet<(usize, usize)> {
+    /// Draws the maze as a picture of open cells, each cell a square of `cell_size` surrounded by walls
+    /// `wall_half_width` thick on each side, and opened up where it is linked to a neighbor. A wall between two cells is
+    /// pierced by a shortcut `shortcut_thickness` wide with a chance of `shortcut_probability`, drawn from `rng`.
+    pub fn render_to_mask(&self, cell_size: usize, wall_half_width: usize, shortcut_probability: f32, shortcut_thickness: usize, rng: &mut impl Rng) -> HashSet<(usize, usize)> {
         let grid_spacing = cell_size + 2 * wall_half_width;
         let mut result_mask: HashSet<(usize, usize)> = HashSet::new();
         for row in 0..self.height {
@@ -391,7 +814,7 @@ impl MaskedGrid {
                 let shortcut_bottom = shortcut_top + shortcut_thickness;
                 let shortcut_right = shortcut_left + shortcut_thickness;
 
-                if (self.mask)(row, col) {
+                if self.mask.contains(row, col) {
                     result_mask.extend(Self::mask_rectangle(cell_top, cell_left, cell_bottom, cell_right));
                 } else {
                     continue;
@@ -404,7 +827,7 @@ impl MaskedGrid {
                 if !self.is_h_wall(row + 1, col) {
                     result_mask.extend(Self::mask_rectangle(cell_bottom, cell_left, grid_bottom, cell_right));
                 } else if self.cell_grid.contains_key(&(row, col)) && self.cell_grid.contains_key(&(row + 1, col)) {
-                    if random::<f32>() < shortcut_probability {
+                    if rng.gen::<f32>() < shortcut_probability {
                         result_mask.extend(Self::mask_rectangle(cell_bottom, shortcut_left, cell_top + grid_spacing, shortcut_right));
                     }
                 }
@@ -416,7 +839,7 @@ impl MaskedGrid {
                 if !self.is_v_wall(row, col + 1) {
                     result_mask.extend(Self::mask_rectangle(cell_top, cell_right, cell_bottom, grid_right))
                 } else if self.cell_grid.contains_key(&(row, col)) && self.cell_grid.contains_key(&(row, col + 1)) {
-                    if random::<f32>() < shortcut_probability {
+                    if rng.gen::<f32>() < shortcut_probability {
                         result_mask.extend(Self::mask_rectangle(shortcut_top, cell_right, shortcut_bottom, cell_left + grid_spacing));
                     }
                 }
@@ -426,27 +849,108 @@ impl MaskedGrid {
         result_mask
     }
 
+    /// Builds a finer grid over the picture [`MaskedGrid::render_to_mask`] draws of the maze, as `level` says. Every
+    /// route through this maze is open in the finer grid, and cell `(row, col)` lies around
+    /// [`NestLevel::center_of`]`(row, col)`.
+    pub fn nest(&self, level: &NestLevel, rng: &mut impl Rng) -> MaskedGrid {
+        let open = self.render_to_mask(level.cell_size, level.wall_half_width, level.shortcut_probability, level.shortcut_thickness, rng);
+        let (width, height) = (self.width * level.spacing(), self.height * level.spacing());
+        MaskedGrid::with_parts(width, height, Mask::from_fn(width, height, |row, col| open.contains(&(row, col))))
+    }
+
+    /// Builds a grid `size` times finer, turning every cell of the maze into a block of `size` by `size` cells carved into
+    /// a maze of its own by `algo`. Neighboring blocks are joined through a single door, at random along the side they
+    /// share, wherever their cells are linked, so that the blocks form one maze following this one. Cell `(row, col)`
+    /// becomes the block from `(row * size, col * size)`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `size` is 0.
+    pub fn subdivide(&self, size: usize, algo: Algorithm, rng: &mut impl Rng, observer: &mut impl CarveObserver) -> MaskedGrid {
+        assert!(size > 0, "Cells cannot be subdivided into blocks of no cells");
+        let (width, height) = (self.width * size, self.height * size);
+        let mut grid = MaskedGrid::with_parts(width, height, Mask::from_fn(width, height, |row, col| {
+            self.cell_grid.contains_key(&(row / size, col / size))
+        }));
+
+        // Blocks are carved separately, as parts of their own, by setting aside the adjacencies between them
+        let block_of = |(row, col): (usize, usize)| (row / size, col / size);
+        let between_blocks: Vec<(NodeId, NodeId)> = grid.pool.iter_node_ids()
+            .flat_map(|id| grid.pool[id].adjacencies.iter().map(move |&neighbor| (id, neighbor)))
+            .filter(|&(here, there)| block_of(grid.pool[here].payload) != block_of(grid.pool[there].payload))
+            .collect();
+        for &(here, there) in &between_blocks {
+            grid.pool[here].adjacencies.remove(&there);
+        }
+        algo.generate(&mut grid.pool, rng, observer);
+        for &(here, there) in &between_blocks {
+            grid.pool.make_adjacent(here, there, false);
+        }
+
+        for id in self.pool.iter_node_ids() {
+            let (row, col) = self.pool[id].payload;
+            for &(next_row, next_col) in &[(row + 1, col), (row, col + 1)] {
+                if !self.cell_grid.get(&(next_row, next_col)).is_some_and(|&next| self.is_linked(id, next)) {
+                    continue;
+                }
+                let offset = rng.gen_range(0..size);
+                let (here, there) = if next_row > row {
+                    ((next_row * size - 1, col * size + offset), (next_row * size, col * size + offset))
+                } else {
+                    ((row * size + offset, next_col * size - 1), (row * size + offset, next_col * size))
+                };
+                let (here, there) = (grid.cell_grid[&here], grid.cell_grid[&there]);
+                grid.pool.link_cells(here, there, true);
+                observer.observe(CarveEvent::Link(here, there));
+            }
+        }
+        grid
+    }
+
     pub fn write_maze(&self, out: impl Write) -> Result<(), io::Error> {
-        let mut out = BufWriter::new(out);
         let f = self.pool.furthest_pair().unwrap();
         let start = self.pool.get(f.0).payload;
         let end = self.pool.get(f.1).payload;
 
+        self.write_maze_with_metadata(out, &MazeMetadata::with_endpoints(start, end), CellEncoding::default())
+    }
 
-        out.write_all(&(self.width as u32).to_be_bytes())?;
-        out.write_all(&(self.height as u32).to_be_bytes())?;
+    pub fn write_maze_with_metadata(&self, out: impl Write, metadata: &MazeMetadata, encoding: CellEncoding) -> Result<(), io::Error> {
+        let mut out = BufWriter::new(out);
+        MazeHeader::new(Topology::Cartesian { width: self.width, height: self.height }, encoding, metadata.clone()).write(&mut out)?;
+
+        let mut cells = CompressedWriter::new(&mut out, encoding.compression);
+        match encoding.layout {
+            CellLayout::Bytes => {
+                for row in 0..self.height {
+                    for col in 0..self.width {
+                        cells.write_all(&[self.cell_to_byte(row, col)])?;
+                    }
+                }
+            },
+            CellLayout::Packed => self.write_packed_cells(&mut cells)?,
+        }
+        cells.finish()?;
 
-        out.write_all(&(start.0 as u32).to_be_bytes())?;
-        out.write_all(&(start.1 as u32).to_be_bytes())?;
-        out.write_all(&(end.0 as u32).to_be_bytes())?;
-        out.write_all(&(end.1 as u32).to_be_bytes())?;
+        out.flush()
+    }
 
+    /// Writes the mask as a bitmap, then the south and east links of every cell.
+    fn write_packed_cells(&self, out: impl Write) -> Result<(), io::Error> {
+        let mut bits = BitWriter::new(out);
         for row in 0..self.height {
             for col in 0..self.width {
-                out.write(&[self.cell_to_byte(row, col)])?;
+                bits.write_bit(self.mask.contains(row, col))?;
             }
         }
-
+        bits.align()?;
+        for row in 0..self.height {
+            for col in 0..self.width {
+                bits.write_bit(row + 1 < self.height && self.is_linked_at(row, col, row + 1, col))?;
+                bits.write_bit(col + 1 < self.width && self.is_linked_at(row, col, row, col + 1))?;
+            }
+        }
+        bits.into_inner()?;
         Ok(())
     }
 
@@ -559,83 +1063,407 @@ impl MaskedGrid {
     }
 
     pub fn read_maze(input: impl Read) -> Result<Self, GridReadError> {
-        let mut input = BufReader::new(input);
-
-        let width = u32::from_be_bytes({
-            let mut bytes = [0u8; 4];
-            input.read_exact(&mut bytes)?;
-            bytes
-        }) as usize;
-        let height = u32::from_be_bytes({
-            let mut bytes = [0u8; 4];
-            input.read_exact(&mut bytes)?;
-            bytes
-        }) as usize;
-
-        let (_start_row, _start_col, _end_row, _end_col) = {
-            let mut byte_bytes = [[0u8; 4]; 4];
-            input.read_exact(&mut byte_bytes[0])?;
-            input.read_exact(&mut byte_bytes[1])?;
-            input.read_exact(&mut byte_bytes[2])?;
-            input.read_exact(&mut byte_bytes[3])?;
-            (
-                u32::from_be_bytes(byte_bytes[0]) as usize,
-                u32::from_be_bytes(byte_bytes[1]) as usize,
-                u32::from_be_bytes(byte_bytes[2]) as usize,
-                u32::from_be_bytes(byte_bytes[3]) as usize,
-            )
+        Ok(Self::read_maze_with_metadata(input)?.0)
+    }
+
+    /// Reads a maze file along with its metadata. Stored endpoints are checked to lie within the maze.
+    pub fn read_maze_with_metadata(input: impl Read) -> Result<(Self, MazeMetadata), GridReadError> {
+        let (header, body) = MazeHeader::read(input)?;
+        let (width, height) = match header.topology {
+            Topology::Cartesian { width, height } => (width, height),
+            other => return Err(GridReadError::TopologyMismatch(other)),
         };
-        
-        let mut news_grid: HashMap<(usize, usize), u8> = HashMap::new();
+        let grid = Self::read_cells(width, height, header.encoding, body)?;
+        if let Some((start, end)) = header.metadata.endpoints {
+            grid.endpoint_id(start)?;
+            grid.endpoint_id(end)?;
+        }
+        Ok((grid, header.metadata))
+    }
 
-        let mut node_bytes = input.bytes();
+    /// Looks up the node at a position read from a maze file.
+    pub fn endpoint_id(&self, (row, col): (usize, usize)) -> Result<NodeId, GridReadError> {
+        self.get_id_at(row, col).ok_or(GridReadError::EndpointOutOfMaze((row, col)))
+    }
+
+    /// Reads the cell data following a maze file's header.
+    pub fn read_cells(width: usize, height: usize, encoding: CellEncoding, input: impl Read) -> Result<Self, GridReadError> {
+        let input = BufReader::new(DecompressedReader::new(BufReader::new(input), encoding.compression));
+        match encoding.layout {
+            CellLayout::Bytes => Self::read_news_bytes(width, height, input),
+            CellLayout::Packed => Self::read_packed_cells(width, height, input),
+        }
+    }
+
+    /// Reads packed cell data as written by `write_packed_cells`. Links are made as they are read, so nothing but the mask
+    /// bitmap is held on to.
+    fn read_packed_cells(width: usize, height: usize, input: impl Read) -> Result<Self, GridReadError> {
+        let mut bits = BitReader::new(input);
+        let cells = (0..width * height).map(|_| bits.read_bit()).collect::<Result<Vec<bool>, _>>()?;
+        bits.align();
+
+        let mut result = MaskedGrid::with_parts(width, height, Mask::Cells { width, height, cells });
 
         for row in 0..height {
             for col in 0..width {
-                match node_bytes.next() {
-                    Some(b) => {
-                        let b = b?;
-                        // b == 0 -> this cell is not part of the maze, so we should skip this byte and move on to the next grid position
-                        if b == 0 {
-                            continue;
-                        }
-                        news_grid.insert((row, col), b);
-                    },
-                    None => {
-                        return Err(GridReadError::NotEnoughBytes);
+                let south = bits.read_bit()?;
+                let east = bits.read_bit()?;
+                for (linked, direction, (there_row, there_col)) in [(south, Direction::South, (row + 1, col)), (east, Direction::East, (row, col + 1))] {
+                    if !linked {
+                        continue;
+                    }
+                    let here = result.get_id_at(row, col).ok_or(NewsGridError::LinkedOutsideMask { cell: (row, col), direction })?;
+                    if there_row == height || there_col == width {
+                        return Err(NewsGridError::ConnectedOutOfBounds { cell: (row, col), direction }.into());
                     }
+                    let there = result.get_id_at(there_row, there_col).ok_or(NewsGridError::ConnectedOutOfMask {
+                        linked: (row, col),
+                        missing: (there_row, there_col),
+                        direction,
+                    })?;
+                    result.pool.link_cells(here, there, true);
                 }
             }
         }
-        if let Some(_) = node_bytes.next() {
-            return Err(GridReadError::TooManyBytes);
-        }
+        bits.align();
+        bits.finish()?;
 
-        Self::validate_news_grid(&news_grid)?;
-        // Here, news_grid is complete and ready to be used
+        Ok(result)
+    }
 
-        let mask: HashSet<(usize, usize)> = news_grid.keys().cloned().collect();
+    /// Reads cell data with one byte per grid position, as written by `cell_to_byte`. Each row is checked against the row
+    /// above as it is read, and kept only as the mask bitmap and south and east links of the packed layout, which
+    /// `read_packed_cells` then links row by row.
+    fn read_news_bytes(width: usize, height: usize, mut input: impl Read) -> Result<Self, GridReadError> {
+        let mut mask = BitWriter::new(vec![]);
+        let mut links = BitWriter::new(vec![]);
+        let mut above = vec![0u8; width];
+        let mut cells = vec![0u8; width];
+        for row in 0..height {
+            input.read_exact(&mut cells).map_err(|e| match e.kind() {
+                io::ErrorKind::UnexpectedEof => GridReadError::NotEnoughBytes,
+                _ => e.into(),
+            })?;
+            Self::validate_news_row(row, &above, &cells, row + 1 == height)?;
+            for &b in &cells {
+                mask.write_bit(b != 0)?;
+                links.write_bit(Self::south(b))?;
+                links.write_bit(Self::east(b))?;
+            }
+            std::mem::swap(&mut above, &mut cells);
+        }
+        if input.read(&mut [0])? != 0 {
+            return Err(GridReadError::TooManyBytes);
+        }
 
-        let mut result = MaskedGrid::new(width, height, Box::new(move |row, col| {
-            mask.contains(&(row, col))
-        }));
+        let (mask, links) = (mask.into_inner()?, links.into_inner()?);
+        Self::read_packed_cells(width, height, mask.as_slice().chain(links.as_slice()))
+    }
 
-        for (&(row, col), &b) in news_grid.iter() {
+    /// Checks the bytes of a row of cells against each other, and against the row above. Links south are checked once the
+    /// row below is read, or lead out of the mask from the `last` row.
+    fn validate_news_row(row: usize, above: &[u8], cells: &[u8], last: bool) -> Result<(), NewsGridError> {
+        for (col, (&up, &b)) in above.iter().zip(cells).enumerate() {
+            if Self::south(up) && b == 0 {
+                return Err(NewsGridError::ConnectedOutOfMask { linked: (row - 1, col), missing: (row, col), direction: Direction::South });
+            }
+            if Self::south(up) && !Self::north(b) {
+                return Err(NewsGridError::UnrequitedConnection { linked: (row - 1, col), unlinked: (row, col), direction: Direction::South });
+            }
+            if b == 0 {
+                continue;
+            }
             if Self::north(b) {
-                result.pool.link_cells(result.get_id_at(row, col).unwrap(), result.get_id_at(row - 1, col).unwrap(), true);
+                if row == 0 {
+                    return Err(NewsGridError::ConnectedOutOfBounds { cell: (row, col), direction: Direction::North });
+                }
+                if up == 0 {
+                    return Err(NewsGridError::ConnectedOutOfMask { linked: (row, col), missing: (row - 1, col), direction: Direction::North });
+                }
+                if !Self::south(up) {
+                    return Err(NewsGridError::UnrequitedConnection { linked: (row, col), unlinked: (row - 1, col), direction: Direction::North });
+                }
+            }
+            if Self::west(b) {
+                let Some(&left) = col.checked_sub(1).map(|left| &cells[left]) else {
+                    return Err(NewsGridError::ConnectedOutOfBounds { cell: (row, col), direction: Direction::West });
+                };
+                if left == 0 {
+                    return Err(NewsGridError::ConnectedOutOfMask { linked: (row, col), missing: (row, col - 1), direction: Direction::West });
+                }
+                if !Self::east(left) {
+                    return Err(NewsGridError::UnrequitedConnection { linked: (row, col), unlinked: (row, col - 1), direction: Direction::West });
+                }
             }
             if Self::east(b) {
-                result.pool.link_cells(result.get_id_at(row, col).unwrap(), result.get_id_at(row, col + 1).unwrap(), true);
+                match cells.get(col + 1) {
+                    None | Some(0) => {
+                        return Err(NewsGridError::ConnectedOutOfMask { linked: (row, col), missing: (row, col + 1), direction: Direction::East });
+                    },
+                    Some(&right) if !Self::west(right) => {
+                        return Err(NewsGridError::UnrequitedConnection { linked: (row, col), unlinked: (row, col + 1), direction: Direction::East });
+                    },
+                    Some(_) => {},
+                }
             }
-            if Self::west(b) {
-                result.pool.link_cells(result.get_id_at(row, col).unwrap(), result.get_id_at(row, col - 1).unwrap(), true);
+            if Self::south(b) && last {
+                return Err(NewsGridError::ConnectedOutOfMask { linked: (row, col), missing: (row + 1, col), direction: Direction::South });
             }
-            if Self::south(b) {
-                result.pool.link_cells(result.get_id_at(row, col).unwrap(), result.get_id_at(row + 1, col).unwrap(), true);
+        }
+        Ok(())
+    }
+
+    /// Reads a maze drawn as text, as printed by `text_print`, along with the cells at gaps in its border. See
+    /// [`MaskedGrid::from_text_with_openings`].
+    pub fn read_text_maze(mut input: impl Read) -> Result<(Self, MarkedEndpoints), GridReadError> {
+        let mut bytes = vec![];
+        input.read_to_end(&mut bytes)?;
+        let text = parsers::decode_text(&bytes).ok_or(TextMazeError::new(1, 1, TextMazeErrorKind::InvalidEncoding))?;
+        Ok(Self::from_text_with_openings(&text)?)
+    }
+
+    /// Reads a mask drawn as text, as read by [`Mask::read_text_with_endpoints`], into a grid of unlinked cells along with
+    /// the cells marked as its start and end. A mask falling into several parts is handled as `policy` says, but a marked
+    /// cell must stay in the grid, and the start and end must lie in the same part.
+    pub fn read_text_mask(mut input: impl Read, policy: DisconnectedMask) -> Result<(Self, MarkedEndpoints), GridReadError> {
+        let mut bytes = vec![];
+        input.read_to_end(&mut bytes)?;
+        let (mask, (start, end)) = Mask::read_text_with_endpoints(&bytes)?;
+        let (width, height) = mask.bounds().unwrap_or((0, 0));
+        let grid = Self::with_policy(width, height, mask, policy)?;
+        let marked = MarkedEndpoints {
+            start: start.map(|start| grid.endpoint_id(start)).transpose()?,
+            end: end.map(|end| grid.endpoint_id(end)).transpose()?,
+        };
+        if let (Some(start_id), Some(end_id), Some(start), Some(end)) = (marked.start, marked.end, start, end) {
+            if !grid.pool.parts().iter().any(|part| part.contains(&start_id) && part.contains(&end_id)) {
+                return Err(GridReadError::EndpointsApart { start, end });
             }
         }
+        Ok((grid, marked))
+    }
 
+    /// Builds a linked maze from one drawn as text, see [`MaskedGrid::from_text_with_openings`].
+    pub fn from_text(text: &str) -> Result<Self, TextMazeError> {
+        Self::from_text_with_openings(text).map(|(grid, _)| grid)
+    }
 
-        Ok(result)
+    /// Builds a linked maze from one drawn as text, along with the cells at gaps in its border, as its start and end.
+    ///
+    /// Regions open to the outside of the drawing are masked out, unless they reach it through at most two gaps in the
+    /// edge of the drawing, such as an entrance and an exit. So are any enclosed regions besides the largest one. What's
+    /// left are the cells of the maze, linked wherever no wall separates them.
+    pub fn from_text_with_openings(text: &str) -> Result<(Self, MarkedEndpoints), TextMazeError> {
+        let walls = parsers::text_maze(text)?;
+        Self::from_text_walls(&walls).ok_or(TextMazeError::new(1, 1, TextMazeErrorKind::NoCells))
     }
-}
\ No newline at end of file
+
+    fn from_text_walls(walls: &TextMazeWalls) -> Option<(Self, MarkedEndpoints)> {
+        let TextMazeWalls { width, height, h_walls, v_walls } = walls;
+        let (width, height) = (*width, *height);
+        // Open neighbors of a cell, with `None` standing for the outside of the drawing
+        let open_neighbors = |row: usize, col: usize| {
+            let mut neighbors: Vec<Option<(usize, usize)>> = vec![];
+            if !h_walls[row][col] {
+                neighbors.push(row.checked_sub(1).map(|r| (r, col)));
+            }
+            if !h_walls[row + 1][col] {
+                neighbors.push((row + 1 < height).then_some((row + 1, col)));
+            }
+            if !v_walls[row][col] {
+                neighbors.push(col.checked_sub(1).map(|c| (row, c)));
+            }
+            if !v_walls[row][col + 1] {
+                neighbors.push((col + 1 < width).then_some((row, col + 1)));
+            }
+            neighbors
+        };
+
+        // Label regions of cells connected through missing walls
+        let mut region: HashMap<(usize, usize), usize> = HashMap::new();
+        let mut region_sizes: Vec<usize> = vec![];
+        // Cells of each region with a gap in the edge of the drawing, once for every gap
+        let mut openings: Vec<Vec<(usize, usize)>> = vec![];
+        for row in 0..height {
+            for col in 0..width {
+                if region.contains_key(&(row, col)) {
+                    continue;
+                }
+                let label = region_sizes.len();
+                region_sizes.push(0);
+                openings.push(vec![]);
+                let mut stack = vec![(row, col)];
+                region.insert((row, col), label);
+                while let Some((r, c)) = stack.pop() {
+                    region_sizes[label] += 1;
+                    for neighbor in open_neighbors(r, c) {
+                        match neighbor {
+                            None => openings[label].push((r, c)),
+                            Some(n) => {
+                                if let Entry::Vacant(e) = region.entry(n) {
+                                    e.insert(label);
+                                    stack.push(n);
+                                }
+                            },
+                        }
+                    }
+                }
+            }
+        }
+
+        let maze_region = (0..region_sizes.len()).filter(|&l| openings[l].len() <= 2).max_by_key(|&l| region_sizes[l])?;
+        let mask: HashSet<(usize, usize)> = region.iter().filter(|(_, &l)| l == maze_region).map(|(&p, _)| p).collect();
+        let mut result = MaskedGrid::new(width, height, Mask::from_fn(width, height, |row, col| mask.contains(&(row, col))));
+        for row in 0..height {
+            for col in 0..width {
+                let Some(here) = result.get_id_at(row, col) else { continue };
+                for (r, c) in open_neighbors(row, col).into_iter().flatten() {
+                    let there = result.get_id_at(r, c).unwrap();
+                    result.pool.link_cells(here, there, true);
+                }
+            }
+        }
+        openings[maze_region].sort();
+        let opening = |i: usize| openings[maze_region].get(i).and_then(|&(row, col)| result.get_id_at(row, col));
+        let marked = MarkedEndpoints { start: opening(0), end: opening(1) };
+        Some((result, marked))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use crate::maze::{Algorithm, Maze};
+
+    use super::*;
+
+    /// Two blocks of cells with a gap of two columns, and a lone cell further down.
+    fn islands() -> Mask {
+        Mask::rectangle(0, 0, 2, 3).union(Mask::rectangle(0, 4, 2, 3)).union(Mask::rectangle(5, 0, 1, 1))
+    }
+
+    #[test]
+    fn disconnected_policies() {
+        let error = MaskedGrid::with_policy(6, 6, islands(), DisconnectedMask::Reject).err().unwrap();
+        assert_eq!(error.parts.iter().map(|p| p.len()).collect::<Vec<usize>>(), [6, 6, 1]);
+        assert_eq!(error.to_string(), "the mask falls into 3 parts that no cells join: 6 cells from (0, 0), 6 cells from (0, 4), 1 cell from (5, 0)");
+
+        let largest = MaskedGrid::with_policy(6, 6, islands(), DisconnectedMask::KeepLargest).unwrap();
+        assert_eq!(largest.total_cells(), 6);
+        assert!(largest.mask.contains(2, 1) && !largest.mask.contains(0, 4));
+
+        let bridged = MaskedGrid::with_policy(6, 6, islands(), DisconnectedMask::Bridge).unwrap();
+        // Two cells across the gap, and two down to the lone cell
+        assert_eq!(bridged.total_cells(), 13 + 4);
+        assert_eq!(bridged.pool.parts().len(), 1);
+
+        let mut separate = MaskedGrid::with_policy(6, 6, islands(), DisconnectedMask::Separate).unwrap();
+        assert_eq!(separate.pool.parts().len(), 3);
+        for algorithm in Algorithm::ALL {
+            separate.pool.clear_links();
+            algorithm.generate(&mut separate.pool, &mut StdRng::seed_from_u64(3), &mut ());
+            let links: usize = separate.pool.nodes.iter().map(|n| n.links.len()).sum();
+            // A spanning tree of each part
+            assert_eq!(links / 2, 13 - 3, "{}", algorithm);
+        }
+    }
+
+    #[test]
+    fn walls_of_tall_grids() {
+        // Rows past the width still have walls between their cells
+        let grid = MaskedGrid::new_unmasked(2, 4);
+        for row in 0..4 {
+            assert!(grid.is_v_wall(row, 1), "no wall in row {}", row);
+        }
+        assert!(!grid.is_v_wall(4, 1));
+    }
+
+    #[test]
+    fn text_mask_endpoints() {
+        let read = |text: &str, policy| MaskedGrid::read_text_mask(text.as_bytes(), policy);
+        let (grid, marked) = read("S##\n..#\nE##\n", DisconnectedMask::Reject).unwrap();
+        assert_eq!(grid.total_cells(), 7);
+        assert_eq!(marked, MarkedEndpoints { start: grid.get_id_at(0, 0), end: grid.get_id_at(2, 0) });
+
+        // Without an end, the maze ends as far from the start as it can
+        let (grid, marked) = read("##S##\n", DisconnectedMask::Reject).unwrap();
+        let maze = Maze::carve_masked_between(grid, marked, Algorithm::Wilson, &mut StdRng::seed_from_u64(1), &mut ());
+        let Maze::MaskedMaze { maze: grid, start, end } = maze else { panic!("expected a masked maze") };
+        assert_eq!(grid.pool[start].payload, (0, 2));
+        assert!([(0, 0), (0, 4)].contains(&grid.pool[end].payload));
+
+        assert!(matches!(read("S#.E#\n", DisconnectedMask::Reject), Err(GridReadError::DisconnectedMask(_))));
+        assert!(matches!(read("S#.E#\n", DisconnectedMask::Separate), Err(GridReadError::EndpointsApart { start: (0, 0), end: (0, 3) })));
+        assert!(matches!(read("S.E##\n", DisconnectedMask::KeepLargest), Err(GridReadError::EndpointOutOfMaze((0, 0)))));
+        assert!(read("S.##\n.E##\n", DisconnectedMask::Bridge).is_ok());
+        assert!(matches!(read("S?\n", DisconnectedMask::Reject), Err(GridReadError::InvalidMask(_))));
+    }
+
+    #[test]
+    fn nested_mazes_follow_the_coarse_solution() {
+        let mut rng = StdRng::seed_from_u64(8);
+        let coarse = Maze::carve_masked(MaskedGrid::new(6, 4, Mask::disk((2.0, 3.0), 2.9)), Algorithm::Wilson, &mut rng, &mut ());
+        let level: NestLevel = "3,1,0,1".parse().unwrap();
+        let blocks = |maze: &Maze, spacing: usize| -> BTreeSet<(usize, usize)> {
+            let Maze::MaskedMaze { maze: grid, .. } = maze else { panic!("expected a masked maze") };
+            maze.solution().into_iter().map(|id| grid.pool[id].payload).map(|(row, col)| (row / spacing, col / spacing)).collect()
+        };
+        let coarse_route = blocks(&coarse, 1);
+        let nested = coarse.nest(&[level, level], Algorithm::RecursiveBacktracker, &mut rng, &mut ()).unwrap();
+        let Maze::MaskedMaze { maze: grid, .. } = &nested else { panic!("expected a masked maze") };
+        assert_eq!((grid.width, grid.height), (6 * 25, 4 * 25));
+        // The finest solution walks through exactly the cells of the coarse one
+        assert_eq!(blocks(&nested, 25), coarse_route);
+        // Shortcuts pierce walls along the coarse solution, without opening a way around any of it
+        for (seed, spec) in ["3,1,0.5,1", "2,2,1,2", "1,1,0.3,1"].into_iter().cycle().take(12).enumerate() {
+            let mut rng = StdRng::seed_from_u64(seed as u64);
+            let coarse = Maze::carve_masked(MaskedGrid::new_unmasked(8, 6), Algorithm::Wilson, &mut rng, &mut ());
+            let level: NestLevel = spec.parse().unwrap();
+            let coarse_route = blocks(&coarse, 1);
+            let nested = coarse.nest(&[level], Algorithm::HuntAndKill, &mut rng, &mut ()).unwrap();
+            assert_eq!(blocks(&nested, level.spacing()), coarse_route, "{} with seed {}", spec, seed);
+        }
+
+        let shortcuts = NestLevel { shortcut_probability: 1.0, ..level };
+        // Every wall of an uncarved grid is pierced, through both halves
+        let uncarved = MaskedGrid::new_unmasked(3, 3);
+        assert_eq!(uncarved.nest(&level, &mut rng).total_cells(), 9 * 9);
+        assert_eq!(uncarved.nest(&shortcuts, &mut rng).total_cells(), 9 * 9 + 12 * 2);
+        assert!("3,1,0.5".parse::<NestLevel>().is_err());
+        assert!("3,1,0.5,4".parse::<NestLevel>().is_err());
+        assert!("3,0,0,1".parse::<NestLevel>().is_err());
+    }
+
+    #[test]
+    fn subdivided_mazes_follow_the_coarse_maze() {
+        let mut rng = StdRng::seed_from_u64(3);
+        let coarse = Maze::carve_masked(MaskedGrid::new(6, 4, Mask::disk((2.0, 3.0), 2.9)), Algorithm::Wilson, &mut rng, &mut ());
+        let Maze::MaskedMaze { maze: coarse_grid, .. } = &coarse else { panic!("expected a masked maze") };
+        let passages = |grid: &MaskedGrid, block: usize| -> BTreeSet<((usize, usize), (usize, usize))> {
+            grid.pool.iter_node_ids()
+                .flat_map(|id| grid.pool[id].links.iter().map(move |&other| (grid.pool[id].payload, grid.pool[other].payload)))
+                .map(|((row, col), (other_row, other_col))| ((row / block, col / block), (other_row / block, other_col / block)))
+                .filter(|(here, there)| here < there)
+                .collect()
+        };
+        let coarse_passages = passages(coarse_grid, 1);
+        let coarse_cells = coarse_grid.total_cells();
+        let coarse_route: BTreeSet<(usize, usize)> = coarse.solution().into_iter().map(|id| coarse_grid.pool[id].payload).collect();
+
+        let fine = coarse.subdivide(&[3, 2], Algorithm::HuntAndKill, &mut rng, &mut ()).unwrap();
+        let Maze::MaskedMaze { maze: grid, .. } = &fine else { panic!("expected a masked maze") };
+        assert_eq!((grid.width, grid.height, grid.total_cells()), (36, 24, coarse_cells * 36));
+        // Still a perfect maze, whose blocks are joined just as the coarse cells were
+        let links: usize = grid.pool.iter_node_ids().map(|id| grid.pool[id].links.len()).sum();
+        assert_eq!(links / 2, grid.total_cells() - 1);
+        let doors: Vec<_> = grid.pool.iter_node_ids()
+            .flat_map(|id| grid.pool[id].links.iter().map(move |&other| (grid.pool[id].payload, grid.pool[other].payload)))
+            .filter(|&((row, col), (other_row, other_col))| (row / 6, col / 6) < (other_row / 6, other_col / 6))
+            .collect();
+        assert_eq!(doors.len(), coarse_passages.len());
+        assert_eq!(passages(grid, 6), coarse_passages);
+        let route: BTreeSet<(usize, usize)> = fine.solution().into_iter().map(|id| grid.pool[id].payload).map(|(row, col)| (row / 6, col / 6)).collect();
+        assert_eq!(route, coarse_route);
+    }
+}
@@ -1,9 +1,9 @@
-use std::io::{self, Write};
+use std::{io::{self, Write, Read}, str::FromStr, fmt::Display, collections::{HashSet, HashMap}, path::{Path, PathBuf}};
 
-use rand::rngs::ThreadRng;
-use tiny_skia::{Pixmap, Paint};
+use rand::Rng;
+use tiny_skia::{Pixmap, Paint, Color};
 
-use crate::{masked_grid::MaskedGrid, pool::NodeId, polar_grid::PolarGrid, lerp::multi_lerp, color_gradients};
+use crate::{masked_grid::{MaskedGrid, GridReadError, MarkedEndpoints, NestLevel}, mask::Mask, pool::{NodeId, Pool, CarveObserver, CarveEvent}, polar_grid::PolarGrid, color_gradients::Gradient, dijkstra::{DijkstraPad, Distances, DistanceRemap}, maze_file::{MazeHeader, MazeMetadata, Topology, CellEncoding}, text_render::TextCharset, render_style::RenderStyle, animation::FrameRecorder, trace::{Trace, ReplayError}};
 
 
 
@@ -20,80 +20,554 @@ pub enum Maze {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
 pub enum Algorithm {
     AldousBroder,
+    #[default]
     HuntAndKill,
+    RecursiveBacktracker,
+    Wilson,
 }
 
+impl Algorithm {
+    pub const ALL: [Algorithm; 4] = [Algorithm::AldousBroder, Algorithm::HuntAndKill, Algorithm::RecursiveBacktracker, Algorithm::Wilson];
+
+    /// The name used on the command line and in maze file metadata.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Algorithm::AldousBroder => "aldous-broder",
+            Algorithm::HuntAndKill => "hunt-and-kill",
+            Algorithm::RecursiveBacktracker => "recursive-backtracker",
+            Algorithm::Wilson => "wilson",
+        }
+    }
+
+    /// Carves a perfect maze out of the adjacencies of `pool`, reporting each step to `observer`. Parts of the pool that no
+    /// adjacencies join each get a maze of their own, carved one after the other.
+    pub fn generate<T>(&self, pool: &mut Pool<T>, rng: &mut impl Rng, observer: &mut impl CarveObserver) {
+        let parts = pool.parts();
+        if parts.len() <= 1 {
+            return self.carve(pool, rng, observer);
+        }
+        for part in parts {
+            let mut sub_pool = pool.sub_pool(&part);
+            self.carve(&mut sub_pool, rng, &mut Renumbered { ids: &part, observer: &mut *observer });
+            for node in &sub_pool.nodes {
+                for link in &node.links {
+                    pool.link_cells(part[node.id.index()], part[link.index()], false);
+                }
+            }
+        }
+    }
+
+    fn carve<T>(&self, pool: &mut Pool<T>, rng: &mut impl Rng, observer: &mut impl CarveObserver) {
+        match self {
+            Algorithm::AldousBroder => pool.aldous_broder(rng, observer),
+            Algorithm::HuntAndKill => pool.hunt_and_kill(rng, observer),
+            Algorithm::RecursiveBacktracker => pool.recursive_backtracker(rng, observer),
+            Algorithm::Wilson => pool.wilson(rng, observer),
+        }
+    }
+}
+
+/// Reports the steps taken in a [`Pool::sub_pool`] with the ids of the nodes they were copied from.
+struct Renumbered<'a, O> {
+    ids: &'a [NodeId],
+    observer: &'a mut O,
+}
+
+impl<O: CarveObserver> CarveObserver for Renumbered<'_, O> {
+    fn observe(&mut self, event: CarveEvent<'_>) {
+        let id = |n: NodeId| self.ids[n.index()];
+        let nodes = |ns: &[NodeId]| ns.iter().map(|&n| id(n)).collect::<Vec<NodeId>>();
+        match event {
+            CarveEvent::Visit(n) => self.observer.observe(CarveEvent::Visit(id(n))),
+            CarveEvent::Link(here, there) => self.observer.observe(CarveEvent::Link(id(here), id(there))),
+            CarveEvent::LinkRemoved(here, there) => self.observer.observe(CarveEvent::LinkRemoved(id(here), id(there))),
+            CarveEvent::WalkerStep { head, path } => self.observer.observe(CarveEvent::WalkerStep { head: id(head), path: &nodes(path) }),
+            CarveEvent::LoopErased { head, erased } => self.observer.observe(CarveEvent::LoopErased { head: id(head), erased: &nodes(erased) }),
+        }
+    }
+}
+
+impl Display for Algorithm {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl FromStr for Algorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Algorithm::ALL.into_iter().find(|a| a.name() == s).ok_or_else(|| {
+            let names: Vec<&str> = Algorithm::ALL.iter().map(|a| a.name()).collect();
+            format!("unknown algorithm `{}`, expected one of: {}", s, names.join(", "))
+        })
+    }
+}
+
+/// How the cells of a maze are filled in when rendering it.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub enum Coloring {
+    /// White cells, with the start and end marked.
+    #[default]
+    Plain,
+    /// Cells colored by their distance from the start, spread along the gradient by the remap.
+    Distances(Gradient, DistanceRemap),
+}
+
+const MOUSE_PNG: &[u8] = include_bytes!("../assets/mouse.png");
+const CHEESE_PNG: &[u8] = include_bytes!("../assets/cheese.png");
+
+/// A picture drawn over a cell of an image.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Icon {
+    /// The mouse at the start, or the cheese at the end, built into the program.
+    Embedded,
+    File(PathBuf),
+}
+
+/// Which cells of an image get icons.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IconOptions {
+    /// The start is only colored when `None`.
+    pub start: Option<Icon>,
+    /// The end is only colored when `None`.
+    pub end: Option<Icon>,
+    /// Icons for any other cells, by row and column, or by ring and column for radial mazes.
+    pub cells: Vec<((usize, usize), PathBuf)>,
+}
+
+impl Default for IconOptions {
+    fn default() -> Self {
+        IconOptions { start: Some(Icon::Embedded), end: Some(Icon::Embedded), cells: vec![] }
+    }
+}
+
+/// Problems with the icons asked of an image.
+#[derive(Debug)]
+pub enum IconError {
+    Load { path: PathBuf, message: String },
+    /// There is no cell at this position to put an icon on.
+    NoCell((usize, usize)),
+}
+
+impl Display for IconError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            IconError::Load { path, message } => write!(f, "could not load the icon {}: {}", path.display(), message),
+            IconError::NoCell((a, b)) => write!(f, "there is no cell at {},{} to put an icon on", a, b),
+        }
+    }
+}
+
+fn load_icon(path: &Path) -> Result<Pixmap, IconError> {
+    Pixmap::load_png(path).map_err(|e| IconError::Load { path: path.to_path_buf(), message: e.to_string() })
+}
+
+/// Options shared by the image and text renderings of a [`Maze`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct RenderOptions {
+    pub style: RenderStyle,
+    /// Size of each cell (or ring) in pixels. When `None`, cells are sized to fit the requested image width.
+    pub cell_size: Option<usize>,
+    pub coloring: Coloring,
+    /// Whether to mark the shortest path from start to end.
+    pub show_solution: bool,
+    /// Only used for images.
+    pub icons: IconOptions,
+}
+
+impl Default for RenderOptions {
+    fn default() -> Self {
+        RenderOptions { style: RenderStyle::default(), cell_size: None, coloring: Coloring::Plain, show_solution: false, icons: IconOptions::default() }
+    }
+}
+
+/// Summary figures about the shape of a maze.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MazeStats {
+    pub topology: Topology,
+    pub cells: usize,
+    pub passages: usize,
+    /// Cells with a single passage out.
+    pub dead_ends: usize,
+    /// Cells with three or more passages out.
+    pub junctions: usize,
+    /// Number of steps from start to end.
+    pub solution_length: usize,
+    /// Number of steps between the two cells furthest apart.
+    pub longest_path: usize,
+}
+
+impl Display for MazeStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.topology {
+            Topology::Cartesian { width, height } => writeln!(f, "topology: cartesian {}x{}", width, height)?,
+            Topology::Polar { starting_branch_count, ring_count } => writeln!(f, "topology: polar, {} branches, {} rings", starting_branch_count, ring_count)?,
+        }
+        writeln!(f, "cells: {}", self.cells)?;
+        writeln!(f, "passages: {}", self.passages)?;
+        writeln!(f, "dead ends: {}", self.dead_ends)?;
+        writeln!(f, "junctions: {}", self.junctions)?;
+        writeln!(f, "solution length: {}", self.solution_length)?;
+        writeln!(f, "longest path: {}", self.longest_path)
+    }
+}
+
+const START_COLOR: (u8, u8, u8) = (0, 38, u8::MAX);
+const END_COLOR: (u8, u8, u8) = (u8::MAX, 106, 0);
+/// Colors of the cell a generator is at, of the random walk it is taking, and of the cells it has not reached yet.
+const CURRENT_COLOR: (u8, u8, u8) = (255, 214, 0);
+const WALK_COLOR: (u8, u8, u8) = (120, 180, 255);
+const UNVISITED_COLOR: (u8, u8, u8) = (190, 190, 190);
+const SOLUTION_COLOR: (u8, u8, u8) = (125, 220, 125);
+
 impl Maze {
-    pub fn new_unmasked_cartesian(width: usize, height: usize, algo: Algorithm, rng: &mut ThreadRng) -> Self {
-        let mut g = MaskedGrid::new_unmasked(width, height);
-        match algo {
-            Algorithm::AldousBroder => { g.aldous_broder(rng); },
-            Algorithm::HuntAndKill => { g.hunt_and_kill(rng); },
+    pub fn new_unmasked_cartesian(width: usize, height: usize, algo: Algorithm, rng: &mut impl Rng, observer: &mut impl CarveObserver) -> Self {
+        Self::carve_masked(MaskedGrid::new_unmasked(width, height), algo, rng, observer)
+    }
+
+    pub fn new_masked_cartesian(width: usize, height: usize, mask: Mask, algo: Algorithm, rng: &mut impl Rng, observer: &mut impl CarveObserver) -> Self {
+        Self::carve_masked(MaskedGrid::new(width, height, mask), algo, rng, observer)
+    }
+
+    /// Carves a maze into `grid`. The start and end lie in its largest part.
+    pub fn carve_masked(grid: MaskedGrid, algo: Algorithm, rng: &mut impl Rng, observer: &mut impl CarveObserver) -> Self {
+        Self::carve_masked_between(grid, MarkedEndpoints::default(), algo, rng, observer)
+    }
+
+    /// Carves a maze into `grid`, starting and ending on the `marked` cells. A missing endpoint is the cell furthest from
+    /// the other one, and with neither marked the start and end lie in the grid's largest part.
+    pub fn carve_masked_between(mut grid: MaskedGrid, marked: MarkedEndpoints, algo: Algorithm, rng: &mut impl Rng, observer: &mut impl CarveObserver) -> Self {
+        algo.generate(&mut grid.pool, rng, observer);
+        let (start, end) = marked.or_furthest(&grid.pool).unwrap();
+        Self::MaskedMaze { maze: grid, start, end }
+    }
+
+    /// Draws a rectangular maze into a finer grid for each of `levels` in turn, see [`MaskedGrid::nest`], and carves it
+    /// to start and end within the cells the coarser maze does. The finer grid is confined to the cells drawing the
+    /// coarser solution, see [`Pool::confine_to_route`], so that the finer solution passes through those cells and no
+    /// others, whatever shortcuts were drawn. Only the finest maze is reported to `observer`. Radial mazes cannot be
+    /// nested.
+    pub fn nest(self, levels: &[NestLevel], algo: Algorithm, rng: &mut impl Rng, observer: &mut impl CarveObserver) -> Option<Self> {
+        if !matches!(self, Maze::MaskedMaze { .. }) {
+            return None;
         }
-        let furthest_pair = g.pool.furthest_pair().unwrap();
-        Self::MaskedMaze { maze: g, start: furthest_pair.0, end: furthest_pair.1 }
+        let mut coarse = self;
+        for (i, level) in levels.iter().enumerate() {
+            let solution = coarse.solution();
+            let Maze::MaskedMaze { maze, start, end } = &coarse else {
+                unreachable!("Masked grids carve into masked mazes");
+            };
+            let mut finer = maze.nest(level, rng);
+            let route: HashMap<(usize, usize), usize> = solution.iter().enumerate().map(|(stage, &id)| (maze.pool[id].payload, stage)).collect();
+            let stages: Vec<Option<usize>> = finer.pool.nodes.iter()
+                .map(|n| route.get(&(n.payload.0 / level.spacing(), n.payload.1 / level.spacing())).copied())
+                .collect();
+            finer.pool.confine_to_route(|id| stages[id.index()], rng);
+            let within = |id: NodeId| {
+                let (row, col) = level.center_of(maze.pool[id].payload);
+                finer.get_id_at(row, col)
+            };
+            let marked = MarkedEndpoints { start: within(*start), end: within(*end) };
+            coarse = if i + 1 == levels.len() {
+                Self::carve_masked_between(finer, marked, algo, rng, observer)
+            } else {
+                Self::carve_masked_between(finer, marked, algo, rng, &mut ())
+            };
+        }
+        Some(coarse)
     }
 
-    pub fn new_masked_cartesian(width: usize, height: usize, mask: Box<dyn Fn(usize, usize) -> bool>, algo: Algorithm, rng: &mut ThreadRng) -> Self {
-        let mut g = MaskedGrid::new(width, height, mask);
-        match algo {
-            Algorithm::AldousBroder => { g.aldous_broder(rng); },
-            Algorithm::HuntAndKill => { g.hunt_and_kill(rng); },
+    /// Turns every cell of a rectangular maze into a maze of its own, `sizes` cells across, one level of blocks after
+    /// another, see [`MaskedGrid::subdivide`]. The start and end lie in the middle of the blocks the coarser maze starts
+    /// and ends in, so that the finer solution passes through the blocks of the coarser one. Only the finest maze is
+    /// reported to `observer`. Radial mazes cannot be subdivided.
+    pub fn subdivide(self, sizes: &[usize], algo: Algorithm, rng: &mut impl Rng, observer: &mut impl CarveObserver) -> Option<Self> {
+        let Maze::MaskedMaze { mut maze, mut start, mut end } = self else {
+            return None;
+        };
+        for (i, &size) in sizes.iter().enumerate() {
+            let finer = if i + 1 == sizes.len() {
+                maze.subdivide(size, algo, rng, observer)
+            } else {
+                maze.subdivide(size, algo, rng, &mut ())
+            };
+            let within = |id: NodeId| {
+                let (row, col) = maze.pool[id].payload;
+                finer.get_id_at(row * size + size / 2, col * size + size / 2).expect("Blocks hold every cell")
+            };
+            (start, end) = (within(start), within(end));
+            maze = finer;
         }
-        let furthest_pair = g.pool.furthest_pair().unwrap();
-        Self::MaskedMaze { maze: g, start: furthest_pair.0, end: furthest_pair.1 }
+        Some(Self::MaskedMaze { maze, start, end })
     }
 
-    pub fn new_unmasked_radial(starting_branch_count: usize, ring_count: usize, algo: Algorithm, rng: &mut ThreadRng) -> Self {
+    pub fn new_unmasked_radial(starting_branch_count: usize, ring_count: usize, algo: Algorithm, rng: &mut impl Rng, observer: &mut impl CarveObserver) -> Self {
         let mut g = PolarGrid::new(starting_branch_count, ring_count);
-        //g.pool.debug_connect_all();
-        match algo {
-            Algorithm::AldousBroder => { g.pool.aldous_broder(rng); },
-            Algorithm::HuntAndKill => { g.pool.hunt_and_kill(rng); },
-        }
+        algo.generate(&mut g.pool, rng, observer);
         let furthest_pair = g.pool.furthest_pair().unwrap();
         Self::RadialMaze { maze: g, start: furthest_pair.0, end: furthest_pair.1 }
     }
 
-    pub fn print_image(&self, width: usize, padding: usize) -> Pixmap {
+    pub fn start_and_end(&self) -> (NodeId, NodeId) {
         match self {
-            Maze::MaskedMaze { maze, start, end } => {
-                let cell_size = (width - 2 * padding) / maze.width;
-                let mouse_icon = Pixmap::load_png("mouse.png").unwrap();
-                let cheese_icon = Pixmap::load_png("cheese.png").unwrap();
-                let pix = maze.print_image(cell_size, padding, true, |n| {
-                    let mut paint = Paint::default();
-                    if n == *start {
-                        paint.set_color_rgba8(0, 38, u8::MAX, u8::MAX);
-                    } else if n == *end {
-                        paint.set_color_rgba8(u8::MAX, 106, 0, u8::MAX);
-                    } else {
-                        paint.set_color_rgba8(u8::MAX, u8::MAX, u8::MAX, u8::MAX);
-                    }
-                    paint
-                }, vec![(*start, mouse_icon), (*end, cheese_icon)]);
-
-                pix
+            Maze::MaskedMaze { start, end, .. } | Maze::RadialMaze { start, end, .. } => (*start, *end),
+        }
+    }
+
+    pub fn topology(&self) -> Topology {
+        match self {
+            Maze::MaskedMaze { maze, .. } => Topology::Cartesian { width: maze.width, height: maze.height },
+            Maze::RadialMaze { maze, .. } => maze.topology(),
+        }
+    }
+
+    fn distances_from_start(&self) -> Distances {
+        let (start, _) = self.start_and_end();
+        match self {
+            Maze::MaskedMaze { maze, .. } => DijkstraPad::new(&maze.pool, start).perform(),
+            Maze::RadialMaze { maze, .. } => DijkstraPad::new(&maze.pool, start).perform(),
+        }
+    }
+
+    /// The shortest path from start to end, both included.
+    pub fn solution(&self) -> Vec<NodeId> {
+        let (start, end) = self.start_and_end();
+        self.distances_from_start().path_to(end).unwrap_or_else(|| vec![start])
+    }
+
+    /// Paints a cell according to `options`, given the distances from the start if they were computed.
+    /// `position` places nodes along the gradient when coloring by distance.
+    fn cell_paint(&self, n: NodeId, options: &RenderOptions, position: Option<&dyn Fn(NodeId) -> f64>, solution: &HashSet<NodeId>) -> Paint<'static> {
+        let (start, end) = self.start_and_end();
+        let mut paint = Paint::default();
+        let rgb = |paint: &mut Paint, (r, g, b): (u8, u8, u8)| paint.set_color_rgba8(r, g, b, u8::MAX);
+        if n == start {
+            rgb(&mut paint, START_COLOR);
+        } else if n == end {
+            rgb(&mut paint, END_COLOR);
+        } else if solution.contains(&n) {
+            rgb(&mut paint, SOLUTION_COLOR);
+        } else if let (Coloring::Distances(gradient, _), Some(position)) = (&options.coloring, position) {
+            paint.set_color(gradient.color_at(position(n)));
+        } else {
+            paint.set_color(Self::plain_color(options));
+        }
+        paint
+    }
+
+    /// The color of cells that are not marked in any way.
+    fn plain_color(options: &RenderOptions) -> Color {
+        match options.style.corridors {
+            // Plain corridors are drawn like walls
+            Some(_) => options.style.wall_color,
+            None => Color::WHITE,
+        }
+    }
+
+    /// Loads the icons asked for by `options`, paired with the cells they go on.
+    fn icons(&self, options: &IconOptions) -> Result<Vec<(NodeId, Pixmap)>, IconError> {
+        let (start, end) = self.start_and_end();
+        let mut icons = vec![];
+        for (n, icon, embedded) in [(start, &options.start, MOUSE_PNG), (end, &options.end, CHEESE_PNG)] {
+            match icon {
+                Some(Icon::Embedded) => icons.push((n, Pixmap::decode_png(embedded).expect("Embedded icons are valid PNGs"))),
+                Some(Icon::File(path)) => icons.push((n, load_icon(path)?)),
+                None => {},
+            }
+        }
+        for (position, path) in &options.cells {
+            let n = match self {
+                Maze::MaskedMaze { maze, .. } => maze.endpoint_id(*position),
+                Maze::RadialMaze { maze, .. } => maze.endpoint_id(*position),
+            }.map_err(|_| IconError::NoCell(*position))?;
+            icons.push((n, load_icon(path)?));
+        }
+        Ok(icons)
+    }
+
+    pub fn print_image(&self, width: usize, options: &RenderOptions) -> Result<Pixmap, IconError> {
+        let icons = self.icons(&options.icons)?;
+        let distances = match &options.coloring {
+            Coloring::Plain => None,
+            Coloring::Distances(_, remap) => Some((self.distances_from_start(), *remap)),
+        };
+        let position = distances.as_ref().map(|(distances, remap)| distances.normalized(*remap));
+        let position = position.as_ref().map(|p| p as &dyn Fn(NodeId) -> f64);
+        let solution: HashSet<NodeId> = if options.show_solution { self.solution().into_iter().collect() } else { HashSet::new() };
+        Ok(self.render(width, options, |n| self.cell_paint(n, options, position, &solution), icons))
+    }
+
+    /// Draws the maze with its grid's renderer, sized to `width` unless `options` fixes the cell size.
+    fn render(&self, width: usize, options: &RenderOptions, paint_function: impl Fn(NodeId) -> Paint<'static>, icons: Vec<(NodeId, Pixmap)>) -> Pixmap {
+        let padding = options.style.padding;
+        match self {
+            Maze::MaskedMaze { maze, .. } => {
+                let cell_size = options.cell_size.unwrap_or((width - 2 * padding) / maze.width);
+                maze.print_image(cell_size, &options.style, true, paint_function, icons)
+            },
+            Maze::RadialMaze { maze, .. } => {
+                let radius = match options.cell_size {
+                    Some(ring_width) => ring_width * maze.rings.len(),
+                    None => (width - 2 * padding) / 2,
+                };
+                maze.print_image(radius, &options.style, paint_function, icons)
             },
-            Maze::RadialMaze { maze, start, .. } => {
-                let radius = (width - 2 * padding) / 2;
-                maze.print_image_distances(radius, padding, *start,
-                    multi_lerp(color_gradients::fire_colors())
-                )
+        }
+    }
+
+    /// Draws the frames recorded while this maze was generated, followed by the finished maze.
+    ///
+    /// The recorded links are carved again from scratch, so `recorder` must have watched this very maze being generated.
+    pub fn animation_frames(&mut self, recorder: &FrameRecorder, width: usize, options: &RenderOptions) -> Result<Vec<Pixmap>, IconError> {
+        self.clear_links();
+        let mut carved = 0;
+        let mut frames = vec![];
+        for mark in &recorder.frames {
+            for &(here, there) in &recorder.links[carved..mark.links] {
+                self.link(here, there);
+            }
+            carved = mark.links;
+            let walk: HashSet<NodeId> = mark.walk.iter().copied().collect();
+            frames.push(self.render(width, options, |n| {
+                let mut paint = Paint::default();
+                let rgb = |paint: &mut Paint, (r, g, b): (u8, u8, u8)| paint.set_color_rgba8(r, g, b, u8::MAX);
+                if Some(n) == mark.current {
+                    rgb(&mut paint, CURRENT_COLOR);
+                } else if walk.contains(&n) {
+                    rgb(&mut paint, WALK_COLOR);
+                } else if self.is_unvisited(n) {
+                    rgb(&mut paint, UNVISITED_COLOR);
+                } else {
+                    paint.set_color(Self::plain_color(options));
+                }
+                paint
+            }, vec![]));
+        }
+        for &(here, there) in &recorder.links[carved..] {
+            self.link(here, there);
+        }
+        frames.push(self.print_image(width, options)?);
+        Ok(frames)
+    }
+
+    /// Checks that replaying `trace` carves exactly the passages of this maze.
+    pub fn verify_trace(&self, trace: &Trace) -> Result<(), ReplayError> {
+        match self {
+            Maze::MaskedMaze { maze, .. } => trace.verify(&maze.pool),
+            Maze::RadialMaze { maze, .. } => trace.verify(&maze.pool),
+        }
+    }
+
+    fn clear_links(&mut self) {
+        match self {
+            Maze::MaskedMaze { maze, .. } => maze.pool.clear_links(),
+            Maze::RadialMaze { maze, .. } => maze.pool.clear_links(),
+        }
+    }
+
+    fn link(&mut self, here: NodeId, there: NodeId) {
+        match self {
+            Maze::MaskedMaze { maze, .. } => maze.pool.link_cells(here, there, true),
+            Maze::RadialMaze { maze, .. } => maze.pool.link_cells(here, there, true),
+        }
+    }
+
+    fn is_unvisited(&self, n: NodeId) -> bool {
+        match self {
+            Maze::MaskedMaze { maze, .. } => maze.pool.get(n).links.is_empty(),
+            Maze::RadialMaze { maze, .. } => maze.pool.get(n).links.is_empty(),
+        }
+    }
+
+    /// Prints the maze as text. Returns `None` for what text cannot show: distances and solutions of radial mazes.
+    pub fn text_print(&self, charset: TextCharset, options: &RenderOptions) -> Option<String> {
+        match self {
+            Maze::MaskedMaze { maze, start, .. } => Some(match (&options.coloring, options.show_solution) {
+                (_, true) => maze.text_print_path(charset, &self.solution()),
+                (Coloring::Distances(..), false) => maze.text_print_distances(charset, *start),
+                (Coloring::Plain, false) => maze.text_print(charset, |_| None),
+            }),
+            Maze::RadialMaze { maze, .. } => match (&options.coloring, options.show_solution) {
+                (Coloring::Plain, false) => Some(maze.text_print()),
+                _ => None,
             },
         }
     }
 
+    pub fn stats(&self) -> MazeStats {
+        let (_, end) = self.start_and_end();
+        let distances = self.distances_from_start();
+        let (link_counts, longest_path) = match self {
+            Maze::MaskedMaze { maze, .. } => (Self::link_counts(&maze.pool), Self::longest_path(&maze.pool)),
+            Maze::RadialMaze { maze, .. } => (Self::link_counts(&maze.pool), Self::longest_path(&maze.pool)),
+        };
+        MazeStats {
+            topology: self.topology(),
+            cells: link_counts.len(),
+            passages: link_counts.iter().sum::<usize>() / 2,
+            dead_ends: link_counts.iter().filter(|&&c| c == 1).count(),
+            junctions: link_counts.iter().filter(|&&c| c >= 3).count(),
+            solution_length: distances.pool.get(end).payload.as_finite().unwrap_or(0),
+            longest_path,
+        }
+    }
+
+    fn link_counts<T>(pool: &Pool<T>) -> Vec<usize> {
+        pool.nodes.iter().map(|n| n.links.len()).collect()
+    }
+
+    fn longest_path<T>(pool: &Pool<T>) -> usize {
+        match pool.furthest_pair() {
+            Some((a, b)) => DijkstraPad::new(pool, a).perform().pool.get(b).payload.as_finite().unwrap_or(0),
+            None => 0,
+        }
+    }
+
     pub fn write_maze(&self, out: impl Write) -> Result<(), io::Error> {
+        self.write_maze_with_metadata(out, &MazeMetadata::default(), CellEncoding::default())
+    }
+
+    /// Writes the maze along with `metadata`. The stored endpoints are always this maze's start and end.
+    pub fn write_maze_with_metadata(&self, out: impl Write, metadata: &MazeMetadata, encoding: CellEncoding) -> Result<(), io::Error> {
         match self {
-            Maze::MaskedMaze { maze, .. } => {
-                maze.write_maze(out)
+            Maze::MaskedMaze { maze, start, end } => {
+                let endpoints = (maze.pool.get(*start).payload, maze.pool.get(*end).payload);
+                maze.write_maze_with_metadata(out, &MazeMetadata { endpoints: Some(endpoints), ..metadata.clone() }, encoding)
             },
-            Maze::RadialMaze { maze: _maze, start: _start, end: _end } => {
-                todo!();
+            Maze::RadialMaze { maze, start, end } => {
+                let start = maze.position_of(*start).unwrap();
+                let end = maze.position_of(*end).unwrap();
+                let endpoints = ((start.ring, start.column), (end.ring, end.column));
+                maze.write_maze_with_metadata(out, &MazeMetadata { endpoints: Some(endpoints), ..metadata.clone() }, encoding)
             },
         }
     }
-}
\ No newline at end of file
+
+    /// Reads a maze file of any topology. Endpoints are taken from the file when present, and computed otherwise.
+    pub fn read_maze(input: impl Read) -> Result<(Self, MazeMetadata), GridReadError> {
+        let (header, body) = MazeHeader::read(input)?;
+        let maze = match header.topology {
+            Topology::Cartesian { width, height } => {
+                let maze = MaskedGrid::read_cells(width, height, header.encoding, body)?;
+                let (start, end) = match header.metadata.endpoints {
+                    Some((start, end)) => (maze.endpoint_id(start)?, maze.endpoint_id(end)?),
+                    None => maze.pool.furthest_pair().unwrap(),
+                };
+                Self::MaskedMaze { maze, start, end }
+            },
+            Topology::Polar { starting_branch_count, ring_count } => {
+                let maze = PolarGrid::read_cells(starting_branch_count, ring_count, header.encoding, body)?;
+                let (start, end) = match header.metadata.endpoints {
+                    Some((start, end)) => (maze.endpoint_id(start)?, maze.endpoint_id(end)?),
+                    None => maze.pool.furthest_pair().unwrap(),
+                };
+                Self::RadialMaze { maze, start, end }
+            },
+        };
+        Ok((maze, header.metadata))
+    }
+}
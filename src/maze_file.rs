@@ -0,0 +1,545 @@
+use std::{io::{self, Read, Write, Cursor, Chain}, str::FromStr};
+
+use nom::IResult;
+
+use crate::{masked_grid::GridReadError, parsers::{self, Preamble}, compression::Compression};
+
+
+
+/// The version of the `.maze` format written by this crate.
+pub const FORMAT_VERSION: u16 = 2;
+
+/// Magic bytes every versioned `.maze` file starts with.
+pub const FILE_TAG: [u8; 4] = *b"MAZE";
+
+/// The cell data of a maze file, following its header.
+pub type MazeBody<R> = Chain<Cursor<Vec<u8>>, R>;
+
+/// The shape of grid stored in a maze file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Topology {
+    Cartesian {
+        width: usize,
+        height: usize,
+    },
+    Polar {
+        starting_branch_count: usize,
+        ring_count: usize,
+    },
+}
+
+impl std::fmt::Display for Topology {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Topology::Cartesian { width, height } => write!(f, "a {} by {} rectangular grid", width, height),
+            Topology::Polar { starting_branch_count, ring_count } => {
+                write!(f, "a radial grid of {} rings starting with {} branches", ring_count, starting_branch_count)
+            },
+        }
+    }
+}
+
+/// How each cell's links are laid out in the cell data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CellLayout {
+    /// One byte per cell, holding all four of its links. Cells outside the mask are `0`.
+    Bytes,
+    /// Cartesian grids store a bitmap of the mask followed by the south and east links of every cell, two bits each. Polar
+    /// grids store the clockwise and downward links of every cell, two bits each.
+    #[default]
+    Packed,
+}
+
+impl CellLayout {
+    pub fn kind(&self) -> u8 {
+        match self {
+            CellLayout::Bytes => 0,
+            CellLayout::Packed => 1,
+        }
+    }
+
+    pub fn from_kind(kind: u8) -> Option<Self> {
+        match kind {
+            0 => Some(CellLayout::Bytes),
+            1 => Some(CellLayout::Packed),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for CellLayout {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bytes" => Ok(CellLayout::Bytes),
+            "packed" => Ok(CellLayout::Packed),
+            _ => Err(format!("unknown cell layout `{}`, expected one of: bytes, packed", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct CellEncoding {
+    pub layout: CellLayout,
+    pub compression: Compression,
+}
+
+impl CellEncoding {
+    pub fn new(layout: CellLayout, compression: Compression) -> Self {
+        CellEncoding { layout, compression }
+    }
+
+    /// The encoding of files from before the encoding was configurable.
+    pub fn uncompressed_bytes() -> Self {
+        CellEncoding { layout: CellLayout::Bytes, compression: Compression::None }
+    }
+}
+
+/// Information about how a maze was built, stored alongside it.
+///
+/// Endpoints are stored as grid positions: `(row, col)` for cartesian mazes, `(ring, column)` for polar ones.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MazeMetadata {
+    pub seed: Option<u64>,
+    pub algorithm: Option<String>,
+    pub endpoints: Option<((usize, usize), (usize, usize))>,
+    pub properties: Vec<(String, String)>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MazeHeader {
+    /// Format version the header was read as. Headerless legacy files report version `0`.
+    pub version: u16,
+    pub topology: Topology,
+    pub encoding: CellEncoding,
+    pub metadata: MazeMetadata,
+}
+
+/// Kinds of entries in the metadata section.
+pub(crate) mod entry_kind {
+    pub const SEED: u8 = 0;
+    pub const ALGORITHM: u8 = 1;
+    pub const ENDPOINTS: u8 = 2;
+    pub const PROPERTY: u8 = 3;
+}
+
+/// Codes identifying each [`Topology`] in the header.
+pub(crate) mod topology_kind {
+    pub const CARTESIAN: u8 = 0;
+    pub const POLAR: u8 = 1;
+}
+
+impl Topology {
+    pub fn kind(&self) -> u8 {
+        match self {
+            Topology::Cartesian { .. } => topology_kind::CARTESIAN,
+            Topology::Polar { .. } => topology_kind::POLAR,
+        }
+    }
+}
+
+impl MazeMetadata {
+    pub fn with_endpoints(start: (usize, usize), end: (usize, usize)) -> Self {
+        MazeMetadata { endpoints: Some((start, end)), ..Default::default() }
+    }
+
+    /// Looks up a free-form property by key.
+    pub fn property(&self, key: &str) -> Option<&str> {
+        self.properties.iter().find(|(k, _)| k == key).map(|(_, v)| v.as_str())
+    }
+
+    fn entry_count(&self) -> usize {
+        self.seed.iter().count() + self.algorithm.iter().count() + self.endpoints.iter().count() + self.properties.len()
+    }
+
+    fn write(&self, out: &mut impl Write) -> Result<(), io::Error> {
+        out.write_all(&(self.entry_count() as u16).to_be_bytes())?;
+        if let Some(seed) = self.seed {
+            out.write_all(&[entry_kind::SEED])?;
+            out.write_all(&seed.to_be_bytes())?;
+        }
+        if let Some(algorithm) = &self.algorithm {
+            out.write_all(&[entry_kind::ALGORITHM])?;
+            write_string(out, algorithm)?;
+        }
+        if let Some((start, end)) = self.endpoints {
+            out.write_all(&[entry_kind::ENDPOINTS])?;
+            for coordinate in [start.0, start.1, end.0, end.1] {
+                out.write_all(&(coordinate as u32).to_be_bytes())?;
+            }
+        }
+        for (key, value) in &self.properties {
+            out.write_all(&[entry_kind::PROPERTY])?;
+            write_string(out, key)?;
+            write_string(out, value)?;
+        }
+        Ok(())
+    }
+}
+
+fn write_string(out: &mut impl Write, s: &str) -> Result<(), io::Error> {
+    out.write_all(&(s.len() as u32).to_be_bytes())?;
+    out.write_all(s.as_bytes())
+}
+
+impl MazeHeader {
+    pub fn new(topology: Topology, encoding: CellEncoding, metadata: MazeMetadata) -> Self {
+        MazeHeader { version: FORMAT_VERSION, topology, encoding, metadata }
+    }
+
+    /// Writes the header in the current format version, regardless of `self.version`.
+    pub fn write(&self, out: &mut impl Write) -> Result<(), io::Error> {
+        out.write_all(&FILE_TAG)?;
+        out.write_all(&FORMAT_VERSION.to_be_bytes())?;
+        out.write_all(&[self.topology.kind()])?;
+        let (a, b) = match self.topology {
+            Topology::Cartesian { width, height } => (width, height),
+            Topology::Polar { starting_branch_count, ring_count } => (starting_branch_count, ring_count),
+        };
+        out.write_all(&(a as u32).to_be_bytes())?;
+        out.write_all(&(b as u32).to_be_bytes())?;
+        out.write_all(&[self.encoding.layout.kind(), self.encoding.compression.kind()])?;
+        self.metadata.write(out)
+    }
+
+    /// Reads the header of a maze file, versioned or legacy.
+    ///
+    /// Returns the header along with a reader positioned at the start of the cell data.
+    pub fn read<R: Read>(input: R) -> Result<(Self, MazeBody<R>), GridReadError> {
+        let mut reader = HeaderReader { input, buffer: vec![], offset: 0 };
+        let header = match reader.parse(parsers::preamble)? {
+            Preamble::Legacy(header) => header,
+            Preamble::Versioned(1) => {
+                let (topology, metadata) = reader.parse(parsers::header_v1)?;
+                MazeHeader { version: 1, topology, encoding: CellEncoding::uncompressed_bytes(), metadata }
+            },
+            Preamble::Versioned(FORMAT_VERSION) => {
+                let (topology, encoding, metadata) = reader.parse(parsers::header_v2)?;
+                MazeHeader { version: FORMAT_VERSION, topology, encoding, metadata }
+            },
+            Preamble::Versioned(version) => return Err(GridReadError::UnsupportedVersion(version)),
+        };
+        Ok((header, reader.into_body()))
+    }
+}
+
+/// Feeds bytes from a reader into `nom` streaming parsers until they have enough input.
+struct HeaderReader<R> {
+    input: R,
+    buffer: Vec<u8>,
+    /// Number of bytes consumed by previous parses.
+    offset: usize,
+}
+
+enum ParseAttempt<O> {
+    Done(usize, O),
+    NeedMore,
+    FailedAt(usize),
+}
+
+impl<R: Read> HeaderReader<R> {
+    fn parse<O>(&mut self, parser: impl Fn(&[u8]) -> IResult<&[u8], O>) -> Result<O, GridReadError> {
+        loop {
+            let attempt = match parser(&self.buffer) {
+                Ok((rest, output)) => ParseAttempt::Done(self.buffer.len() - rest.len(), output),
+                Err(nom::Err::Incomplete(_)) => ParseAttempt::NeedMore,
+                Err(nom::Err::Error(e) | nom::Err::Failure(e)) => ParseAttempt::FailedAt(self.buffer.len() - e.input.len()),
+            };
+            match attempt {
+                ParseAttempt::Done(used, output) => {
+                    self.buffer.drain(..used);
+                    self.offset += used;
+                    return Ok(output);
+                },
+                ParseAttempt::NeedMore => {
+                    let mut chunk = [0u8; 256];
+                    let read = self.input.read(&mut chunk)?;
+                    if read == 0 {
+                        return Err(GridReadError::NotEnoughBytes);
+                    }
+                    self.buffer.extend_from_slice(&chunk[..read]);
+                },
+                ParseAttempt::FailedAt(position) => {
+                    return Err(GridReadError::MalformedHeader { offset: self.offset + position });
+                },
+            }
+        }
+    }
+
+    fn into_body(self) -> MazeBody<R> {
+        Cursor::new(self.buffer).chain(self.input)
+    }
+}
+
+/// Writes individual bits, most significant first.
+pub struct BitWriter<W: Write> {
+    out: W,
+    byte: u8,
+    filled: u8,
+}
+
+impl<W: Write> BitWriter<W> {
+    pub fn new(out: W) -> Self {
+        BitWriter { out, byte: 0, filled: 0 }
+    }
+
+    pub fn write_bit(&mut self, bit: bool) -> Result<(), io::Error> {
+        self.byte |= (bit as u8) << (7 - self.filled);
+        self.filled += 1;
+        if self.filled == 8 {
+            self.out.write_all(&[self.byte])?;
+            self.byte = 0;
+            self.filled = 0;
+        }
+        Ok(())
+    }
+
+    /// Pads the current byte with zeroes so the next bit starts a new byte.
+    pub fn align(&mut self) -> Result<(), io::Error> {
+        while self.filled != 0 {
+            self.write_bit(false)?;
+        }
+        Ok(())
+    }
+
+    pub fn into_inner(mut self) -> Result<W, io::Error> {
+        self.align()?;
+        Ok(self.out)
+    }
+}
+
+/// Reads bits written by a [`BitWriter`].
+pub struct BitReader<R: Read> {
+    input: R,
+    byte: u8,
+    remaining: u8,
+}
+
+impl<R: Read> BitReader<R> {
+    pub fn new(input: R) -> Self {
+        BitReader { input, byte: 0, remaining: 0 }
+    }
+
+    pub fn read_bit(&mut self) -> Result<bool, GridReadError> {
+        if self.remaining == 0 {
+            let mut byte = [0u8];
+            match self.input.read_exact(&mut byte) {
+                Ok(()) => {},
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Err(GridReadError::NotEnoughBytes),
+                Err(e) => return Err(e.into()),
+            }
+            self.byte = byte[0];
+            self.remaining = 8;
+        }
+        self.remaining -= 1;
+        Ok((self.byte >> self.remaining) & 1 == 1)
+    }
+
+    /// Skips the padding left in the current byte.
+    pub fn align(&mut self) {
+        self.remaining = 0;
+    }
+
+    /// Checks that no data follows the last aligned byte.
+    pub fn finish(mut self) -> Result<(), GridReadError> {
+        let mut byte = [0u8];
+        match self.input.read(&mut byte)? {
+            0 => Ok(()),
+            _ => Err(GridReadError::TooManyBytes),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::thread_rng;
+
+    use crate::{masked_grid::{MaskedGrid, NewsGridError}, mask::Mask, maze::{Maze, Algorithm}, grid::Direction};
+
+    use super::*;
+
+    fn sample_metadata() -> MazeMetadata {
+        MazeMetadata {
+            seed: Some(0xDEADBEEF),
+            algorithm: Some("hunt-and-kill".to_string()),
+            endpoints: Some(((0, 1), (3, 2))),
+            properties: vec![("author".to_string(), "mazeworld".to_string())],
+        }
+    }
+
+    #[test]
+    fn header_round_trip() {
+        let encoding = CellEncoding::new(CellLayout::Packed, Compression::Lzss);
+        let header = MazeHeader::new(Topology::Cartesian { width: 4, height: 5 }, encoding, sample_metadata());
+        let mut bytes = vec![];
+        header.write(&mut bytes).unwrap();
+        bytes.extend_from_slice(&[1, 2, 3]);
+
+        let (read_header, mut body) = MazeHeader::read(bytes.as_slice()).unwrap();
+        assert_eq!(read_header, header);
+        let mut rest = vec![];
+        body.read_to_end(&mut rest).unwrap();
+        assert_eq!(rest, vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn legacy_header() {
+        let mut bytes = vec![];
+        for n in [2u32, 1, 0, 0, 0, 1] {
+            bytes.extend_from_slice(&n.to_be_bytes());
+        }
+        bytes.extend_from_slice(&[0b0100, 0b0010]);
+
+        let (header, _) = MazeHeader::read(bytes.as_slice()).unwrap();
+        assert_eq!(header.version, 0);
+        assert_eq!(header.topology, Topology::Cartesian { width: 2, height: 1 });
+        assert_eq!(header.metadata.endpoints, Some(((0, 0), (0, 1))));
+
+        let (grid, metadata) = MaskedGrid::read_maze_with_metadata(bytes.as_slice()).unwrap();
+        assert!(grid.is_linked_at(0, 0, 0, 1));
+        assert_eq!(metadata.endpoints, Some(((0, 0), (0, 1))));
+    }
+
+    #[test]
+    fn unsupported_version() {
+        let mut bytes = FILE_TAG.to_vec();
+        bytes.extend_from_slice(&(FORMAT_VERSION + 1).to_be_bytes());
+        assert!(matches!(MazeHeader::read(bytes.as_slice()), Err(GridReadError::UnsupportedVersion(v)) if v == FORMAT_VERSION + 1));
+    }
+
+    #[test]
+    fn malformed_header_offset() {
+        let mut bytes = vec![];
+        MazeHeader::new(Topology::Cartesian { width: 1, height: 1 }, CellEncoding::default(), MazeMetadata::default()).write(&mut bytes).unwrap();
+        // Corrupt the topology kind
+        bytes[6] = 9;
+        assert!(matches!(MazeHeader::read(bytes.as_slice()), Err(GridReadError::MalformedHeader { offset: 6 })));
+    }
+
+    #[test]
+    fn masked_endpoints_survive_round_trip() {
+        let mut rng = thread_rng();
+        let mut grid = MaskedGrid::new_unmasked(6, 4);
+        grid.hunt_and_kill(&mut rng);
+        // Pick endpoints furthest_pair would never choose on its own
+        let start = grid.get_id_at(0, 0).unwrap();
+        let end = grid.get_id_at(0, 1).unwrap();
+        let maze = Maze::MaskedMaze { maze: grid, start, end };
+
+        let mut bytes = vec![];
+        maze.write_maze_with_metadata(&mut bytes, &sample_metadata(), CellEncoding::default()).unwrap();
+        let (read, metadata) = Maze::read_maze(bytes.as_slice()).unwrap();
+        assert_eq!(metadata.seed, Some(0xDEADBEEF));
+        assert_eq!(metadata.property("author"), Some("mazeworld"));
+        match (maze, read) {
+            (Maze::MaskedMaze { maze, start, end }, Maze::MaskedMaze { maze: read_maze, start: read_start, end: read_end }) => {
+                assert!(maze == read_maze);
+                assert_eq!((start, end), (read_start, read_end));
+            },
+            _ => panic!("Topology changed during round trip"),
+        }
+    }
+
+    fn all_encodings() -> Vec<CellEncoding> {
+        let mut encodings = vec![];
+        for layout in [CellLayout::Bytes, CellLayout::Packed] {
+            for compression in [Compression::None, Compression::RunLength, Compression::Lzss] {
+                encodings.push(CellEncoding::new(layout, compression));
+            }
+        }
+        encodings
+    }
+
+    #[test]
+    fn masked_encodings_round_trip() {
+        let mut rng = thread_rng();
+        let mut grid = MaskedGrid::new(13, 9, Mask::disk((5.0, 7.0), 4.5));
+        grid.hunt_and_kill(&mut rng);
+        for encoding in all_encodings() {
+            let mut bytes = vec![];
+            grid.write_maze_with_metadata(&mut bytes, &MazeMetadata::default(), encoding).unwrap();
+            let (read, _) = MaskedGrid::read_maze_with_metadata(bytes.as_slice()).unwrap();
+            assert!(grid == read, "{:?} changed the maze", encoding);
+        }
+    }
+
+    #[test]
+    fn packed_is_smaller() {
+        let mut rng = thread_rng();
+        let mut grid = MaskedGrid::new_unmasked(32, 32);
+        grid.hunt_and_kill(&mut rng);
+        let mut bytes = vec![];
+        grid.write_maze_with_metadata(&mut bytes, &MazeMetadata::default(), CellEncoding::uncompressed_bytes()).unwrap();
+        let mut packed = vec![];
+        grid.write_maze_with_metadata(&mut packed, &MazeMetadata::default(), CellEncoding::default()).unwrap();
+        assert!(packed.len() < bytes.len() / 2);
+    }
+
+    #[test]
+    fn version_1_still_readable() {
+        let mut bytes = FILE_TAG.to_vec();
+        bytes.extend_from_slice(&1u16.to_be_bytes());
+        bytes.push(topology_kind::CARTESIAN);
+        bytes.extend_from_slice(&2u32.to_be_bytes());
+        bytes.extend_from_slice(&1u32.to_be_bytes());
+        MazeMetadata::with_endpoints((0, 1), (0, 0)).write(&mut bytes).unwrap();
+        bytes.extend_from_slice(&[0b0100, 0b0010]);
+
+        let (grid, metadata) = MaskedGrid::read_maze_with_metadata(bytes.as_slice()).unwrap();
+        assert!(grid.is_linked_at(0, 0, 0, 1));
+        assert_eq!(metadata.endpoints, Some(((0, 1), (0, 0))));
+    }
+
+    #[test]
+    fn packed_link_out_of_bounds() {
+        let mut bytes = vec![];
+        MazeHeader::new(Topology::Cartesian { width: 2, height: 1 }, CellEncoding::default(), MazeMetadata::default()).write(&mut bytes).unwrap();
+        // Both cells present, then the second cell links east off the grid
+        bytes.extend_from_slice(&[0b1100_0000, 0b0100_0000 | 0b0001_0000]);
+        assert!(matches!(
+            MaskedGrid::read_maze(bytes.as_slice()),
+            Err(GridReadError::InvalidNewsGrid(NewsGridError::ConnectedOutOfBounds { cell: (0, 1), direction: Direction::East }))
+        ));
+    }
+
+    #[test]
+    fn byte_links_checked_row_by_row() {
+        let read = |cells: &[u8]| {
+            let mut bytes = vec![];
+            MazeHeader::new(Topology::Cartesian { width: 2, height: 2 }, CellEncoding::uncompressed_bytes(), MazeMetadata::default()).write(&mut bytes).unwrap();
+            bytes.extend_from_slice(cells);
+            MaskedGrid::read_maze(bytes.as_slice())
+        };
+        let grid = read(&[0b0101, 0b0010, 0b1100, 0b0010]).unwrap();
+        assert!(grid.is_linked_at(0, 0, 1, 0) && grid.is_linked_at(1, 0, 1, 1) && !grid.is_linked_at(0, 1, 1, 1));
+        // The cell below does not link back north
+        assert!(matches!(
+            read(&[0b0101, 0b0010, 0b0100, 0b0010]),
+            Err(GridReadError::InvalidNewsGrid(NewsGridError::UnrequitedConnection { linked: (0, 0), unlinked: (1, 0), direction: Direction::South }))
+        ));
+        assert!(matches!(read(&[0b0101, 0b0010, 0b1100]), Err(GridReadError::NotEnoughBytes)));
+        assert!(matches!(read(&[0b0101, 0b0010, 0b1100, 0b0010, 0]), Err(GridReadError::TooManyBytes)));
+    }
+
+    #[test]
+    fn polar_round_trip() {
+        let mut rng = thread_rng();
+        let maze = Maze::new_unmasked_radial(6, 5, Algorithm::AldousBroder, &mut rng, &mut ());
+        for encoding in all_encodings() {
+            let mut bytes = vec![];
+            maze.write_maze_with_metadata(&mut bytes, &MazeMetadata::default(), encoding).unwrap();
+            let (read, _) = Maze::read_maze(bytes.as_slice()).unwrap();
+            match (&maze, read) {
+                (Maze::RadialMaze { maze, start, end }, Maze::RadialMaze { maze: read_maze, start: read_start, end: read_end }) => {
+                    assert_eq!(maze.pool, read_maze.pool);
+                    assert_eq!((*start, *end), (read_start, read_end));
+                },
+                _ => panic!("Topology changed during round trip"),
+            }
+        }
+        let mut bytes = vec![];
+        maze.write_maze(&mut bytes).unwrap();
+        assert!(matches!(MaskedGrid::read_maze(bytes.as_slice()), Err(GridReadError::TopologyMismatch(Topology::Polar { .. }))));
+    }
+}
@@ -1,9 +1,799 @@
+use std::fmt::Display;
+
 use nom::IResult;
-use nom::bytes::complete;
+use nom::branch::alt;
+use nom::bytes::streaming::tag;
+use nom::combinator::{map, map_opt, map_res};
+use nom::multi::{length_count, length_data};
+use nom::number::streaming::{be_u8, be_u16, be_u32, be_u64};
+use nom::sequence::{pair, preceded, tuple};
 
+use crate::compression::Compression;
+use crate::maze_file::{MazeHeader, Topology, MazeMetadata, CellEncoding, CellLayout, FILE_TAG, entry_kind, topology_kind};
+use crate::trace::{TraceEvent, TRACE_TAG, event_kind};
+use crate::mask::Mask;
+use crate::procedural::{Procedure, CaveRules};
+use crate::vector_mask::PathCommand;
 
 
 
 pub fn file_tag(i: &[u8]) -> IResult<&[u8],&[u8]> {
-    complete::tag([77u8, 65u8, 90u8, 69u8])(i)
+    tag(FILE_TAG)(i)
+}
+
+/// The first few bytes of a maze file, which tell apart the versioned format from the legacy headerless one.
+pub enum Preamble {
+    /// The file starts with [`FILE_TAG`] followed by the given format version.
+    Versioned(u16),
+    /// The file has no tag. Legacy files keep their dimensions and endpoints up front, which are all we need.
+    Legacy(MazeHeader),
+}
+
+enum MetadataEntry {
+    Seed(u64),
+    Algorithm(String),
+    Endpoints(((usize, usize), (usize, usize))),
+    Property(String, String),
+}
+
+fn dimension(i: &[u8]) -> IResult<&[u8], usize> {
+    map(be_u32, |n| n as usize)(i)
+}
+
+fn position(i: &[u8]) -> IResult<&[u8], (usize, usize)> {
+    pair(dimension, dimension)(i)
+}
+
+fn string(i: &[u8]) -> IResult<&[u8], String> {
+    map_res(length_data(be_u32), |bytes: &[u8]| String::from_utf8(bytes.to_vec()))(i)
+}
+
+pub fn preamble(i: &[u8]) -> IResult<&[u8], Preamble> {
+    alt((
+        map(preceded(file_tag, be_u16), Preamble::Versioned),
+        map(legacy_header, Preamble::Legacy),
+    ))(i)
+}
+
+/// Width, height, start and end, each as a big endian `u32`.
+pub fn legacy_header(i: &[u8]) -> IResult<&[u8], MazeHeader> {
+    map(tuple((dimension, dimension, position, position)), |(width, height, start, end)| {
+        MazeHeader {
+            version: 0,
+            topology: Topology::Cartesian { width, height },
+            encoding: CellEncoding::uncompressed_bytes(),
+            metadata: MazeMetadata::with_endpoints(start, end),
+        }
+    })(i)
+}
+
+pub fn topology(i: &[u8]) -> IResult<&[u8], Topology> {
+    alt((
+        map(preceded(tag([topology_kind::CARTESIAN]), pair(dimension, dimension)), |(width, height)| {
+            Topology::Cartesian { width, height }
+        }),
+        map(preceded(tag([topology_kind::POLAR]), pair(dimension, dimension)), |(starting_branch_count, ring_count)| {
+            Topology::Polar { starting_branch_count, ring_count }
+        }),
+    ))(i)
+}
+
+pub fn cell_encoding(i: &[u8]) -> IResult<&[u8], CellEncoding> {
+    map_opt(pair(be_u8, be_u8), |(layout, compression)| {
+        Some(CellEncoding::new(CellLayout::from_kind(layout)?, Compression::from_kind(compression)?))
+    })(i)
+}
+
+fn metadata_entry(i: &[u8]) -> IResult<&[u8], MetadataEntry> {
+    alt((
+        map(preceded(tag([entry_kind::SEED]), be_u64), MetadataEntry::Seed),
+        map(preceded(tag([entry_kind::ALGORITHM]), string), MetadataEntry::Algorithm),
+        map(preceded(tag([entry_kind::ENDPOINTS]), pair(position, position)), MetadataEntry::Endpoints),
+        map(preceded(tag([entry_kind::PROPERTY]), pair(string, string)), |(key, value)| MetadataEntry::Property(key, value)),
+    ))(i)
+}
+
+pub fn metadata(i: &[u8]) -> IResult<&[u8], MazeMetadata> {
+    map(length_count(be_u16, metadata_entry), |entries| {
+        let mut metadata = MazeMetadata::default();
+        for entry in entries {
+            match entry {
+                MetadataEntry::Seed(seed) => metadata.seed = Some(seed),
+                MetadataEntry::Algorithm(algorithm) => metadata.algorithm = Some(algorithm),
+                MetadataEntry::Endpoints(endpoints) => metadata.endpoints = Some(endpoints),
+                MetadataEntry::Property(key, value) => metadata.properties.push((key, value)),
+            }
+        }
+        metadata
+    })(i)
+}
+
+/// Everything in a version 1 header after the preamble.
+pub fn header_v1(i: &[u8]) -> IResult<&[u8], (Topology, MazeMetadata)> {
+    pair(topology, metadata)(i)
+}
+
+/// Everything in a version 2 header after the preamble.
+pub fn header_v2(i: &[u8]) -> IResult<&[u8], (Topology, CellEncoding, MazeMetadata)> {
+    tuple((topology, cell_encoding, metadata))(i)
+}
+
+/// Walls of a maze drawn as text, before any mask is inferred from them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TextMazeWalls {
+    pub width: usize,
+    pub height: usize,
+    /// `h_walls[row][col]` is the wall along the top of row `row` (`0..=height`), under column `col`.
+    pub h_walls: Vec<Vec<bool>>,
+    /// `v_walls[row][col]` is the wall along the left of column `col` (`0..=width`), across row `row`.
+    pub v_walls: Vec<Vec<bool>>,
+}
+
+/// An error in a text maze. Lines and columns count characters and start at 1.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TextMazeError {
+    pub line: usize,
+    pub column: usize,
+    pub kind: TextMazeErrorKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TextMazeErrorKind {
+    /// The bytes are neither UTF-8 nor UTF-16 with a byte order mark.
+    InvalidEncoding,
+    /// There are no walls to infer the size of cells from.
+    NoWalls,
+    /// Mazes alternate wall and cell lines, starting and ending with a wall line.
+    MissingBottomEdge,
+    UnexpectedCharacter(char),
+    /// No cells are enclosed by walls.
+    NoCells,
+}
+
+impl TextMazeError {
+    pub fn new(line: usize, column: usize, kind: TextMazeErrorKind) -> Self {
+        TextMazeError { line, column, kind }
+    }
+}
+
+impl Display for TextMazeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "line {}, column {}: ", self.line, self.column)?;
+        match self.kind {
+            TextMazeErrorKind::InvalidEncoding => write!(f, "the text is neither UTF-8 nor UTF-16"),
+            TextMazeErrorKind::NoWalls => write!(f, "there are no walls to tell the size of the cells from"),
+            TextMazeErrorKind::MissingBottomEdge => write!(f, "the maze has no bottom edge"),
+            TextMazeErrorKind::UnexpectedCharacter(c) => write!(f, "unexpected {:?}", c),
+            TextMazeErrorKind::NoCells => write!(f, "no cells are enclosed by walls"),
+        }
+    }
+}
+
+const JUNCTION_GLYPHS: &str = "+┌┐└┘├┤┬┴┼╴╵╶╷";
+const HORIZONTAL_GLYPHS: &str = "-─";
+const VERTICAL_GLYPHS: &str = "|│";
+
+/// Decodes text saved as UTF-8, or as UTF-16 with a byte order mark.
+pub fn decode_text(bytes: &[u8]) -> Option<String> {
+    let utf16 = |bytes: &[u8], from: fn([u8; 2]) -> u16| {
+        if !bytes.len().is_multiple_of(2) {
+            return None;
+        }
+        let units: Vec<u16> = bytes.chunks(2).map(|c| from([c[0], c[1]])).collect();
+        String::from_utf16(&units).ok()
+    };
+    match bytes {
+        [0xFF, 0xFE, rest @ ..] => utf16(rest, u16::from_le_bytes),
+        [0xFE, 0xFF, rest @ ..] => utf16(rest, u16::from_be_bytes),
+        [0xEF, 0xBB, 0xBF, rest @ ..] => String::from_utf8(rest.to_vec()).ok(),
+        _ => String::from_utf8(bytes.to_vec()).ok(),
+    }
+}
+
+/// Splits text on `\n` or `\r\n`. Stops early at a lone `\r`.
+fn text_lines(i: &str) -> IResult<&str, Vec<&str>> {
+    use nom::bytes::complete::take_till;
+    use nom::character::complete::line_ending;
+    use nom::multi::separated_list0;
+    separated_list0(line_ending, take_till(|c| c == '\n' || c == '\r'))(i)
+}
+
+/// A junction, or a lone wall segment passing through one.
+fn junction_glyph(i: &str) -> IResult<&str, char> {
+    use nom::character::complete::one_of;
+    alt((one_of(JUNCTION_GLYPHS), one_of(HORIZONTAL_GLYPHS), one_of(VERTICAL_GLYPHS), one_of(" ")))(i)
+}
+
+/// The stretch of a wall line between two junctions. Returns whether a wall is drawn there.
+fn wall_segment(cell_width: usize) -> impl Fn(&str) -> IResult<&str, bool> {
+    use nom::character::complete::one_of;
+    use nom::multi::count;
+    move |i| {
+        map(count(alt((one_of(HORIZONTAL_GLYPHS), one_of(" "))), cell_width), |glyphs| {
+            glyphs.iter().any(|&g| g != ' ')
+        })(i)
+    }
+}
+
+/// A line of junctions and horizontal walls.
+fn wall_line(cell_width: usize, width: usize) -> impl Fn(&str) -> IResult<&str, Vec<bool>> {
+    use nom::combinator::eof;
+    use nom::multi::count;
+    use nom::sequence::terminated;
+    move |i| {
+        map(terminated(pair(count(preceded(junction_glyph, wall_segment(cell_width)), width), junction_glyph), eof), |(walls, _)| walls)(i)
+    }
+}
+
+/// A line of cell interiors separated by vertical walls. Interiors may hold anything, such as distances or path markers.
+fn cell_line(cell_width: usize, width: usize) -> impl Fn(&str) -> IResult<&str, Vec<bool>> {
+    use nom::bytes::complete::take;
+    use nom::character::complete::one_of;
+    use nom::combinator::{eof, value};
+    use nom::multi::count;
+    use nom::sequence::terminated;
+    move |i| {
+        let vertical_wall = |i| alt((value(true, one_of(VERTICAL_GLYPHS)), value(false, one_of(" "))))(i);
+        map(terminated(pair(count(terminated(vertical_wall, take(cell_width)), width), vertical_wall), eof), |(mut walls, last)| {
+            walls.push(last);
+            walls
+        })(i)
+    }
+}
+
+fn gcd(a: usize, b: usize) -> usize {
+    if b == 0 { a } else { gcd(b, a % b) }
+}
+
+/// Parses a maze drawn with `+---+` and `|` walls, as printed by `text_print`. Box drawing characters are accepted too.
+///
+/// Cells may be any uniform number of characters wide; the width is inferred from where junctions and vertical walls sit.
+pub fn text_maze(text: &str) -> Result<TextMazeWalls, TextMazeError> {
+    let mut lines: Vec<Vec<char>> = match text_lines(text) {
+        Ok(("", lines)) => lines.into_iter().map(|l| l.chars().collect()).collect(),
+        Ok((_, lines)) => {
+            let column = lines.last().map(|l| l.chars().count()).unwrap_or(0);
+            return Err(TextMazeError::new(lines.len().max(1), column + 1, TextMazeErrorKind::UnexpectedCharacter('\r')));
+        },
+        Err(_) => unreachable!("Splitting into lines cannot fail"),
+    };
+    // Trailing whitespace carries no walls, and text_print trims it anyway
+    for line in lines.iter_mut() {
+        while line.last().is_some_and(|c| c.is_whitespace()) {
+            line.pop();
+        }
+    }
+    while lines.last().is_some_and(|l| l.is_empty()) {
+        lines.pop();
+    }
+
+    // Every junction and vertical wall sits on a multiple of the cell pitch
+    let mut pitch = 0;
+    for (line_index, line) in lines.iter().enumerate() {
+        for (x, &c) in line.iter().enumerate() {
+            let is_marker = if line_index % 2 == 0 { JUNCTION_GLYPHS.contains(c) } else { VERTICAL_GLYPHS.contains(c) };
+            if is_marker {
+                pitch = gcd(pitch, x);
+            }
+        }
+    }
+    if pitch < 2 {
+        return Err(TextMazeError::new(1, 1, TextMazeErrorKind::NoWalls));
+    }
+    if lines.len().is_multiple_of(2) {
+        return Err(TextMazeError::new(lines.len() + 1, 1, TextMazeErrorKind::MissingBottomEdge));
+    }
+
+    let line_length = lines.iter().map(|l| l.len()).max().unwrap_or(0);
+    let width = (line_length - 1) / pitch;
+    let height = lines.len() / 2;
+    let full_length = width * pitch + 1;
+
+    let mut h_walls = vec![];
+    let mut v_walls = vec![];
+    for (line_index, line) in lines.iter().enumerate() {
+        let mut padded: String = line.iter().collect();
+        for _ in line.len()..full_length {
+            padded.push(' ');
+        }
+        let parsed = if line_index % 2 == 0 {
+            wall_line(pitch - 1, width)(&padded)
+        } else {
+            cell_line(pitch - 1, width)(&padded)
+        };
+        match parsed {
+            Ok((_, walls)) => {
+                if line_index % 2 == 0 { h_walls.push(walls) } else { v_walls.push(walls) }
+            },
+            Err(nom::Err::Error(e) | nom::Err::Failure(e)) => {
+                // Spaces are valid anywhere a glyph is, so the offending character is the first that isn't one
+                let offending = e.input.trim_start_matches(' ');
+                let column = line.len().max(full_length) - offending.chars().count();
+                let c = offending.chars().next().unwrap_or(' ');
+                return Err(TextMazeError::new(line_index + 1, column + 1, TextMazeErrorKind::UnexpectedCharacter(c)));
+            },
+            Err(nom::Err::Incomplete(_)) => unreachable!("Complete parsers never ask for more input"),
+        }
+    }
+
+    Ok(TextMazeWalls { width, height, h_walls, v_walls })
+}
+
+/// One `[name]` section of a batch job file, with its `key = value` fields in order.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JobSection {
+    pub name: String,
+    /// Line the section header is on, starting at 1.
+    pub line: usize,
+    pub fields: Vec<(String, String)>,
+}
+
+/// An error in the layout of a batch job file. Lines start at 1.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JobFileError {
+    pub line: usize,
+    pub kind: JobFileErrorKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum JobFileErrorKind {
+    /// The line is neither a `[name]` header, a `key = value` field, a `#` comment nor blank.
+    ExpectedHeaderOrField,
+    /// A field comes before the first section header.
+    FieldOutsideJob,
+}
+
+impl Display for JobFileErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            JobFileErrorKind::ExpectedHeaderOrField => write!(f, "expected a `[name]` header or a `key = value` field"),
+            JobFileErrorKind::FieldOutsideJob => write!(f, "a field comes before the first `[name]` header"),
+        }
+    }
+}
+
+#[derive(Clone)]
+enum JobLine<'a> {
+    Blank,
+    Header(&'a str),
+    Field(&'a str, &'a str),
+}
+
+fn job_line(i: &str) -> IResult<&str, JobLine<'_>> {
+    use nom::bytes::complete::{take_till, take_till1};
+    use nom::character::complete::{char, space0};
+    use nom::combinator::{eof, rest, value};
+    use nom::sequence::{delimited, separated_pair, terminated};
+    let comment = |i| preceded(char('#'), rest)(i);
+    let blank = value(JobLine::Blank, pair(space0, alt((comment, eof))));
+    let header = map(delimited(pair(space0, char('[')), take_till1(|c| c == ']'), tuple((char(']'), space0, eof))), |name: &str| {
+        JobLine::Header(name.trim())
+    });
+    let key = take_till1(|c: char| c == '=' || c.is_whitespace() || c == '#' || c == '[');
+    let field = map(terminated(separated_pair(preceded(space0, key), pair(space0, char('=')), take_till(|_| false)), eof), |(key, value): (&str, &str)| {
+        JobLine::Field(key, value.trim())
+    });
+    alt((blank, header, field))(i)
+}
+
+/// Parses a batch job file: `[name]` headers, each followed by the `key = value` fields of that job. Blank lines and lines
+/// starting with `#` are ignored.
+pub fn job_file(text: &str) -> Result<Vec<JobSection>, JobFileError> {
+    let lines = match text_lines(text) {
+        Ok((_, lines)) => lines,
+        Err(_) => unreachable!("Splitting into lines cannot fail"),
+    };
+    let mut sections: Vec<JobSection> = vec![];
+    for (index, line) in lines.into_iter().enumerate() {
+        let line_number = index + 1;
+        match job_line(line) {
+            Ok((_, JobLine::Blank)) => {},
+            Ok((_, JobLine::Header(name))) => sections.push(JobSection { name: name.to_string(), line: line_number, fields: vec![] }),
+            Ok((_, JobLine::Field(key, value))) => match sections.last_mut() {
+                Some(section) => section.fields.push((key.to_string(), value.to_string())),
+                None => return Err(JobFileError { line: line_number, kind: JobFileErrorKind::FieldOutsideJob }),
+            },
+            Err(_) => return Err(JobFileError { line: line_number, kind: JobFileErrorKind::ExpectedHeaderOrField }),
+        }
+    }
+    Ok(sections)
+}
+
+/// The tag and format version a binary trace starts with.
+pub fn trace_preamble(i: &[u8]) -> IResult<&[u8], u8> {
+    preceded(tag(TRACE_TAG), be_u8)(i)
+}
+
+/// A little endian base 128 varint, as nodes are written in binary traces.
+fn varint(i: &[u8]) -> IResult<&[u8], usize> {
+    let mut n = 0usize;
+    for (k, &byte) in i.iter().enumerate() {
+        let bits = (byte & 0x7F) as usize;
+        if 7 * k >= usize::BITS as usize || (bits << (7 * k)) >> (7 * k) != bits {
+            break;
+        }
+        n |= bits << (7 * k);
+        if byte & 0x80 == 0 {
+            return Ok((&i[k + 1..], n));
+        }
+    }
+    Err(nom::Err::Error(nom::error::Error::new(i, nom::error::ErrorKind::TooLarge)))
+}
+
+/// One event of a binary trace: its kind, then its nodes.
+pub fn trace_binary_event(i: &[u8]) -> IResult<&[u8], TraceEvent> {
+    alt((
+        map(preceded(tag([event_kind::VISIT]), varint), TraceEvent::Visit),
+        map(preceded(tag([event_kind::LINK]), pair(varint, varint)), |(here, there)| TraceEvent::Link(here, there)),
+        map(preceded(tag([event_kind::UNLINK]), pair(varint, varint)), |(here, there)| TraceEvent::LinkRemoved(here, there)),
+        map(preceded(tag([event_kind::STEP]), varint), TraceEvent::WalkerStep),
+        map(preceded(tag([event_kind::ERASE]), length_count(varint, varint)), TraceEvent::LoopErased),
+    ))(i)
+}
+
+fn json_char<'a>(c: char) -> impl FnMut(&'a str) -> IResult<&'a str, char> {
+    use nom::character::complete::{char, multispace0};
+    use nom::sequence::delimited;
+    delimited(multispace0, char(c), multispace0)
+}
+
+fn json_string<'a>(s: &'static str) -> impl FnMut(&'a str) -> IResult<&'a str, &'a str> {
+    use nom::bytes::complete::tag;
+    use nom::character::complete::{char, multispace0};
+    use nom::sequence::delimited;
+    delimited(pair(multispace0, char('"')), tag(s), char('"'))
+}
+
+/// `"name":`, the key of a field.
+fn json_key<'a>(name: &'static str) -> impl FnMut(&'a str) -> IResult<&'a str, char> {
+    preceded(json_string(name), json_char(':'))
+}
+
+fn json_node(i: &str) -> IResult<&str, usize> {
+    use nom::character::complete::u64;
+    map(u64, |n| n as usize)(i)
+}
+
+fn json_nodes(i: &str) -> IResult<&str, Vec<usize>> {
+    use nom::multi::separated_list0;
+    use nom::sequence::delimited;
+    delimited(json_char('['), separated_list0(json_char(','), json_node), json_char(']'))(i)
+}
+
+/// `"event":"kind","field":`, everything in an event before its nodes.
+fn json_event<'a>(kind: &'static str, field: &'static str) -> impl FnMut(&'a str) -> IResult<&'a str, char> {
+    preceded(tuple((json_key("event"), json_string(kind), json_char(','))), json_key(field))
+}
+
+/// One line of a JSON lines trace, such as `{"event":"link","nodes":[3,4]}`. Fields must come in the order they are written.
+pub fn trace_json_line(i: &str) -> IResult<&str, TraceEvent> {
+    use nom::combinator::eof;
+    use nom::sequence::{delimited, terminated};
+    let two = |nodes: Vec<usize>| match nodes[..] {
+        [here, there] => Some((here, there)),
+        _ => None,
+    };
+    let event = alt((
+        map(preceded(json_event("visit", "node"), json_node), TraceEvent::Visit),
+        map_opt(preceded(json_event("link", "nodes"), json_nodes), move |nodes| two(nodes).map(|(here, there)| TraceEvent::Link(here, there))),
+        map_opt(preceded(json_event("unlink", "nodes"), json_nodes), move |nodes| two(nodes).map(|(here, there)| TraceEvent::LinkRemoved(here, there))),
+        map(preceded(json_event("step", "node"), json_node), TraceEvent::WalkerStep),
+        map(preceded(json_event("erase", "nodes"), json_nodes), TraceEvent::LoopErased),
+    ));
+    terminated(delimited(json_char('{'), event, json_char('}')), eof)(i)
+}
+
+/// An error in a mask drawn as text. Lines and columns count characters and start at 1.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TextMaskError {
+    pub line: usize,
+    pub column: usize,
+    pub kind: TextMaskErrorKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TextMaskErrorKind {
+    /// The bytes are neither UTF-8 nor UTF-16 with a byte order mark.
+    InvalidEncoding,
+    UnexpectedCharacter(char),
+    /// The start, `S`, or end, `E`, is marked more than once.
+    RepeatedMarker(char),
+}
+
+impl TextMaskError {
+    pub fn new(line: usize, column: usize, kind: TextMaskErrorKind) -> Self {
+        TextMaskError { line, column, kind }
+    }
+}
+
+impl Display for TextMaskError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "line {}, column {}: ", self.line, self.column)?;
+        match self.kind {
+            TextMaskErrorKind::InvalidEncoding => write!(f, "the text is neither UTF-8 nor UTF-16"),
+            TextMaskErrorKind::UnexpectedCharacter(c) => write!(f, "unexpected {:?}", c),
+            TextMaskErrorKind::RepeatedMarker(c) => write!(f, "{:?} marks more than one cell", c),
+        }
+    }
+}
+
+/// A mask drawn as text, with the cells marked as the start and end of the maze.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TextMask {
+    pub rows: Vec<Vec<bool>>,
+    pub start: Option<(usize, usize)>,
+    pub end: Option<(usize, usize)>,
+}
+
+/// Reads the rows of a mask drawn as text: `#` or `X` for cells that are present, `.` or a space for those that are not,
+/// and `S` and `E` for the cells the maze starts and ends on. Trailing blank lines are dropped.
+pub fn text_mask(text: &str) -> Result<TextMask, TextMaskError> {
+    let mut lines = match text_lines(text) {
+        Ok((_, lines)) => lines,
+        Err(_) => unreachable!("Splitting into lines cannot fail"),
+    };
+    while lines.last().is_some_and(|line| line.trim().is_empty()) {
+        lines.pop();
+    }
+    let mut mask = TextMask { rows: vec![], start: None, end: None };
+    for (row, line) in lines.into_iter().enumerate() {
+        let mut cells = vec![];
+        for (col, c) in line.chars().enumerate() {
+            let error = |kind| TextMaskError::new(row + 1, col + 1, kind);
+            let marker = match c {
+                'S' => Some(&mut mask.start),
+                'E' => Some(&mut mask.end),
+                _ => None,
+            };
+            match (c, marker) {
+                (_, Some(Some(_))) => return Err(error(TextMaskErrorKind::RepeatedMarker(c))),
+                (_, Some(marker)) => {
+                    *marker = Some((row, col));
+                    cells.push(true);
+                },
+                ('#' | 'X', None) => cells.push(true),
+                ('.' | ' ', None) => cells.push(false),
+                _ => return Err(error(TextMaskErrorKind::UnexpectedCharacter(c))),
+            }
+        }
+        mask.rows.push(cells);
+    }
+    Ok(mask)
+}
+
+/// Wraps a parser of part of a mask, allowing spaces around it.
+fn spaced<'a, O>(parser: impl FnMut(&'a str) -> IResult<&'a str, O>) -> impl FnMut(&'a str) -> IResult<&'a str, O> {
+    use nom::character::complete::multispace0;
+    use nom::sequence::delimited;
+    delimited(multispace0, parser, multispace0)
+}
+
+fn mask_count(i: &str) -> IResult<&str, usize> {
+    use nom::character::complete::digit1;
+    spaced(map_res(digit1, str::parse))(i)
+}
+
+fn mask_offset(i: &str) -> IResult<&str, isize> {
+    use nom::character::complete::{char, digit1};
+    use nom::combinator::{opt, recognize};
+    spaced(map_res(recognize(pair(opt(char('-')), digit1)), str::parse))(i)
+}
+
+fn mask_number(i: &str) -> IResult<&str, f64> {
+    use nom::number::complete::double;
+    spaced(double)(i)
+}
+
+fn mask_point(i: &str) -> IResult<&str, (f64, f64)> {
+    use nom::character::complete::char;
+    use nom::sequence::separated_pair;
+    separated_pair(mask_number, char(','), mask_number)(i)
+}
+
+/// A mask written as `name(arguments)`, as [`Mask`] is displayed.
+pub fn mask_spec(i: &str) -> IResult<&str, Mask> {
+    use nom::bytes::complete::take_while;
+    use nom::character::complete::{char, alpha1};
+    use nom::combinator::{value, verify};
+    use nom::multi::separated_list1;
+    use nom::sequence::{delimited, terminated};
+    let comma = |i| char(',')(i);
+    let call = |name: &'static str| terminated(spaced(verify(alpha1, move |n: &str| n == name)), char('('));
+    let rows = map(separated_list1(char('/'), take_while(|c| c == '#' || c == '.')), |rows: Vec<&str>| {
+        rows.into_iter().map(|row| row.chars().map(|c| c == '#')).collect::<Vec<_>>()
+    });
+
+    let shapes = alt((
+        value(Mask::Full, spaced(verify(alpha1, |n: &str| n == "full"))),
+        map(delimited(call("rect"), tuple((mask_count, comma, mask_count, comma, mask_count, comma, mask_count)), char(')')), |(row, _, col, _, width, _, height)| {
+            Mask::rectangle(row, col, width, height)
+        }),
+        map(delimited(call("disk"), tuple((mask_point, comma, mask_number)), char(')')), |(center, _, radius)| Mask::disk(center, radius)),
+        map(delimited(call("ellipse"), tuple((mask_point, comma, mask_point)), char(')')), |(center, _, radii)| Mask::ellipse(center, radii)),
+        map(delimited(call("polygon"), separated_list1(comma, mask_point), char(')')), Mask::polygon),
+        map(delimited(call("stripes"), tuple((verify(mask_count, |&period| period > 0), comma, mask_count)), char(')')), |(period, _, thickness)| Mask::stripes(period, thickness)),
+        map(delimited(call("checkerboard"), verify(mask_count, |&size| size > 0), char(')')), Mask::checkerboard),
+        map_opt(delimited(call("cells"), tuple((mask_count, comma, mask_count, comma, spaced(rows))), char(')')), |(width, _, height, _, rows)| {
+            let cells: Vec<bool> = rows.into_iter().flatten().collect();
+            (cells.len() == width * height).then_some(Mask::Cells { width, height, cells })
+        }),
+    ));
+    let pairs = alt((
+        map(delimited(call("union"), tuple((mask_spec, comma, mask_spec)), char(')')), |(a, _, b)| a.union(b)),
+        map(delimited(call("intersection"), tuple((mask_spec, comma, mask_spec)), char(')')), |(a, _, b)| a.intersection(b)),
+        map(delimited(call("difference"), tuple((mask_spec, comma, mask_spec)), char(')')), |(a, _, b)| a.difference(b)),
+        map(delimited(call("xor"), tuple((mask_spec, comma, mask_spec)), char(')')), |(a, _, b)| a.xor(b)),
+    ));
+    let transforms = alt((
+        map(delimited(call("invert"), mask_spec, char(')')), Mask::invert),
+        map(delimited(call("translate"), tuple((mask_spec, comma, mask_offset, comma, mask_offset)), char(')')), |(mask, _, rows, _, cols)| {
+            mask.translate(rows, cols)
+        }),
+        map(delimited(call("scale"), tuple((mask_spec, comma, verify(mask_number, |&f| f > 0.0))), char(')')), |(mask, _, factor)| mask.scale(factor)),
+        map(delimited(call("rotate"), tuple((mask_spec, comma, mask_number, comma, mask_point)), char(')')), |(mask, _, degrees, _, center)| {
+            mask.rotate(degrees, center)
+        }),
+        map(delimited(call("tile"), tuple((mask_spec, comma, verify(mask_count, |&w| w > 0), comma, verify(mask_count, |&h| h > 0))), char(')')), |(mask, _, width, _, height)| {
+            mask.tile(width, height)
+        }),
+    ));
+    spaced(alt((shapes, pairs, transforms)))(i)
+}
+
+/// Cellular automaton rules in B/S notation, such as `B5678/S45678`.
+pub fn cave_rules(i: &str) -> IResult<&str, CaveRules> {
+    use nom::character::complete::{char, one_of};
+    use nom::multi::many0;
+    use nom::sequence::separated_pair;
+    let counts = |i| map(many0(one_of("012345678")), |digits: Vec<char>| {
+        let mut rule = [false; 9];
+        digits.into_iter().for_each(|d| rule[d as usize - '0' as usize] = true);
+        rule
+    })(i);
+    map(
+        separated_pair(preceded(char('B'), counts), char('/'), preceded(char('S'), counts)),
+        |(birth, survival)| CaveRules { birth, survival },
+    )(i)
+}
+
+/// A procedure written as `name(arguments)`, as [`Procedure`] is displayed.
+pub fn procedure_spec(i: &str) -> IResult<&str, Procedure> {
+    use nom::character::complete::{char, alpha1};
+    use nom::combinator::verify;
+    use nom::sequence::{delimited, terminated};
+    let comma = |i| char(',')(i);
+    let call = |name: &'static str| terminated(spaced(verify(alpha1, move |n: &str| n == name)), char('('));
+    let share = |i| verify(mask_number, |&f| (0.0..=1.0).contains(&f))(i);
+    spaced(alt((
+        map(
+            delimited(call("noise"), tuple((spaced(map_res(alpha1, str::parse)), comma, verify(mask_number, |&f| f > 0.0), comma, share)), char(')')),
+            |(kind, _, scale, _, threshold)| Procedure::Noise { kind, scale, threshold },
+        ),
+        map(delimited(call("cave"), tuple((share, comma, spaced(cave_rules), comma, mask_count)), char(')')), |(fill, _, rules, _, iterations)| {
+            Procedure::Cave { fill, rules, iterations }
+        }),
+        map(delimited(call("blob"), tuple((mask_count, comma, mask_count)), char(')')), |(walkers, _, steps)| Procedure::Blob { walkers, steps }),
+    )))(i)
+}
+
+/// One line of a vector mask file, `None` for a blank one. Anything after a `#` is skipped.
+pub fn path_command(i: &str) -> IResult<&str, Option<PathCommand>> {
+    use nom::bytes::complete::take_while;
+    use nom::character::complete::{alpha1, char, space0, space1};
+    use nom::combinator::{opt, value, verify};
+    use nom::sequence::terminated;
+    let keyword = |name: &'static str| terminated(verify(alpha1, move |n: &str| n == name), space0);
+    let number = |i| map(terminated(nom::number::complete::double, space0), |n| n as f32)(i);
+    let command = alt((
+        map(preceded(keyword("size"), pair(number, number)), |(width, height)| PathCommand::Size(width, height)),
+        map(preceded(keyword("fill"), alt((
+            value(tiny_skia::FillRule::Winding, terminated(tag("nonzero"), space0)),
+            value(tiny_skia::FillRule::EvenOdd, terminated(tag("evenodd"), space0)),
+        ))), PathCommand::Fill),
+        map(preceded(keyword("move"), pair(number, number)), |(x, y)| PathCommand::Move(x, y)),
+        map(preceded(keyword("line"), pair(number, number)), |(x, y)| PathCommand::Line(x, y)),
+        map(preceded(keyword("quad"), tuple((number, number, number, number))), |(x1, y1, x, y)| PathCommand::Quad(x1, y1, x, y)),
+        value(PathCommand::Close, keyword("close")),
+    ));
+    let comment = opt(pair(char('#'), take_while(|_| true)));
+    let (i, _) = opt(space1)(i)?;
+    terminated(opt(command), comment)(i)
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::thread_rng;
+
+    use crate::{masked_grid::{MaskedGrid, MarkedEndpoints}, grid::FlatSquareGrid, text_render::TextCharset};
+
+    use super::*;
+
+    #[test]
+    fn reads_shipped_maze_txt() {
+        let (g, _) = MaskedGrid::read_text_maze(std::fs::File::open("maze.txt").unwrap()).unwrap();
+        assert_eq!((g.width, g.height), (10, 10));
+        assert_eq!(g.total_cells(), 100);
+        let link_count: usize = g.pool.nodes.iter().map(|n| n.links.len()).sum();
+        assert_eq!(link_count, 2 * 99);
+        assert!(g.is_linked_at(0, 0, 0, 1));
+        assert!(!g.is_linked_at(0, 0, 1, 0));
+    }
+
+    #[test]
+    fn reads_flat_grid_text_print() {
+        let mut grid = FlatSquareGrid::new(7, 5);
+        grid.recursive_backtracker(&mut thread_rng());
+        let g = MaskedGrid::from_text(&grid.text_print()).unwrap();
+        for row in 0..5 {
+            for col in 0..6 {
+                assert_eq!(g.is_linked_at(row, col, row, col + 1), grid.is_linked_at(row, col, row, col + 1));
+            }
+        }
+    }
+
+    #[test]
+    fn masked_text_round_trip() {
+        let mut rng = thread_rng();
+        // Text can't tell apart trailing masked out rows and columns, so the mask touches every edge
+        let mut g = MaskedGrid::new(11, 8, Mask::rectangle(2, 0, 11, 6).union(Mask::rectangle(0, 3, 8, 2)).difference(Mask::rectangle(4, 5, 1, 1)));
+        g.hunt_and_kill(&mut rng);
+        for charset in [TextCharset::Ascii, TextCharset::Unicode] {
+            let read = MaskedGrid::from_text(&g.text_print(charset, |_| None)).unwrap();
+            assert!(g == read, "Round trip through {:?} text changed the maze", charset);
+        }
+        let start = g.get_id_at(4, 4).unwrap();
+        let read = MaskedGrid::from_text(&g.text_print_distances(TextCharset::Ascii, start)).unwrap();
+        assert!(g == read);
+    }
+
+    #[test]
+    fn border_openings_mark_the_ends() {
+        let (g, openings) = MaskedGrid::from_text_with_openings("+   +---+\n|       |\n+---+   +\n").unwrap();
+        assert_eq!(g.total_cells(), 2);
+        assert!(g.is_linked_at(0, 0, 0, 1));
+        assert_eq!((openings.start, openings.end), (g.get_id_at(0, 0), g.get_id_at(0, 1)));
+
+        // Masked out cells are open all along the edge, and stay out of the maze even when there are more of them
+        let text = "+---+       +\n|   |\n+   +\n|   |\n+---+       +\n";
+        let (g, openings) = MaskedGrid::from_text_with_openings(text).unwrap();
+        assert_eq!((g.width, g.height, g.total_cells()), (3, 2, 2));
+        assert_eq!(openings, MarkedEndpoints::default());
+    }
+
+    #[test]
+    fn spaced_walls() {
+        let text = "+ - - - + - - - +\n|               |\n+ - - - + - - - +\n";
+        let walls = text_maze(text).unwrap();
+        assert_eq!((walls.width, walls.height), (2, 1));
+        assert_eq!(walls.h_walls, vec![vec![true, true], vec![true, true]]);
+        assert_eq!(walls.v_walls, vec![vec![true, false, true]]);
+    }
+
+    #[test]
+    fn error_positions() {
+        let text = "+---+---+\r\n| x     |\r\n+---#---+\r\n";
+        assert_eq!(text_maze(text), Err(TextMazeError::new(3, 5, TextMazeErrorKind::UnexpectedCharacter('#'))));
+
+        let text = "+---+---+  x\n|   |   |\n+---+---+\n";
+        assert_eq!(text_maze(text), Err(TextMazeError::new(1, 12, TextMazeErrorKind::UnexpectedCharacter('x'))));
+
+        let text = "+---+\n|   |\n";
+        assert_eq!(text_maze(text), Err(TextMazeError::new(3, 1, TextMazeErrorKind::MissingBottomEdge)));
+
+        assert_eq!(text_maze("hello"), Err(TextMazeError::new(1, 1, TextMazeErrorKind::NoWalls)));
+    }
+
+    #[test]
+    fn job_files() {
+        let text = "# Release mazes\r\n[title]\nwidth = 40\n  height=30  \n\n[ solved title ]\ninput = out/title.maze # not a comment\n";
+        let sections = job_file(text).unwrap();
+        assert_eq!(sections, vec![
+            JobSection { name: "title".into(), line: 2, fields: vec![("width".into(), "40".into()), ("height".into(), "30".into())] },
+            JobSection { name: "solved title".into(), line: 6, fields: vec![("input".into(), "out/title.maze # not a comment".into())] },
+        ]);
+
+        assert_eq!(job_file("width = 3\n[a]"), Err(JobFileError { line: 1, kind: JobFileErrorKind::FieldOutsideJob }));
+        assert_eq!(job_file("[a]\nwidth 3"), Err(JobFileError { line: 2, kind: JobFileErrorKind::ExpectedHeaderOrField }));
+        assert_eq!(job_file("[a]\n[b"), Err(JobFileError { line: 2, kind: JobFileErrorKind::ExpectedHeaderOrField }));
+    }
 }
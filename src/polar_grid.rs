@@ -1,8 +1,8 @@
-use std::{f64::consts::PI, ops::Index, fmt::Display};
+use std::{f64::consts::PI, ops::Index, fmt::Display, io::{self, Write, BufWriter, Read, BufReader}};
 
-use tiny_skia::{Pixmap, Paint, Stroke, LineCap, LineJoin, PathBuilder, Transform, FillRule, Color};
+use tiny_skia::{Pixmap, Paint, PathBuilder, Transform, FillRule};
 
-use crate::{pool::{Pool, NodeId}, geometry::{CartesianPoint, PolarPoint}, dijkstra::{DijkstraPad, Distance}};
+use crate::{pool::{Pool, NodeId}, geometry::{CartesianPoint, PolarPoint}, dijkstra::{DijkstraPad, DistanceRemap}, masked_grid::GridReadError, maze_file::{MazeHeader, MazeMetadata, Topology, CellEncoding, CellLayout, BitWriter, BitReader}, compression::{CompressedWriter, DecompressedReader}, render_style::{RenderStyle, CorridorStyle, Spoke, draw_icon}, color_gradients::Gradient};
 
 
 
@@ -281,38 +281,156 @@ impl PolarGrid {
         }
     }
 
-    pub fn print_image(&self, radius: usize, padding: usize, paint_function: impl Fn(NodeId) -> Paint<'static>) -> Pixmap {
-        //For now only print the "cup" of rings 1 and greater
-        let mut pixmap = Pixmap::new(2 * (radius + padding) as u32, 2 * (radius + padding) as u32).unwrap();
-        let center = (radius + padding) as f32;
-        let black = {
-            let mut paint = Paint::default();
-            paint.set_color_rgba8(0,0,0, u8::MAX);
-            paint.anti_alias = true;
-            paint
-        };
-        let white = {
-            let mut paint = Paint::default();
-            paint.set_color_rgba8(u8::MAX, u8::MAX, u8::MAX, u8::MAX);
-            paint.anti_alias = true;
-            paint
-        };
+    pub fn topology(&self) -> Topology {
+        Topology::Polar { starting_branch_count: self.profile.0, ring_count: self.rings.len() }
+    }
 
-        let stroke = {
-            let mut stroke = Stroke::default();
-            stroke.width = 3.0;
-            stroke.line_cap = LineCap::Round;
-            stroke.line_join = LineJoin::Round;
-            stroke
-        };
+    /// Finds the position of a node in the grid.
+    pub fn position_of(&self, id: NodeId) -> Option<RingPosition> {
+        for (ring, nodes) in self.rings.iter().enumerate() {
+            if let Some(column) = nodes.iter().position(|&n| n == id) {
+                return Some(RingPosition { ring, column });
+            }
+        }
+        None
+    }
 
-        let thinner_stroke = {
-            let mut stroke = Stroke::default();
-            stroke.width = 1.5;
-            stroke.line_cap = LineCap::Round;
-            stroke.line_join = LineJoin::Round;
-            stroke
-        };
+    fn cw(b: u8) -> bool { (b & 0b01) == 0b01 }
+    fn down(b: u8) -> bool { (b & 0b10) == 0b10 }
+
+    /// Encodes the clockwise and downward links of a cell. Every link in the grid is one of these for exactly one of its cells.
+    pub fn cell_to_byte(&self, pos: RingPosition) -> u8 {
+        let cw: u8 = if self.pool.is_linked(self[pos], self[self.profile.take_step(pos, RingStep::CW).unwrap()]) {
+            0b01
+        } else { 0 };
+        let down: u8 = if self.is_floor(pos) { 0 } else { 0b10 };
+        cw | down
+    }
+
+    pub fn write_maze_with_metadata(&self, out: impl Write, metadata: &MazeMetadata, encoding: CellEncoding) -> Result<(), io::Error> {
+        let mut out = BufWriter::new(out);
+        let topology = self.topology();
+        MazeHeader::new(topology, encoding, metadata.clone()).write(&mut out)?;
+
+        let mut cells = CompressedWriter::new(&mut out, encoding.compression);
+        match encoding.layout {
+            CellLayout::Bytes => {
+                for ring in 1..self.rings.len() {
+                    for column in 0..self.rings[ring].len() {
+                        cells.write_all(&[self.cell_to_byte(RingPosition { ring, column })])?;
+                    }
+                }
+            },
+            CellLayout::Packed => {
+                let mut bits = BitWriter::new(&mut cells);
+                for ring in 1..self.rings.len() {
+                    for column in 0..self.rings[ring].len() {
+                        let b = self.cell_to_byte(RingPosition { ring, column });
+                        bits.write_bit(Self::cw(b))?;
+                        bits.write_bit(Self::down(b))?;
+                    }
+                }
+                bits.into_inner()?;
+            },
+        }
+        cells.finish()?;
+
+        out.flush()
+    }
+
+    /// Looks up the node at a position read from a maze file.
+    pub fn endpoint_id(&self, (ring, column): (usize, usize)) -> Result<NodeId, GridReadError> {
+        self.rings.get(ring).and_then(|r| r.get(column)).cloned().ok_or(GridReadError::EndpointOutOfMaze((ring, column)))
+    }
+
+    /// Reads the cell data following a maze file's header.
+    pub fn read_cells(starting_branch_count: usize, ring_count: usize, encoding: CellEncoding, input: impl Read) -> Result<Self, GridReadError> {
+        if starting_branch_count < 2 || ring_count < 2 {
+            return Err(GridReadError::InvalidTopology(Topology::Polar { starting_branch_count, ring_count }));
+        }
+        let mut grid = PolarGrid::new(starting_branch_count, ring_count);
+        let input = BufReader::new(DecompressedReader::new(BufReader::new(input), encoding.compression));
+
+        match encoding.layout {
+            CellLayout::Bytes => {
+                let mut node_bytes = input.bytes();
+                for ring in 1..ring_count {
+                    for column in 0..grid.rings[ring].len() {
+                        let b = match node_bytes.next() {
+                            Some(b) => b?,
+                            None => return Err(GridReadError::NotEnoughBytes),
+                        };
+                        grid.link_from_byte(RingPosition { ring, column }, b);
+                    }
+                }
+                if node_bytes.next().is_some() {
+                    return Err(GridReadError::TooManyBytes);
+                }
+            },
+            CellLayout::Packed => {
+                let mut bits = BitReader::new(input);
+                for ring in 1..ring_count {
+                    for column in 0..grid.rings[ring].len() {
+                        let cw = bits.read_bit()? as u8;
+                        let down = bits.read_bit()? as u8;
+                        grid.link_from_byte(RingPosition { ring, column }, cw | down << 1);
+                    }
+                }
+                bits.align();
+                bits.finish()?;
+            },
+        }
+
+        Ok(grid)
+    }
+
+    fn link_from_byte(&mut self, here: RingPosition, b: u8) {
+        if Self::cw(b) {
+            let there = self[self.profile.take_step(here, RingStep::CW).unwrap()];
+            self.pool.link_cells(self[here], there, true);
+        }
+        if Self::down(b) {
+            let there = self[self.profile.take_step(here, RingStep::Down).unwrap()];
+            self.pool.link_cells(self[here], there, true);
+        }
+    }
+
+    /// Dumps the maze as text, one line per ring from the center outwards.
+    ///
+    /// Each cell is shown as its counterclockwise wall (`|` if present) followed by its floor (`_` if walled off from the
+    /// ring below). Rings wrap around, so every line ends with the wall left of its first cell again.
+    pub fn text_print(&self) -> String {
+        let mut result = String::new();
+        for ring in 0..self.rings.len() {
+            result.push_str(&format!("{:>3}: ", ring));
+            if ring == 0 {
+                result.push_str("o\n");
+                continue;
+            }
+            for column in 0..self.rings[ring].len() {
+                let pos = RingPosition { ring, column };
+                result.push(if self.is_left_wall(pos) { '|' } else { ' ' });
+                result.push(if self.is_floor(pos) { '_' } else { ' ' });
+            }
+            result.push(if self.is_left_wall(RingPosition { ring, column: 0 }) { '|' } else { ' ' });
+            result.push('\n');
+        }
+        result
+    }
+
+    pub fn print_image(&self, radius: usize, style: &RenderStyle, paint_function: impl Fn(NodeId) -> Paint<'static>, icons: Vec<(NodeId, Pixmap)>) -> Pixmap {
+        if let Some(corridors) = &style.corridors {
+            let mut pixmap = self.print_corridors(radius, style, corridors, paint_function);
+            self.draw_icons(&mut pixmap, radius, style.padding, &icons);
+            return pixmap;
+        }
+        let padding = style.padding;
+        let mut pixmap = style.new_pixmap(2 * (radius + padding), 2 * (radius + padding));
+        let center = (radius + padding) as f32;
+        let ring_width = radius as f32 / self.rings.len() as f32;
+        let stroke = style.wall_stroke(ring_width);
+        // Cells are also outlined in their own color, to hide the seams between them
+        let seam_stroke = style.stroke(stroke.width / 2.0);
 
         let path = {
             let mut pb = PathBuilder::new();
@@ -391,17 +509,14 @@ impl PolarGrid {
 
                 }
             }
-            
-            pb.push_circle(0.0, 0.0, radius as f32);
 
-            pb.finish().unwrap()
+            pb.finish()
         };
 
-        // Paint the interior of all cells
-
+        // Paint the interior of all cells, starting with the center one
         pixmap.fill_path(
-            &PathBuilder::from_circle(0.0, 0.0, radius as f32).unwrap(),
-            &white,
+            &PathBuilder::from_circle(0.0, 0.0, ring_width).unwrap(),
+            &style.faded(paint_function(self.rings[0][0])),
             FillRule::EvenOdd,
             Transform::identity().pre_translate(center, center),
             None
@@ -426,16 +541,17 @@ impl PolarGrid {
                     pb.quad_to(tc.x as f32, tc.y as f32, tl.x as f32, tl.y as f32);
                     pb.finish().unwrap()
                 };
+                let paint = style.faded(paint_function(self.rings[ring][column]));
                 pixmap.stroke_path(
                     &cell,
-                    &paint_function(self.rings[ring][column]),
-                    &thinner_stroke,
+                    &paint,
+                    &seam_stroke,
                     Transform::identity().pre_translate(center, center),
                     None
                 );
                 pixmap.fill_path(
                     &cell,
-                    &paint_function(self.rings[ring][column]),
+                    &paint,
                     FillRule::EvenOdd,
                     Transform::identity().pre_translate(center, center),
                     None
@@ -444,35 +560,112 @@ impl PolarGrid {
         }
         
 
-        pixmap.stroke_path(&path, &black, &stroke, Transform::identity().pre_translate(center, center), None);
-        
+        let wall_paint = style.wall_paint();
+        if let Some(path) = path {
+            pixmap.stroke_path(&path, &wall_paint, &stroke, Transform::identity().pre_translate(center, center), None);
+        }
+        let border = PathBuilder::from_circle(0.0, 0.0, radius as f32).unwrap();
+        pixmap.stroke_path(&border, &wall_paint, &style.border_stroke(ring_width), Transform::identity().pre_translate(center, center), None);
+        self.draw_icons(&mut pixmap, radius, padding, &icons);
 
         pixmap
     }
 
-    pub fn print_image_distances(&self, radius: usize, padding: usize, start_node: NodeId, color_function: impl Fn(f64) -> Color) -> Pixmap {
-        let distances = DijkstraPad::new(&self.pool, start_node).perform();
-        let max_finite_distance = distances.pool.payloads().map(|d| {
-            match d {
-                Distance::Finite(dist) => *dist,
-                Distance::Infinite => 0,
+    /// Draws each icon over the middle of its cell, as large as the cell allows.
+    fn draw_icons(&self, pixmap: &mut Pixmap, radius: usize, padding: usize, icons: &[(NodeId, Pixmap)]) {
+        let center = (radius + padding) as f64;
+        let ring_width = radius as f64 / self.rings.len() as f64;
+        for (id, icon) in icons {
+            let Some(pos) = self.position_of(*id) else { continue };
+            let (cell_center, size) = if pos.ring == 0 {
+                (CartesianPoint { x: 0.0, y: 0.0 }, ring_width * 1.4)
+            } else {
+                let middle = (pos.ring as f64 + 0.5) * ring_width;
+                let arc_length = circumference(middle) / self.rings[pos.ring].len() as f64;
+                let angle = self.profile.six_point_arc(pos).bottom_center.theta;
+                (CartesianPoint::from(PolarPoint::new(middle, angle)), ring_width.min(arc_length) * 0.8)
+            };
+            draw_icon(pixmap, icon, ((center + cell_center.x) as f32, (center + cell_center.y) as f32), size as f32);
+        }
+    }
+
+    /// Draws the passages as strokes through the middle of every cell, curving along the rings between cells of a ring.
+    fn print_corridors(&self, radius: usize, style: &RenderStyle, corridors: &CorridorStyle, paint_function: impl Fn(NodeId) -> Paint<'static>) -> Pixmap {
+        let padding = style.padding;
+        let mut pixmap = style.new_pixmap(2 * (radius + padding), 2 * (radius + padding));
+        let center = (radius + padding) as f32;
+        let ring_width = radius as f64 / self.rings.len() as f64;
+        let stroke = style.stroke(corridors.width.resolve(ring_width as f32));
+        let corner_radius = corridors.corner_radius as f64 * ring_width;
+        // Corridors between rings cross over at the middle of the floor of the outer cell
+        let crossing = |outer: RingPosition| CartesianPoint::from(self.profile.six_point_arc(outer).bottom_center * ring_width);
+
+        for ring in 0..self.rings.len() {
+            for column in 0..self.rings[ring].len() {
+                let here = RingPosition { ring, column };
+                let linked = |there: RingPosition| self.pool.is_linked(self[here], self[there]);
+                let mut spokes = vec![];
+                let aboves = if ring + 1 == self.rings.len() {
+                    vec![]
+                } else {
+                    match self.profile.any_above(here) {
+                        AnyAbove::SplitCenter(aboves) => aboves,
+                        AnyAbove::Split(left, right) => vec![left, right],
+                        AnyAbove::Single(above) => vec![above],
+                    }
+                };
+                spokes.extend(aboves.into_iter().filter(|&above| linked(above)).map(|above| Spoke::straight(crossing(above))));
+
+                let cell_center = if ring == 0 {
+                    CartesianPoint { x: 0.0, y: 0.0 }
+                } else {
+                    let arc = self.profile.six_point_arc(here);
+                    let middle = (ring as f64 + 0.5) * ring_width;
+                    let center_angle = arc.bottom_center.theta;
+                    for (step, side_angle) in [(RingStep::CW, arc.bottom_right.theta), (RingStep::CCW, arc.bottom_left.theta)] {
+                        if linked(self.profile.take_step(here, step).unwrap()) {
+                            // The control point where the tangents at both ends of the arc meet
+                            let half_turn = (side_angle - center_angle) / 2.0;
+                            spokes.push(Spoke {
+                                end: CartesianPoint::from(PolarPoint::new(middle, side_angle)),
+                                via: Some(CartesianPoint::from(PolarPoint::new(middle / half_turn.cos(), center_angle + half_turn))),
+                            });
+                        }
+                    }
+                    if linked(self.profile.take_step(here, RingStep::Down).unwrap()) {
+                        spokes.push(Spoke::straight(crossing(here)));
+                    }
+                    CartesianPoint::from(PolarPoint::new(middle, center_angle))
+                };
+
+                let mut pb = PathBuilder::new();
+                corridors.push_cell(&mut pb, cell_center, &spokes, corner_radius);
+                if let Some(path) = pb.finish() {
+                    let paint = style.faded(paint_function(self[here]));
+                    pixmap.stroke_path(&path, &paint, &stroke, Transform::identity().pre_translate(center, center), None);
+                }
             }
-        }).max().unwrap_or(0) as f64;
+        }
+
+        pixmap
+    }
+
+    pub fn print_image_distances(&self, radius: usize, style: &RenderStyle, start_node: NodeId, gradient: &Gradient, remap: DistanceRemap) -> Pixmap {
+        let distances = DijkstraPad::new(&self.pool, start_node).perform();
+        let position = distances.normalized(remap);
 
-        if max_finite_distance == 0.0 {
-            self.print_image(radius, padding, |_| {
+        if distances.max_finite() == 0 {
+            self.print_image(radius, style, |_| {
                 let mut p = Paint::default();
                 p.set_color_rgba8(u8::MAX, u8::MAX, u8::MAX, u8::MAX);
                 p
-            })
+            }, vec![])
         } else {
-            self.print_image(radius, padding, |node_id| {
-                let dist = distances.pool.get(node_id).payload.as_finite().unwrap_or(0) as f64;
-                let normalized_distance = dist / max_finite_distance;
+            self.print_image(radius, style, |node_id| {
                 let mut p = Paint::default();
-                p.set_color(color_function(normalized_distance));
+                p.set_color(gradient.color_at(position(node_id)));
                 p
-            })
+            }, vec![])
         }
     }
 }
@@ -2,12 +2,12 @@ use std::{collections::HashSet, ops::{Index, IndexMut}, fmt::Display};
 
 use indicatif::{ProgressBar, ProgressIterator, ProgressDrawTarget};
 use partitions::{PartitionVec, partition_vec};
-use rand::{rngs::ThreadRng, Rng};
+use rand::Rng;
 
-use crate::{sample_uniform, dijkstra::DijkstraPad};
+use crate::{sample_uniform, dijkstra::DijkstraPad, grid::walker::Walker};
 
 
-#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Hash)]
 pub struct NodeId(usize);
 
 impl Display for NodeId {
@@ -16,6 +16,13 @@ impl Display for NodeId {
     }
 }
 
+impl NodeId {
+    /// The position of the node in its pool, as used by [`Pool::node_id`].
+    pub fn index(self) -> usize {
+        self.0
+    }
+}
+
 impl PartialEq<usize> for NodeId {
     fn eq(&self, other: &usize) -> bool {
         self.0 == *other
@@ -73,6 +80,39 @@ pub enum FrontierSearchResult {
     NoFrontier,
 }
 
+/// A step taken by a generator, reported to a [`CarveObserver`] as the maze is carved.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CarveEvent<'a> {
+    /// The generator moved to a node.
+    Visit(NodeId),
+    /// Two nodes were linked, in both directions.
+    Link(NodeId, NodeId),
+    /// A link between two nodes was removed, in both directions.
+    LinkRemoved(NodeId, NodeId),
+    /// A random walk moved its head onto a node. `path` is the walk so far, loop erased, from its start to its head.
+    WalkerStep { head: NodeId, path: &'a [NodeId] },
+    /// A random walk stepped back onto itself, and the loop it closed was erased. `erased` is the tail of the walk that
+    /// was dropped, and `head` the node the walk continues from. Always followed by the matching [`CarveEvent::WalkerStep`].
+    LoopErased { head: NodeId, erased: &'a [NodeId] },
+}
+
+/// Watches a generator carve a maze, such as to animate or trace it.
+///
+/// `()` observes nothing, for when the steps are not needed.
+pub trait CarveObserver {
+    fn observe(&mut self, event: CarveEvent<'_>);
+}
+
+impl CarveObserver for () {
+    fn observe(&mut self, _: CarveEvent<'_>) {}
+}
+
+impl<F: FnMut(CarveEvent<'_>)> CarveObserver for F {
+    fn observe(&mut self, event: CarveEvent<'_>) {
+        self(event)
+    }
+}
+
 impl<T> Node<T> {
     pub fn new(id: NodeId, construct: impl FnOnce(NodeId) -> T) -> Self {
         Node {
@@ -107,8 +147,13 @@ impl<T> Pool<T> {
         (0..self.nodes.len()).map(|i| NodeId(i))
     }
 
+    /// Returns the id of the node at `index`, if the pool has that many nodes.
+    pub fn node_id(&self, index: usize) -> Option<NodeId> {
+        (index < self.nodes.len()).then_some(NodeId(index))
+    }
+
     /// Returns a node selected uniformly over all nodes in the pool.
-    pub fn get_random_node_id(&self, rng: &mut ThreadRng) -> NodeId {
+    pub fn get_random_node_id(&self, rng: &mut impl Rng) -> NodeId {
         sample_uniform(&self.nodes, rng).id
     }
 
@@ -126,26 +171,30 @@ impl<T> Pool<T> {
         new_id
     }
 
-    pub fn aldous_broder(&mut self, rng: &mut ThreadRng) {
+    pub fn aldous_broder(&mut self, rng: &mut impl Rng, observer: &mut impl CarveObserver) {
         let mut cell = self.get_random_node_id(rng);
+        observer.observe(CarveEvent::Visit(cell));
         let mut unvisited_count = self.nodes.len() - 1;
 
         while unvisited_count > 0 {
-            let neighbors: Vec<NodeId> = self.neighborhood_of(cell).into_iter().collect();
+            let neighbors = sorted_ids(self.neighborhood_of(cell));
             let random_neighbor = neighbors[rng.gen_range(0..neighbors.len())];
+            observer.observe(CarveEvent::Visit(random_neighbor));
             if self.get(random_neighbor).links.is_empty() {
                 self.link_cells(cell, random_neighbor, true);
+                observer.observe(CarveEvent::Link(cell, random_neighbor));
                 unvisited_count -= 1;
             }
             cell = random_neighbor;
         }
     }
 
-    pub fn hunt_and_kill(&mut self, rng: &mut ThreadRng) {
+    pub fn hunt_and_kill(&mut self, rng: &mut impl Rng, observer: &mut impl CarveObserver) {
         let mut visited: HashSet<NodeId> = HashSet::new();
         if let Some(first) = self.nodes.first() {
             // If there are any nodes at all, start off with the first one
             visited.insert(first.id);
+            observer.observe(CarveEvent::Visit(first.id));
         } else {
             // Otherwise, we're done and we leave
             return;
@@ -153,28 +202,83 @@ impl<T> Pool<T> {
 
         while let FrontierSearchResult::Found{ unvisited: mut current_cell, visited: visited_root}
         = self.scan_frontier(&visited) {
+            observer.observe(CarveEvent::Visit(current_cell));
             self.link_cells(current_cell, visited_root, true);
+            observer.observe(CarveEvent::Link(current_cell, visited_root));
             visited.insert(current_cell);
-            let mut walls: Vec<NodeId> = self.walls_of(current_cell).into_iter().filter(|n| {
+            let mut walls: Vec<NodeId> = sorted_ids(self.walls_of(current_cell)).into_iter().filter(|n| {
                 !visited.contains(n)
             }).collect();
 
             while !walls.is_empty() {
                 let next_cell = *sample_uniform(&walls, rng);
+                observer.observe(CarveEvent::Visit(next_cell));
                 self.link_cells(current_cell, next_cell, true);
+                observer.observe(CarveEvent::Link(current_cell, next_cell));
                 current_cell = next_cell;
                 visited.insert(current_cell);
-                walls = self.walls_of(current_cell).into_iter().filter(|n| !visited.contains(n)).collect();
+                walls = sorted_ids(self.walls_of(current_cell)).into_iter().filter(|n| !visited.contains(n)).collect();
             }
         }
     }
 
+    pub fn recursive_backtracker(&mut self, rng: &mut impl Rng, observer: &mut impl CarveObserver) {
+        let (mut visited, mut stack) = match self.nodes.first() {
+            Some(first) => (HashSet::from([first.id]), vec![first.id]),
+            None => return,
+        };
+        observer.observe(CarveEvent::Visit(stack[0]));
+        while let Some(&top_of_stack) = stack.last() {
+            let viable_cells = sorted_ids(self.unvisited_neighborhood_of(&visited, top_of_stack));
+            if viable_cells.is_empty() {
+                stack.pop();
+                if let Some(&back) = stack.last() {
+                    observer.observe(CarveEvent::Visit(back));
+                }
+            } else {
+                let next_cell = *sample_uniform(&viable_cells, rng);
+                observer.observe(CarveEvent::Visit(next_cell));
+                self.link_cells(top_of_stack, next_cell, true);
+                observer.observe(CarveEvent::Link(top_of_stack, next_cell));
+                stack.push(next_cell);
+                visited.insert(next_cell);
+            }
+        }
+    }
+
+    /// Wilson's algorithm, carving loop-erased random walks into the growing maze until every node is in it.
+    pub fn wilson(&mut self, rng: &mut impl Rng, observer: &mut impl CarveObserver) {
+        let mut starts_list = self.iter_node_ids().collect::<Vec<NodeId>>();
+        let mut visited_set: HashSet<NodeId> = HashSet::new();
+        if let Some(needle) = starts_list.pop() {
+            visited_set.insert(needle);
+            observer.observe(CarveEvent::Visit(needle));
+        }
+        while let Some(start) = starts_list.pop() {
+            if visited_set.contains(&start) {
+                continue;
+            }
+            observer.observe(CarveEvent::Visit(start));
+            let mut path = Walker::new(start);
+            path.loop_erased_walk_into_haystack(self, &visited_set, rng, observer);
+            path.carve_path(self, observer);
+            visited_set.extend(path.total_path());
+        }
+    }
+
+    /// Removes every link, leaving only the adjacencies.
+    pub fn clear_links(&mut self) {
+        for node in &mut self.nodes {
+            node.links.clear();
+        }
+    }
+
     /// Finds a node in the pool adjacent to nodes in the `visited` set. The node itself will not be in `visited`.
     pub fn scan_frontier(&self, visited: &HashSet<NodeId>) -> FrontierSearchResult {
         for node in self.nodes.iter().filter(|n| {
             !visited.contains(&n.id)
         }) {
-            for wall in self.walls_of(node.id) {
+            for wall in sorted_ids(self.walls_of(node.id)) {
                 if visited.contains(&wall) {
                     return FrontierSearchResult::Found { unvisited: node.id, visited: wall };
                 }
@@ -184,20 +288,114 @@ impl<T> Pool<T> {
         return FrontierSearchResult::NoFrontier;
     }
 
+    /// Finds two nodes far apart by passages, both in the largest part of the pool.
     pub fn furthest_pair(&self) -> Option<(NodeId, NodeId)> {
-        let arbitrary_id = self.get_arbitrary_node_id();
-        let distances_from_arbitrary = DijkstraPad::new(self, arbitrary_id).perform();
-        let furthest_from_arbitrary = distances_from_arbitrary.pool.nodes.into_iter().max_by_key(|n| {
-            n.payload.as_finite().unwrap_or(0)
-        })?.id;
-        let distances_from_furthest = DijkstraPad::new(self, furthest_from_arbitrary).perform();
-        let furthest_from_furthest = distances_from_furthest.pool.nodes.into_iter().max_by_key(|n| {
-            n.payload.as_finite().unwrap_or(0)
-        })?.id;
+        let parts = self.parts();
+        let largest = parts.iter().rev().max_by_key(|part| part.len())?;
+        let furthest_from_arbitrary = self.furthest_from(largest[0]);
+        let furthest_from_furthest = self.furthest_from(furthest_from_arbitrary);
 
         Some((furthest_from_arbitrary, furthest_from_furthest))
     }
 
+    /// Finds the node the most passages away from `id`.
+    pub fn furthest_from(&self, id: NodeId) -> NodeId {
+        let distances = DijkstraPad::new(self, id).perform();
+        distances.pool.nodes.into_iter().max_by_key(|n| n.payload.as_finite().unwrap_or(0)).map_or(id, |n| n.id)
+    }
+
+    /// Groups the nodes into the parts of the adjacency graph, which no adjacencies join to each other. Each part is in
+    /// order of id, and the parts are in order of their first node.
+    pub fn parts(&self) -> Vec<Vec<NodeId>> {
+        let mut seen = vec![false; self.nodes.len()];
+        let mut parts = vec![];
+        for start in self.iter_node_ids() {
+            if seen[start.0] {
+                continue;
+            }
+            seen[start.0] = true;
+            let mut part = vec![];
+            let mut stack = vec![start];
+            while let Some(id) = stack.pop() {
+                part.push(id);
+                for &neighbor in &self[id].adjacencies {
+                    if !seen[neighbor.0] {
+                        seen[neighbor.0] = true;
+                        stack.push(neighbor);
+                    }
+                }
+            }
+            part.sort();
+            parts.push(part);
+        }
+        parts
+    }
+
+    /// Removes adjacencies so that every route from the first stage of a route to its last passes through each stage in
+    /// between, and through nothing else. `stage` numbers the nodes on the route, and leaves the rest out.
+    ///
+    /// Stages are only left adjacent to the stages next to them. Each part of the nodes off the route keeps a single
+    /// adjacency, at random, to a node on it, so that a route leaving it cannot come back anywhere else.
+    pub fn confine_to_route(&mut self, stage: impl Fn(NodeId) -> Option<usize>, rng: &mut impl Rng) {
+        let ids: Vec<NodeId> = self.iter_node_ids().collect();
+        for &here in &ids {
+            let Some(here_stage) = stage(here) else { continue };
+            for there in sorted_ids(self.neighborhood_of(here)) {
+                if stage(there).is_some_and(|there_stage| there_stage.abs_diff(here_stage) > 1) {
+                    self.make_non_adjacent(here, there, true);
+                }
+            }
+        }
+
+        let mut seen = vec![false; self.nodes.len()];
+        for &start in &ids {
+            if seen[start.0] || stage(start).is_some() {
+                continue;
+            }
+            seen[start.0] = true;
+            // Adjacencies from the part onto the route
+            let mut doors = vec![];
+            let mut stack = vec![start];
+            while let Some(id) = stack.pop() {
+                for neighbor in sorted_ids(self.neighborhood_of(id)) {
+                    if stage(neighbor).is_some() {
+                        doors.push((id, neighbor));
+                    } else if !seen[neighbor.0] {
+                        seen[neighbor.0] = true;
+                        stack.push(neighbor);
+                    }
+                }
+            }
+            if doors.is_empty() {
+                continue;
+            }
+            let kept = *sample_uniform(&doors, rng);
+            for (off, on) in doors {
+                if (off, on) != kept {
+                    self.make_non_adjacent(off, on, true);
+                }
+            }
+        }
+    }
+
+    /// Copies the adjacencies among `ids` into a pool of their own, where the node `ids[i]` becomes node `i`. Links are
+    /// not copied.
+    pub fn sub_pool(&self, ids: &[NodeId]) -> Pool<()> {
+        let mut renumbered = vec![None; self.nodes.len()];
+        let mut sub_pool = Pool::new();
+        for &id in ids {
+            renumbered[id.0] = Some(sub_pool.new_node(|_| ()));
+        }
+        for &id in ids {
+            for neighbor in sorted_ids(self.neighborhood_of(id)) {
+                if let (Some(here), Some(there)) = (renumbered[id.0], renumbered[neighbor.0]) {
+                    sub_pool.make_adjacent(here, there, false);
+                }
+            }
+        }
+        sub_pool
+    }
+
     /// Connects all nodes according to all adjacencies present.
     pub fn debug_connect_all(&mut self) where T: Clone {
         for n in self.nodes.clone() {
@@ -246,6 +444,13 @@ impl<T> Pool<T> {
         }
     }
 
+    pub fn make_non_adjacent(&mut self, here: NodeId, there: NodeId, bidirectional: bool) {
+        self[here].adjacencies.remove(&there);
+        if bidirectional {
+            self[there].adjacencies.remove(&here);
+        }
+    }
+
     pub fn unlink_cells(&mut self, here: NodeId, there: NodeId, bidirectional: bool) {
         self[here].links.remove(&there);
         if bidirectional {
@@ -304,8 +509,17 @@ impl<T> Pool<T> {
     }
 }
 
+/// Sorts a set of node ids, so that random choices among them depend only on the random number generator.
+pub fn sorted_ids(ids: HashSet<NodeId>) -> Vec<NodeId> {
+    let mut ids: Vec<NodeId> = ids.into_iter().collect();
+    ids.sort();
+    ids
+}
+
 #[cfg(test)]
 mod tests {
+    use rand::{rngs::StdRng, SeedableRng};
+
     use super::*;
 
     #[test]
@@ -325,4 +539,36 @@ mod tests {
         
         assert!(pool.is_adjacently_connected());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn seeded_generation_is_reproducible() {
+        let square = || {
+            let mut pool: Pool<()> = Pool::new();
+            let ids: Vec<NodeId> = (0..25).map(|_| pool.new_node(|_| ())).collect();
+            for i in 0..25 {
+                if i % 5 < 4 {
+                    pool.make_adjacent(ids[i], ids[i + 1], true);
+                }
+                if i < 20 {
+                    pool.make_adjacent(ids[i], ids[i + 5], true);
+                }
+            }
+            pool
+        };
+        let generators: [fn(&mut Pool<()>, &mut StdRng); 4] = [
+            |p, r| p.aldous_broder(r, &mut ()),
+            |p, r| p.hunt_and_kill(r, &mut ()),
+            |p, r| p.recursive_backtracker(r, &mut ()),
+            |p, r| p.wilson(r, &mut ()),
+        ];
+        for generate in generators {
+            let mut first = square();
+            let mut second = square();
+            generate(&mut first, &mut StdRng::seed_from_u64(7));
+            generate(&mut second, &mut StdRng::seed_from_u64(7));
+            assert_eq!(first, second);
+            // A perfect maze over 25 cells has 24 passages
+            assert_eq!(first.nodes.iter().map(|n| n.links.len()).sum::<usize>(), 48);
+        }
+    }
+}
@@ -0,0 +1,252 @@
+use std::{fmt::Display, str::FromStr, f64::consts::{PI, FRAC_1_SQRT_2}};
+
+use rand::Rng;
+
+use crate::{mask::Mask, masked_grid::{MaskedGrid, DisconnectedMask}, easing::Easing, parsers};
+
+
+
+/// The kind of noise a [`Procedure::Noise`] mask is cut from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum NoiseKind {
+    /// Random values at the corners of a lattice, smoothly blended between them.
+    Value,
+    /// Random slopes at the corners of a lattice, smoothly blended between them. Blobs are rounder than value noise's.
+    #[default]
+    Perlin,
+}
+
+impl NoiseKind {
+    pub const ALL: [NoiseKind; 2] = [NoiseKind::Value, NoiseKind::Perlin];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            NoiseKind::Value => "value",
+            NoiseKind::Perlin => "perlin",
+        }
+    }
+}
+
+impl FromStr for NoiseKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        NoiseKind::ALL.into_iter().find(|kind| kind.name() == s).ok_or_else(|| {
+            let names: Vec<&str> = NoiseKind::ALL.iter().map(|kind| kind.name()).collect();
+            format!("unknown noise `{}`, expected one of: {}", s, names.join(", "))
+        })
+    }
+}
+
+/// The rules of a cellular automaton over the cells of a mask, in B/S notation such as `B5678/S45678`: a missing cell
+/// with a birth count of the eight around it present appears, and a present cell with a survival count stays.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CaveRules {
+    pub birth: [bool; 9],
+    pub survival: [bool; 9],
+}
+
+impl Default for CaveRules {
+    /// `B5678/S45678`, which smooths noise into caves.
+    fn default() -> Self {
+        CaveRules { birth: [false, false, false, false, false, true, true, true, true], survival: [false, false, false, false, true, true, true, true, true] }
+    }
+}
+
+impl Display for CaveRules {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let counts = |rule: &[bool; 9]| (0..9).filter(|&n| rule[n]).map(|n| n.to_string()).collect::<String>();
+        write!(f, "B{}/S{}", counts(&self.birth), counts(&self.survival))
+    }
+}
+
+impl FromStr for CaveRules {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match parsers::cave_rules(s) {
+            Ok(("", rules)) => Ok(rules),
+            _ => Err(format!("`{}` is not a set of rules, such as `B5678/S45678`", s)),
+        }
+    }
+}
+
+/// A way of growing a mask at random. Every procedure keeps only the largest part of what it grows, so that the mask
+/// can be handed to [`MaskedGrid::new`].
+#[derive(Debug, Clone, PartialEq)]
+pub enum Procedure {
+    /// The cells where noise, with features about `scale` cells across, rises above `threshold`, from 0 to 1.
+    Noise {
+        kind: NoiseKind,
+        scale: f64,
+        threshold: f64,
+    },
+    /// The cells left after `iterations` steps of a cellular automaton, starting with a `fill` share of the cells.
+    Cave {
+        fill: f64,
+        rules: CaveRules,
+        iterations: usize,
+    },
+    /// The cells visited by `walkers` random walks of `steps` steps, each starting from the middle of the grid.
+    Blob {
+        walkers: usize,
+        steps: usize,
+    },
+}
+
+impl Procedure {
+    /// Grows a mask over `height` rows of `width` cells. The mask is empty if nothing grew.
+    pub fn generate(&self, width: usize, height: usize, rng: &mut impl Rng) -> Mask {
+        let cells = match self {
+            Procedure::Noise { kind, scale, threshold } => {
+                let noise = Noise::new(*kind, width as f64 / scale, height as f64 / scale, rng);
+                Mask::from_fn(width, height, |row, col| {
+                    noise.at((col as f64 + 0.5) / scale, (row as f64 + 0.5) / scale) > *threshold
+                })
+            },
+            Procedure::Cave { fill, rules, iterations } => {
+                let mut cells: Vec<bool> = (0..width * height).map(|_| rng.gen_bool(fill.clamp(0.0, 1.0))).collect();
+                for _ in 0..*iterations {
+                    cells = cave_step(&cells, width, height, rules);
+                }
+                Mask::Cells { width, height, cells }
+            },
+            Procedure::Blob { walkers, steps } => {
+                let mut cells = vec![false; width * height];
+                for _ in 0..*walkers {
+                    if cells.is_empty() {
+                        break;
+                    }
+                    let (mut row, mut col) = (height / 2, width / 2);
+                    cells[row * width + col] = true;
+                    for _ in 0..*steps {
+                        match rng.gen_range(0..4) {
+                            0 => row = row.saturating_sub(1),
+                            1 => row = (row + 1).min(height - 1),
+                            2 => col = col.saturating_sub(1),
+                            _ => col = (col + 1).min(width - 1),
+                        }
+                        cells[row * width + col] = true;
+                    }
+                }
+                Mask::Cells { width, height, cells }
+            },
+        };
+        let largest = MaskedGrid::with_policy(width, height, cells, DisconnectedMask::KeepLargest).expect("Keeping the largest part joins the mask");
+        Mask::from_fn(width, height, |row, col| largest.get_id_at(row, col).is_some())
+    }
+}
+
+/// Steps a cellular automaton once, cells beyond the edges counting as missing.
+fn cave_step(cells: &[bool], width: usize, height: usize, rules: &CaveRules) -> Vec<bool> {
+    let present = |row: isize, col: isize| {
+        (0..height as isize).contains(&row) && (0..width as isize).contains(&col) && cells[row as usize * width + col as usize]
+    };
+    (0..height * width).map(|i| {
+        let (row, col) = ((i / width) as isize, (i % width) as isize);
+        let around = (-1..=1).flat_map(|dr| (-1..=1).map(move |dc| (dr, dc)))
+            .filter(|&(dr, dc)| (dr, dc) != (0, 0) && present(row + dr, col + dc))
+            .count();
+        if cells[i] { rules.survival[around] } else { rules.birth[around] }
+    }).collect()
+}
+
+/// Noise over a lattice of unit squares, from 0 to 1.
+struct Noise {
+    kind: NoiseKind,
+    columns: usize,
+    /// A value, or the angle of a slope, at each corner of the lattice.
+    corners: Vec<f64>,
+}
+
+impl Noise {
+    /// Rolls the corners of a lattice covering `width` by `height` units.
+    fn new(kind: NoiseKind, width: f64, height: f64, rng: &mut impl Rng) -> Self {
+        let columns = width.max(0.0).ceil() as usize + 2;
+        let rows = height.max(0.0).ceil() as usize + 2;
+        let corners = (0..rows * columns).map(|_| match kind {
+            NoiseKind::Value => rng.gen::<f64>(),
+            NoiseKind::Perlin => rng.gen_range(0.0..2.0 * PI),
+        }).collect();
+        Noise { kind, columns, corners }
+    }
+
+    fn at(&self, x: f64, y: f64) -> f64 {
+        let (left, top) = (x.floor() as usize, y.floor() as usize);
+        let (dx, dy) = (x - left as f64, y - top as f64);
+        let corner = |right: usize, below: usize| {
+            let value = self.corners[(top + below) * self.columns + left + right];
+            match self.kind {
+                NoiseKind::Value => value,
+                NoiseKind::Perlin => value.cos() * (dx - right as f64) + value.sin() * (dy - below as f64),
+            }
+        };
+        let lerp = |a: f64, b: f64, t: f64| a + (b - a) * Easing::Smoothstep.apply(t);
+        let blended = lerp(lerp(corner(0, 0), corner(1, 0), dx), lerp(corner(0, 1), corner(1, 1), dx), dy);
+        match self.kind {
+            NoiseKind::Value => blended,
+            // Perlin noise lies within ±√½
+            NoiseKind::Perlin => 0.5 + blended * FRAC_1_SQRT_2,
+        }
+    }
+}
+
+impl Display for Procedure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Procedure::Noise { kind, scale, threshold } => write!(f, "noise({},{},{})", kind.name(), scale, threshold),
+            Procedure::Cave { fill, rules, iterations } => write!(f, "cave({},{},{})", fill, rules, iterations),
+            Procedure::Blob { walkers, steps } => write!(f, "blob({},{})", walkers, steps),
+        }
+    }
+}
+
+impl FromStr for Procedure {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match parsers::procedure_spec(s) {
+            Ok(("", procedure)) => Ok(procedure),
+            Ok((rest, _)) => Err(format!("unexpected `{}` after the procedure", rest)),
+            Err(_) => Err(format!("`{}` is not a procedure, such as `noise(perlin,8,0.5)`, `cave(0.55,B5678/S45678,5)` or `blob(4,800)`", s)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use super::*;
+
+    #[test]
+    fn grown_masks_are_joined_and_repeat() {
+        let procedures: [Procedure; 4] = [
+            "noise(perlin,6,0.5)".parse().unwrap(),
+            "noise(value,4,0.45)".parse().unwrap(),
+            "cave(0.55,B5678/S45678,4)".parse().unwrap(),
+            "blob(3,300)".parse().unwrap(),
+        ];
+        for procedure in procedures {
+            let mask = procedure.generate(40, 30, &mut StdRng::seed_from_u64(5));
+            let grid = MaskedGrid::new(40, 30, mask.clone());
+            assert!(grid.total_cells() > 40, "{}", procedure);
+            assert!(grid.pool.is_adjacently_connected(), "{}", procedure);
+            assert_eq!(procedure.generate(40, 30, &mut StdRng::seed_from_u64(5)), mask, "{}", procedure);
+            assert_ne!(procedure.generate(40, 30, &mut StdRng::seed_from_u64(6)), mask, "{}", procedure);
+            assert_eq!(procedure.to_string().parse::<Procedure>().unwrap(), procedure);
+        }
+        let nothing = Procedure::Noise { kind: NoiseKind::Perlin, scale: 5.0, threshold: 1.0 };
+        assert_eq!(MaskedGrid::with_parts(10, 10, nothing.generate(10, 10, &mut StdRng::seed_from_u64(1))).total_cells(), 0);
+    }
+
+    #[test]
+    fn cave_rules() {
+        assert_eq!(CaveRules::default().to_string(), "B5678/S45678");
+        let life: CaveRules = "B3/S23".parse().unwrap();
+        // A blinker turns on its side
+        let blinker = [false, false, false, true, true, true, false, false, false];
+        assert_eq!(cave_step(&blinker, 3, 3, &life), [false, true, false, false, true, false, false, true, false]);
+        assert!("B9/S1".parse::<CaveRules>().is_err());
+    }
+}
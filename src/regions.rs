@@ -0,0 +1,185 @@
+use std::collections::{BTreeMap, VecDeque};
+
+use rand::Rng;
+use tiny_skia::Pixmap;
+
+use crate::{mask::Mask, masked_grid::MaskedGrid, maze::Algorithm, pool::{Pool, NodeId, CarveObserver, CarveEvent}};
+
+
+
+/// The cells of a grid grouped into regions, each a joined group of cells sharing a label, such as a flat color of an
+/// image. Regions are numbered from 0, in the order their first cells come row by row.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RegionMap {
+    width: usize,
+    height: usize,
+    regions: Vec<Option<usize>>,
+    count: usize,
+}
+
+impl RegionMap {
+    /// Groups `height` rows of `width` cells by `label`. Cells without a label are left out of every region, and of the
+    /// maze.
+    pub fn from_labels<L: PartialEq>(width: usize, height: usize, label: impl Fn(usize, usize) -> Option<L>) -> Self {
+        let labels: Vec<Option<L>> = (0..height).flat_map(|row| (0..width).map(move |col| (row, col))).map(|(row, col)| label(row, col)).collect();
+        let mut regions = vec![None; width * height];
+        let mut count = 0;
+        for start in 0..labels.len() {
+            if labels[start].is_none() || regions[start].is_some() {
+                continue;
+            }
+            regions[start] = Some(count);
+            let mut queue = VecDeque::from([start]);
+            while let Some(i) = queue.pop_front() {
+                let (row, col) = (i / width, i % width);
+                let neighbors = [
+                    row.checked_sub(1).map(|row| row * width + col),
+                    (row + 1 < height).then(|| i + width),
+                    col.checked_sub(1).map(|_| i - 1),
+                    (col + 1 < width).then(|| i + 1),
+                ];
+                for neighbor in neighbors.into_iter().flatten() {
+                    if regions[neighbor].is_none() && labels[neighbor].is_some() && labels[neighbor] == labels[i] {
+                        regions[neighbor] = Some(count);
+                        queue.push_back(neighbor);
+                    }
+                }
+            }
+            count += 1;
+        }
+        RegionMap { width, height, regions, count }
+    }
+
+    /// Groups the pixels of an image by color, one cell to a pixel. Fully transparent pixels are left out.
+    pub fn from_pixmap(pixmap: &Pixmap) -> Self {
+        RegionMap::from_labels(pixmap.width() as usize, pixmap.height() as usize, |row, col| {
+            let pixel = pixmap.pixel(col as u32, row as u32).unwrap();
+            (pixel.alpha() > 0).then(|| (pixel.red(), pixel.green(), pixel.blue(), pixel.alpha()))
+        })
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// Number of regions.
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    /// The region of the cell at `row` and `col`, if it is in one.
+    pub fn region_at(&self, row: usize, col: usize) -> Option<usize> {
+        if row >= self.height || col >= self.width {
+            return None;
+        }
+        self.regions[row * self.width + col]
+    }
+
+    /// The cells in any region.
+    pub fn mask(&self) -> Mask {
+        Mask::from_fn(self.width, self.height, |row, col| self.region_at(row, col).is_some())
+    }
+
+    /// Carves `grid`, built over [`RegionMap::mask`], into a maze of rooms: the regions are joined along a spanning tree
+    /// of the regions that touch, carved by `algo` over the regions, and every region is then carved into a maze of its
+    /// own. Two regions joined by the tree are opened to each other through exactly one door, at random along the side
+    /// they share, and regions not joined by the tree never are. Returns the doors, each going from the region numbered
+    /// lower, in order of the regions they join.
+    pub fn carve(&self, grid: &mut MaskedGrid, algo: Algorithm, rng: &mut impl Rng, observer: &mut impl CarveObserver) -> Vec<(NodeId, NodeId)> {
+        // Every pair of neighboring cells in different regions, row by row, grouped by the regions they are in
+        let mut sides: BTreeMap<(usize, usize), Vec<(NodeId, NodeId)>> = BTreeMap::new();
+        for row in 0..self.height {
+            for col in 0..self.width {
+                let (Some(here), Some(region)) = (grid.get_id_at(row, col), self.region_at(row, col)) else {
+                    continue;
+                };
+                for (next_row, next_col) in [(row + 1, col), (row, col + 1)] {
+                    let (Some(there), Some(next_region)) = (grid.get_id_at(next_row, next_col), self.region_at(next_row, next_col)) else {
+                        continue;
+                    };
+                    match region.cmp(&next_region) {
+                        std::cmp::Ordering::Less => sides.entry((region, next_region)).or_default().push((here, there)),
+                        std::cmp::Ordering::Greater => sides.entry((next_region, region)).or_default().push((there, here)),
+                        std::cmp::Ordering::Equal => {},
+                    }
+                }
+            }
+        }
+
+        let mut tree: Pool<()> = Pool::new();
+        for _ in 0..self.count {
+            tree.new_node(|_| ());
+        }
+        let region_ids: Vec<NodeId> = tree.iter_node_ids().collect();
+        for &(a, b) in sides.keys() {
+            tree.make_adjacent(region_ids[a], region_ids[b], true);
+        }
+        if self.count > 1 {
+            algo.generate(&mut tree, rng, &mut ());
+        }
+
+        // Regions are carved separately, as parts of their own, by setting aside the adjacencies between them
+        for &(here, there) in sides.values().flatten() {
+            grid.pool[here].adjacencies.remove(&there);
+            grid.pool[there].adjacencies.remove(&here);
+        }
+        algo.generate(&mut grid.pool, rng, observer);
+        for &(here, there) in sides.values().flatten() {
+            grid.pool.make_adjacent(here, there, true);
+        }
+
+        let mut doors = vec![];
+        for (&(a, b), side) in &sides {
+            if !tree.is_linked(region_ids[a], region_ids[b]) {
+                continue;
+            }
+            let (here, there) = side[rng.gen_range(0..side.len())];
+            grid.pool.link_cells(here, there, true);
+            observer.observe(CarveEvent::Link(here, there));
+            doors.push((here, there));
+        }
+        doors
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use super::*;
+
+    #[test]
+    fn rooms_are_joined_by_one_door_each() {
+        // Four rooms around a courtyard, and a cell left out in the corner
+        let plan = ["AAABB", "AACBB", "DCCCB", "DDCEE", "DDEE."];
+        let regions = RegionMap::from_labels(5, 5, |row, col| Some(plan[row].as_bytes()[col]).filter(|&c| c != b'.'));
+        assert_eq!((regions.count(), regions.region_at(0, 0), regions.region_at(1, 2), regions.region_at(4, 4)), (5, Some(0), Some(2), None));
+
+        for seed in 0..8 {
+            let mut grid = MaskedGrid::new(5, 5, regions.mask());
+            let doors = regions.carve(&mut grid, Algorithm::Wilson, &mut StdRng::seed_from_u64(seed), &mut ());
+            // A spanning tree over the five regions, and a perfect maze over every cell
+            assert_eq!(doors.len(), regions.count() - 1);
+            let links: usize = grid.pool.iter_node_ids().map(|id| grid.pool[id].links.len()).sum();
+            assert_eq!(links / 2, grid.total_cells() - 1);
+            let region = |id: NodeId| {
+                let (row, col) = grid.pool[id].payload;
+                regions.region_at(row, col).unwrap()
+            };
+            // Every passage between two regions is one of the doors, and no two doors join the same regions
+            let mut crossings: Vec<(usize, usize)> = grid.pool.iter_node_ids()
+                .flat_map(|id| grid.pool[id].links.iter().map(move |&other| (id, other)))
+                .map(|(here, there)| (region(here), region(there)))
+                .filter(|(a, b)| a < b)
+                .collect();
+            crossings.sort();
+            let joined: Vec<(usize, usize)> = doors.iter().map(|&(here, there)| (region(here), region(there))).collect();
+            assert_eq!(crossings, joined);
+            assert!(joined.windows(2).all(|pair| pair[0] < pair[1]));
+        }
+    }
+}
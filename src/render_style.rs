@@ -0,0 +1,431 @@
+use tiny_skia::{BlendMode, Color, FilterQuality, LineCap, LineJoin, Paint, PathBuilder, Pixmap, PixmapPaint, Rect, Stroke, Transform};
+
+use crate::{grid::Direction, geometry::CartesianPoint, regions::RegionMap};
+
+
+
+/// How thick a line is drawn.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WallWidth {
+    /// A fixed number of pixels.
+    Pixels(f32),
+    /// A fraction of the cell size, so that walls keep their proportions at any resolution.
+    CellFraction(f32),
+}
+
+impl WallWidth {
+    pub fn resolve(&self, cell_size: f32) -> f32 {
+        match self {
+            WallWidth::Pixels(width) => *width,
+            WallWidth::CellFraction(fraction) => fraction * cell_size,
+        }
+    }
+}
+
+/// Draws the passages of a maze, as strokes through the centres of linked cells, instead of its walls.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CorridorStyle {
+    /// Width of the strokes.
+    pub width: WallWidth,
+    /// Radius of the curve where a corridor turns inside a cell, as a fraction of the cell size.
+    pub corner_radius: f32,
+}
+
+impl Default for CorridorStyle {
+    fn default() -> Self {
+        CorridorStyle { width: WallWidth::CellFraction(0.4), corner_radius: 0.3 }
+    }
+}
+
+/// Half of a corridor, from the centre of a cell to the side it shares with a linked neighbour.
+#[derive(Debug, Clone, Copy)]
+pub struct Spoke {
+    pub end: CartesianPoint,
+    /// Control point bending the spoke into a quadratic curve, such as along a ring. Straight when `None`.
+    pub via: Option<CartesianPoint>,
+}
+
+impl Spoke {
+    pub fn straight(end: CartesianPoint) -> Self {
+        Spoke { end, via: None }
+    }
+}
+
+impl CorridorStyle {
+    /// Adds the corridors of a cell to `pb`. A cell that two straight spokes pass through is drawn as one path, turning
+    /// along a curve of `corner_radius` pixels. Every other cell is drawn as its separate spokes.
+    pub fn push_cell(&self, pb: &mut PathBuilder, center: CartesianPoint, spokes: &[Spoke], corner_radius: f64) {
+        if let [Spoke { end: a, via: None }, Spoke { end: b, via: None }] = spokes {
+            let toward = |end: CartesianPoint| {
+                let (dx, dy) = (end.x - center.x, end.y - center.y);
+                let length = dx.hypot(dy);
+                let r = corner_radius.min(length);
+                CartesianPoint { x: center.x + dx / length * r, y: center.y + dy / length * r }
+            };
+            let (turn_start, turn_end) = (toward(*a), toward(*b));
+            pb.move_to(a.x as f32, a.y as f32);
+            pb.line_to(turn_start.x as f32, turn_start.y as f32);
+            pb.quad_to(center.x as f32, center.y as f32, turn_end.x as f32, turn_end.y as f32);
+            pb.line_to(b.x as f32, b.y as f32);
+            return;
+        }
+        for spoke in spokes {
+            pb.move_to(center.x as f32, center.y as f32);
+            match spoke.via {
+                Some(via) => pb.quad_to(via.x as f32, via.y as f32, spoke.end.x as f32, spoke.end.y as f32),
+                None => pb.line_to(spoke.end.x as f32, spoke.end.y as f32),
+            }
+        }
+    }
+}
+
+/// Groups of cells whose walls with other groups are drawn heavier, see [`ZoneWalls`].
+#[derive(Debug, Clone, PartialEq)]
+pub enum Zones {
+    /// Square blocks of `size` by `size` cells from the top left corner, such as the cells of the coarsest level of a
+    /// subdivided maze.
+    Blocks(usize),
+    /// The regions of a maze of rooms. Cells outside every region form no zone.
+    Regions(RegionMap),
+}
+
+impl Zones {
+    /// Whether the cells at `a` and `b` lie in different zones.
+    pub fn divides(&self, a: (usize, usize), b: (usize, usize)) -> bool {
+        match self {
+            Zones::Blocks(size) => (a.0 / size, a.1 / size) != (b.0 / size, b.1 / size),
+            Zones::Regions(regions) => regions.region_at(a.0, a.1) != regions.region_at(b.0, b.1),
+        }
+    }
+}
+
+/// Draws the walls between two cells of different [`Zones`] heavier than the others.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ZoneWalls {
+    pub zones: Zones,
+    /// Twice the width of the other walls when `None`.
+    pub width: Option<WallWidth>,
+}
+
+/// The look shared by every grid renderer: walls, outline, background and spacing.
+///
+/// The default draws 3 pixel wide black walls with round caps and joins, over a transparent background.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RenderStyle {
+    /// Empty space around the maze, in pixels.
+    pub padding: usize,
+    pub wall_color: Color,
+    pub wall_width: WallWidth,
+    /// Width of the outline around the maze. The same as `wall_width` when `None`.
+    pub border_width: Option<WallWidth>,
+    pub line_cap: LineCap,
+    pub line_join: LineJoin,
+    /// Fills the whole image, padding included, before anything else is drawn.
+    pub background: Color,
+    /// Fills the cells left out by the mask of masked grids.
+    pub masked_out: Color,
+    /// Opacity of cells and walls, from `0.0` to `1.0`. Below `1.0` the background shows through.
+    pub opacity: f32,
+    /// Fraction of the cell size trimmed off each side of a cell, below `0.5`. Above `0.0`, square grids draw every cell
+    /// as a smaller room with corridors toward its linked neighbours, instead of drawing walls on the cell boundaries.
+    pub inset: f32,
+    /// Draws passages instead of walls when set, taking precedence over `inset`.
+    pub corridors: Option<CorridorStyle>,
+    /// Draws the walls between zones heavier when set. Only walls drawn along the lattice of square grids are affected.
+    pub zone_walls: Option<ZoneWalls>,
+}
+
+impl Default for RenderStyle {
+    fn default() -> Self {
+        RenderStyle {
+            padding: 8,
+            wall_color: Color::BLACK,
+            wall_width: WallWidth::Pixels(3.0),
+            border_width: None,
+            line_cap: LineCap::Round,
+            line_join: LineJoin::Round,
+            background: Color::TRANSPARENT,
+            masked_out: Color::TRANSPARENT,
+            opacity: 1.0,
+            inset: 0.0,
+            corridors: None,
+            zone_walls: None,
+        }
+    }
+}
+
+impl RenderStyle {
+    pub fn wall_paint(&self) -> Paint<'static> {
+        let mut paint = Paint::default();
+        paint.set_color(self.wall_color);
+        paint.anti_alias = true;
+        self.faded(paint)
+    }
+
+    pub fn wall_stroke(&self, cell_size: f32) -> Stroke {
+        self.stroke(self.wall_width.resolve(cell_size))
+    }
+
+    pub fn border_stroke(&self, cell_size: f32) -> Stroke {
+        self.stroke(self.border_width.unwrap_or(self.wall_width).resolve(cell_size))
+    }
+
+    /// The stroke of walls between zones, twice as wide as the other walls unless `zone_walls` gives a width.
+    pub fn zone_stroke(&self, cell_size: f32) -> Stroke {
+        let width = self.zone_walls.as_ref().and_then(|zone_walls| zone_walls.width);
+        self.stroke(width.map_or(2.0 * self.wall_width.resolve(cell_size), |width| width.resolve(cell_size)))
+    }
+
+    /// A stroke with this style's caps and joins.
+    pub fn stroke(&self, width: f32) -> Stroke {
+        Stroke { width, line_cap: self.line_cap, line_join: self.line_join, ..Stroke::default() }
+    }
+
+    /// Applies `opacity` to a paint for cells or walls.
+    pub fn faded(&self, mut paint: Paint<'static>) -> Paint<'static> {
+        paint.shader.apply_opacity(self.opacity);
+        paint
+    }
+
+    pub fn masked_out_paint(&self) -> Paint<'static> {
+        let mut paint = Paint::default();
+        paint.set_color(self.masked_out);
+        paint
+    }
+
+    /// Whether square cells are drawn as inset rooms.
+    pub fn is_inset(&self) -> bool {
+        self.inset > 0.0
+    }
+
+    /// Adds the room of an inset `cell` to `floor`, along with a corridor toward each side for which `open` holds, and adds
+    /// the outline of both to `walls`. Corridors stop at the cell boundary, where the neighbour's corridor continues.
+    pub fn inset_cell(&self, cell: Rect, open: impl Fn(Direction) -> bool, floor: &mut PathBuilder, walls: &mut PathBuilder) {
+        let (left, top, right, bottom) = (cell.left(), cell.top(), cell.right(), cell.bottom());
+        let trim = self.inset * cell.width().min(cell.height());
+        let (inner_left, inner_top, inner_right, inner_bottom) = (left + trim, top + trim, right - trim, bottom - trim);
+        floor.push_rect(Rect::from_ltrb(inner_left, inner_top, inner_right, inner_bottom).unwrap());
+
+        let mut line = |x0, y0, x1, y1| {
+            walls.move_to(x0, y0);
+            walls.line_to(x1, y1);
+        };
+        let sides = [
+            (Direction::North, Rect::from_ltrb(inner_left, top, inner_right, inner_top)),
+            (Direction::South, Rect::from_ltrb(inner_left, inner_bottom, inner_right, bottom)),
+            (Direction::West, Rect::from_ltrb(left, inner_top, inner_left, inner_bottom)),
+            (Direction::East, Rect::from_ltrb(inner_right, inner_top, right, inner_bottom)),
+        ];
+        for (direction, corridor) in sides {
+            let corridor = corridor.unwrap();
+            match (direction, open(direction)) {
+                (Direction::North | Direction::South, true) => {
+                    floor.push_rect(corridor);
+                    line(corridor.left(), corridor.top(), corridor.left(), corridor.bottom());
+                    line(corridor.right(), corridor.top(), corridor.right(), corridor.bottom());
+                },
+                (Direction::West | Direction::East, true) => {
+                    floor.push_rect(corridor);
+                    line(corridor.left(), corridor.top(), corridor.right(), corridor.top());
+                    line(corridor.left(), corridor.bottom(), corridor.right(), corridor.bottom());
+                },
+                (Direction::North, false) => line(inner_left, inner_top, inner_right, inner_top),
+                (Direction::South, false) => line(inner_left, inner_bottom, inner_right, inner_bottom),
+                (Direction::West, false) => line(inner_left, inner_top, inner_left, inner_bottom),
+                (Direction::East, false) => line(inner_right, inner_top, inner_right, inner_bottom),
+            }
+        }
+    }
+
+    /// Creates an image of the given size, filled with the background.
+    pub fn new_pixmap(&self, width: usize, height: usize) -> Pixmap {
+        let mut pixmap = Pixmap::new(width as u32, height as u32).unwrap();
+        pixmap.fill(self.background);
+        pixmap
+    }
+}
+
+/// Draws `icon` centred on `(x, y)`, scaled to fit a square `size` pixels wide without changing its proportions.
+pub fn draw_icon(pixmap: &mut Pixmap, icon: &Pixmap, (x, y): (f32, f32), size: f32) {
+    let scale = size / icon.width().max(icon.height()) as f32;
+    let (left, top) = (x - icon.width() as f32 * scale / 2.0, y - icon.height() as f32 * scale / 2.0);
+    let paint = PixmapPaint { blend_mode: BlendMode::SourceOver, quality: FilterQuality::Bicubic, ..PixmapPaint::default() };
+    pixmap.draw_pixmap(0, 0, icon.as_ref(), &paint, Transform::from_row(scale, 0.0, 0.0, scale, left, top), None);
+}
+
+/// Parses `#rgb`, `#rrggbb` or `#rrggbbaa` hex colors, as well as `black`, `white` and `transparent`.
+pub fn parse_color(s: &str) -> Result<Color, String> {
+    let invalid = || format!("invalid color `{}`, expected #rgb, #rrggbb, #rrggbbaa, black, white or transparent", s);
+    match s {
+        "black" => return Ok(Color::BLACK),
+        "white" => return Ok(Color::WHITE),
+        "transparent" => return Ok(Color::TRANSPARENT),
+        _ => {},
+    }
+    let digits = s.strip_prefix('#').filter(|d| d.chars().all(|c| c.is_ascii_hexdigit())).ok_or_else(invalid)?;
+    let channel = |i: usize, len: usize| {
+        let value = u8::from_str_radix(&digits[i * len..(i + 1) * len], 16).unwrap();
+        if len == 1 { value * 17 } else { value }
+    };
+    match digits.len() {
+        3 => Ok(Color::from_rgba8(channel(0, 1), channel(1, 1), channel(2, 1), u8::MAX)),
+        6 => Ok(Color::from_rgba8(channel(0, 2), channel(1, 2), channel(2, 2), u8::MAX)),
+        8 => Ok(Color::from_rgba8(channel(0, 2), channel(1, 2), channel(2, 2), channel(3, 2))),
+        _ => Err(invalid()),
+    }
+}
+
+/// Parses an inset ratio, which must be at least `0.0` and below `0.5`.
+pub fn parse_inset(s: &str) -> Result<f32, String> {
+    match s.parse::<f32>() {
+        Ok(inset) if (0.0..0.5).contains(&inset) => Ok(inset),
+        Ok(_) => Err(format!("inset `{}` is out of range, expected at least 0 and below 0.5", s)),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+pub fn parse_line_cap(s: &str) -> Result<LineCap, String> {
+    match s {
+        "butt" => Ok(LineCap::Butt),
+        "round" => Ok(LineCap::Round),
+        "square" => Ok(LineCap::Square),
+        _ => Err(format!("unknown line cap `{}`, expected one of: butt, round, square", s)),
+    }
+}
+
+pub fn parse_line_join(s: &str) -> Result<LineJoin, String> {
+    match s {
+        "miter" => Ok(LineJoin::Miter),
+        "round" => Ok(LineJoin::Round),
+        "bevel" => Ok(LineJoin::Bevel),
+        _ => Err(format!("unknown line join `{}`, expected one of: miter, round, bevel", s)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{masked_grid::MaskedGrid, mask::Mask, polar_grid::PolarGrid, grid::FlatSquareGrid};
+
+    use super::*;
+
+    #[test]
+    fn colors() {
+        assert_eq!(parse_color("#f80"), Ok(Color::from_rgba8(255, 136, 0, 255)));
+        assert_eq!(parse_color("#102030"), Ok(Color::from_rgba8(16, 32, 48, 255)));
+        assert_eq!(parse_color("#10203040"), Ok(Color::from_rgba8(16, 32, 48, 64)));
+        assert_eq!(parse_color("transparent"), Ok(Color::TRANSPARENT));
+        assert!(parse_color("#12345").is_err());
+        assert!(parse_color("red").is_err());
+    }
+
+    #[test]
+    fn scaled_walls() {
+        let style = RenderStyle { wall_width: WallWidth::CellFraction(0.25), border_width: Some(WallWidth::Pixels(7.0)), ..RenderStyle::default() };
+        assert_eq!(style.wall_stroke(20.0).width, 5.0);
+        assert_eq!(style.border_stroke(20.0).width, 7.0);
+        assert_eq!(RenderStyle::default().border_stroke(20.0).width, 3.0);
+    }
+
+    #[test]
+    fn heavier_zone_walls() {
+        let zones = ZoneWalls { zones: Zones::Blocks(2), width: None };
+        let style = RenderStyle { padding: 0, wall_width: WallWidth::Pixels(2.0), zone_walls: Some(zones), ..RenderStyle::default() };
+        assert_eq!(style.zone_stroke(20.0).width, 4.0);
+        assert!(Zones::Blocks(2).divides((1, 1), (1, 2)) && !Zones::Blocks(2).divides((0, 0), (1, 1)));
+
+        // Four cells in a row, none linked, in two blocks of two
+        let masked = MaskedGrid::new(4, 1, Mask::full());
+        let pixmap = masked.print_image(20, &style, true, |_| {
+            let mut paint = Paint::default();
+            paint.set_color(Color::TRANSPARENT);
+            paint
+        }, vec![]);
+        let alpha = |x, y| pixmap.pixel(x, y).unwrap().alpha();
+        // Just beside the wall within the first block, and the wall between the blocks
+        assert_eq!((alpha(21, 10), alpha(41, 10), alpha(38, 10)), (0, 255, 255));
+    }
+
+    fn white(_: crate::pool::NodeId) -> Paint<'static> {
+        let mut paint = Paint::default();
+        paint.set_color(Color::WHITE);
+        paint
+    }
+
+    #[test]
+    fn every_renderer_uses_the_style() {
+        let style = RenderStyle {
+            padding: 4,
+            background: Color::from_rgba8(255, 0, 0, 255),
+            masked_out: Color::from_rgba8(0, 0, 255, 255),
+            ..RenderStyle::default()
+        };
+        let red = |pixmap: &Pixmap| pixmap.pixel(0, 0).unwrap().red() == 255 && pixmap.pixel(0, 0).unwrap().alpha() == 255;
+
+        let masked = MaskedGrid::new(3, 3, Mask::rectangle(0, 0, 1, 1).invert());
+        let pixmap = masked.print_image(10, &style, true, white, vec![]);
+        assert_eq!((pixmap.width(), pixmap.height()), (38, 38));
+        assert!(red(&pixmap));
+        // Middle of the masked out top left cell
+        assert_eq!(pixmap.pixel(9, 9).unwrap().blue(), 255);
+
+        let flat = FlatSquareGrid::new(3, 3);
+        assert!(red(&flat.image_print(10, &style, white)));
+
+        let polar = PolarGrid::new(6, 3);
+        assert!(red(&polar.print_image(30, &style, white, vec![])));
+    }
+
+    #[test]
+    fn inset_rooms() {
+        assert_eq!(parse_inset("0.25"), Ok(0.25));
+        assert!(parse_inset("0.5").is_err());
+
+        let style = RenderStyle { padding: 0, inset: 0.25, wall_width: WallWidth::Pixels(1.0), ..RenderStyle::default() };
+        let mut flat = FlatSquareGrid::new(2, 1);
+        let (left, right) = (flat.get_by_position(0, 0).id, flat.get_by_position(0, 1).id);
+        flat.node_pool.link_cells(left, right, true);
+        let mut masked = MaskedGrid::new(2, 2, Mask::rectangle(0, 0, 2, 1));
+        let (left, right) = (masked.get_id_at(0, 0).unwrap(), masked.get_id_at(0, 1).unwrap());
+        masked.pool.link_cells(left, right, true);
+
+        for pixmap in [flat.image_print(20, &style, white), masked.print_image(20, &style, true, white, vec![])] {
+            let alpha = |x, y| pixmap.pixel(x, y).unwrap().alpha();
+            // Room, corridor between the two rooms, trimmed corner, and the closed side of the left room
+            assert_eq!((alpha(10, 10), alpha(19, 10), alpha(2, 2), alpha(2, 10)), (255, 255, 0, 0));
+        }
+    }
+
+    #[test]
+    fn icons_are_centred() {
+        let mut icon = Pixmap::new(4, 2).unwrap();
+        icon.fill(Color::from_rgba8(255, 0, 0, 255));
+        let mut pixmap = Pixmap::new(40, 40).unwrap();
+        // Scaled to 10 by 5 pixels, from (15, 17.5) to (25, 22.5)
+        draw_icon(&mut pixmap, &icon, (20.0, 20.0), 10.0);
+        let alpha = |x, y| pixmap.pixel(x, y).unwrap().alpha();
+        assert_eq!((alpha(20, 20), alpha(15, 20), alpha(24, 20), alpha(20, 18), alpha(20, 21)), (255, 255, 255, 255, 255));
+        assert_eq!((alpha(13, 20), alpha(26, 20), alpha(20, 16), alpha(20, 23)), (0, 0, 0, 0));
+    }
+
+    #[test]
+    fn corridors_follow_links() {
+        let style = RenderStyle { padding: 0, corridors: Some(CorridorStyle::default()), ..RenderStyle::default() };
+        let mut masked = MaskedGrid::new(2, 2, Mask::full());
+        let (top_left, top_right, bottom_right) = (masked.get_id_at(0, 0).unwrap(), masked.get_id_at(0, 1).unwrap(), masked.get_id_at(1, 1).unwrap());
+        masked.pool.link_cells(top_left, top_right, true);
+        masked.pool.link_cells(top_right, bottom_right, true);
+        let pixmap = masked.print_image(20, &style, true, white, vec![]);
+        let alpha = |x, y| pixmap.pixel(x, y).unwrap().alpha();
+        // Along the corridor, inside the turn, and the unlinked cell
+        assert_eq!((alpha(10, 10), alpha(20, 10), alpha(30, 20), alpha(10, 30)), (255, 255, 255, 0));
+        // The turn is rounded, leaving the corner of the cell empty
+        assert_eq!(alpha(37, 3), 0);
+
+        let mut polar = PolarGrid::new(6, 2);
+        let (center, first) = (polar.rings[0][0], polar.rings[1][0]);
+        polar.pool.link_cells(center, first, true);
+        let pixmap = polar.print_image(20, &style, white, vec![]);
+        // Halfway along the corridor from the centre to the first cell of the ring
+        assert_eq!(pixmap.pixel(24, 22).unwrap().alpha(), 255);
+    }
+}
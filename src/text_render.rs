@@ -0,0 +1,137 @@
+/// Character set used when printing mazes as text.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TextCharset {
+    /// `+---+` and `|` walls, as in [`crate::grid::FlatSquareGrid::text_print`].
+    #[default]
+    Ascii,
+    /// Box drawing characters, with junctions that match the walls meeting at them.
+    Unicode,
+}
+
+impl TextCharset {
+    pub fn horizontal(&self) -> char {
+        match self {
+            TextCharset::Ascii => '-',
+            TextCharset::Unicode => '─',
+        }
+    }
+
+    pub fn vertical(&self) -> char {
+        match self {
+            TextCharset::Ascii => '|',
+            TextCharset::Unicode => '│',
+        }
+    }
+
+    /// Marks a cell on a highlighted path.
+    pub fn path_marker(&self) -> char {
+        match self {
+            TextCharset::Ascii => '*',
+            TextCharset::Unicode => '•',
+        }
+    }
+
+    /// The character drawn where wall segments meet, given which directions have a wall leaving the junction.
+    pub fn junction(&self, up: bool, down: bool, left: bool, right: bool) -> char {
+        match self {
+            TextCharset::Ascii => if up || down || left || right { '+' } else { ' ' },
+            TextCharset::Unicode => match (up, down, left, right) {
+                (false, false, false, false) => ' ',
+                (true, false, false, false) => '╵',
+                (false, true, false, false) => '╷',
+                (false, false, true, false) => '╴',
+                (false, false, false, true) => '╶',
+                (true, true, false, false) => '│',
+                (false, false, true, true) => '─',
+                (true, false, false, true) => '└',
+                (true, false, true, false) => '┘',
+                (false, true, false, true) => '┌',
+                (false, true, true, false) => '┐',
+                (true, true, false, true) => '├',
+                (true, true, true, false) => '┤',
+                (false, true, true, true) => '┬',
+                (true, false, true, true) => '┴',
+                (true, true, true, true) => '┼',
+            },
+        }
+    }
+}
+
+/// Centers `label` in a field `width` characters wide.
+pub fn centered(label: &str, width: usize) -> String {
+    let length = label.chars().count();
+    if length >= width {
+        return label.to_string();
+    }
+    let left = (width - length) / 2;
+    let right = width - length - left;
+    format!("{}{}{}", " ".repeat(left), label, " ".repeat(right))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{masked_grid::MaskedGrid, mask::Mask, dijkstra::DijkstraPad, polar_grid::PolarGrid};
+
+    use super::*;
+
+    /// A 3x2 grid missing its top right cell, linked as a single corridor.
+    fn corridor() -> MaskedGrid {
+        let mut g = MaskedGrid::new(3, 2, Mask::rectangle(0, 2, 1, 1).invert());
+        for (a, b) in [((0, 0), (0, 1)), ((0, 1), (1, 1)), ((1, 1), (1, 0)), ((1, 1), (1, 2))] {
+            let a = g.get_id_at(a.0, a.1).unwrap();
+            let b = g.get_id_at(b.0, b.1).unwrap();
+            g.pool.link_cells(a, b, true);
+        }
+        g
+    }
+
+    #[test]
+    fn ascii_masked() {
+        let g = corridor();
+        let expected = [
+            "+---+---+",
+            "|       |",
+            "+---+   +---+",
+            "|           |",
+            "+---+---+---+",
+        ].join("\n") + "\n";
+        assert_eq!(g.text_print(TextCharset::Ascii, |_| None), expected);
+    }
+
+    #[test]
+    fn unicode_masked() {
+        let g = corridor();
+        let expected = [
+            "┌───────┐",
+            "│       │",
+            "├───╴   └───┐",
+            "│           │",
+            "└───────────┘",
+        ].join("\n") + "\n";
+        assert_eq!(g.text_print(TextCharset::Unicode, |_| None), expected);
+    }
+
+    #[test]
+    fn distances_and_path() {
+        let g = corridor();
+        let start = g.get_id_at(0, 0).unwrap();
+        let end = g.get_id_at(1, 0).unwrap();
+        let distances = g.text_print_distances(TextCharset::Ascii, start);
+        assert_eq!(distances.lines().nth(3).unwrap(), "| 3   2   3 |");
+
+        let path = DijkstraPad::new(&g.pool, start).perform().path_to(end).unwrap();
+        assert_eq!(path.len(), 4);
+        let printed = g.text_print_path(TextCharset::Ascii, &path);
+        assert_eq!(printed.lines().nth(1).unwrap(), "| *   * |");
+        assert_eq!(printed.lines().nth(3).unwrap(), "| *   *     |");
+    }
+
+    #[test]
+    fn polar_dump_has_every_ring() {
+        let g = PolarGrid::new(6, 4);
+        let printed = g.text_print();
+        assert_eq!(printed.lines().count(), 4);
+        // Nothing is linked yet, so every cell has both its walls
+        assert_eq!(printed.lines().nth(1).unwrap(), "  1: |_|_|_|_|_|_|");
+    }
+}
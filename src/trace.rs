@@ -0,0 +1,355 @@
+use std::{io::{self, Write}, fmt::Display, path::Path};
+
+use crate::{parsers, pool::{NodeId, Pool, CarveEvent, CarveObserver}};
+
+
+
+/// Magic bytes every binary trace starts with, followed by its format version.
+pub const TRACE_TAG: [u8; 4] = *b"MZTR";
+
+/// The version of the binary trace format written by this crate.
+pub const TRACE_VERSION: u8 = 1;
+
+/// Kinds of event in a binary trace, each followed by its nodes.
+pub(crate) mod event_kind {
+    pub const VISIT: u8 = 0;
+    pub const LINK: u8 = 1;
+    pub const UNLINK: u8 = 2;
+    pub const STEP: u8 = 3;
+    pub const ERASE: u8 = 4;
+}
+
+/// A [`CarveEvent`] as kept in a trace. Nodes are given by their index in the pool, see [`NodeId::index`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TraceEvent {
+    Visit(usize),
+    Link(usize, usize),
+    LinkRemoved(usize, usize),
+    /// The head of a random walk moved onto a node. The walk itself is not kept, as replaying the steps and erasures
+    /// from the last visit rebuilds it.
+    WalkerStep(usize),
+    /// The nodes dropped from the end of a random walk when it closed a loop.
+    LoopErased(Vec<usize>),
+}
+
+/// How a trace is stored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceFormat {
+    /// One JSON object per line, such as `{"event":"link","nodes":[3,4]}`, for external tools.
+    JsonLines,
+    /// A tag, a version, then each event as a kind byte followed by its nodes as LEB128 varints.
+    Binary,
+}
+
+impl TraceFormat {
+    /// Picks JSON lines for `.json` and `.jsonl` files, and the binary format otherwise.
+    pub fn by_extension(path: &Path) -> Self {
+        match path.extension().map(|e| e.to_string_lossy().to_lowercase()).as_deref() {
+            Some("json" | "jsonl") => TraceFormat::JsonLines,
+            _ => TraceFormat::Binary,
+        }
+    }
+}
+
+/// Every event reported by a generator, in order.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Trace {
+    pub events: Vec<TraceEvent>,
+}
+
+/// Reasons a stored trace cannot be read.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TraceReadError {
+    /// A line of a JSON lines trace is not an event. Lines start at 1.
+    MalformedLine(usize),
+    /// The binary trace does not start with [`TRACE_TAG`].
+    NotATrace,
+    UnsupportedVersion(u8),
+    /// The binary event starting at this byte offset is cut short or of an unknown kind.
+    MalformedEvent(usize),
+}
+
+impl Display for TraceReadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TraceReadError::MalformedLine(line) => write!(f, "line {} is not a trace event", line),
+            TraceReadError::NotATrace => write!(f, "not a maze trace"),
+            TraceReadError::UnsupportedVersion(v) => write!(f, "trace format version {} is not supported", v),
+            TraceReadError::MalformedEvent(offset) => write!(f, "malformed trace event at byte {}", offset),
+        }
+    }
+}
+
+/// Reasons a trace cannot be replayed onto a pool. `event` is the index of the offending event.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReplayError {
+    /// The trace names a node the pool doesn't have.
+    UnknownNode { event: usize, node: usize },
+    /// The trace links two nodes that are not adjacent in the pool.
+    NotAdjacent { event: usize, here: usize, there: usize },
+    /// The trace removes a link that was never made.
+    NotLinked { event: usize, here: usize, there: usize },
+    /// Replay was asked to stop after more events than the trace has.
+    PastEnd { events: usize },
+    /// After the whole trace, the links of this node differ from the generated maze.
+    Mismatch(usize),
+}
+
+impl Display for ReplayError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ReplayError::UnknownNode { event, node } => write!(f, "event {} names node {}, which the maze doesn't have", event, node),
+            ReplayError::NotAdjacent { event, here, there } => write!(f, "event {} links nodes {} and {}, which are not adjacent", event, here, there),
+            ReplayError::NotLinked { event, here, there } => write!(f, "event {} unlinks nodes {} and {}, which are not linked", event, here, there),
+            ReplayError::PastEnd { events } => write!(f, "the trace only has {} events", events),
+            ReplayError::Mismatch(node) => write!(f, "replaying the trace gives node {} different passages than the generated maze", node),
+        }
+    }
+}
+
+impl Trace {
+    pub fn new() -> Self {
+        Trace::default()
+    }
+
+    /// Rebuilds the passages of `pool` as they were after the first `upto` events. The links already in `pool` are
+    /// ignored, only its nodes and adjacencies are used.
+    pub fn replay<T: Clone>(&self, pool: &Pool<T>, upto: usize) -> Result<Pool<T>, ReplayError> {
+        let events = self.events.get(..upto).ok_or(ReplayError::PastEnd { events: self.events.len() })?;
+        let mut replayed = pool.map_nodes(|n| n.payload.clone());
+        replayed.clear_links();
+        for (event, e) in events.iter().enumerate() {
+            let node = |index: usize| replayed.node_id(index).ok_or(ReplayError::UnknownNode { event, node: index });
+            match *e {
+                TraceEvent::Link(here, there) => {
+                    let (h, t) = (node(here)?, node(there)?);
+                    if !replayed.neighborhood_of(h).contains(&t) {
+                        return Err(ReplayError::NotAdjacent { event, here, there });
+                    }
+                    replayed.link_cells(h, t, true);
+                },
+                TraceEvent::LinkRemoved(here, there) => {
+                    let (h, t) = (node(here)?, node(there)?);
+                    if !replayed.is_linked(h, t) {
+                        return Err(ReplayError::NotLinked { event, here, there });
+                    }
+                    replayed.unlink_cells(h, t, true);
+                },
+                TraceEvent::Visit(n) | TraceEvent::WalkerStep(n) => {
+                    node(n)?;
+                },
+                TraceEvent::LoopErased(ref erased) => {
+                    for &n in erased {
+                        node(n)?;
+                    }
+                },
+            }
+        }
+        Ok(replayed)
+    }
+
+    /// Replays the whole trace and checks it ends with the same passages as `generated`.
+    pub fn verify<T: Clone>(&self, generated: &Pool<T>) -> Result<(), ReplayError> {
+        let replayed = self.replay(generated, self.events.len())?;
+        match generated.iter_node_ids().find(|&n| replayed.get(n).links != generated.get(n).links) {
+            Some(n) => Err(ReplayError::Mismatch(n.index())),
+            None => Ok(()),
+        }
+    }
+
+    pub fn write(&self, format: TraceFormat, out: impl Write) -> io::Result<()> {
+        match format {
+            TraceFormat::JsonLines => self.write_json_lines(out),
+            TraceFormat::Binary => self.write_binary(out),
+        }
+    }
+
+    pub fn read(format: TraceFormat, bytes: &[u8]) -> Result<Self, TraceReadError> {
+        match format {
+            TraceFormat::JsonLines => {
+                let text = std::str::from_utf8(bytes).map_err(|_| TraceReadError::MalformedLine(1))?;
+                Self::read_json_lines(text)
+            },
+            TraceFormat::Binary => Self::read_binary(bytes),
+        }
+    }
+
+    pub fn write_json_lines(&self, mut out: impl Write) -> io::Result<()> {
+        let list = |nodes: &[usize]| nodes.iter().map(|n| n.to_string()).collect::<Vec<_>>().join(",");
+        for event in &self.events {
+            match event {
+                TraceEvent::Visit(n) => writeln!(out, "{{\"event\":\"visit\",\"node\":{}}}", n)?,
+                TraceEvent::Link(here, there) => writeln!(out, "{{\"event\":\"link\",\"nodes\":[{},{}]}}", here, there)?,
+                TraceEvent::LinkRemoved(here, there) => writeln!(out, "{{\"event\":\"unlink\",\"nodes\":[{},{}]}}", here, there)?,
+                TraceEvent::WalkerStep(n) => writeln!(out, "{{\"event\":\"step\",\"node\":{}}}", n)?,
+                TraceEvent::LoopErased(erased) => writeln!(out, "{{\"event\":\"erase\",\"nodes\":[{}]}}", list(erased))?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads a trace written by [`Trace::write_json_lines`]. Blank lines are skipped.
+    pub fn read_json_lines(text: &str) -> Result<Self, TraceReadError> {
+        let mut events = vec![];
+        for (index, line) in text.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            match parsers::trace_json_line(line) {
+                Ok((_, event)) => events.push(event),
+                Err(_) => return Err(TraceReadError::MalformedLine(index + 1)),
+            }
+        }
+        Ok(Trace { events })
+    }
+
+    pub fn write_binary(&self, mut out: impl Write) -> io::Result<()> {
+        out.write_all(&TRACE_TAG)?;
+        out.write_all(&[TRACE_VERSION])?;
+        for event in &self.events {
+            let (kind, nodes): (u8, &[usize]) = match event {
+                TraceEvent::Visit(n) => (event_kind::VISIT, std::slice::from_ref(n)),
+                TraceEvent::Link(here, there) => (event_kind::LINK, &[*here, *there]),
+                TraceEvent::LinkRemoved(here, there) => (event_kind::UNLINK, &[*here, *there]),
+                TraceEvent::WalkerStep(n) => (event_kind::STEP, std::slice::from_ref(n)),
+                TraceEvent::LoopErased(erased) => (event_kind::ERASE, erased.as_slice()),
+            };
+            out.write_all(&[kind])?;
+            // Erasures drop any number of nodes, so they are counted first
+            if kind == event_kind::ERASE {
+                write_varint(&mut out, nodes.len())?;
+            }
+            for &n in nodes {
+                write_varint(&mut out, n)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn read_binary(bytes: &[u8]) -> Result<Self, TraceReadError> {
+        let (mut rest, version) = parsers::trace_preamble(bytes).map_err(|_| TraceReadError::NotATrace)?;
+        if version != TRACE_VERSION {
+            return Err(TraceReadError::UnsupportedVersion(version));
+        }
+        let mut events = vec![];
+        while !rest.is_empty() {
+            let offset = bytes.len() - rest.len();
+            let (next, event) = parsers::trace_binary_event(rest).map_err(|_| TraceReadError::MalformedEvent(offset))?;
+            events.push(event);
+            rest = next;
+        }
+        Ok(Trace { events })
+    }
+}
+
+impl CarveObserver for Trace {
+    fn observe(&mut self, event: CarveEvent<'_>) {
+        self.events.push(match event {
+            CarveEvent::Visit(n) => TraceEvent::Visit(n.index()),
+            CarveEvent::Link(here, there) => TraceEvent::Link(here.index(), there.index()),
+            CarveEvent::LinkRemoved(here, there) => TraceEvent::LinkRemoved(here.index(), there.index()),
+            CarveEvent::WalkerStep { head, .. } => TraceEvent::WalkerStep(head.index()),
+            CarveEvent::LoopErased { erased, .. } => TraceEvent::LoopErased(erased.iter().map(|n: &NodeId| n.index()).collect()),
+        });
+    }
+}
+
+/// Writes `n` as a little endian base 128 varint: seven bits per byte, with the high bit set on all but the last.
+fn write_varint(out: &mut impl Write, mut n: usize) -> io::Result<()> {
+    loop {
+        let byte = (n & 0x7F) as u8;
+        n >>= 7;
+        if n == 0 {
+            return out.write_all(&[byte]);
+        }
+        out.write_all(&[byte | 0x80])?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use crate::{masked_grid::MaskedGrid, mask::Mask};
+
+    use super::*;
+
+    fn wilson_trace() -> (MaskedGrid, Trace) {
+        let mut grid = MaskedGrid::new(6, 5, Mask::full());
+        let mut trace = Trace::new();
+        grid.pool.wilson(&mut StdRng::seed_from_u64(3), &mut trace);
+        (grid, trace)
+    }
+
+    #[test]
+    fn replay_rebuilds_the_maze() {
+        let (grid, trace) = wilson_trace();
+        assert_eq!(trace.verify(&grid.pool), Ok(()));
+        assert!(trace.events.iter().any(|e| matches!(e, TraceEvent::LoopErased(erased) if !erased.is_empty())));
+
+        let first_link = trace.events.iter().position(|e| matches!(e, TraceEvent::Link(..))).unwrap();
+        let before = trace.replay(&grid.pool, first_link).unwrap();
+        assert!(before.iter_node_ids().all(|n| before.get(n).links.is_empty()));
+        let after = trace.replay(&grid.pool, first_link + 1).unwrap();
+        assert_eq!(after.iter_node_ids().map(|n| after.get(n).links.len()).sum::<usize>(), 2);
+        assert_eq!(trace.replay(&grid.pool, trace.events.len() + 1).err(), Some(ReplayError::PastEnd { events: trace.events.len() }));
+    }
+
+    #[test]
+    fn replay_catches_bad_traces() {
+        let (grid, mut trace) = wilson_trace();
+        let (at, (here, there)) = trace.events.iter().enumerate().rev().find_map(|(i, e)| match e {
+            TraceEvent::Link(here, there) => Some((i, (*here, *there))),
+            _ => None,
+        }).unwrap();
+        trace.events.remove(at);
+        assert!(matches!(trace.verify(&grid.pool), Err(ReplayError::Mismatch(n)) if n == here || n == there));
+
+        trace.events.push(TraceEvent::LinkRemoved(here, there));
+        assert_eq!(trace.verify(&grid.pool), Err(ReplayError::NotLinked { event: trace.events.len() - 1, here, there }));
+        let bad = Trace { events: vec![TraceEvent::Link(0, 29)] };
+        assert_eq!(bad.verify(&grid.pool), Err(ReplayError::NotAdjacent { event: 0, here: 0, there: 29 }));
+        let bad = Trace { events: vec![TraceEvent::Visit(30)] };
+        assert_eq!(bad.verify(&grid.pool), Err(ReplayError::UnknownNode { event: 0, node: 30 }));
+    }
+
+    #[test]
+    fn replay_removes_links() {
+        let (grid, _) = wilson_trace();
+        let trace = Trace { events: vec![TraceEvent::Link(0, 1), TraceEvent::Link(1, 7), TraceEvent::LinkRemoved(1, 0)] };
+        let replayed = trace.replay(&grid.pool, 3).unwrap();
+        let (a, b, c) = (replayed.node_id(0).unwrap(), replayed.node_id(1).unwrap(), replayed.node_id(7).unwrap());
+        assert!(!replayed.is_linked(a, b) && !replayed.is_linked(b, a));
+        assert!(replayed.is_linked(b, c));
+    }
+
+    #[test]
+    fn formats_round_trip() {
+        let (_, mut trace) = wilson_trace();
+        trace.events.push(TraceEvent::LinkRemoved(300, 1));
+        for format in [TraceFormat::JsonLines, TraceFormat::Binary] {
+            let mut bytes = vec![];
+            trace.write(format, &mut bytes).unwrap();
+            assert_eq!(Trace::read(format, &bytes), Ok(trace.clone()), "{:?} trace changed", format);
+        }
+        let mut json = vec![];
+        trace.write_json_lines(&mut json).unwrap();
+        let mut binary = vec![];
+        trace.write_binary(&mut binary).unwrap();
+        assert!(binary.len() * 4 < json.len());
+    }
+
+    #[test]
+    fn malformed_traces() {
+        let text = "{\"event\":\"visit\",\"node\":3}\n\n{ \"event\" : \"link\", \"nodes\" : [3, 4] }\n{\"event\":\"link\",\"nodes\":[3]}\n";
+        assert_eq!(Trace::read_json_lines(text), Err(TraceReadError::MalformedLine(4)));
+        let text = "{\"event\":\"visit\",\"node\":3}\n\n{ \"event\" : \"link\", \"nodes\" : [3, 4] }\n";
+        assert_eq!(Trace::read_json_lines(text), Ok(Trace { events: vec![TraceEvent::Visit(3), TraceEvent::Link(3, 4)] }));
+
+        assert_eq!(Trace::read_binary(b"MAZE\x01"), Err(TraceReadError::NotATrace));
+        assert_eq!(Trace::read_binary(b"MZTR\x02"), Err(TraceReadError::UnsupportedVersion(2)));
+        assert_eq!(Trace::read_binary(b"MZTR\x01\x00\x85\x01\x01\x02"), Err(TraceReadError::MalformedEvent(8)));
+        assert_eq!(Trace::read_binary(b"MZTR\x01\x00\x85\x01\x07"), Err(TraceReadError::MalformedEvent(8)));
+        assert_eq!(Trace::read_binary(b"MZTR\x01\x00\x85\x01"), Ok(Trace { events: vec![TraceEvent::Visit(133)] }));
+    }
+}
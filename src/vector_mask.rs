@@ -0,0 +1,159 @@
+use std::fmt::Display;
+
+use tiny_skia::{Pixmap, Paint, Path, PathBuilder, Transform, FillRule};
+
+use crate::{mask::Mask, parsers};
+
+
+
+/// One line of a vector mask file. Points are `x y` pairs, `x` running right and `y` down, as with [`PathBuilder`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PathCommand {
+    /// `size WIDTH HEIGHT`: the area mapped onto the grid. Defaults to the bounds of the path, from the origin.
+    Size(f32, f32),
+    /// `fill nonzero` or `fill evenodd`: which parts inside the path are filled. Defaults to non-zero.
+    Fill(FillRule),
+    /// `move X Y`
+    Move(f32, f32),
+    /// `line X Y`
+    Line(f32, f32),
+    /// `quad CONTROL_X CONTROL_Y X Y`
+    Quad(f32, f32, f32, f32),
+    /// `close`
+    Close,
+}
+
+/// An error in a vector mask file. Lines start at 1.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VectorMaskError {
+    pub line: usize,
+    pub kind: VectorMaskErrorKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VectorMaskErrorKind {
+    /// The bytes are neither UTF-8 nor UTF-16 with a byte order mark.
+    InvalidEncoding,
+    /// The line is not a command.
+    Malformed,
+    /// The path encloses nothing.
+    NoShape,
+    /// The size of the area is not positive.
+    EmptySize,
+}
+
+impl Display for VectorMaskError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.kind {
+            VectorMaskErrorKind::InvalidEncoding => write!(f, "the file is neither UTF-8 nor UTF-16"),
+            VectorMaskErrorKind::Malformed => write!(f, "line {} is not `size`, `fill`, `move`, `line`, `quad` or `close`", self.line),
+            VectorMaskErrorKind::NoShape => write!(f, "the path encloses nothing"),
+            VectorMaskErrorKind::EmptySize => write!(f, "the size on line {} is not positive", self.line),
+        }
+    }
+}
+
+/// The cells of a grid that are part of a maze, filling a path. The path is drawn onto the grid at any size, so that the
+/// same file gives a small maze or a large one.
+#[derive(Debug, Clone, PartialEq)]
+pub struct VectorMask {
+    path: Path,
+    size: (f32, f32),
+    fill_rule: FillRule,
+}
+
+impl VectorMask {
+    /// Reads a vector mask file, one [`PathCommand`] to a line. Blank lines, and anything after a `#`, are skipped.
+    pub fn read(bytes: &[u8]) -> Result<Self, VectorMaskError> {
+        let text = parsers::decode_text(bytes).ok_or(VectorMaskError { line: 1, kind: VectorMaskErrorKind::InvalidEncoding })?;
+        let mut builder = PathBuilder::new();
+        let mut size = None;
+        let mut fill_rule = FillRule::Winding;
+        for (index, line) in text.lines().enumerate() {
+            let error = |kind| VectorMaskError { line: index + 1, kind };
+            let command = match parsers::path_command(line) {
+                Ok(("", command)) => command,
+                _ => return Err(error(VectorMaskErrorKind::Malformed)),
+            };
+            match command {
+                Some(PathCommand::Size(width, height)) if width > 0.0 && height > 0.0 => size = Some((width, height)),
+                Some(PathCommand::Size(..)) => return Err(error(VectorMaskErrorKind::EmptySize)),
+                Some(PathCommand::Fill(rule)) => fill_rule = rule,
+                Some(PathCommand::Move(x, y)) => builder.move_to(x, y),
+                Some(PathCommand::Line(x, y)) => builder.line_to(x, y),
+                Some(PathCommand::Quad(x1, y1, x, y)) => builder.quad_to(x1, y1, x, y),
+                Some(PathCommand::Close) => builder.close(),
+                None => {},
+            }
+        }
+        let no_shape = VectorMaskError { line: text.lines().count().max(1), kind: VectorMaskErrorKind::NoShape };
+        let path = builder.finish().ok_or(no_shape.clone())?;
+        let size = size.unwrap_or((path.bounds().right(), path.bounds().bottom()));
+        if size.0 <= 0.0 || size.1 <= 0.0 {
+            return Err(no_shape);
+        }
+        Ok(VectorMask { path, size, fill_rule })
+    }
+
+    /// The size of the area drawn onto the grid, one unit to a cell by default.
+    pub fn size(&self) -> (f32, f32) {
+        self.size
+    }
+
+    /// The grid one cell to a unit of the area, rounded up.
+    pub fn natural_grid(&self) -> (usize, usize) {
+        (self.size.0.ceil() as usize, self.size.1.ceil() as usize)
+    }
+
+    /// Draws the path over `height` rows of `width` cells, stretching its area to fit. Cells at least half covered by
+    /// the path are part of the maze.
+    pub fn rasterize(&self, width: usize, height: usize) -> Mask {
+        let Some(mut pixmap) = Pixmap::new(width as u32, height as u32) else {
+            return Mask::from_fn(width, height, |_, _| false);
+        };
+        let mut paint = Paint::default();
+        paint.set_color_rgba8(0, 0, 0, u8::MAX);
+        paint.anti_alias = true;
+        let transform = Transform::from_scale(width as f32 / self.size.0, height as f32 / self.size.1);
+        pixmap.fill_path(&self.path, &paint, self.fill_rule, transform, None);
+        Mask::from_fn(width, height, |row, col| pixmap.pixel(col as u32, row as u32).unwrap().alpha() > u8::MAX / 2)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Two squares, the second inside the first and drawn the same way round, so only even-odd fill leaves a hole.
+    const NESTED: &str = "# nested squares\nsize 10 10\nmove 0 0\nline 10 0\nline 10 10\nline 0 10\nclose\n\nmove 3 3\nline 7 3\nline 7 7\nline 3 7\nclose\n";
+
+    fn count(mask: &Mask, width: usize, height: usize) -> usize {
+        (0..height).flat_map(|row| (0..width).map(move |col| (row, col))).filter(|&(row, col)| mask.contains(row, col)).count()
+    }
+
+    #[test]
+    fn fill_rules_and_scales() {
+        let winding = VectorMask::read(NESTED.as_bytes()).unwrap();
+        assert_eq!(winding.natural_grid(), (10, 10));
+        assert_eq!(count(&winding.rasterize(10, 10), 10, 10), 100);
+        let even_odd = VectorMask::read(format!("fill evenodd\n{}", NESTED).as_bytes()).unwrap();
+        assert_eq!(count(&even_odd.rasterize(10, 10), 10, 10), 100 - 16);
+        assert_eq!(count(&even_odd.rasterize(200, 200), 200, 200), 200 * 200 - 80 * 80);
+
+        // A square with a corner rounded off, a sixth of it missing at every size
+        let rounded = VectorMask::read(b"move 0 0\nline 1 0\nquad 1 1 0 1\nclose").unwrap();
+        assert_eq!(rounded.size(), (1.0, 1.0));
+        for size in [20, 2000] {
+            let share = count(&rounded.rasterize(size, size), size, size) as f64 / (size * size) as f64;
+            assert!((share - 5.0 / 6.0).abs() < 0.02, "{} at {}", share, size);
+        }
+    }
+
+    #[test]
+    fn errors() {
+        let error = |text: &str| VectorMask::read(text.as_bytes()).unwrap_err();
+        assert_eq!(error("move 0 0\nline 1\n"), VectorMaskError { line: 2, kind: VectorMaskErrorKind::Malformed });
+        assert_eq!(error("size 0 4\n"), VectorMaskError { line: 1, kind: VectorMaskErrorKind::EmptySize });
+        assert_eq!(error("fill evenodd\n").kind, VectorMaskErrorKind::NoShape);
+    }
+}